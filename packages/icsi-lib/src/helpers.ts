@@ -150,9 +150,9 @@ export async function getBalances(
 
       if (tx.operation && tx.operation[0]) {
         if ('Mint' in tx.operation[0]) {
-          amount = tx.operation[0].Mint.amount.e8s;
+          amount = tx.operation[0].Mint.amount;
         } else if ('Transfer' in tx.operation[0]) {
-          amount = tx.operation[0].Transfer.amount.e8s;
+          amount = tx.operation[0].Transfer.amount;
         }
       }
 
@@ -230,10 +230,10 @@ export async function getTransactionsByTokenType(
 
     if (tx.operation && tx.operation[0]) {
       if ('Mint' in tx.operation[0]) {
-        amount = tx.operation[0].Mint.amount.e8s;
+        amount = tx.operation[0].Mint.amount;
         to = Buffer.from(tx.operation[0].Mint.to).toString('hex');
       } else if ('Transfer' in tx.operation[0]) {
-        amount = tx.operation[0].Transfer.amount.e8s;
+        amount = tx.operation[0].Transfer.amount;
         from = Buffer.from(tx.operation[0].Transfer.from).toString('hex');
         to = Buffer.from(tx.operation[0].Transfer.to).toString('hex');
       }
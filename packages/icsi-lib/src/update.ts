@@ -240,7 +240,7 @@ export async function registerToken(
  * @param {HttpAgent} agent - The HTTP agent used for the call.
  * @param {string} userVaultCanisterId - The canister ID of the user vault.
  * @param {string} subaccountId - The ID of the subaccount to sweep from.
- * @param {number} amount - The amount to sweep.
+ * @param {bigint} amount - The amount to sweep, in the token's base units (e8s for ICP).
  * @param {TokenType} [tokenType] - The token type to sweep (ICP, CKUSDC, CKUSDT, or CKBTC). Defaults to ICP if not provided.
  * @returns {Promise<any>} - A promise that resolves with the result of the sweep operation.
  * @throws {Error} - Throws an error if the User Vault Canister ID is undefined.
@@ -249,7 +249,7 @@ export async function sweepSubaccountId(
   agent: HttpAgent,
   userVaultCanisterId: string,
   subaccountId: string,
-  amount: number,
+  amount: bigint,
   tokenType?: TokenType
 ) {
   const actor = createUserVaultActor(agent, userVaultCanisterId);
@@ -3,22 +3,18 @@ import type { ActorMethod } from '@dfinity/agent';
 import type { IDL } from '@dfinity/candid';
 
 export interface Approve {
-  fee: E8s;
+  fee: bigint;
   from: Uint8Array | number[];
-  allowance_e8s: bigint;
-  allowance: E8s;
-  expected_allowance: [] | [E8s];
+  allowance: bigint;
+  expected_allowance: [] | [bigint];
   expires_at: [] | [Timestamp];
   spender: Uint8Array | number[];
 }
 export interface Burn {
   from: Uint8Array | number[];
-  amount: E8s;
+  amount: bigint;
   spender: [] | [Uint8Array | number[]];
 }
-export interface E8s {
-  e8s: bigint;
-}
 export interface Error {
   message: string;
 }
@@ -33,7 +29,7 @@ export interface HttpResponse {
 }
 export interface Mint {
   to: Uint8Array | number[];
-  amount: E8s;
+  amount: bigint;
 }
 export type Network = { Mainnet: null } | { Local: null };
 export type Operation =
@@ -81,9 +77,9 @@ export type TokenType =
   | { CKBTC: null };
 export interface Transfer {
   to: Uint8Array | number[];
-  fee: E8s;
+  fee: bigint;
   from: Uint8Array | number[];
-  amount: E8s;
+  amount: bigint;
   spender: [] | [Uint8Array | number[]];
 }
 export interface TransformArgs {
@@ -124,7 +120,7 @@ export interface _SERVICE {
   single_sweep: ActorMethod<[string], Result_13>;
   sweep: ActorMethod<[], Result_13>;
   sweep_by_token_type: ActorMethod<[TokenType], Result_13>;
-  sweep_subaccount: ActorMethod<[string, number, [] | [TokenType]], Result_12>;
+  sweep_subaccount: ActorMethod<[string, bigint, [] | [TokenType]], Result_12>;
   transform: ActorMethod<[TransformArgs], HttpResponse>;
   validate_icrc_account: ActorMethod<[string], Result_14>;
 }
@@ -140,27 +136,25 @@ export const idlFactory = ({ IDL }: any): IDL.ServiceClass => {
     FailedToSweep: IDL.Null,
     NotSwept: IDL.Null,
   });
-  const E8s = IDL.Record({ e8s: IDL.Nat64 });
   const Approve = IDL.Record({
-    fee: E8s,
+    fee: IDL.Nat,
     from: IDL.Vec(IDL.Nat8),
-    allowance_e8s: IDL.Int64,
-    allowance: E8s,
-    expected_allowance: IDL.Opt(E8s),
+    allowance: IDL.Nat,
+    expected_allowance: IDL.Opt(IDL.Nat),
     expires_at: IDL.Opt(Timestamp),
     spender: IDL.Vec(IDL.Nat8),
   });
   const Burn = IDL.Record({
     from: IDL.Vec(IDL.Nat8),
-    amount: E8s,
+    amount: IDL.Nat,
     spender: IDL.Opt(IDL.Vec(IDL.Nat8)),
   });
-  const Mint = IDL.Record({ to: IDL.Vec(IDL.Nat8), amount: E8s });
+  const Mint = IDL.Record({ to: IDL.Vec(IDL.Nat8), amount: IDL.Nat });
   const Transfer = IDL.Record({
     to: IDL.Vec(IDL.Nat8),
-    fee: E8s,
+    fee: IDL.Nat,
     from: IDL.Vec(IDL.Nat8),
-    amount: E8s,
+    amount: IDL.Nat,
     spender: IDL.Opt(IDL.Vec(IDL.Nat8)),
   });
   const Operation = IDL.Variant({
@@ -271,7 +265,7 @@ export const idlFactory = ({ IDL }: any): IDL.ServiceClass => {
     sweep: IDL.Func([], [Result_13], []),
     sweep_by_token_type: IDL.Func([TokenType], [Result_13], []),
     sweep_subaccount: IDL.Func(
-      [IDL.Text, IDL.Nat32, IDL.Opt(TokenType)],
+      [IDL.Text, IDL.Nat, IDL.Opt(TokenType)],
       [Result_12],
       []
     ),
@@ -9,7 +9,7 @@ export function createMockStoredTransaction(
     operation: [
       {
         Mint: {
-          amount: { e8s: BigInt(1000000) },
+          amount: BigInt(1000000),
           to: new Uint8Array([1, 2, 3]),
         },
       },
@@ -276,7 +276,7 @@ async function testUpdateFunctions() {
         agent,
         DEVNET_CANISTER_ID,
         'test-subaccount',
-        0.001,
+        BigInt(100_000),
         firstTx.token_type
       );
       console.log('   ✅ Sweep subaccount result:', formatResult(result));
@@ -288,7 +288,7 @@ async function testUpdateFunctions() {
         agent,
         DEVNET_CANISTER_ID,
         'test-subaccount',
-        0.001
+        BigInt(100_000)
       );
       console.log('   ✅ Sweep subaccount result:', formatResult(result));
     }
@@ -79,13 +79,13 @@ async function getBalances() {
 
             // Try to extract balance from transaction
             if (tx.operation && tx.operation[0] && 'Mint' in tx.operation[0]) {
-              balance += tx.operation[0].Mint.amount.e8s;
+              balance += tx.operation[0].Mint.amount;
             } else if (
               tx.operation &&
               tx.operation[0] &&
               'Transfer' in tx.operation[0]
             ) {
-              balance += tx.operation[0].Transfer.amount.e8s;
+              balance += tx.operation[0].Transfer.amount;
             }
           }
         }
@@ -286,7 +286,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(1000000) },
+                  amount: BigInt(1000000),
                   to: new Uint8Array([1, 2, 3]),
                 },
               },
@@ -304,7 +304,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(5000000) },
+                  amount: BigInt(5000000),
                   to: new Uint8Array([4, 5, 6]),
                 },
               },
@@ -322,7 +322,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(500000) },
+                  amount: BigInt(500000),
                   to: new Uint8Array([7, 8, 9]),
                 },
               },
@@ -392,7 +392,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(1000000) },
+                  amount: BigInt(1000000),
                   to: new Uint8Array([1, 2, 3]),
                 },
               },
@@ -410,7 +410,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(2000000) },
+                  amount: BigInt(2000000),
                   to: new Uint8Array([4, 5, 6]),
                 },
               },
@@ -445,7 +445,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(1000000) },
+                  amount: BigInt(1000000),
                   to: new Uint8Array([1, 2, 3]),
                 },
               },
@@ -461,7 +461,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(2000000) },
+                  amount: BigInt(2000000),
                   to: new Uint8Array([4, 5, 6]),
                 },
               },
@@ -477,7 +477,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(3000000) },
+                  amount: BigInt(3000000),
                   to: new Uint8Array([7, 8, 9]),
                 },
               },
@@ -517,7 +517,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(1000000) },
+                  amount: BigInt(1000000),
                   to: new Uint8Array([1, 2, 3]),
                 },
               },
@@ -550,7 +550,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(5000000) },
+                  amount: BigInt(5000000),
                   to: new Uint8Array([1, 2, 3]),
                 },
               },
@@ -583,7 +583,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(3000000) },
+                  amount: BigInt(3000000),
                   to: new Uint8Array([1, 2, 3]),
                 },
               },
@@ -647,7 +647,7 @@ describe('Helper Functions', () => {
             operation: [
               {
                 Mint: {
-                  amount: { e8s: BigInt(1500000) },
+                  amount: BigInt(1500000),
                   to: new Uint8Array([1, 2, 3]),
                 },
               },
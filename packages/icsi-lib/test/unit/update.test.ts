@@ -379,7 +379,7 @@ describe('Update Functions', () => {
   describe('sweepSubaccountId', () => {
     it('should handle sweep from specific subaccount', async () => {
       const subaccountId = 'subaccount-123';
-      const amount = 0.001;
+      const amount = 100_000n;
 
       const mockResult = { Ok: 12345n };
       mockActor.sweep_subaccount.mockResolvedValue(mockResult);
@@ -402,7 +402,7 @@ describe('Update Functions', () => {
 
     it('should handle different token types', async () => {
       const subaccountId = 'subaccount-123';
-      const amount = 0.1;
+      const amount = 10_000_000n;
       const tokenTypes = [Tokens.ICP, Tokens.CKUSDC, Tokens.CKUSDT];
 
       for (const tokenType of tokenTypes) {
@@ -0,0 +1,53 @@
+use crate::hashof::{HashOf, HASH_LENGTH};
+use crate::Icrc3Value;
+use sha2::{Digest, Sha256};
+
+fn sha256(bytes: &[u8]) -> [u8; HASH_LENGTH] {
+    Sha256::digest(bytes).into()
+}
+
+/// Representation-independent hash of an ICRC-3 value.
+///
+/// Follows the ICRC-3 standard: numbers are hashed through their (S)LEB128
+/// encoding, text and blobs through their raw bytes, arrays through the
+/// concatenation of their element hashes and maps through the sorted
+/// concatenation of key/value hash pairs.
+pub fn hash_value(value: &Icrc3Value) -> HashOf<Icrc3Value> {
+    HashOf::new(hash_value_bytes(value))
+}
+
+fn hash_value_bytes(value: &Icrc3Value) -> [u8; HASH_LENGTH] {
+    match value {
+        Icrc3Value::Nat(n) => {
+            let mut buf = Vec::new();
+            n.encode(&mut buf).expect("writing to a Vec cannot fail");
+            sha256(&buf)
+        }
+        Icrc3Value::Int(i) => {
+            let mut buf = Vec::new();
+            i.encode(&mut buf).expect("writing to a Vec cannot fail");
+            sha256(&buf)
+        }
+        Icrc3Value::Text(text) => sha256(text.as_bytes()),
+        Icrc3Value::Blob(bytes) => sha256(bytes),
+        Icrc3Value::Array(values) => {
+            let mut buf = Vec::with_capacity(values.len() * HASH_LENGTH);
+            for value in values {
+                buf.extend_from_slice(&hash_value_bytes(value));
+            }
+            sha256(&buf)
+        }
+        Icrc3Value::Map(entries) => {
+            let mut pairs: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut pair = sha256(key.as_bytes()).to_vec();
+                    pair.extend_from_slice(&hash_value_bytes(value));
+                    pair
+                })
+                .collect();
+            pairs.sort();
+            sha256(&pairs.concat())
+        }
+    }
+}
@@ -202,32 +202,6 @@ pub struct Transaction {
     pub icrc1_memo: Option<ByteBuf>,
 }
 
-impl Transaction {
-    pub fn new(
-        from: AccountIdentifier,
-        to: AccountIdentifier,
-        spender: Option<AccountIdentifier>,
-        amount: Tokens,
-        fee: Tokens,
-        memo: Memo,
-        created_at_time: TimeStamp,
-    ) -> Self {
-        let operation = Operation::Transfer {
-            from,
-            to,
-            spender,
-            amount,
-            fee,
-        };
-        Transaction {
-            operation,
-            memo,
-            icrc1_memo: None,
-            created_at_time: Some(created_at_time),
-        }
-    }
-}
-
 pub trait LedgerTransaction: Sized {
     type AccountId: Clone;
     // type Tokens: Tokens;
@@ -9,13 +9,18 @@ use ic_cdk::api::{
 };
 use ic_cdk_macros::*;
 use ic_cdk_timers::TimerId;
+use ic_stable_structures::{StableBTreeMap, Storable};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::{hash_map::DefaultHasher, HashMap};
+use std::collections::{BTreeSet, HashMap};
 use std::future::Future;
-use std::hash::{Hash, Hasher};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::thread::LocalKey;
 
 mod hashof;
+mod icrc3;
 mod ledger;
 mod memory;
 mod tests;
@@ -24,20 +29,37 @@ mod types;
 use ledger::*;
 
 use ic_ledger_types::{
-    query_archived_blocks as ic_query_archived_blocks, query_blocks as ic_query_blocks,
-    AccountIdentifier, BlockIndex, GetBlocksArgs, Memo, Subaccount, Tokens, TransferArgs,
-    DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID,
+    AccountIdentifier, BlockIndex, Memo, Subaccount, Tokens, TransferArgs, DEFAULT_SUBACCOUNT,
+    MAINNET_LEDGER_CANISTER_ID,
 };
 use icrc_ledger_types::icrc1::transfer::TransferArg as Icrc1TransferArg;
 use num_traits::ToPrimitive;
+#[cfg(not(test))]
+use {
+    candid::types::reference::Func,
+    ic_ledger_types::{
+        query_archived_blocks as ic_query_archived_blocks, query_blocks as ic_query_blocks,
+        GetBlocksArgs, QueryArchiveFn,
+    },
+    icrc_ledger_types::icrc::generic_metadata_value::MetadataValue,
+    types::{ArchiveCallback, ArchivedBlock},
+};
 
 use types::{
-    Approve, Block, Burn, E8s, Mint, Operation, Timestamp, TokenType, Transaction, Transfer,
+    Approve, BackfillJob, BackfillStatus, Block, BlockHashRecord, Burn, ChainAlert,
+    Icrc1TransferError, LedgerReset, Mint, Operation, SkippedTicks, Timestamp, TipAnchor,
+    TokenConfig, TokenLag, TokenMetadata, TokenStandard, TokenType, Transaction, Transfer,
 };
 
 use memory::{
-    CONNECTED_NETWORK, CUSTODIAN_PRINCIPAL, INTERVAL_IN_SECONDS, LAST_SUBACCOUNT_NONCE, NEXT_BLOCK,
-    PRINCIPAL, TOKEN_LEDGER_PRINCIPALS, TOKEN_NEXT_BLOCKS, TRANSACTIONS, WEBHOOK_URL,
+    BACKFILL_JOBS, BALANCE_DISCREPANCIES, CONNECTED_NETWORK, CUSTODIAN_PRINCIPAL,
+    INTERVAL_IN_SECONDS, LAST_SUBACCOUNT_NONCE, LEGACY_TOKEN_LEDGER_PRINCIPALS,
+    LEGACY_TOKEN_NEXT_BLOCKS, LEGACY_TRANSACTIONS, MIGRATION_PROGRESS, NEXT_BLOCK, PRINCIPAL,
+    PRUNED_DEPOSITS, SUBACCOUNT_BALANCES, SUBACCOUNT_INDEX_CURSORS, SUBACCOUNT_NONCES,
+    SUBACCOUNT_TX_INDEX, TOKEN_CHAIN_ALERTS, TOKEN_CHAIN_LENGTHS, TOKEN_INDEX_CANISTERS,
+    TOKEN_INTERVALS, TOKEN_LAST_BLOCK_HASH, TOKEN_LEDGER_RESETS, TOKEN_NEXT_BLOCKS, TOKEN_REGISTRY,
+    TOKEN_SKIPPED_TICKS, TOKEN_TIP_ANCHORS, TRANSACTIONS, TX_HASH_INDEX, TX_TIME_INDEX,
+    WEBHOOK_URL,
 };
 
 // Canister IDs for ICRC tokens
@@ -51,17 +73,28 @@ const CKUSDT_LEDGER_CANISTER_ID: Principal =
 const CKBTC_LEDGER_CANISTER_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 2, 48, 0, 6, 1, 1]);
 
 use types::{
-    CallerGuard, CanisterApiManager, CanisterApiManagerTrait, IcCdkSpawnManager,
-    IcCdkSpawnManagerTrait, IcrcAccount, InterCanisterCallManager, InterCanisterCallManagerTrait,
-    Network, QueryBlocksRequest, QueryBlocksResponse, StoredPrincipal, StoredTransactions,
-    SweepStatus, TimerManager, TimerManagerTrait,
+    BackfillGuard, BalanceDiscrepancy, CallerGuard, CanisterApiManager, CanisterApiManagerTrait,
+    IcCdkSpawnManager, IcCdkSpawnManagerTrait, IcrcAccount, InterCanisterCallManager,
+    InterCanisterCallManagerTrait, Memory, MigrationProgress, MigrationStatus, Network,
+    QueryBlocksRequest, QueryBlocksResponse, ReconciliationGuard, ReconciliationReport, SortOrder,
+    StoredPrincipal, StoredTransactions, SubaccountBalance, SweepStatus, TimerManager,
+    TimerManagerTrait, TokenGuard, TransactionCursor, TransactionFilter, TransactionKey,
+    TransactionsPage, Versioned,
 };
 
 thread_local! {
     static NETWORK: RefCell<Network> = const { RefCell::new(Network::Local) };
-    static LIST_OF_SUBACCOUNTS: RefCell<HashMap<u64, Subaccount>> = RefCell::default();
     static TIMERS: RefCell<TimerId> = RefCell::default();
+    // Timers of tokens polled on their own interval
     static TOKEN_LEDGER_TIMERS: RefCell<HashMap<TokenType, TimerId>> = RefCell::default();
+    // Subaccount nonce an index pass that was cut short resumes at
+    static INDEX_RESUME_NONCES: RefCell<HashMap<Principal, u32>> = RefCell::default();
+    // Most blocks a ledger returned for one request, once it returned fewer than asked for
+    static LEDGER_PAGE_SIZES: RefCell<HashMap<Principal, u64>> = RefCell::default();
+    // Runs backfill chunks while a backfill job is running
+    static BACKFILL_TIMER: RefCell<Option<TimerId>> = RefCell::default();
+    // Runs migration batches while a migration is not completed
+    static MIGRATION_TIMER: RefCell<Option<TimerId>> = RefCell::default();
 }
 
 #[derive(Debug, CandidType, Deserialize, Serialize)]
@@ -69,27 +102,6 @@ struct Error {
     message: String,
 }
 
-trait ToU64Hash {
-    fn to_u64_hash(&self) -> u64;
-}
-
-impl ToU64Hash for [u8; 32] {
-    fn to_u64_hash(&self) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        hasher.finish()
-    }
-}
-
-impl ToU64Hash for AccountIdentifier {
-    fn to_u64_hash(&self) -> u64 {
-        let bytes = from_hex(&self.to_hex()).unwrap();
-        let mut hasher = DefaultHasher::new();
-        bytes.hash(&mut hasher);
-        hasher.finish()
-    }
-}
-
 fn network() -> Network {
     NETWORK.with(|net| *net.borrow())
 }
@@ -124,33 +136,34 @@ fn authenticate() -> Result<(), String> {
     Ok(())
 }
 
-fn includes_hash(vec_to_check: &[u8]) -> bool {
-    match vec_to_check.len() {
-        32 => {
-            let array_ref: Option<&[u8; 32]> = vec_to_check.try_into().ok();
-
-            match array_ref {
-                Some(array_ref) => {
-                    let data: [u8; 32] = *array_ref;
-                    let hash_key = data.to_u64_hash();
-
-                    LIST_OF_SUBACCOUNTS.with(|subaccounts| {
-                        let subaccounts_borrow = subaccounts.borrow();
-
-                        ic_cdk::println!("hash_key: {}", hash_key);
-                        subaccounts_borrow.get(&hash_key).is_some()
-                    })
-                }
-                None => false,
-            }
+// Whether account identifier bytes from a block belong to one of the
+// canister's subaccounts. ICRC accounts are matched through their account
+// identifier, see icrc_account_bytes.
+fn is_canister_account(vec_to_check: &[u8]) -> bool {
+    match <[u8; 32]>::try_from(vec_to_check) {
+        Ok(account_id) => {
+            SUBACCOUNT_NONCES.with(|nonces| nonces.borrow().contains_key(&account_id))
         }
-        other => {
-            ic_cdk::println!("vec_to_check len: {}", other);
+        Err(_) => {
+            ic_cdk::println!("vec_to_check len: {}", vec_to_check.len());
             false
         }
     }
 }
 
+// Records the account identifier of a subaccount nonce
+fn index_subaccount(nonce: u32) -> AccountIdentifier {
+    let subaccountid = to_subaccount_id(to_subaccount(nonce));
+    let mut account_id = [0u8; 32];
+    account_id.copy_from_slice(subaccountid.as_ref());
+    SUBACCOUNT_NONCES.with(|nonces| nonces.borrow_mut().insert(account_id, nonce));
+    subaccountid
+}
+
+fn subaccount_nonce(accountid: &AccountIdentifier) -> Option<u32> {
+    account_id_nonce(accountid.as_ref())
+}
+
 #[update]
 async fn set_next_block(block: u64) -> Result<u64, Error> {
     authenticate().map_err(|e| {
@@ -270,47 +283,227 @@ impl IcCdkSpawnManagerTrait for IcCdkSpawnManager {
 }
 
 fn get_subaccount(accountid: &AccountIdentifier) -> Result<Subaccount, Error> {
-    LIST_OF_SUBACCOUNTS.with(|subaccounts| {
-        let subaccounts_borrow = subaccounts.borrow();
-        let account_id_hash = accountid.to_u64_hash();
-        // find matching hashkey
-        match subaccounts_borrow.get(&account_id_hash) {
-            Some(value) => Ok(*value),
-            None => {
-                let error_msg = "Account not found".to_string();
-                ic_cdk::println!("Error: {}", error_msg);
-                Err(Error { message: error_msg })
-            }
+    match subaccount_nonce(accountid) {
+        Some(nonce) => Ok(to_subaccount(nonce)),
+        None => {
+            let error_msg = "Account not found".to_string();
+            ic_cdk::println!("Error: {}", error_msg);
+            Err(Error { message: error_msg })
         }
+    }
+}
+
+// Tokens that ship with built-in presets
+const BUILTIN_TOKENS: [TokenType; 4] = [
+    TokenType::ICP,
+    TokenType::CKUSDC,
+    TokenType::CKUSDT,
+    TokenType::CKBTC,
+];
+
+// Preset metadata for the built-in tokens, used until a token is registered
+fn preset_token_config(token_type: &TokenType) -> Option<TokenConfig> {
+    let (ledger_canister_id, symbol, decimals, fee, standard) = match token_type {
+        // The ledger configured at init is the one the default ICP poller indexes
+        TokenType::ICP => (
+            PRINCIPAL
+                .with(|stored_ref| stored_ref.borrow().get().get_principal())
+                .unwrap_or(MAINNET_LEDGER_CANISTER_ID),
+            "ICP",
+            8,
+            10_000,
+            TokenStandard::ICP,
+        ),
+        TokenType::CKUSDC => (
+            CKUSDC_LEDGER_CANISTER_ID,
+            "ckUSDC",
+            6,
+            10_000,
+            TokenStandard::ICRC1,
+        ),
+        TokenType::CKUSDT => (
+            CKUSDT_LEDGER_CANISTER_ID,
+            "ckUSDT",
+            6,
+            10_000,
+            TokenStandard::ICRC1,
+        ),
+        TokenType::CKBTC => (
+            CKBTC_LEDGER_CANISTER_ID,
+            "ckBTC",
+            8,
+            10, // 10 satoshis for ckBTC
+            TokenStandard::ICRC1,
+        ),
+        TokenType::Custom(_) => return None,
+    };
+
+    Some(TokenConfig {
+        token_type: token_type.clone(),
+        ledger_canister_id,
+        symbol: symbol.to_string(),
+        decimals,
+        fee,
+        standard,
+        fee_updated_at: None,
     })
 }
 
-// Helper functions for per-token block tracking
-fn get_token_id(token_type: &TokenType) -> u8 {
-    match token_type {
-        TokenType::ICP => 1,
-        TokenType::CKUSDC => 2,
-        TokenType::CKUSDT => 3,
-        TokenType::CKBTC => 4,
+fn get_token_config(token_type: &TokenType) -> Option<TokenConfig> {
+    let registered = TOKEN_REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .iter()
+            .map(|(_, config)| config)
+            .find(|config| config.token_type == *token_type)
+    });
+
+    registered.or_else(|| preset_token_config(token_type))
+}
+
+#[cfg(not(test))]
+fn get_token_config_by_ledger(ledger_principal: Principal) -> Option<TokenConfig> {
+    TOKEN_REGISTRY
+        .with(|registry| registry.borrow().get(&ledger_principal))
+        .or_else(|| {
+            BUILTIN_TOKENS
+                .iter()
+                .filter_map(preset_token_config)
+                .find(|config| config.ledger_canister_id == ledger_principal)
+        })
+}
+
+fn is_icrc_token(token_type: &TokenType) -> bool {
+    match get_token_config(token_type) {
+        Some(config) => config.standard == TokenStandard::ICRC1,
+        // Unregistered custom tokens can only be ICRC-1 ledgers
+        None => true,
+    }
+}
+
+fn get_token_fee(token_type: &TokenType) -> u64 {
+    get_token_config(token_type)
+        .map(|config| config.fee)
+        .unwrap_or(10_000)
+}
+
+// Ledger fees can change through upgrades, so cached fees are re-read hourly
+const FEE_REFRESH_INTERVAL_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+fn is_fee_stale(config: &TokenConfig, now: u64) -> bool {
+    match config.fee_updated_at {
+        Some(updated_at) => now.saturating_sub(updated_at) >= FEE_REFRESH_INTERVAL_NANOS,
+        None => true,
+    }
+}
+
+// A failed refresh is retried this long after, rather than on every tick
+const FEE_RETRY_INTERVAL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+fn update_token_config(ledger_principal: Principal, update: impl FnOnce(&mut TokenConfig)) {
+    TOKEN_REGISTRY.with(|registry| {
+        let mut registry_mut = registry.borrow_mut();
+        if let Some(mut config) = registry_mut.get(&ledger_principal) {
+            update(&mut config);
+            registry_mut.insert(ledger_principal, config);
+        }
+    });
+}
+
+fn store_token_fee(ledger_principal: Principal, fee: u64) {
+    let now = CanisterApiManager::time();
+    update_token_config(ledger_principal, |config| {
+        config.fee = fee;
+        config.fee_updated_at = Some(now);
+    });
+}
+
+async fn refresh_token_fee(ledger_principal: Principal) -> Result<u64, String> {
+    let fee = InterCanisterCallManager::query_token_fee(ledger_principal).await?;
+    store_token_fee(ledger_principal, fee);
+    Ok(fee)
+}
+
+async fn refresh_stale_token_fees() {
+    let now = CanisterApiManager::time();
+    let stale: Vec<Principal> = TOKEN_REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .iter()
+            .filter(|(_, config)| is_fee_stale(config, now))
+            .map(|(ledger, _)| ledger)
+            .collect()
+    });
+
+    for ledger_principal in stale {
+        match refresh_token_fee(ledger_principal).await {
+            Ok(fee) => ic_cdk::println!("Refreshed fee for ledger {}: {}", ledger_principal, fee),
+            Err(e) => {
+                ic_cdk::println!(
+                    "Failed to refresh fee for ledger {}: {}",
+                    ledger_principal,
+                    e
+                );
+                // The cached fee stays in use and turns stale again after the retry interval
+                update_token_config(ledger_principal, |config| {
+                    config.fee_updated_at = Some(
+                        now.saturating_sub(FEE_REFRESH_INTERVAL_NANOS) + FEE_RETRY_INTERVAL_NANOS,
+                    );
+                });
+            }
+        }
     }
 }
 
+// Built-in tokens followed by the custom tokens in the registry
+fn known_token_types() -> Vec<TokenType> {
+    let mut token_types = BUILTIN_TOKENS.to_vec();
+    TOKEN_REGISTRY.with(|registry| {
+        for (_, config) in registry.borrow().iter() {
+            if !token_types.contains(&config.token_type) {
+                token_types.push(config.token_type);
+            }
+        }
+    });
+    token_types
+}
+
+// Helper functions for per-token block tracking, keyed by ledger canister id
 fn get_token_next_block(token_type: &TokenType) -> u64 {
+    let ledger_principal = get_token_ledger_canister_id(token_type);
     TOKEN_NEXT_BLOCKS.with(|blocks| {
         let blocks_borrow = blocks.borrow();
-        let token_id = get_token_id(token_type);
-        blocks_borrow.get(&token_id).unwrap_or(1) // Default to block 1
+        blocks_borrow.get(&ledger_principal).unwrap_or(1) // Default to block 1
     })
 }
 
 fn set_token_next_block(token_type: &TokenType, block: u64) {
+    let ledger_principal = get_token_ledger_canister_id(token_type);
     TOKEN_NEXT_BLOCKS.with(|blocks| {
         let mut blocks_mut = blocks.borrow_mut();
-        let token_id = get_token_id(token_type);
-        blocks_mut.insert(token_id, block);
+        blocks_mut.insert(ledger_principal, block);
     });
 }
 
+fn amount_after_fee(amount: &candid::Nat, fee: u64) -> Result<candid::Nat, Error> {
+    let fee = candid::Nat::from(fee);
+    if *amount < fee {
+        let error_msg = format!("Amount {} is less than the fee {}", amount, fee);
+        ic_cdk::println!("Error: {}", error_msg);
+        return Err(Error { message: error_msg });
+    }
+    Ok(amount.clone() - fee)
+}
+
+// ICP ledger transfers take u64 e8s amounts
+fn to_e8s(amount: &candid::Nat) -> Result<u64, Error> {
+    amount.0.to_u64().ok_or_else(|| {
+        let error_msg = format!("Amount {} does not fit in u64 e8s", amount);
+        ic_cdk::println!("Error: {}", error_msg);
+        Error { message: error_msg }
+    })
+}
+
 fn to_sweep_args(tx: &StoredTransactions) -> Result<(TransferArgs, Principal), Error> {
     let custodian_id = get_custodian_id().map_err(|e| {
         ic_cdk::println!("Error getting custodian ID: {}", e);
@@ -322,11 +515,20 @@ fn to_sweep_args(tx: &StoredTransactions) -> Result<(TransferArgs, Principal), E
         Error { message: error_msg }
     })?;
     match operation {
-        Operation::Transfer(data) => {
+        // Minted deposits are swept like transfers, as on ICRC ledgers
+        Operation::Transfer(Transfer {
+            to: topup_to,
+            amount: deposit_amount,
+            ..
+        })
+        | Operation::Mint(Mint {
+            to: topup_to,
+            amount: deposit_amount,
+        }) => {
             // construct sweep destination -> custodian id
 
             // construct sweep source of funds
-            let topup_to = data.to.clone();
+            let topup_to = topup_to.clone();
             let topup_to = topup_to.as_slice();
             let sweep_from = AccountIdentifier::from_slice(topup_to).map_err(|err| {
                 let error_msg = format!("Error converting to to AccountIdentifier: {:?}", err);
@@ -341,7 +543,8 @@ fn to_sweep_args(tx: &StoredTransactions) -> Result<(TransferArgs, Principal), E
             })?;
 
             // calculate amount
-            let amount = data.amount.e8s - 10_000;
+            let fee = get_token_fee(&tx.token_type);
+            let amount = to_e8s(&amount_after_fee(deposit_amount, fee)?)?;
 
             // Get the ledger canister ID for the token type
             let token_ledger_canister_id = get_token_ledger_canister_id(&tx.token_type);
@@ -351,7 +554,7 @@ fn to_sweep_args(tx: &StoredTransactions) -> Result<(TransferArgs, Principal), E
                     memo: Memo(0),
                     amount: Tokens::from_e8s(amount),
                     from_subaccount: Some(sweep_source_subaccount),
-                    fee: Tokens::from_e8s(10_000),
+                    fee: Tokens::from_e8s(fee),
                     to: custodian_id,
                     created_at_time: None,
                 },
@@ -359,7 +562,7 @@ fn to_sweep_args(tx: &StoredTransactions) -> Result<(TransferArgs, Principal), E
             ))
         }
         _ => {
-            let error_msg = "Operation is not a transfer".to_string();
+            let error_msg = "Operation is not a transfer or mint".to_string();
             ic_cdk::println!("Error: {}", error_msg);
             Err(Error { message: error_msg })
         }
@@ -399,13 +602,14 @@ fn to_refund_args(tx: &StoredTransactions) -> Result<(TransferArgs, Principal),
             // Get the ledger canister ID for the token type
             let token_ledger_canister_id = get_token_ledger_canister_id(&tx.token_type);
 
-            let amount = data.amount.e8s - 10_000;
+            let fee = get_token_fee(&tx.token_type);
+            let amount = to_e8s(&amount_after_fee(&data.amount, fee)?)?;
             Ok((
                 TransferArgs {
                     memo: Memo(0),
                     amount: Tokens::from_e8s(amount),
                     from_subaccount: Some(refund_source_subaccount),
-                    fee: Tokens::from_e8s(10_000),
+                    fee: Tokens::from_e8s(fee),
                     to: refund_to,
                     created_at_time: None,
                 },
@@ -436,9 +640,18 @@ fn to_icrc1_sweep_args(tx: &StoredTransactions) -> Result<(Icrc1TransferArg, Pri
     })?;
 
     match operation {
-        Operation::Transfer(data) => {
+        // Minter deposits (e.g. ckBTC) land in the subaccount as mint blocks
+        Operation::Transfer(Transfer {
+            to: topup_to,
+            amount: deposit_amount,
+            ..
+        })
+        | Operation::Mint(Mint {
+            to: topup_to,
+            amount: deposit_amount,
+        }) => {
             // Get the subaccount that received the funds
-            let topup_to = data.to.clone();
+            let topup_to = topup_to.clone();
             let topup_to = topup_to.as_slice();
             let sweep_from = AccountIdentifier::from_slice(topup_to).map_err(|err| {
                 let error_msg = format!("Error converting to to AccountIdentifier: {:?}", err);
@@ -456,13 +669,10 @@ fn to_icrc1_sweep_args(tx: &StoredTransactions) -> Result<(Icrc1TransferArg, Pri
             let token_ledger_canister_id = get_token_ledger_canister_id(&tx.token_type);
 
             // Different tokens have different fees
-            let fee: u64 = match tx.token_type {
-                TokenType::CKBTC => 10, // 10 satoshis for ckBTC
-                _ => 10_000,            // 10,000 units for ckUSDC/ckUSDT
-            };
+            let fee = get_token_fee(&tx.token_type);
 
             // Calculate amount (subtract fee)
-            let amount = data.amount.e8s - fee;
+            let amount = amount_after_fee(deposit_amount, fee)?;
 
             // Create ICRC-1 transfer arguments
             let transfer_arg = Icrc1TransferArg {
@@ -474,7 +684,65 @@ fn to_icrc1_sweep_args(tx: &StoredTransactions) -> Result<(Icrc1TransferArg, Pri
                 memo: None,
                 from_subaccount: Some(sweep_source_subaccount.0),
                 created_at_time: None,
-                amount: candid::Nat::from(amount),
+                amount,
+            };
+
+            Ok((transfer_arg, token_ledger_canister_id))
+        }
+        _ => {
+            let error_msg = "Operation is not a transfer or mint".to_string();
+            ic_cdk::println!("Error: {}", error_msg);
+            Err(Error { message: error_msg })
+        }
+    }
+}
+
+fn to_icrc1_refund_args(tx: &StoredTransactions) -> Result<(Icrc1TransferArg, Principal), Error> {
+    let operation = tx.operation.as_ref().ok_or_else(|| {
+        let error_msg = "Operation is None".to_string();
+        ic_cdk::println!("Error: {}", error_msg);
+        Error { message: error_msg }
+    })?;
+
+    match operation {
+        Operation::Transfer(data) => {
+            // The sender's AccountIdentifier cannot be turned back into an ICRC account
+            let refund_to = tx.from_account.clone().ok_or_else(|| {
+                let error_msg = format!(
+                    "Transaction {} has no ICRC sender account to refund to",
+                    tx.index
+                );
+                ic_cdk::println!("Error: {}", error_msg);
+                Error { message: error_msg }
+            })?;
+
+            let topup_to = data.to.clone();
+            let topup_to = topup_to.as_slice();
+            let refund_source = AccountIdentifier::from_slice(topup_to).map_err(|err| {
+                let error_msg = format!("Error converting to to AccountIdentifier: {:?}", err);
+                ic_cdk::println!("{}", error_msg);
+                Error { message: error_msg }
+            })?;
+            let refund_source_subaccount = get_subaccount(&refund_source).map_err(|err| {
+                let error_msg = format!("Error getting to_subaccount: {}", err.message);
+                ic_cdk::println!("{}", error_msg);
+                Error { message: error_msg }
+            })?;
+
+            let token_ledger_canister_id = get_token_ledger_canister_id(&tx.token_type);
+            let fee = get_token_fee(&tx.token_type);
+            let amount = amount_after_fee(&data.amount, fee)?;
+
+            let transfer_arg = Icrc1TransferArg {
+                to: icrc_ledger_types::icrc1::account::Account {
+                    owner: refund_to.owner,
+                    subaccount: refund_to.subaccount,
+                },
+                fee: Some(candid::Nat::from(fee)),
+                memo: None,
+                from_subaccount: Some(refund_source_subaccount.0),
+                created_at_time: None,
+                amount,
             };
 
             Ok((transfer_arg, token_ledger_canister_id))
@@ -487,16 +755,20 @@ fn to_icrc1_sweep_args(tx: &StoredTransactions) -> Result<(Icrc1TransferArg, Pri
     }
 }
 
+fn transaction_key(tx: &StoredTransactions) -> TransactionKey {
+    let ledger_principal = tx
+        .token_ledger_canister_id
+        .unwrap_or_else(|| get_token_ledger_canister_id(&tx.token_type));
+    (ledger_principal, tx.index)
+}
+
 fn update_status(tx: &StoredTransactions, status: SweepStatus) -> Result<(), Error> {
     let index = tx.index;
     let mut tx_clone = tx.clone();
 
     tx_clone.sweep_status = status;
 
-    let prev_tx = TRANSACTIONS.with(|transactions_ref| {
-        let mut transactions = transactions_ref.borrow_mut();
-        transactions.insert(index, tx_clone)
-    });
+    let prev_tx = insert_transaction(transaction_key(tx), tx_clone);
 
     match prev_tx {
         Some(_) => Ok(()),
@@ -508,29 +780,286 @@ fn update_status(tx: &StoredTransactions, status: SweepStatus) -> Result<(), Err
     }
 }
 
-#[derive(CandidType, Deserialize, Debug, Clone)]
-enum Icrc1TransferError {
-    BadFee {
-        expected_fee: candid::Nat,
-    },
-    BadBurn {
-        min_burn_amount: candid::Nat,
-    },
-    InsufficientFunds {
-        balance: candid::Nat,
-    },
-    TooOld,
-    CreatedInFuture {
-        ledger_time: u64,
-    },
-    Duplicate {
-        duplicate_of: candid::Nat,
-    },
-    TemporarilyUnavailable,
-    GenericError {
-        error_code: candid::Nat,
-        message: String,
-    },
+// tx hashes are free-form strings, the index is keyed by their SHA-256 to keep
+// keys bounded
+fn tx_hash_digest(tx_hash: &str) -> [u8; 32] {
+    Sha256::digest(tx_hash.as_bytes()).into()
+}
+
+// Generated subaccounts a transaction touches
+fn transaction_nonces(tx: &StoredTransactions) -> BTreeSet<u32> {
+    tx.operation
+        .as_ref()
+        .map(|operation| {
+            operation
+                .accounts()
+                .into_iter()
+                .filter_map(account_id_nonce)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn index_transaction(key: TransactionKey, tx: &StoredTransactions) {
+    TX_HASH_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert((tx_hash_digest(&tx.tx_hash), key), ())
+    });
+    TX_TIME_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .insert((tx.created_at_time.timestamp_nanos, key), ())
+    });
+    SUBACCOUNT_TX_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for nonce in transaction_nonces(tx) {
+            index.insert((nonce, (tx.created_at_time.timestamp_nanos, key)), ());
+        }
+    });
+}
+
+fn unindex_transaction(key: TransactionKey, tx: &StoredTransactions) {
+    TX_HASH_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(tx_hash_digest(&tx.tx_hash), key))
+    });
+    TX_TIME_INDEX.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(tx.created_at_time.timestamp_nanos, key))
+    });
+    SUBACCOUNT_TX_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for nonce in transaction_nonces(tx) {
+            index.remove(&(nonce, (tx.created_at_time.timestamp_nanos, key)));
+        }
+    });
+}
+
+// Stores a transaction and keeps the secondary indexes in step. All writes to
+// TRANSACTIONS go through here and remove_transaction.
+fn insert_transaction(key: TransactionKey, tx: StoredTransactions) -> Option<StoredTransactions> {
+    let previous =
+        TRANSACTIONS.with(|transactions_ref| transactions_ref.borrow_mut().insert(key, tx.clone()));
+    if let Some(previous) = previous.as_ref() {
+        unindex_transaction(key, previous);
+    }
+    index_transaction(key, &tx);
+    previous
+}
+
+fn remove_transaction(key: &TransactionKey) -> Option<StoredTransactions> {
+    let removed = TRANSACTIONS.with(|transactions_ref| transactions_ref.borrow_mut().remove(key));
+    if let Some(removed) = removed.as_ref() {
+        unindex_transaction(*key, removed);
+    }
+    removed
+}
+
+// Up to `limit` time index entries of transactions created in
+// [from_nanos, to_nanos), oldest first, starting at the cursor when one is given
+fn transaction_keys_between(
+    from_nanos: u64,
+    to_nanos: u64,
+    cursor: Option<&TransactionCursor>,
+    limit: usize,
+) -> Vec<(u64, TransactionKey)> {
+    let start = match cursor {
+        Some(cursor) if cursor.timestamp_nanos >= from_nanos => cursor.entry(),
+        _ => (from_nanos, (Principal::from_slice(&[]), 0)),
+    };
+    TX_TIME_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(start..)
+            .take_while(|((timestamp_nanos, _), _)| *timestamp_nanos < to_nanos)
+            .take(limit)
+            .map(|(entry, _)| entry)
+            .collect()
+    })
+}
+
+// Stored transactions with the given tx hash; usually one, but blocks whose
+// hash could not be computed share a placeholder
+fn transactions_by_hash(tx_hash: &str) -> Vec<(TransactionKey, StoredTransactions)> {
+    let digest = tx_hash_digest(tx_hash);
+    let keys: Vec<TransactionKey> = TX_HASH_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((digest, (Principal::from_slice(&[]), 0))..)
+            .take_while(|((entry_digest, _), _)| *entry_digest == digest)
+            .map(|((_, key), _)| key)
+            .collect()
+    });
+    TRANSACTIONS.with(|transactions_ref| {
+        let transactions = transactions_ref.borrow();
+        keys.into_iter()
+            .filter_map(|key| transactions.get(&key).map(|tx| (key, tx)))
+            .filter(|(_, tx)| tx.tx_hash == tx_hash)
+            .collect()
+    })
+}
+
+fn update_subaccount_balance(
+    nonce: u32,
+    ledger_principal: Principal,
+    token_type: &TokenType,
+    update: impl FnOnce(&mut SubaccountBalance),
+) {
+    SUBACCOUNT_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let key = (nonce, ledger_principal);
+        let mut balance = balances.get(&key).unwrap_or_else(|| SubaccountBalance {
+            nonce,
+            token_type: token_type.clone(),
+            ledger_canister_id: ledger_principal,
+            balance: candid::Nat::from(0u64),
+            credited: candid::Nat::from(0u64),
+            debited: candid::Nat::from(0u64),
+            updated_at: 0,
+        });
+        update(&mut balance);
+        balance.updated_at = CanisterApiManager::time();
+        balances.insert(key, balance);
+    });
+}
+
+// Generated subaccount a transaction deposits into, with the amount
+fn subaccount_deposit(tx: &StoredTransactions) -> Option<(u32, &candid::Nat)> {
+    let (to, amount) = match tx.operation.as_ref() {
+        Some(Operation::Transfer(data)) => (&data.to, &data.amount),
+        Some(Operation::Mint(data)) => (&data.to, &data.amount),
+        _ => return None,
+    };
+    account_id_nonce(to).map(|nonce| (nonce, amount))
+}
+
+// Deposits into a generated subaccount credit its running balance once, when
+// the block is first indexed or seeded. Swept deposits have already left the
+// subaccount and are not counted at all.
+fn credit_deposit(key: TransactionKey, tx: &StoredTransactions) {
+    if tx.sweep_status == SweepStatus::Swept {
+        return;
+    }
+    if let Some((nonce, amount)) = subaccount_deposit(tx) {
+        update_subaccount_balance(nonce, key.0, &tx.token_type, |balance| {
+            balance.balance += amount.clone();
+            balance.credited += amount.clone();
+        });
+    }
+}
+
+// Whether the running balances account for the deposit stored under the key.
+// While balances are seeded from the stored transactions, the deposits past
+// the seeding cursor, and their sweeps, are left to the seeding.
+fn balance_tracks_deposit(key: &TransactionKey) -> bool {
+    MIGRATION_PROGRESS
+        .with(|progress| progress.borrow().get(&BALANCE_SEED_MIGRATION_ID))
+        .filter(|progress| progress.status != MigrationStatus::Completed)
+        .is_none_or(|progress| {
+            progress
+                .cursor
+                .is_some_and(|cursor| *key <= TransactionKey::from_bytes(Cow::Owned(cursor)))
+        })
+}
+
+// Transfers out of a subaccount debit amount and fee from its running balance.
+// Only what the balance has credited is debited, funds it never counted (e.g.
+// deposits the seeding has not reached yet) are left to reconciliation.
+fn debit_subaccount(
+    subaccount: Subaccount,
+    ledger_principal: Principal,
+    token_type: &TokenType,
+    amount: candid::Nat,
+) {
+    let account_id = AccountIdentifier::new(&CanisterApiManager::id(), &subaccount);
+    if let Some(nonce) = subaccount_nonce(&account_id) {
+        update_subaccount_balance(nonce, ledger_principal, token_type, |balance| {
+            let debit = amount.min(balance.balance.clone());
+            balance.balance -= debit.clone();
+            balance.debited += debit;
+        });
+    }
+}
+
+// Sweeps and refunds pass the deposit they move out of the subaccount
+async fn transfer_from_subaccount(
+    args: TransferArgs,
+    ledger_principal: Principal,
+    token_type: &TokenType,
+    deposit: Option<TransactionKey>,
+) -> Result<BlockIndex, String> {
+    let from_subaccount = args.from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT);
+    let debit = candid::Nat::from(args.amount.e8s()) + candid::Nat::from(args.fee.e8s());
+    let block_index = InterCanisterCallManager::transfer(args, ledger_principal).await?;
+    if deposit.as_ref().is_none_or(balance_tracks_deposit) {
+        debit_subaccount(from_subaccount, ledger_principal, token_type, debit);
+    }
+    Ok(block_index)
+}
+
+async fn icrc1_transfer_from_subaccount(
+    args: Icrc1TransferArg,
+    ledger_principal: Principal,
+    token_type: &TokenType,
+    deposit: Option<TransactionKey>,
+) -> Result<candid::Nat, String> {
+    let from_subaccount = Subaccount(args.from_subaccount.unwrap_or_default());
+    let fee = args
+        .fee
+        .clone()
+        .unwrap_or_else(|| candid::Nat::from(get_token_fee(token_type)));
+    let (block_index, debit) =
+        match InterCanisterCallManager::icrc1_transfer(args.clone(), ledger_principal).await? {
+            Ok(block_index) => (block_index, args.amount + fee),
+            // The cached fee is out of date, store the ledger's and retry once with it
+            Err(Icrc1TransferError::BadFee { expected_fee }) => {
+                ic_cdk::println!(
+                    "Fee of {:?} changed from {} to {}, retrying the transfer",
+                    token_type,
+                    fee,
+                    expected_fee
+                );
+                if let Some(expected_fee) = expected_fee.0.to_u64() {
+                    store_token_fee(ledger_principal, expected_fee);
+                }
+                // Sweeps and refunds move the whole deposit, the fee comes out of it
+                let amount = if deposit.is_some() {
+                    let total = args.amount + fee;
+                    if total < expected_fee {
+                        let error_msg =
+                            format!("Amount {} is less than the fee {}", total, expected_fee);
+                        ic_cdk::println!("Error: {}", error_msg);
+                        return Err(error_msg);
+                    }
+                    total - expected_fee.clone()
+                } else {
+                    args.amount
+                };
+                let retry = Icrc1TransferArg {
+                    amount: amount.clone(),
+                    fee: Some(expected_fee.clone()),
+                    ..args
+                };
+                let block_index = InterCanisterCallManager::icrc1_transfer(retry, ledger_principal)
+                    .await?
+                    .map_err(icrc1_transfer_error)?;
+                (block_index, amount + expected_fee)
+            }
+            Err(err) => return Err(icrc1_transfer_error(err)),
+        };
+    if deposit.as_ref().is_none_or(balance_tracks_deposit) {
+        debit_subaccount(from_subaccount, ledger_principal, token_type, debit);
+    }
+    Ok(block_index)
+}
+
+fn icrc1_transfer_error(err: Icrc1TransferError) -> String {
+    let error_message = format!("ICRC-1 transfer error: {:?}", err);
+    ic_cdk::println!("{}", error_message);
+    error_message
 }
 
 #[cfg(not(test))]
@@ -539,218 +1068,158 @@ impl InterCanisterCallManagerTrait for InterCanisterCallManager {
         ledger_principal: Principal,
         req: QueryBlocksRequest,
     ) -> CallResult<(QueryBlocksResponse,)> {
-        // Check if this is an ICRC-1 token (ckUSDC, ckUSDT, or ckBTC)
-        if ledger_principal == CKUSDC_LEDGER_CANISTER_ID
-            || ledger_principal == CKUSDT_LEDGER_CANISTER_ID
-            || ledger_principal == CKBTC_LEDGER_CANISTER_ID
-        {
+        if is_icrc_ledger(ledger_principal) {
             // ICRC-1 tokens use icrc3_get_blocks with a different structure
-            // Create GetBlocksRequest for ICRC-3
-            #[derive(CandidType, Deserialize)]
-            struct Icrc3GetBlocksRequest {
-                start: candid::Nat,
-                length: candid::Nat,
-            }
-
-            #[derive(CandidType, Deserialize)]
-            struct Icrc3BlockWithId {
-                id: candid::Nat,
-                block: Icrc3Value,
-            }
-
-            #[derive(CandidType, Deserialize)]
-            struct Icrc3GetBlocksResult {
-                log_length: candid::Nat,
-                blocks: Vec<Icrc3BlockWithId>,
-                archived_blocks: Vec<Icrc3ArchivedBlocks>,
-            }
-
-            #[derive(CandidType, Deserialize)]
-            struct Icrc3ArchivedBlocks {
-                args: Vec<Icrc3GetBlocksRequest>,
-                // We'll skip the callback for now
-            }
-
-            #[derive(CandidType, Deserialize, Clone, Debug)]
-            enum Icrc3Value {
-                Int(candid::Int),
-                Map(Vec<(String, Icrc3Value)>),
-                Nat(candid::Nat),
-                Blob(Vec<u8>),
-                Text(String),
-                Array(Vec<Icrc3Value>),
-            }
-
             let icrc3_req = vec![Icrc3GetBlocksRequest {
                 start: candid::Nat::from(req.start),
                 length: candid::Nat::from(req.length),
             }];
 
-            // Call icrc3_get_blocks
-            match ic_cdk::call::<_, (Icrc3GetBlocksResult,)>(
+            let (icrc3_response,) = ic_cdk::call::<_, (Icrc3GetBlocksResult,)>(
                 ledger_principal,
                 "icrc3_get_blocks",
                 (icrc3_req,),
             )
             .await
-            {
-                Ok((icrc3_response,)) => {
-                    // Convert ICRC-3 blocks to our format
-                    let mut blocks = Vec::new();
-
-                    for icrc3_block in icrc3_response.blocks {
-                        // Parse ICRC-3 block structure
-                        if let Icrc3Value::Map(map) = icrc3_block.block {
-                            let mut tx_map = None;
-                            let mut ts_nanos = 0u64;
-                            let mut phash = None;
-
-                            for (key, value) in map {
-                                match key.as_str() {
-                                    "tx" => tx_map = Some(value),
-                                    "ts" => {
-                                        if let Icrc3Value::Nat(n) = value {
-                                            ts_nanos = n.0.to_u64().unwrap_or(0);
-                                        }
-                                    }
-                                    "phash" => {
-                                        if let Icrc3Value::Blob(b) = value {
-                                            phash = Some(b);
-                                        }
-                                    }
-                                    _ => {}
-                                }
-                            }
-
-                            // Parse transaction from tx_map
-                            if let Some(Icrc3Value::Map(tx_fields)) = tx_map {
-                                let mut from_bytes = vec![];
-                                let mut to_bytes = vec![];
-                                let mut amount = 0u64;
-                                let mut fee = 0u64;
-                                let mut memo = 0u64;
-                                let mut operation_type = "";
-
-                                for (key, value) in tx_fields {
-                                    match key.as_str() {
-                                        "op" => {
-                                            if let Icrc3Value::Text(op) = value {
-                                                operation_type = match op.as_str() {
-                                                    "xfer" => "transfer",
-                                                    "mint" => "mint",
-                                                    "burn" => "burn",
-                                                    "approve" => "approve",
-                                                    _ => "unknown",
-                                                };
-                                            }
-                                        }
-                                        "from" => {
-                                            if let Icrc3Value::Array(arr) = value {
-                                                if let Some(Icrc3Value::Blob(b)) = arr.first() {
-                                                    from_bytes = AccountIdentifier::new(
-                                                        &Principal::from_slice(b),
-                                                        &DEFAULT_SUBACCOUNT,
-                                                    )
-                                                    .as_ref()
-                                                    .to_vec();
-                                                }
-                                            }
-                                        }
-                                        "to" => {
-                                            if let Icrc3Value::Array(arr) = value {
-                                                if arr.len() >= 2 {
-                                                    if let (
-                                                        Some(Icrc3Value::Blob(principal)),
-                                                        Some(Icrc3Value::Blob(subaccount)),
-                                                    ) = (arr.first(), arr.get(1))
-                                                    {
-                                                        let p = Principal::from_slice(principal);
-                                                        let sub = if subaccount.len() == 32 {
-                                                            let mut arr = [0u8; 32];
-                                                            arr.copy_from_slice(subaccount);
-                                                            Subaccount(arr)
-                                                        } else {
-                                                            DEFAULT_SUBACCOUNT
-                                                        };
-                                                        to_bytes = AccountIdentifier::new(&p, &sub)
-                                                            .as_ref()
-                                                            .to_vec();
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        "amt" => {
-                                            if let Icrc3Value::Nat(n) = value {
-                                                amount = n.0.to_u64().unwrap_or(0);
-                                            }
-                                        }
-                                        "fee" => {
-                                            if let Icrc3Value::Nat(n) = value {
-                                                fee = n.0.to_u64().unwrap_or(0);
-                                            }
-                                        }
-                                        "memo" => {
-                                            if let Icrc3Value::Nat(n) = value {
-                                                memo = n.0.to_u64().unwrap_or(0);
-                                            }
-                                        }
-                                        _ => {}
-                                    }
-                                }
-
-                                // Create block
-                                let operation = if operation_type == "transfer" {
-                                    Some(Operation::Transfer(Transfer {
-                                        from: from_bytes,
-                                        to: to_bytes,
-                                        amount: E8s { e8s: amount },
-                                        fee: E8s { e8s: fee },
-                                        spender: None,
-                                    }))
-                                } else {
-                                    None // For now, only handle transfers
-                                };
-
-                                let block = Block {
-                                    transaction: Transaction {
-                                        memo,
-                                        icrc1_memo: None,
-                                        operation,
-                                        created_at_time: Timestamp {
-                                            timestamp_nanos: ts_nanos,
-                                        },
-                                    },
-                                    timestamp: Timestamp {
-                                        timestamp_nanos: ts_nanos,
-                                    },
-                                    parent_hash: phash,
-                                };
-
-                                blocks.push(block);
-                            }
-                        }
-                    }
+            .map_err(|e| {
+                ic_cdk::println!("ICRC-3 call failed: {:?}", e);
+                e
+            })?;
 
-                    let response = QueryBlocksResponse {
-                        certificate: None,
-                        blocks,
-                        chain_length: icrc3_response.log_length.0.to_u64().unwrap_or(0),
-                        first_block_index: req.start,
-                        archived_blocks: vec![],
-                    };
+            // Resolve the archived ranges to the archive canisters that hold them
+            let archived_blocks = if icrc3_response.archived_blocks.is_empty() {
+                vec![]
+            } else {
+                resolve_icrc3_archives(ledger_principal, &icrc3_response.archived_blocks).await?
+            };
 
-                    Ok((response,))
-                }
-                Err(e) => {
-                    ic_cdk::println!("ICRC-3 call failed: {:?}", e);
-                    Err(e)
-                }
-            }
+            let first_block_index = icrc3_response
+                .blocks
+                .first()
+                .and_then(|b| b.id.0.to_u64())
+                .unwrap_or(req.start);
+
+            // icrc3_get_tip_certificate only answers query calls, so blocks
+            // fetched by the canister come without a certificate
+            let response = QueryBlocksResponse {
+                certificate: None,
+                hash_tree: None,
+                blocks: icrc3_response
+                    .blocks
+                    .iter()
+                    .map(icrc3_block_to_block)
+                    .collect(),
+                chain_length: icrc3_response.log_length.0.to_u64().unwrap_or(0),
+                first_block_index,
+                archived_blocks,
+            };
+
+            Ok((response,))
         } else {
             // ICP ledger uses traditional query_blocks
-            ic_cdk::call(ledger_principal, "query_blocks", (req,)).await
-        }
-    }
+            let args = GetBlocksArgs {
+                start: req.start,
+                length: req.length,
+            };
+            // ic-ledger-types is built against its own ic-cdk, convert its rejection code
+            let icp_response = ic_query_blocks(ledger_principal, args)
+                .await
+                .map_err(|(code, msg)| (api::call::RejectionCode::from(code as i32), msg))?;
+
+            let archived_blocks = icp_response
+                .archived_blocks
+                .into_iter()
+                .map(|range| {
+                    let callback: Func = range.callback.into();
+                    ArchivedBlock {
+                        callback: ArchiveCallback {
+                            canister_id: callback.principal,
+                            method: callback.method,
+                        },
+                        start: range.start,
+                        length: range.length,
+                    }
+                })
+                .collect();
+
+            let mut blocks: Vec<Block> = icp_response
+                .blocks
+                .into_iter()
+                .map(icp_block_to_block)
+                .collect();
+            if !blocks.is_empty() {
+                // query_blocks returns decoded blocks, their hashes come from the
+                // encodings. Without them the batch cannot be chain checked, so a
+                // failed call fails the poll and the blocks are fetched again.
+                let (encoded,): (IcpQueryEncodedBlocksResponse,) = ic_cdk::call(
+                    ledger_principal,
+                    "query_encoded_blocks",
+                    (GetBlocksArgs {
+                        start: req.start,
+                        length: req.length,
+                    },),
+                )
+                .await?;
+                attach_encoded_block_hashes(
+                    &mut blocks,
+                    icp_response.first_block_index,
+                    encoded.first_block_index,
+                    &encoded.blocks,
+                );
+            }
+
+            let response = QueryBlocksResponse {
+                certificate: icp_response.certificate.map(|c| c.into_vec()),
+                hash_tree: None,
+                blocks,
+                chain_length: icp_response.chain_length,
+                first_block_index: icp_response.first_block_index,
+                archived_blocks,
+            };
+
+            Ok((response,))
+        }
+    }
+
+    async fn query_archived_blocks(
+        ledger_principal: Principal,
+        archived: ArchivedBlock,
+        req: QueryBlocksRequest,
+    ) -> Result<Vec<Block>, String> {
+        if is_icrc_ledger(ledger_principal) {
+            let icrc3_req = vec![Icrc3GetBlocksRequest {
+                start: candid::Nat::from(req.start),
+                length: candid::Nat::from(req.length),
+            }];
+
+            let (res,): (Icrc3GetBlocksResult,) = ic_cdk::call(
+                archived.callback.canister_id,
+                &archived.callback.method,
+                (icrc3_req,),
+            )
+            .await
+            .map_err(|(code, msg)| {
+                format!("archive icrc3_get_blocks failed: {:?}: {}", code, msg)
+            })?;
+
+            Ok(res.blocks.iter().map(icrc3_block_to_block).collect())
+        } else {
+            let callback = QueryArchiveFn::from(Func {
+                principal: archived.callback.canister_id,
+                method: archived.callback.method,
+            });
+            let args = GetBlocksArgs {
+                start: req.start,
+                length: req.length,
+            };
+
+            match ic_query_archived_blocks(&callback, args).await {
+                Ok(Ok(range)) => Ok(range.blocks.into_iter().map(icp_block_to_block).collect()),
+                Ok(Err(e)) => Err(format!("archive get_blocks error: {:?}", e)),
+                Err((code, msg)) => Err(format!("archive call failed: {:?}: {}", code, msg)),
+            }
+        }
+    }
 
     async fn transfer(
         args: TransferArgs,
@@ -772,17 +1241,12 @@ impl InterCanisterCallManagerTrait for InterCanisterCallManager {
     async fn icrc1_transfer(
         args: icrc_ledger_types::icrc1::transfer::TransferArg,
         token_ledger_canister_id: Principal,
-    ) -> Result<candid::Nat, String> {
+    ) -> Result<Result<candid::Nat, Icrc1TransferError>, String> {
         let result: CallResult<(Result<candid::Nat, Icrc1TransferError>,)> =
             ic_cdk::call(token_ledger_canister_id, "icrc1_transfer", (args,)).await;
 
         match result {
-            Ok((Ok(block_index),)) => Ok(block_index),
-            Ok((Err(transfer_error),)) => {
-                let error_message = format!("ICRC-1 transfer error: {:?}", transfer_error);
-                ic_cdk::println!("{}", error_message);
-                Err(error_message)
-            }
+            Ok((result,)) => Ok(result),
             Err((code, message)) => {
                 let error_message = format!(
                     "ICRC-1 transfer call failed: {:?}, message: {}",
@@ -793,64 +1257,215 @@ impl InterCanisterCallManagerTrait for InterCanisterCallManager {
             }
         }
     }
-}
 
-fn hash_transaction(tx: &Transaction) -> Result<String, String> {
-    let transfer = match &tx.operation {
-        Some(Operation::Transfer(transfer)) => transfer,
-        _ => unreachable!("tx.operation should always be Operation::Transfer"),
-    };
-    let sender_slice = transfer.from.as_slice();
-    let from_account = match ledger::AccountIdentifier::from_slice(sender_slice) {
-        Ok(account) => account,
-        Err(e) => {
-            let error_msg = format!("Failed to create from: {:?}", e);
-            ic_cdk::println!("Error: {}", error_msg);
-            return Err(error_msg);
-        }
-    };
+    async fn query_token_metadata(ledger_principal: Principal) -> Result<TokenMetadata, String> {
+        let (metadata,) = ic_cdk::call::<_, (Vec<(String, MetadataValue)>,)>(
+            ledger_principal,
+            "icrc1_metadata",
+            (),
+        )
+        .await
+        .map_err(|(code, msg)| format!("icrc1_metadata call failed: {:?}: {}", code, msg))?;
+        let (decimals,) = ic_cdk::call::<_, (u8,)>(ledger_principal, "icrc1_decimals", ())
+            .await
+            .map_err(|(code, msg)| format!("icrc1_decimals call failed: {:?}: {}", code, msg))?;
+        let fee = Self::query_token_fee(ledger_principal).await?;
+        let (standards,) = ic_cdk::call::<_, (Vec<Icrc1SupportedStandard>,)>(
+            ledger_principal,
+            "icrc1_supported_standards",
+            (),
+        )
+        .await
+        .map_err(|(code, msg)| {
+            format!("icrc1_supported_standards call failed: {:?}: {}", code, msg)
+        })?;
 
-    let receiver_slice = transfer.to.as_slice();
-    let to_account = match ledger::AccountIdentifier::from_slice(receiver_slice) {
-        Ok(account) => account,
-        Err(e) => {
-            let error_msg = format!("Failed to create to: {:?}", e);
-            ic_cdk::println!("Error: {}", error_msg);
-            return Err(error_msg);
-        }
-    };
+        let symbol = metadata
+            .into_iter()
+            .find_map(|(key, value)| match (key.as_str(), value) {
+                ("icrc1:symbol", MetadataValue::Text(symbol)) => Some(symbol),
+                _ => None,
+            })
+            .ok_or_else(|| "icrc1_metadata has no icrc1:symbol entry".to_string())?;
+
+        let standard = if standards.iter().any(|standard| standard.name == "ICRC-1") {
+            TokenStandard::ICRC1
+        } else {
+            TokenStandard::ICP
+        };
+
+        Ok(TokenMetadata {
+            symbol,
+            decimals,
+            fee,
+            standard,
+        })
+    }
 
-    let spender = match &transfer.spender {
-        Some(spender) => {
-            let spender_slice = spender.as_slice();
-            match ledger::AccountIdentifier::from_slice(spender_slice) {
-                Ok(account) => Some(account),
-                Err(e) => {
-                    let error_msg = format!("Failed to create spender: {:?}", e);
-                    ic_cdk::println!("Error: {}", error_msg);
-                    return Err(error_msg);
+    async fn query_token_fee(ledger_principal: Principal) -> Result<u64, String> {
+        let (fee,) = ic_cdk::call::<_, (candid::Nat,)>(ledger_principal, "icrc1_fee", ())
+            .await
+            .map_err(|(code, msg)| format!("icrc1_fee call failed: {:?}: {}", code, msg))?;
+        fee.0
+            .to_u64()
+            .ok_or_else(|| format!("icrc1_fee {} does not fit in u64", fee))
+    }
+    async fn query_balance(
+        ledger_principal: Principal,
+        subaccount: Subaccount,
+    ) -> Result<candid::Nat, String> {
+        if is_icrc_ledger(ledger_principal) {
+            let account = icrc_ledger_types::icrc1::account::Account {
+                owner: CanisterApiManager::id(),
+                subaccount: Some(subaccount.0),
+            };
+            let (balance,) =
+                ic_cdk::call::<_, (candid::Nat,)>(ledger_principal, "icrc1_balance_of", (account,))
+                    .await
+                    .map_err(|(code, msg)| {
+                        format!("icrc1_balance_of call failed: {:?}: {}", code, msg)
+                    })?;
+            Ok(balance)
+        } else {
+            let args = ic_ledger_types::AccountBalanceArgs {
+                account: AccountIdentifier::new(&CanisterApiManager::id(), &subaccount),
+            };
+            let tokens = ic_ledger_types::account_balance(ledger_principal, args)
+                .await
+                .map_err(|(code, msg)| {
+                    format!("account_balance call failed: {:?}: {}", code, msg)
+                })?;
+            Ok(candid::Nat::from(tokens.e8s()))
+        }
+    }
+    async fn query_account_transaction_ids(
+        index_principal: Principal,
+        ledger_principal: Principal,
+        subaccount: Subaccount,
+        start: Option<u64>,
+        max_results: u64,
+    ) -> Result<Vec<u64>, String> {
+        if is_icrc_ledger(ledger_principal) {
+            // ICRC index-ng: get_account_transactions
+            let args = IcrcIndexGetAccountTransactionsArgs {
+                account: icrc_ledger_types::icrc1::account::Account {
+                    owner: CanisterApiManager::id(),
+                    subaccount: Some(subaccount.0),
+                },
+                start: start.map(candid::Nat::from),
+                max_results: candid::Nat::from(max_results),
+            };
+            let (result,) = ic_cdk::call::<_, (IcrcIndexGetTransactionsResult,)>(
+                index_principal,
+                "get_account_transactions",
+                (args,),
+            )
+            .await
+            .map_err(|(code, msg)| {
+                format!("get_account_transactions failed: {:?}: {}", code, msg)
+            })?;
+            match result {
+                IcrcIndexGetTransactionsResult::Ok(page) => {
+                    page.transactions
+                        .into_iter()
+                        .map(|tx| {
+                            tx.id.0.to_u64().ok_or_else(|| {
+                                format!("Transaction id {} does not fit in u64", tx.id)
+                            })
+                        })
+                        .collect()
+                }
+                IcrcIndexGetTransactionsResult::Err(err) => Err(err.message),
+            }
+        } else {
+            // ICP index: get_account_identifier_transactions
+            let args = IcpIndexGetAccountTransactionsArgs {
+                account_identifier: to_subaccount_id(subaccount).to_hex(),
+                start,
+                max_results,
+            };
+            let (result,) = ic_cdk::call::<_, (IcpIndexGetTransactionsResult,)>(
+                index_principal,
+                "get_account_identifier_transactions",
+                (args,),
+            )
+            .await
+            .map_err(|(code, msg)| {
+                format!(
+                    "get_account_identifier_transactions failed: {:?}: {}",
+                    code, msg
+                )
+            })?;
+            match result {
+                IcpIndexGetTransactionsResult::Ok(page) => {
+                    Ok(page.transactions.into_iter().map(|tx| tx.id).collect())
                 }
+                IcpIndexGetTransactionsResult::Err(err) => Err(err.message),
             }
         }
-        None => None,
-    };
+    }
+}
 
-    let amount = transfer.amount.e8s;
-    let fee = transfer.fee.e8s;
-    let memo = tx.memo;
-    let created_at_time = tx.created_at_time.timestamp_nanos;
-
-    let tx_hash = ledger::Transaction::new(
-        from_account,
-        to_account,
-        spender,
-        Tokens::from_e8s(amount),
-        Tokens::from_e8s(fee),
-        Memo(memo),
-        ledger::TimeStamp {
-            timestamp_nanos: created_at_time,
+fn to_ledger_account(bytes: &[u8], field: &str) -> Result<ledger::AccountIdentifier, String> {
+    ledger::AccountIdentifier::from_slice(bytes).map_err(|e| {
+        let error_msg = format!("Failed to create {}: {:?}", field, e);
+        ic_cdk::println!("Error: {}", error_msg);
+        error_msg
+    })
+}
+
+fn to_ledger_tokens(amount: &candid::Nat) -> Result<Tokens, String> {
+    to_e8s(amount).map(Tokens::from_e8s).map_err(|e| e.message)
+}
+
+fn hash_transaction(tx: &Transaction) -> Result<String, String> {
+    let operation = match &tx.operation {
+        Some(Operation::Transfer(transfer)) => ledger::Operation::Transfer {
+            from: to_ledger_account(&transfer.from, "from")?,
+            to: to_ledger_account(&transfer.to, "to")?,
+            amount: to_ledger_tokens(&transfer.amount)?,
+            fee: to_ledger_tokens(&transfer.fee)?,
+            spender: match &transfer.spender {
+                Some(spender) => Some(to_ledger_account(spender, "spender")?),
+                None => None,
+            },
         },
-    )
+        Some(Operation::Mint(mint)) => ledger::Operation::Mint {
+            to: to_ledger_account(&mint.to, "to")?,
+            amount: to_ledger_tokens(&mint.amount)?,
+        },
+        Some(Operation::Burn(burn)) => ledger::Operation::Burn {
+            from: to_ledger_account(&burn.from, "from")?,
+            amount: to_ledger_tokens(&burn.amount)?,
+            spender: match &burn.spender {
+                Some(spender) => Some(to_ledger_account(spender, "spender")?),
+                None => None,
+            },
+        },
+        Some(Operation::Approve(approve)) => ledger::Operation::Approve {
+            from: to_ledger_account(&approve.from, "from")?,
+            spender: to_ledger_account(&approve.spender, "spender")?,
+            allowance: to_ledger_tokens(&approve.allowance)?,
+            expected_allowance: match &approve.expected_allowance {
+                Some(expected) => Some(to_ledger_tokens(expected)?),
+                None => None,
+            },
+            expires_at: approve.expires_at.as_ref().map(|ts| ledger::TimeStamp {
+                timestamp_nanos: ts.timestamp_nanos,
+            }),
+            fee: to_ledger_tokens(&approve.fee)?,
+        },
+        None => return Err("Transaction has no operation".to_string()),
+    };
+
+    let tx_hash = ledger::Transaction {
+        operation,
+        memo: Memo(tx.memo),
+        icrc1_memo: tx.icrc1_memo.clone().map(serde_bytes::ByteBuf::from),
+        created_at_time: Some(ledger::TimeStamp {
+            timestamp_nanos: tx.created_at_time.timestamp_nanos,
+        }),
+    }
     .generate_hash();
 
     Ok(tx_hash.to_hex())
@@ -919,6 +1534,297 @@ fn transform(args: TransformArgs) -> HttpResponse {
     args.response
 }
 
+// Checks that a block chains onto the given last block and returns the record
+// the next block is checked against. Blocks after a gap are not checked and
+// re-anchor the chain; blocks without a derivable hash pass through.
+fn check_parent_hash(
+    token_type: &TokenType,
+    last: Option<BlockHashRecord>,
+    block_index: u64,
+    block: &Block,
+) -> Result<Option<BlockHashRecord>, ChainAlert> {
+    if let Some(last) = &last {
+        if last.block_index + 1 == block_index && block.parent_hash.as_ref() != Some(&last.hash) {
+            return Err(ChainAlert {
+                token_type: token_type.clone(),
+                block_index,
+                expected_parent_hash: hex::encode(&last.hash),
+                found_parent_hash: block.parent_hash.as_ref().map(hex::encode),
+                detected_at: CanisterApiManager::time(),
+            });
+        }
+    }
+
+    Ok(match &block.block_hash {
+        Some(hash) => Some(BlockHashRecord {
+            block_index,
+            hash: hash.clone(),
+        }),
+        None => last,
+    })
+}
+
+// Checks that a block chains onto the last verified block of its ledger and
+// records its hash for the next one
+fn verify_parent_hash(
+    token_type: &TokenType,
+    ledger_principal: Principal,
+    block_index: u64,
+    block: &Block,
+) -> Result<(), ChainAlert> {
+    let last = TOKEN_LAST_BLOCK_HASH.with(|hashes| hashes.borrow().get(&ledger_principal));
+    let record = check_parent_hash(token_type, last, block_index, block)?;
+
+    // Only blocks with a hash move the recorded chain forward
+    if let (Some(record), Some(_)) = (record, &block.block_hash) {
+        TOKEN_LAST_BLOCK_HASH.with(|hashes| hashes.borrow_mut().insert(ledger_principal, record));
+    }
+
+    Ok(())
+}
+
+fn raise_chain_alert(ledger_principal: Principal, alert: ChainAlert) {
+    ic_cdk::println!(
+        "ALERT: parent hash mismatch for {:?} at block {}: expected {}, found {:?}. Indexing halted",
+        alert.token_type,
+        alert.block_index,
+        alert.expected_parent_hash,
+        alert.found_parent_hash
+    );
+    TOKEN_CHAIN_ALERTS.with(|alerts| alerts.borrow_mut().insert(ledger_principal, alert));
+}
+
+// Every indexing path stops on a ledger with a raised chain alert until an
+// operator clears it
+fn chain_alert_raised(token_type: &TokenType, ledger_principal: Principal) -> bool {
+    let raised = TOKEN_CHAIN_ALERTS.with(|alerts| alerts.borrow().contains_key(&ledger_principal));
+    if raised {
+        ic_cdk::println!(
+            "Indexing halted for {:?} by a chain alert, clear it to resume",
+            token_type
+        );
+    }
+    raised
+}
+
+// Sets the hash of each block to the SHA-256 of its encoding, which is what
+// the ICP ledger chains its parent hashes over. Encodings of another range
+// leave the hashes unset.
+fn attach_encoded_block_hashes(
+    blocks: &mut [Block],
+    first_block_index: u64,
+    encoded_first_block_index: u64,
+    encoded_blocks: &[impl AsRef<[u8]>],
+) {
+    if first_block_index != encoded_first_block_index || blocks.len() != encoded_blocks.len() {
+        ic_cdk::println!(
+            "Encoded blocks from {} do not match blocks from {}, leaving them unhashed",
+            encoded_first_block_index,
+            first_block_index
+        );
+        return;
+    }
+    for (block, encoded) in blocks.iter_mut().zip(encoded_blocks) {
+        block.block_hash = Some(Sha256::digest(encoded.as_ref()).to_vec());
+    }
+}
+
+// Flags a token whose ledger reports a chain shorter than its next block and
+// returns the block to continue from. Local ledgers are reinstalled all the
+// time, so there the token is rewound to the first block and transactions of
+// the old chain are dropped; elsewhere it stays put until an operator sets
+// its next block.
+fn handle_ledger_reset(
+    token_type: &TokenType,
+    ledger_principal: Principal,
+    next_block: u64,
+    chain_length: u64,
+) -> u64 {
+    let rewound = network() == Network::Local;
+    ic_cdk::println!(
+        "ALERT: ledger of {:?} reports {} blocks but the next block is {}{}",
+        token_type,
+        chain_length,
+        next_block,
+        if rewound {
+            ", rewinding to block 0"
+        } else {
+            ""
+        }
+    );
+
+    let already_flagged =
+        TOKEN_LEDGER_RESETS.with(|resets| resets.borrow().contains_key(&ledger_principal));
+    if !already_flagged || rewound {
+        let reset = LedgerReset {
+            token_type: token_type.clone(),
+            next_block,
+            chain_length,
+            detected_at: CanisterApiManager::time(),
+            rewound,
+        };
+        TOKEN_LEDGER_RESETS.with(|resets| resets.borrow_mut().insert(ledger_principal, reset));
+    }
+    if !rewound {
+        return next_block;
+    }
+
+    TOKEN_LAST_BLOCK_HASH.with(|hashes| hashes.borrow_mut().remove(&ledger_principal));
+    TOKEN_TIP_ANCHORS.with(|anchors| anchors.borrow_mut().remove(&ledger_principal));
+    drop_index_cursors(ledger_principal, 0);
+    INDEX_RESUME_NONCES.with(|resume| resume.borrow_mut().remove(&ledger_principal));
+    let stale: Vec<TransactionKey> = TRANSACTIONS.with(|transactions_ref| {
+        transactions_ref
+            .borrow()
+            .range((ledger_principal, 0)..=(ledger_principal, u64::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    ic_cdk::println!(
+        "Dropping {} transactions of the previous {:?} ledger",
+        stale.len(),
+        token_type
+    );
+    for key in stale {
+        remove_transaction(&key);
+    }
+    // Re-indexing from the first block credits the deposits again
+    PRUNED_DEPOSITS.with(|pruned| {
+        let mut pruned = pruned.borrow_mut();
+        let keys: Vec<TransactionKey> = pruned
+            .range((ledger_principal, 0)..=(ledger_principal, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            pruned.remove(&key);
+        }
+    });
+    SUBACCOUNT_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        let keys: Vec<(u32, Principal)> = balances
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(_, ledger)| *ledger == ledger_principal)
+            .collect();
+        for key in keys {
+            balances.remove(&key);
+        }
+    });
+    0
+}
+
+// Records the ledger state a batch was indexed against, replacing the token's
+// previous anchor. Ledgers only return tip certificates to query calls, so the
+// canister cannot verify the anchor itself; its last block hash is meant to be
+// compared off-chain with a query to the ledger's icrc3_get_tip_certificate.
+fn anchor_batch(
+    token_type: &TokenType,
+    ledger_principal: Principal,
+    start_block: u64,
+    next_block: u64,
+) {
+    let last = TOKEN_LAST_BLOCK_HASH
+        .with(|hashes| hashes.borrow().get(&ledger_principal))
+        .filter(|last| last.block_index + 1 == next_block);
+
+    let anchor = TipAnchor {
+        token_type: token_type.clone(),
+        start_block,
+        next_block,
+        last_block_hash: last.map(|last| hex::encode(last.hash)),
+        recorded_at: CanisterApiManager::time(),
+    };
+    TOKEN_TIP_ANCHORS.with(|anchors| anchors.borrow_mut().insert(ledger_principal, anchor));
+}
+
+// Checks whether a block touches one of the canister subaccounts and stores it
+// under (ledger, block index). Returns the tx hash when a new entry was stored.
+fn index_block(
+    token_type: &TokenType,
+    ledger_principal: Principal,
+    block_index: u64,
+    block: &Block,
+) -> Option<String> {
+    let operation = block.transaction.operation.as_ref()?;
+    ic_cdk::println!("Operation for {:?}: {:?}", token_type, operation);
+
+    let subaccount_exist = match operation {
+        Operation::Approve(data) => {
+            ic_cdk::println!("Approve detected for {:?}", token_type);
+            is_canister_account(&data.from) || is_canister_account(&data.spender)
+        }
+        Operation::Burn(data) => {
+            ic_cdk::println!("Burn detected for {:?}", token_type);
+            is_canister_account(&data.from)
+                || data
+                    .spender
+                    .as_ref()
+                    .map(|s| is_canister_account(s))
+                    .unwrap_or(false)
+        }
+        Operation::Mint(data) => {
+            ic_cdk::println!("Mint detected for {:?}", token_type);
+            is_canister_account(&data.to)
+        }
+        Operation::Transfer(data) => {
+            ic_cdk::println!("Transfer detected for {:?}", token_type);
+            is_canister_account(&data.to)
+                || data
+                    .spender
+                    .as_ref()
+                    .map(|s| is_canister_account(s))
+                    .unwrap_or(false)
+        }
+    };
+
+    if !subaccount_exist {
+        return None;
+    }
+
+    ic_cdk::println!("Subaccount exists for {:?}", token_type);
+    let key = (ledger_principal, block_index);
+    if TRANSACTIONS.with(|transactions_ref| transactions_ref.borrow().contains_key(&key)) {
+        ic_cdk::println!("Transaction already exists for {:?}", token_type);
+        return None;
+    }
+
+    let hash = match block
+        .tx_hash
+        .clone()
+        .map_or_else(|| hash_transaction(&block.transaction), Ok)
+    {
+        Ok(content) => content,
+        Err(err) => {
+            ic_cdk::println!("ERROR in index_block when hashing transaction:");
+            ic_cdk::println!("  Error message: {}", err);
+            ic_cdk::println!("  Token type: {:?}", token_type);
+            ic_cdk::println!("  Transaction: {:?}", block.transaction);
+            "HASH-IS-NOT-AVAILABLE".to_string()
+        }
+    };
+    ic_cdk::println!("Hash for {:?}: {:?}", token_type, hash);
+
+    let transaction = StoredTransactions::new(
+        block_index,
+        block.transaction.clone(),
+        hash.clone(),
+        token_type.clone(),
+        ledger_principal,
+    );
+
+    // A deposit pruned by clear_transactions was credited when first indexed
+    let pruned = PRUNED_DEPOSITS
+        .with(|pruned| pruned.borrow_mut().remove(&key))
+        .is_some();
+    if !pruned && balance_tracks_deposit(&key) {
+        credit_deposit(key, &transaction);
+    }
+
+    ic_cdk::println!("Inserting transaction for {:?}", token_type);
+    insert_transaction(key, transaction);
+    Some(hash)
+}
+
 async fn query_token_ledger(
     token_type: TokenType,
     token_principal: Principal,
@@ -926,13 +1832,22 @@ async fn query_token_ledger(
 ) -> u64 {
     ic_cdk::println!("Querying token ledger for {:?}", token_type);
 
+    if chain_alert_raised(&token_type, token_principal) {
+        return next_block;
+    }
+
+    let length = if is_catching_up(token_principal, next_block) {
+        catch_up_batch_size(token_principal)
+    } else {
+        STEADY_STATE_BATCH_SIZE
+    };
     let req = QueryBlocksRequest {
         start: next_block,
-        length: 100,
+        length,
     };
 
     let call_result: CallResult<(QueryBlocksResponse,)> =
-        InterCanisterCallManager::query_blocks(token_principal, req).await;
+        InterCanisterCallManager::query_blocks(token_principal, req.clone()).await;
 
     let response = match call_result {
         Ok((response,)) => response,
@@ -948,115 +1863,130 @@ async fn query_token_ledger(
     };
 
     ic_cdk::println!("Response for {:?}: {:?}", token_type, response);
+    record_ledger_page(token_principal, &req, &response);
 
-    // Check if there are archived blocks (just log, don't auto-query)
-    if !response.archived_blocks.is_empty() && token_type == TokenType::ICP {
-        ic_cdk::println!(
-            "Found {} archived blocks for {:?}. Use process_archived_block to query them.",
-            response.archived_blocks.len(),
-            token_type
+    TOKEN_CHAIN_LENGTHS.with(|lengths| {
+        lengths
+            .borrow_mut()
+            .insert(token_principal, response.chain_length)
+    });
+
+    if response.chain_length < next_block {
+        return handle_ledger_reset(
+            &token_type,
+            token_principal,
+            next_block,
+            response.chain_length,
         );
     }
 
     let mut first_block_hash = String::default();
     let mut block_count = next_block;
 
-    // Process only the blocks returned by the ledger (not archived ones)
-    response.blocks.iter().for_each(|block| {
-        if let Some(operation) = block.transaction.operation.as_ref() {
-            ic_cdk::println!("Operation for {:?}: {:?}", token_type, operation);
-
-            let subaccount_exist = match operation {
-                Operation::Approve(data) => {
-                    ic_cdk::println!("Approve detected for {:?}", token_type);
-                    let from = data.from.clone();
-                    if includes_hash(&from) {
-                        true
-                    } else {
-                        let spender = data.spender.clone();
-                        includes_hash(&spender)
-                    }
-                }
-                Operation::Burn(data) => {
-                    ic_cdk::println!("Burn detected for {:?}", token_type);
-                    let from = data.from.clone();
-                    if includes_hash(&from) {
-                        true
-                    } else {
-                        match &data.spender {
-                            Some(spender) => includes_hash(spender),
-                            None => false,
-                        }
-                    }
-                }
-                Operation::Mint(data) => {
-                    ic_cdk::println!("Mint detected for {:?}", token_type);
-                    let to = data.to.clone();
-                    includes_hash(&to)
-                }
-                Operation::Transfer(data) => {
-                    ic_cdk::println!("Transfer detected for {:?}", token_type);
-                    let to = data.to.clone();
-                    if includes_hash(&to) {
-                        true
-                    } else {
-                        match &data.spender {
-                            Some(spender) => includes_hash(spender),
-                            None => false,
-                        }
-                    }
-                }
-            };
+    // Blocks before first_block_index have been moved to archives, fetch those first
+    let mut archives_complete = true;
+    let mut chain_broken = false;
+    for archived in response.archived_blocks.iter() {
+        let archived_end = archived.start + archived.length;
+        if archived_end <= block_count {
+            continue;
+        }
+        if archived.start > block_count {
+            ic_cdk::println!(
+                "Gap before archived range {} for {:?}, expected block {}",
+                archived.start,
+                token_type,
+                block_count
+            );
+            archives_complete = false;
+            break;
+        }
 
-            if subaccount_exist {
-                ic_cdk::println!("Subaccount exists for {:?}", token_type);
-                TRANSACTIONS.with(|transactions_ref| {
-                    let mut transactions = transactions_ref.borrow_mut();
-
-                    let hash = match hash_transaction(&block.transaction) {
-                        Ok(content) => content,
-                        Err(err) => {
-                            ic_cdk::println!(
-                                "ERROR in query_token_ledger when hashing transaction:"
-                            );
-                            ic_cdk::println!("  Error message: {}", err);
-                            ic_cdk::println!("  Token type: {:?}", token_type);
-                            ic_cdk::println!("  Transaction: {:?}", block.transaction);
-                            "HASH-IS-NOT-AVAILABLE".to_string()
-                        }
-                    };
-                    ic_cdk::println!("Hash for {:?}: {:?}", token_type, hash);
-
-                    let transaction = StoredTransactions::new(
-                        block_count,
-                        block.transaction.clone(),
-                        hash.clone(),
-                        token_type.clone(),
-                        token_principal,
-                    );
+        ic_cdk::println!(
+            "Fetching archived blocks {}..{} for {:?} from {}",
+            block_count,
+            archived_end,
+            token_type,
+            archived.callback.canister_id
+        );
 
-                    if !transactions.contains_key(&block_count) {
-                        // Filter keys that exist
-                        ic_cdk::println!("Inserting transaction for {:?}", token_type);
-                        let _ = transactions.insert(block_count, transaction);
-
-                        // Track the first block hash in the iter
-                        if first_block_hash.is_empty() {
-                            ic_cdk::println!(
-                                "Setting webhook tx_hash for {:?}: {:?}",
-                                token_type,
-                                hash
-                            );
-                            first_block_hash = hash;
-                        }
-                    } else {
-                        ic_cdk::println!("Transaction already exists for {:?}", token_type);
-                    }
-                });
+        let archived_req = QueryBlocksRequest {
+            start: block_count,
+            length: archived_end - block_count,
+        };
+        let blocks = match InterCanisterCallManager::query_archived_blocks(
+            token_principal,
+            archived.clone(),
+            archived_req,
+        )
+        .await
+        {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                ic_cdk::println!(
+                    "ERROR fetching archived blocks for {:?}: {}",
+                    token_type,
+                    err
+                );
+                archives_complete = false;
+                break;
             }
         };
-        block_count += 1;
-    });
+
+        for block in blocks.iter() {
+            if let Err(alert) = verify_parent_hash(&token_type, token_principal, block_count, block)
+            {
+                raise_chain_alert(token_principal, alert);
+                chain_broken = true;
+                break;
+            }
+            if let Some(hash) = index_block(&token_type, token_principal, block_count, block) {
+                if first_block_hash.is_empty() {
+                    first_block_hash = hash;
+                }
+            }
+            block_count += 1;
+        }
+
+        // Archives may return fewer blocks than asked for, continue on the next tick
+        if chain_broken || block_count < archived_end {
+            archives_complete = false;
+            break;
+        }
+    }
+
+    // Blocks returned by the ledger itself are numbered from first_block_index
+    if archives_complete && block_count >= response.first_block_index {
+        for (offset, block) in response.blocks.iter().enumerate() {
+            let block_index = response.first_block_index + offset as u64;
+            if block_index < block_count {
+                continue;
+            }
+            if let Err(alert) = verify_parent_hash(&token_type, token_principal, block_index, block)
+            {
+                raise_chain_alert(token_principal, alert);
+                chain_broken = true;
+                break;
+            }
+            if let Some(hash) = index_block(&token_type, token_principal, block_index, block) {
+                if first_block_hash.is_empty() {
+                    ic_cdk::println!("Setting webhook tx_hash for {:?}: {:?}", token_type, hash);
+                    first_block_hash = hash;
+                }
+            }
+            block_count = block_index + 1;
+        }
+    } else if !chain_broken && !response.blocks.is_empty() {
+        ic_cdk::println!(
+            "Archived blocks for {:?} not fully fetched, resuming from block {}",
+            token_type,
+            block_count
+        );
+    }
+
+    if !chain_broken {
+        anchor_batch(&token_type, token_principal, next_block, block_count);
+    }
 
     // If the first block hash in not empty
     // Send the webhook
@@ -1069,75 +1999,389 @@ async fn query_token_ledger(
     block_count
 }
 
-async fn call_query_blocks() {
-    ic_cdk::println!("Starting periodic block checking");
+// Blocks requested per tick once a token has reached the chain tip
+const STEADY_STATE_BATCH_SIZE: u64 = 100;
+// Blocks requested per catch-up batch, unless the ledger has returned fewer
+const CATCH_UP_BATCH_SIZE: u64 = 2_000;
+// Instructions a polling pass may use before leaving the rest to the next tick
+const CATCH_UP_INSTRUCTION_BUDGET: u64 = 10_000_000_000;
+
+// Blocks between the next block to index and the last chain length the ledger
+// reported, None until the ledger has been queried
+fn token_lag(ledger_principal: Principal, next_block: u64) -> Option<u64> {
+    TOKEN_CHAIN_LENGTHS
+        .with(|lengths| lengths.borrow().get(&ledger_principal))
+        .map(|chain_length| chain_length.saturating_sub(next_block))
+}
+
+// Ledgers cap how many blocks they return per call, so a batch asks for no
+// more than the ledger returned the last time it fell short
+fn catch_up_batch_size(ledger_principal: Principal) -> u64 {
+    LEDGER_PAGE_SIZES
+        .with(|sizes| sizes.borrow().get(&ledger_principal).copied())
+        .map_or(CATCH_UP_BATCH_SIZE, |size| size.min(CATCH_UP_BATCH_SIZE))
+}
+
+// Records the page size of a ledger that returned fewer of the requested
+// blocks than it holds
+fn record_ledger_page(
+    ledger_principal: Principal,
+    req: &QueryBlocksRequest,
+    response: &QueryBlocksResponse,
+) {
+    let returned = response.blocks.len() as u64;
+    let held = (req.start + req.length)
+        .min(response.chain_length)
+        .saturating_sub(response.first_block_index.max(req.start));
+    if returned > 0 && returned < held {
+        ic_cdk::println!(
+            "Ledger {} returned {} of {} blocks, capping its batches",
+            ledger_principal,
+            returned,
+            held
+        );
+        LEDGER_PAGE_SIZES.with(|sizes| sizes.borrow_mut().insert(ledger_principal, returned));
+    }
+}
 
-    // Process each registered token with its own block counter
-    TOKEN_LEDGER_PRINCIPALS.with(|tl| {
-        for (_, (token_type, token_principal)) in tl.borrow().iter() {
-            let token_type_clone = token_type.clone();
-            let token_principal_clone = token_principal;
+fn is_catching_up(ledger_principal: Principal, next_block: u64) -> bool {
+    token_lag(ledger_principal, next_block).unwrap_or(0) > STEADY_STATE_BATCH_SIZE
+}
+
+// Transaction ids requested from an index canister per call
+const INDEX_PAGE_SIZE: u64 = 100;
+// Subaccounts an index pass looks up per tick, the rest resume on the next one
+const INDEX_SUBACCOUNTS_PER_TICK: u32 = 100;
 
-            // Get the specific next block for this token
-            let token_next_block = get_token_next_block(&token_type_clone);
+// Ids of transactions of a subaccount newer than its cursor, oldest first
+async fn new_transaction_ids(
+    index_principal: Principal,
+    ledger_principal: Principal,
+    subaccount: Subaccount,
+    cursor: Option<u64>,
+) -> Result<Vec<u64>, String> {
+    let mut ids = vec![];
+    let mut start = None;
+    loop {
+        let page = InterCanisterCallManager::query_account_transaction_ids(
+            index_principal,
+            ledger_principal,
+            subaccount,
+            start,
+            INDEX_PAGE_SIZE,
+        )
+        .await?;
+        let full_page = page.len() as u64 >= INDEX_PAGE_SIZE;
+
+        let mut reached_cursor = false;
+        for id in page {
+            if cursor.is_some_and(|cursor| id <= cursor) {
+                reached_cursor = true;
+                break;
+            }
+            ids.push(id);
+        }
 
+        // Pages run from newest to oldest, continue below the oldest id seen
+        start = match ids.last() {
+            Some(oldest) if full_page && !reached_cursor => oldest.checked_sub(1),
+            _ => None,
+        };
+        if start.is_none() {
+            break;
+        }
+    }
+    ids.reverse();
+    Ok(ids)
+}
+
+// Block after the highest transaction id discovered through the index
+// canister, which stands in for the next block of tokens that are not scanned
+fn index_next_block(ledger_principal: Principal) -> u64 {
+    SUBACCOUNT_INDEX_CURSORS.with(|cursors| {
+        cursors
+            .borrow()
+            .range((ledger_principal, 0)..=(ledger_principal, u32::MAX))
+            .map(|(_, id)| id + 1)
+            .max()
+            .unwrap_or(0)
+    })
+}
+
+// Forgets the subaccount cursors at or past a block, so their transactions
+// are discovered again from the index canister
+fn drop_index_cursors(ledger_principal: Principal, from_block: u64) {
+    SUBACCOUNT_INDEX_CURSORS.with(|cursors| {
+        let mut cursors = cursors.borrow_mut();
+        let stale: Vec<(Principal, u32)> = cursors
+            .range((ledger_principal, 0)..=(ledger_principal, u32::MAX))
+            .filter(|(_, id)| *id >= from_block)
+            .map(|(key, _)| key)
+            .collect();
+        for key in stale {
+            cursors.remove(&key);
+        }
+    });
+}
+
+// Discovers new transactions of the canister's subaccounts through the token's
+// index canister and indexes the ledger blocks they point at, so stored
+// transactions are the same as with a ledger scan. The blocks are fetched one
+// by one and not contiguous, so unlike a scan they are stored without a parent
+// hash check.
+async fn index_token_via_index(
+    token_type: &TokenType,
+    ledger_principal: Principal,
+    index_principal: Principal,
+) {
+    if chain_alert_raised(token_type, ledger_principal) {
+        return;
+    }
+
+    // A ledger reinstalled under the index is caught the same way as on a scan
+    let next_block = index_next_block(ledger_principal);
+    let req = QueryBlocksRequest {
+        start: next_block,
+        length: 0,
+    };
+    match InterCanisterCallManager::query_blocks(ledger_principal, req).await {
+        Ok((response,)) => {
+            TOKEN_CHAIN_LENGTHS.with(|lengths| {
+                lengths
+                    .borrow_mut()
+                    .insert(ledger_principal, response.chain_length)
+            });
+            if response.chain_length < next_block {
+                handle_ledger_reset(
+                    token_type,
+                    ledger_principal,
+                    next_block,
+                    response.chain_length,
+                );
+                return;
+            }
+        }
+        Err((code, msg)) => {
             ic_cdk::println!(
-                "Processing {:?} from block {} on ledger {}",
-                token_type_clone,
-                token_next_block,
-                token_principal_clone
+                "ERROR querying chain length of {:?}: {:?} {}",
+                token_type,
+                code,
+                msg
             );
+            return;
+        }
+    }
 
-            IcCdkSpawnManager::run(async move {
-                let result = query_token_ledger(
-                    token_type_clone.clone(),
-                    token_principal_clone,
-                    token_next_block,
-                )
-                .await;
+    let subaccount_count = nonce();
+    let first_nonce = INDEX_RESUME_NONCES
+        .with(|resume| resume.borrow_mut().remove(&ledger_principal))
+        .filter(|nonce| *nonce < subaccount_count)
+        .unwrap_or(0);
+    ic_cdk::println!(
+        "Discovering {:?} transactions through index {} from subaccount {}",
+        token_type,
+        index_principal,
+        first_nonce
+    );
+
+    let last_nonce = first_nonce
+        .saturating_add(INDEX_SUBACCOUNTS_PER_TICK)
+        .min(subaccount_count);
+    if last_nonce < subaccount_count {
+        INDEX_RESUME_NONCES.with(|resume| resume.borrow_mut().insert(ledger_principal, last_nonce));
+    }
+
+    let mut first_tx_hash = String::default();
+    for subaccount_nonce in first_nonce..last_nonce {
+        if CanisterApiManager::instruction_counter() >= CATCH_UP_INSTRUCTION_BUDGET {
+            ic_cdk::println!(
+                "Instruction budget spent on {:?} index discovery, resuming at subaccount {}",
+                token_type,
+                subaccount_nonce
+            );
+            INDEX_RESUME_NONCES.with(|resume| {
+                resume
+                    .borrow_mut()
+                    .insert(ledger_principal, subaccount_nonce)
+            });
+            break;
+        }
 
+        let cursor_key = (ledger_principal, subaccount_nonce);
+        let cursor = SUBACCOUNT_INDEX_CURSORS.with(|cursors| cursors.borrow().get(&cursor_key));
+        let ids = match new_transaction_ids(
+            index_principal,
+            ledger_principal,
+            to_subaccount(subaccount_nonce),
+            cursor,
+        )
+        .await
+        {
+            Ok(ids) => ids,
+            Err(err) => {
                 ic_cdk::println!(
-                    "{:?} ledger query completed. New block: {}",
-                    token_type_clone,
-                    result
+                    "ERROR querying index {} for subaccount {}: {}",
+                    index_principal,
+                    subaccount_nonce,
+                    err
                 );
+                continue;
+            }
+        };
 
-                // Update the token-specific next block
-                set_token_next_block(&token_type_clone, result);
-
-                // For ICP, also update legacy NEXT_BLOCK for backward compatibility
-                if token_type_clone == TokenType::ICP {
-                    NEXT_BLOCK.with(|next_block_ref| {
-                        let _ = next_block_ref.borrow_mut().set(result);
-                    });
+        for id in ids {
+            let block = match fetch_single_block(ledger_principal, id).await {
+                Ok(Some(block)) => block,
+                Ok(None) => {
+                    ic_cdk::println!("Block {} of {:?} not found on ledger", id, token_type);
+                    break;
+                }
+                Err(err) => {
+                    ic_cdk::println!("ERROR fetching {:?} block {}: {}", token_type, id, err);
+                    break;
+                }
+            };
+            if let Some(hash) = index_block(token_type, ledger_principal, id, &block) {
+                if first_tx_hash.is_empty() {
+                    first_tx_hash = hash;
                 }
+            }
+            SUBACCOUNT_INDEX_CURSORS.with(|cursors| cursors.borrow_mut().insert(cursor_key, id));
+        }
+    }
+
+    if !first_tx_hash.is_empty() {
+        let res = send_webhook(first_tx_hash).await;
+        ic_cdk::println!("HTTP Outcall result for {:?}: {}", token_type, res);
+    }
+}
+
+// Runs one indexing pass for a token, unless the pass started by an earlier
+// tick is still in flight
+async fn index_token(token_type: TokenType, token_principal: Principal) {
+    let now = CanisterApiManager::time();
+    let _guard = match TokenGuard::new(token_principal, now) {
+        Ok(guard) => guard,
+        Err(started_at) => {
+            record_skipped_tick(&token_type, token_principal, now, started_at);
+            return;
+        }
+    };
+
+    let index_principal =
+        TOKEN_INDEX_CANISTERS.with(|indexes| indexes.borrow().get(&token_principal));
+    if let Some(index_principal) = index_principal {
+        index_token_via_index(&token_type, token_principal, index_principal).await;
+        return;
+    }
+
+    // Read under the guard so overlapping passes never index the same range
+    let mut next_block = get_token_next_block(&token_type);
+
+    // A lagging token keeps fetching batches until it reaches the chain tip,
+    // the instruction budget is spent or the ledger stops returning blocks
+    loop {
+        ic_cdk::println!(
+            "Processing {:?} from block {} on ledger {}",
+            token_type,
+            next_block,
+            token_principal
+        );
+
+        let result = query_token_ledger(token_type.clone(), token_principal, next_block).await;
+        ic_cdk::println!(
+            "{:?} ledger query completed. New block: {}",
+            token_type,
+            result
+        );
+
+        // Update the token-specific next block
+        set_token_next_block(&token_type, result);
+
+        // For ICP, also update legacy NEXT_BLOCK for backward compatibility
+        if token_type == TokenType::ICP {
+            NEXT_BLOCK.with(|next_block_ref| {
+                let _ = next_block_ref.borrow_mut().set(result);
             });
         }
+
+        let progressed = result > next_block;
+        next_block = result;
+        if !progressed || token_lag(token_principal, next_block).unwrap_or(0) == 0 {
+            break;
+        }
+        if CanisterApiManager::instruction_counter() >= CATCH_UP_INSTRUCTION_BUDGET {
+            ic_cdk::println!(
+                "Instruction budget spent catching up {:?}, resuming from block {} next tick",
+                token_type,
+                next_block
+            );
+            break;
+        }
+    }
+}
+
+fn record_skipped_tick(
+    token_type: &TokenType,
+    token_principal: Principal,
+    now: u64,
+    started_at: u64,
+) {
+    let in_flight_nanos = now.saturating_sub(started_at);
+    ic_cdk::println!(
+        "Skipping tick for {:?}: previous pass in flight for {}ns",
+        token_type,
+        in_flight_nanos
+    );
+
+    TOKEN_SKIPPED_TICKS.with(|skipped| {
+        let mut skipped = skipped.borrow_mut();
+        let count = skipped
+            .get(&token_principal)
+            .map(|ticks| ticks.count)
+            .unwrap_or(0);
+        skipped.insert(
+            token_principal,
+            SkippedTicks {
+                token_type: token_type.clone(),
+                count: count + 1,
+                last_skipped_at: now,
+                last_in_flight_nanos: in_flight_nanos,
+            },
+        );
+    });
+}
+
+async fn call_query_blocks() {
+    ic_cdk::println!("Starting periodic block checking");
+
+    IcCdkSpawnManager::run(refresh_stale_token_fees());
+
+    // Process each registered token with its own block counter, tokens with
+    // their own interval are polled by their own timer
+    TOKEN_REGISTRY.with(|registry| {
+        for (token_principal, config) in registry.borrow().iter() {
+            if !has_own_interval(token_principal) {
+                IcCdkSpawnManager::run(index_token(config.token_type, token_principal));
+            }
+        }
     });
 
     // Handle default ICP if not in registered tokens
-    let icp_registered = TOKEN_LEDGER_PRINCIPALS.with(|tl| {
-        tl.borrow()
+    let icp_registered = TOKEN_REGISTRY.with(|registry| {
+        registry
+            .borrow()
             .iter()
-            .any(|(_, (token_type, _))| token_type == TokenType::ICP)
+            .any(|(_, config)| config.token_type == TokenType::ICP)
     });
 
     if !icp_registered {
         if let Some(icp_principal) =
             PRINCIPAL.with(|stored_ref| stored_ref.borrow().get().get_principal())
         {
-            let icp_next_block = get_token_next_block(&TokenType::ICP);
-            ic_cdk::println!("Processing default ICP from block {}", icp_next_block);
-
-            let icp_result =
-                query_token_ledger(TokenType::ICP, icp_principal, icp_next_block).await;
-
-            set_token_next_block(&TokenType::ICP, icp_result);
-
-            // Also update legacy NEXT_BLOCK for backward compatibility
-            NEXT_BLOCK.with(|next_block_ref| {
-                let _ = next_block_ref.borrow_mut().set(icp_result);
-            });
+            if !has_own_interval(icp_principal) {
+                index_token(TokenType::ICP, icp_principal).await;
+            }
         }
     }
 
@@ -1149,6 +2393,14 @@ impl CanisterApiManagerTrait for CanisterApiManager {
     fn id() -> Principal {
         api::id()
     }
+
+    fn time() -> u64 {
+        api::time()
+    }
+
+    fn instruction_counter() -> u64 {
+        api::call_context_instruction_counter()
+    }
 }
 
 #[cfg(not(test))]
@@ -1160,6 +2412,33 @@ impl TimerManagerTrait for TimerManager {
         })
     }
 
+    fn set_token_timer(
+        interval: std::time::Duration,
+        token_type: TokenType,
+        ledger_principal: Principal,
+    ) -> TimerId {
+        ic_cdk::println!(
+            "Starting a periodic task for {:?} with interval {:?}",
+            token_type,
+            interval
+        );
+        ic_cdk_timers::set_timer_interval(interval, move || {
+            IcCdkSpawnManager::run(index_token(token_type.clone(), ledger_principal));
+        })
+    }
+
+    fn set_backfill_timer(interval: std::time::Duration) -> TimerId {
+        ic_cdk::println!("Starting backfill task with interval {:?}", interval);
+        ic_cdk_timers::set_timer_interval(interval, || {
+            IcCdkSpawnManager::run(run_backfill_jobs());
+        })
+    }
+
+    fn set_migration_timer(interval: std::time::Duration) -> TimerId {
+        ic_cdk::println!("Starting migration task with interval {:?}", interval);
+        ic_cdk_timers::set_timer_interval(interval, run_migrations)
+    }
+
     fn clear_timer(timer_id: TimerId) {
         ic_cdk_timers::clear_timer(timer_id);
     }
@@ -1217,40 +2496,24 @@ async fn init(
     set_token_next_block(&TokenType::CKUSDT, 1);
     set_token_next_block(&TokenType::CKBTC, 1);
 
-    reconstruct_subaccounts();
+    index_missing_subaccounts();
+
+    // A fresh canister writes every value at the current schema version
+    register_migrations(true);
 }
 
-fn reconstruct_subaccounts() {
+// Subaccounts are indexed in nonce order, so only nonces handed out before
+// the index existed (or passed to init) are missing from it
+fn index_missing_subaccounts() {
     let nonce: u32 = nonce();
-    let account = CanisterApiManager::id();
-
-    ic_cdk::println!("Reconstructing subaccounts for account: {:?}", account);
-    for i in 0..nonce {
-        ic_cdk::println!("nonce: {}", i);
-        let subaccount = to_subaccount(i);
-        let subaccountid: AccountIdentifier = to_subaccount_id(subaccount);
-        let account_id_hash = subaccountid.to_u64_hash();
-
-        // Check if this is for an ICRC-1 token and get the text representation
-        let display_account_id = if account == CKUSDC_LEDGER_CANISTER_ID
-            || account == CKUSDT_LEDGER_CANISTER_ID
-            || account == CKBTC_LEDGER_CANISTER_ID
-        {
-            let icrc_account = IcrcAccount::from_principal_and_index(account, i);
-            icrc_account.to_text()
-        } else {
-            subaccountid.to_hex()
-        };
-
-        LIST_OF_SUBACCOUNTS.with(|list_ref| {
-            // print hash + AccountIdentifier_hex or ICRC-1 textual representation
-            ic_cdk::println!(
-                "hash: {}, subaccountid: {}",
-                account_id_hash,
-                display_account_id
-            );
-            list_ref.borrow_mut().insert(account_id_hash, subaccount);
-        });
+    let indexed = SUBACCOUNT_NONCES.with(|nonces| nonces.borrow().len()) as u32;
+    if indexed >= nonce {
+        return;
+    }
+
+    ic_cdk::println!("Indexing subaccounts {}..{}", indexed, nonce);
+    for i in indexed..nonce {
+        index_subaccount(i);
     }
 }
 
@@ -1265,6 +2528,50 @@ fn reconstruct_network() {
     });
 }
 
+fn migrate_token_registry() {
+    // Registered ledgers used to be stored under a fixed token id
+    let legacy_tokens: Vec<(u64, (TokenType, Principal))> =
+        LEGACY_TOKEN_LEDGER_PRINCIPALS.with(|tl| tl.borrow().iter().collect());
+
+    for (token_id, (token_type, principal)) in legacy_tokens {
+        match preset_token_config(&token_type) {
+            Some(preset) => {
+                ic_cdk::println!(
+                    "Migrating registered {:?} on ledger {} to the token registry",
+                    token_type,
+                    principal
+                );
+                let config = TokenConfig {
+                    ledger_canister_id: principal,
+                    ..preset
+                };
+                TOKEN_REGISTRY.with(|registry| registry.borrow_mut().insert(principal, config));
+            }
+            None => ic_cdk::println!("Skipping legacy token without preset: {:?}", token_type),
+        }
+        LEGACY_TOKEN_LEDGER_PRINCIPALS.with(|tl| tl.borrow_mut().remove(&token_id));
+    }
+
+    // Next blocks used to be keyed by the same token id
+    let legacy_blocks: Vec<(u8, u64)> =
+        LEGACY_TOKEN_NEXT_BLOCKS.with(|blocks| blocks.borrow().iter().collect());
+
+    for (token_id, block) in legacy_blocks {
+        let token_type = match token_id {
+            1 => Some(TokenType::ICP),
+            2 => Some(TokenType::CKUSDC),
+            3 => Some(TokenType::CKUSDT),
+            4 => Some(TokenType::CKBTC),
+            _ => None,
+        };
+        if let Some(token_type) = token_type {
+            ic_cdk::println!("Migrating next block {} for {:?}", block, token_type);
+            set_token_next_block(&token_type, block);
+        }
+        LEGACY_TOKEN_NEXT_BLOCKS.with(|blocks| blocks.borrow_mut().remove(&token_id));
+    }
+}
+
 fn migrate_block_tracking() {
     // Check if migration is needed
     let needs_migration = TOKEN_NEXT_BLOCKS.with(|blocks| blocks.borrow().is_empty());
@@ -1292,18 +2599,379 @@ fn migrate_block_tracking() {
     }
 }
 
+// Moves transactions keyed by block index only to (ledger, block index) keys
+fn migrate_transaction_keys(cursor: Option<&[u8]>, limit: u64) -> (u64, Option<Vec<u8>>) {
+    let legacy_transactions = entries_after(&LEGACY_TRANSACTIONS, cursor, limit);
+    for (index, tx) in legacy_transactions.iter() {
+        let mut tx = tx.clone();
+        // Entries stored before per-token tracking have no ledger id
+        let ledger_principal = tx
+            .token_ledger_canister_id
+            .unwrap_or_else(|| get_token_ledger_canister_id(&tx.token_type));
+        tx.token_ledger_canister_id = Some(ledger_principal);
+
+        insert_transaction((ledger_principal, *index), tx);
+        LEGACY_TRANSACTIONS.with(|legacy_ref| legacy_ref.borrow_mut().remove(index));
+    }
+    batch_progress(&legacy_transactions)
+}
+
+// Seeds running balances from the stored transactions: unswept deposits are
+// still held, swept ones were moved on in full and are skipped.
+// Deposits pruned before the upgrade are left to reconciliation.
+fn seed_subaccount_balances(cursor: Option<&[u8]>, limit: u64) -> (u64, Option<Vec<u8>>) {
+    let transactions = entries_after(&TRANSACTIONS, cursor, limit);
+    for (key, tx) in transactions.iter() {
+        credit_deposit(*key, tx);
+    }
+    batch_progress(&transactions)
+}
+
+const MIGRATION_BATCH_SIZE: u64 = 500;
+const MIGRATION_INTERVAL_SECONDS: u64 = 1;
+const BALANCE_SEED_MIGRATION_ID: u32 = 15;
+
+// Migrates up to `limit` values after the cursor, returning how many were
+// migrated and the key of the last one
+type BatchFn = fn(Option<&[u8]>, u64) -> (u64, Option<Vec<u8>>);
+
+// Rewrites the values of a stable structure at a schema version. Values are
+// decoded at any version up to the current one, so a structure stays readable
+// while its migration is pending; rewriting lets older decoders be retired.
+// Migrations that build data from stored values are versioned on their own,
+// bumping the version runs them again.
+struct Migration {
+    id: u32,
+    name: &'static str,
+    version: u8,
+    len: fn() -> u64,
+    run_batch: BatchFn,
+}
+
+// Ids are stored with the progress of each migration and must not be reused.
+// Migrations run one at a time in the order listed.
+const MIGRATIONS: &[Migration] = &[
+    // Transactions stored under the legacy keys are not visible until moved
+    Migration {
+        id: 14,
+        name: "transaction_keys",
+        version: 1,
+        len: || LEGACY_TRANSACTIONS.with(|map| map.borrow().len()),
+        run_batch: migrate_transaction_keys,
+    },
+    Migration {
+        id: 1,
+        name: "transactions",
+        version: StoredTransactions::VERSION,
+        len: || TRANSACTIONS.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&TRANSACTIONS, cursor, limit),
+    },
+    Migration {
+        id: 2,
+        name: "token_registry",
+        version: TokenConfig::VERSION,
+        len: || TOKEN_REGISTRY.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&TOKEN_REGISTRY, cursor, limit),
+    },
+    Migration {
+        id: 3,
+        name: "token_last_block_hash",
+        version: BlockHashRecord::VERSION,
+        len: || TOKEN_LAST_BLOCK_HASH.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&TOKEN_LAST_BLOCK_HASH, cursor, limit),
+    },
+    Migration {
+        id: 4,
+        name: "token_chain_alerts",
+        version: ChainAlert::VERSION,
+        len: || TOKEN_CHAIN_ALERTS.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&TOKEN_CHAIN_ALERTS, cursor, limit),
+    },
+    Migration {
+        id: 5,
+        name: "token_tip_anchors",
+        version: TipAnchor::VERSION,
+        len: || TOKEN_TIP_ANCHORS.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&TOKEN_TIP_ANCHORS, cursor, limit),
+    },
+    Migration {
+        id: 6,
+        name: "token_skipped_ticks",
+        version: SkippedTicks::VERSION,
+        len: || TOKEN_SKIPPED_TICKS.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&TOKEN_SKIPPED_TICKS, cursor, limit),
+    },
+    Migration {
+        id: 7,
+        name: "backfill_jobs",
+        version: BackfillJob::VERSION,
+        len: || BACKFILL_JOBS.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&BACKFILL_JOBS, cursor, limit),
+    },
+    Migration {
+        id: 8,
+        name: "token_ledger_resets",
+        version: LedgerReset::VERSION,
+        len: || TOKEN_LEDGER_RESETS.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&TOKEN_LEDGER_RESETS, cursor, limit),
+    },
+    Migration {
+        id: 9,
+        name: "subaccount_balances",
+        version: SubaccountBalance::VERSION,
+        len: || SUBACCOUNT_BALANCES.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&SUBACCOUNT_BALANCES, cursor, limit),
+    },
+    Migration {
+        id: 10,
+        name: "balance_discrepancies",
+        version: BalanceDiscrepancy::VERSION,
+        len: || BALANCE_DISCREPANCIES.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| rewrite_batch(&BALANCE_DISCREPANCIES, cursor, limit),
+    },
+    // Single values are rewritten at once
+    Migration {
+        id: 11,
+        name: "principals",
+        version: StoredPrincipal::VERSION,
+        len: || 2,
+        run_batch: |_, _| {
+            PRINCIPAL.with(|cell| {
+                let value = cell.borrow().get().clone();
+                let _ = cell.borrow_mut().set(value);
+            });
+            CUSTODIAN_PRINCIPAL.with(|cell| {
+                let value = cell.borrow().get().clone();
+                let _ = cell.borrow_mut().set(value);
+            });
+            (2, None)
+        },
+    },
+    Migration {
+        id: 12,
+        name: "network",
+        version: Network::VERSION,
+        len: || 1,
+        run_batch: |_, _| {
+            CONNECTED_NETWORK.with(|cell| {
+                let value = *cell.borrow().get();
+                let _ = cell.borrow_mut().set(value);
+            });
+            (1, None)
+        },
+    },
+    // Transactions stored before the secondary indexes existed are missing from
+    // hash, time and subaccount lookups until this completes
+    Migration {
+        id: 13,
+        name: "transaction_indexes",
+        version: 1,
+        len: || TRANSACTIONS.with(|map| map.borrow().len()),
+        run_batch: |cursor, limit| {
+            let entries = entries_after(&TRANSACTIONS, cursor, limit);
+            for (key, tx) in entries.iter() {
+                index_transaction(*key, tx);
+            }
+            batch_progress(&entries)
+        },
+    },
+    // Starts running balances for subaccounts indexed before they existed
+    Migration {
+        id: BALANCE_SEED_MIGRATION_ID,
+        name: "subaccount_balances_seed",
+        version: 1,
+        len: || TRANSACTIONS.with(|map| map.borrow().len()),
+        run_batch: seed_subaccount_balances,
+    },
+];
+
+// Up to `limit` entries of a map after the cursor key
+fn entries_after<K, V>(
+    map: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    cursor: Option<&[u8]>,
+    limit: u64,
+) -> Vec<(K, V)>
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let start = match cursor {
+        Some(bytes) => Excluded(K::from_bytes(Cow::Borrowed(bytes))),
+        None => Unbounded,
+    };
+    map.with(|map_ref| {
+        map_ref
+            .borrow()
+            .range((start, Unbounded))
+            .take(limit as usize)
+            .collect()
+    })
+}
+
+fn batch_progress<K: Storable, V>(entries: &[(K, V)]) -> (u64, Option<Vec<u8>>) {
+    let last = entries.last().map(|(key, _)| key.to_bytes().into_owned());
+    (entries.len() as u64, last)
+}
+
+fn rewrite_batch<K, V>(
+    map: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    cursor: Option<&[u8]>,
+    limit: u64,
+) -> (u64, Option<Vec<u8>>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let entries = entries_after(map, cursor, limit);
+    let progress = batch_progress(&entries);
+    map.with(|map_ref| {
+        let mut map = map_ref.borrow_mut();
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+    });
+    progress
+}
+
+// Records a migration for every structure whose values are not known to be
+// at the current schema version. Migrations already registered at that
+// version keep their progress.
+fn register_migrations(completed: bool) {
+    let now = CanisterApiManager::time();
+    for migration in MIGRATIONS {
+        let registered = MIGRATION_PROGRESS.with(|progress| progress.borrow().get(&migration.id));
+        if registered.is_some_and(|progress| progress.version == migration.version) {
+            continue;
+        }
+
+        let (status, total) = if completed {
+            (MigrationStatus::Completed, 0)
+        } else {
+            (MigrationStatus::Pending, (migration.len)())
+        };
+        ic_cdk::println!(
+            "Registering migration {} of {} values to version {}",
+            migration.name,
+            total,
+            migration.version
+        );
+        let progress = MigrationProgress {
+            id: migration.id,
+            name: migration.name.to_string(),
+            version: migration.version,
+            status,
+            cursor: None,
+            migrated: 0,
+            total,
+            started_at: now,
+            updated_at: now,
+        };
+        MIGRATION_PROGRESS.with(|map| map.borrow_mut().insert(migration.id, progress));
+    }
+}
+
+// First migration not completed, in the order of MIGRATIONS; progress of a
+// migration no longer listed comes last
+fn next_migration() -> Option<MigrationProgress> {
+    MIGRATION_PROGRESS.with(|progress| {
+        let progress = progress.borrow();
+        MIGRATIONS
+            .iter()
+            .filter_map(|migration| progress.get(&migration.id))
+            .chain(progress.iter().map(|(_, progress)| progress))
+            .find(|progress| progress.status != MigrationStatus::Completed)
+    })
+}
+
+// Keeps the migration timer running exactly while a migration is not completed
+fn schedule_migrations() {
+    let pending = next_migration().is_some();
+    MIGRATION_TIMER.with(|timer_ref| {
+        let mut timer = timer_ref.borrow_mut();
+        if pending && timer.is_none() {
+            let interval = std::time::Duration::from_secs(MIGRATION_INTERVAL_SECONDS);
+            *timer = Some(TimerManager::set_migration_timer(interval));
+        } else if !pending {
+            if let Some(timer_id) = timer.take() {
+                TimerManager::clear_timer(timer_id);
+            }
+        }
+    });
+}
+
+// Runs the next batch of the first migration not completed
+fn run_migrations() {
+    if let Some(mut progress) = next_migration() {
+        match MIGRATIONS
+            .iter()
+            .find(|migration| migration.id == progress.id)
+        {
+            Some(migration) => {
+                let (migrated, last) =
+                    (migration.run_batch)(progress.cursor.as_deref(), MIGRATION_BATCH_SIZE);
+                progress.migrated += migrated;
+                if last.is_some() {
+                    progress.cursor = last;
+                }
+                progress.status = if migrated < MIGRATION_BATCH_SIZE {
+                    MigrationStatus::Completed
+                } else {
+                    MigrationStatus::Running
+                };
+                ic_cdk::println!(
+                    "Migration {}: {} of {} values migrated",
+                    progress.name,
+                    progress.migrated,
+                    progress.total
+                );
+            }
+            None => {
+                ic_cdk::println!("Unknown migration {}, marking it completed", progress.id);
+                progress.status = MigrationStatus::Completed;
+            }
+        }
+        progress.updated_at = CanisterApiManager::time();
+        MIGRATION_PROGRESS.with(|map| map.borrow_mut().insert(progress.id, progress));
+    }
+    schedule_migrations();
+}
+
+#[query]
+fn get_migration_status() -> Result<Vec<MigrationProgress>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    Ok(MIGRATION_PROGRESS.with(|progress| {
+        progress
+            .borrow()
+            .iter()
+            .map(|(_, progress)| progress)
+            .collect()
+    }))
+}
+
 #[ic_cdk::post_upgrade]
 async fn post_upgrade() {
     ic_cdk::println!("Running post_upgrade...");
 
-    let _ = set_interval(500);
-
-    reconstruct_subaccounts();
+    index_missing_subaccounts();
     reconstruct_network();
 
+    // Move token id based registrations and block counters to ledger keyed storage
+    migrate_token_registry();
+
     // Migrate existing deployments to per-token block tracking
     migrate_block_tracking();
 
+    // Rewrite stored values at their current schema version and build data
+    // that did not exist before, in batches run by the migration timer
+    register_migrations(false);
+
+    // Timers do not survive upgrades; restart them once the registry is migrated
+    restore_timers();
+
     // Set the current caller as custodian principal if not already set
     let caller = api::caller();
     ic_cdk::println!("Post-upgrade caller: {}", caller.to_string());
@@ -1356,6 +3024,220 @@ fn set_interval(seconds: u64) -> Result<u64, Error> {
     Ok(seconds)
 }
 
+fn has_own_interval(ledger_principal: Principal) -> bool {
+    TOKEN_INTERVALS.with(|intervals| intervals.borrow().contains_key(&ledger_principal))
+}
+
+// Ledger the polling loop indexes for a token: its registered ledger, or the
+// init ledger for ICP when ICP is not registered
+fn polled_ledger(token_type: &TokenType) -> Option<Principal> {
+    TOKEN_REGISTRY
+        .with(|registry| {
+            registry
+                .borrow()
+                .iter()
+                .find(|(_, config)| config.token_type == *token_type)
+                .map(|(ledger_principal, _)| ledger_principal)
+        })
+        .or_else(|| match token_type {
+            TokenType::ICP => {
+                PRINCIPAL.with(|stored_ref| stored_ref.borrow().get().get_principal())
+            }
+            _ => None,
+        })
+}
+
+fn start_token_timer(token_type: &TokenType, ledger_principal: Principal, seconds: u64) {
+    let interval = std::time::Duration::from_secs(seconds);
+    let timer_id = TimerManager::set_token_timer(interval, token_type.clone(), ledger_principal);
+    let previous = TOKEN_LEDGER_TIMERS
+        .with(|timers_ref| timers_ref.borrow_mut().insert(token_type.clone(), timer_id));
+    if let Some(previous) = previous {
+        TimerManager::clear_timer(previous);
+    }
+}
+
+fn stop_token_timer(token_type: &TokenType) {
+    let previous =
+        TOKEN_LEDGER_TIMERS.with(|timers_ref| timers_ref.borrow_mut().remove(token_type));
+    if let Some(previous) = previous {
+        TimerManager::clear_timer(previous);
+    }
+}
+
+fn restore_timers() {
+    let seconds = INTERVAL_IN_SECONDS.with(|interval_ref| *interval_ref.borrow().get());
+    let timer_id = TimerManager::set_timer(std::time::Duration::from_secs(seconds));
+    TIMERS.with(|timers_ref| {
+        timers_ref.replace(timer_id);
+    });
+
+    let intervals: Vec<(Principal, u64)> =
+        TOKEN_INTERVALS.with(|intervals| intervals.borrow().iter().collect());
+    for (ledger_principal, seconds) in intervals {
+        let token_type = TOKEN_REGISTRY
+            .with(|registry| registry.borrow().get(&ledger_principal))
+            .map(|config| config.token_type)
+            .or_else(|| {
+                let icp_principal =
+                    PRINCIPAL.with(|stored_ref| stored_ref.borrow().get().get_principal());
+                (icp_principal == Some(ledger_principal)).then_some(TokenType::ICP)
+            });
+        match token_type {
+            Some(token_type) => start_token_timer(&token_type, ledger_principal, seconds),
+            None => ic_cdk::println!(
+                "No polled token for ledger {}, not restoring its {}s interval",
+                ledger_principal,
+                seconds
+            ),
+        }
+    }
+
+    // Resume backfill jobs left running
+    schedule_backfills();
+
+    // Resume migrations left pending or running
+    schedule_migrations();
+}
+
+#[query]
+fn get_token_intervals() -> Result<Vec<(TokenType, u64)>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let default_seconds = INTERVAL_IN_SECONDS.with(|interval_ref| *interval_ref.borrow().get());
+    let mut polled: Vec<(TokenType, Principal)> = TOKEN_REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .iter()
+            .map(|(ledger_principal, config)| (config.token_type, ledger_principal))
+            .collect()
+    });
+    if !polled
+        .iter()
+        .any(|(token_type, _)| *token_type == TokenType::ICP)
+    {
+        if let Some(icp_principal) = polled_ledger(&TokenType::ICP) {
+            polled.push((TokenType::ICP, icp_principal));
+        }
+    }
+
+    Ok(polled
+        .into_iter()
+        .map(|(token_type, ledger_principal)| {
+            let seconds = TOKEN_INTERVALS
+                .with(|intervals| intervals.borrow().get(&ledger_principal))
+                .unwrap_or(default_seconds);
+            (token_type, seconds)
+        })
+        .collect())
+}
+
+// Polls a token on its own interval; None puts it back on the shared interval
+#[update]
+fn set_token_interval(token_type: TokenType, seconds: Option<u64>) -> Result<u64, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let ledger_principal = polled_ledger(&token_type).ok_or_else(|| {
+        let error_msg = format!("Token {:?} is not registered", token_type);
+        ic_cdk::println!("Error: {}", error_msg);
+        Error { message: error_msg }
+    })?;
+
+    match seconds {
+        Some(0) => {
+            let error_msg = "Interval must be at least one second".to_string();
+            ic_cdk::println!("Error: {}", error_msg);
+            Err(Error { message: error_msg })
+        }
+        Some(seconds) => {
+            TOKEN_INTERVALS
+                .with(|intervals| intervals.borrow_mut().insert(ledger_principal, seconds));
+            start_token_timer(&token_type, ledger_principal, seconds);
+            Ok(seconds)
+        }
+        None => {
+            TOKEN_INTERVALS.with(|intervals| intervals.borrow_mut().remove(&ledger_principal));
+            stop_token_timer(&token_type);
+            Ok(INTERVAL_IN_SECONDS.with(|interval_ref| *interval_ref.borrow().get()))
+        }
+    }
+}
+
+#[query]
+fn get_token_index_canisters() -> Result<Vec<(TokenType, Principal)>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let indexes: Vec<(Principal, Principal)> =
+        TOKEN_INDEX_CANISTERS.with(|indexes| indexes.borrow().iter().collect());
+    Ok(indexes
+        .into_iter()
+        .filter_map(|(ledger_principal, index_principal)| {
+            let token_type = TOKEN_REGISTRY
+                .with(|registry| registry.borrow().get(&ledger_principal))
+                .map(|config| config.token_type)
+                .or_else(|| {
+                    (polled_ledger(&TokenType::ICP) == Some(ledger_principal))
+                        .then_some(TokenType::ICP)
+                })?;
+            Some((token_type, index_principal))
+        })
+        .collect())
+}
+
+// Discovers a token's transactions through its index canister (ICP index or
+// ICRC index-ng) instead of scanning the ledger; None goes back to scanning
+#[update]
+fn set_token_index_canister(
+    token_type: TokenType,
+    index_principal: Option<String>,
+) -> Result<String, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let ledger_principal = polled_ledger(&token_type).ok_or_else(|| {
+        let error_msg = format!("Token {:?} is not registered", token_type);
+        ic_cdk::println!("Error: {}", error_msg);
+        Error { message: error_msg }
+    })?;
+
+    match index_principal {
+        Some(index_principal) => {
+            let index_principal = Principal::from_text(&index_principal).map_err(|e| {
+                let error_msg = format!("Invalid index canister principal: {}", e);
+                ic_cdk::println!("Error: {}", error_msg);
+                Error { message: error_msg }
+            })?;
+            TOKEN_INDEX_CANISTERS.with(|indexes| {
+                indexes
+                    .borrow_mut()
+                    .insert(ledger_principal, index_principal)
+            });
+            Ok(format!(
+                "{:?} transactions are discovered through index {}",
+                token_type, index_principal
+            ))
+        }
+        None => {
+            TOKEN_INDEX_CANISTERS.with(|indexes| indexes.borrow_mut().remove(&ledger_principal));
+            Ok(format!(
+                "{:?} transactions are discovered by scanning the ledger",
+                token_type
+            ))
+        }
+    }
+}
+
 fn nonce() -> u32 {
     LAST_SUBACCOUNT_NONCE.with(|nonce_ref| *nonce_ref.borrow().get())
 }
@@ -1406,13 +3288,7 @@ fn add_subaccount(token_type: Option<TokenType>) -> Result<String, Error> {
     })?;
 
     let nonce = nonce();
-    let subaccount = to_subaccount(nonce); // needed for storing the subaccount
-    let subaccountid: AccountIdentifier = to_subaccount_id(subaccount); // needed to get the hashkey & to return to user
-    let account_id_hash = subaccountid.to_u64_hash();
-
-    LIST_OF_SUBACCOUNTS.with(|list_ref| {
-        list_ref.borrow_mut().insert(account_id_hash, subaccount);
-    });
+    let subaccountid = index_subaccount(nonce);
 
     LAST_SUBACCOUNT_NONCE.with(|nonce_ref| {
         let _ = nonce_ref.borrow_mut().set(nonce + 1);
@@ -1421,11 +3297,8 @@ fn add_subaccount(token_type: Option<TokenType>) -> Result<String, Error> {
     // Determine the token type
     let token_type = token_type.unwrap_or(TokenType::ICP);
 
-    // For ICRC-1 tokens (ckUSDC/ckUSDT/ckBTC), use the ICRC-1 textual representation
-    if token_type == TokenType::CKUSDC
-        || token_type == TokenType::CKUSDT
-        || token_type == TokenType::CKBTC
-    {
+    // For ICRC-1 tokens, use the ICRC-1 textual representation
+    if is_icrc_token(&token_type) {
         let canister_id = CanisterApiManager::id();
         let icrc_account = IcrcAccount::from_principal_and_index(canister_id, nonce);
         return Ok(icrc_account.to_text());
@@ -1453,40 +3326,29 @@ fn get_subaccountid(nonce_param: u32, token_type: Option<TokenType>) -> Result<S
     // First, get the traditional account ID for compatibility with existing code
     let subaccount = to_subaccount(nonce_param);
     let subaccountid: AccountIdentifier = to_subaccount_id(subaccount);
-    let account_id_hash = subaccountid.to_u64_hash();
-
-    LIST_OF_SUBACCOUNTS.with(|subaccounts| {
-        let subaccounts_borrow = subaccounts.borrow();
-
-        ic_cdk::println!("account_id_hash to search: {}", account_id_hash);
-
-        // Check if the subaccount exists in our store
-        match subaccounts_borrow.get(&account_id_hash) {
-            Some(_) => {
-                // Determine the token type
-                let token_type = token_type.unwrap_or(TokenType::ICP);
-                let canister_id = CanisterApiManager::id();
 
-                // For ICRC-1 tokens (ckUSDC/ckUSDT/ckBTC), use the ICRC-1 textual representation
-                if token_type == TokenType::CKUSDC
-                    || token_type == TokenType::CKUSDT
-                    || token_type == TokenType::CKBTC
-                {
-                    let icrc_account =
-                        IcrcAccount::from_principal_and_index(canister_id, nonce_param);
-                    Ok(icrc_account.to_text())
-                } else {
-                    // For ICP, use the traditional account ID
-                    Ok(subaccountid.to_hex())
-                }
-            }
-            None => {
-                let error_msg = "Account not found".to_string();
-                ic_cdk::println!("Error: {}", error_msg);
-                Err(Error { message: error_msg })
+    // Check if the subaccount exists in our store
+    match subaccount_nonce(&subaccountid) {
+        Some(_) => {
+            // Determine the token type
+            let token_type = token_type.unwrap_or(TokenType::ICP);
+            let canister_id = CanisterApiManager::id();
+
+            // For ICRC-1 tokens, use the ICRC-1 textual representation
+            if is_icrc_token(&token_type) {
+                let icrc_account = IcrcAccount::from_principal_and_index(canister_id, nonce_param);
+                Ok(icrc_account.to_text())
+            } else {
+                // For ICP, use the traditional account ID
+                Ok(subaccountid.to_hex())
             }
         }
-    })
+        None => {
+            let error_msg = "Account not found".to_string();
+            ic_cdk::println!("Error: {}", error_msg);
+            Err(Error { message: error_msg })
+        }
+    }
 }
 
 #[query]
@@ -1511,7 +3373,7 @@ fn get_icrc_account(nonce_param: u32) -> Result<String, Error> {
 
 #[query]
 fn get_subaccount_count() -> Result<u32, String> {
-    Ok(LIST_OF_SUBACCOUNTS.with(|subaccounts| subaccounts.borrow().len() as u32))
+    Ok(SUBACCOUNT_NONCES.with(|nonces| nonces.borrow().len() as u32))
 }
 
 #[query]
@@ -1520,10 +3382,16 @@ fn get_transactions_count() -> Result<u32, String> {
 }
 
 #[query]
-fn get_oldest_block() -> Result<Option<u64>, String> {
+fn get_oldest_block(token_type: Option<TokenType>) -> Result<Option<u64>, String> {
+    let token_type = token_type.unwrap_or(TokenType::ICP);
+    let ledger_principal = get_token_ledger_canister_id(&token_type);
+
     Ok(TRANSACTIONS.with(|transactions_ref| {
         let transactions_borrow = transactions_ref.borrow();
-        transactions_borrow.iter().next().map(|(key, _value)| key)
+        transactions_borrow
+            .range((ledger_principal, 0)..=(ledger_principal, u64::MAX))
+            .next()
+            .map(|((_ledger, index), _value)| index)
     }))
 }
 
@@ -1561,66 +3429,201 @@ fn list_transactions(up_to_count: Option<u64>) -> Result<Vec<StoredTransactions>
     Ok(result)
 }
 
-async fn process_archived_block(block_index: u64) -> Result<String, String> {
-    use ic_ledger_types as iclt;
-
-    // Figure out which ICP ledger canister to talk to (stored or mainnet default)
-    let icp_ledger = PRINCIPAL
-        .with(|stored_ref| stored_ref.borrow().get().get_principal())
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
-
-    // Ask the ledger for the single block at `block_index`.
-    let args = GetBlocksArgs {
-        start: block_index,
-        length: 1,
-    };
+// Most transactions one page of a listing query returns
+const MAX_TRANSACTIONS_PAGE_SIZE: u64 = 1_000;
 
-    let resp = match ic_query_blocks(icp_ledger, args.clone()).await {
-        Ok(r) => r,
-        Err((code, msg)) => return Err(format!("query_blocks failed: {:?}: {}", code, msg)),
+// Transactions created in [from_ts, to_ts), oldest first. Pass the returned
+// next_cursor back to continue where the page stopped.
+#[query]
+fn list_transactions_between(
+    from_ts: Timestamp,
+    to_ts: Timestamp,
+    cursor: Option<TransactionCursor>,
+    limit: Option<u64>,
+) -> Result<TransactionsPage, Error> {
+    if from_ts.timestamp_nanos > to_ts.timestamp_nanos {
+        let error_msg = format!(
+            "Invalid range: from_ts {} is after to_ts {}",
+            from_ts.timestamp_nanos, to_ts.timestamp_nanos
+        );
+        ic_cdk::println!("Error: {}", error_msg);
+        return Err(Error { message: error_msg });
+    }
+    let limit = limit.unwrap_or(100).clamp(1, MAX_TRANSACTIONS_PAGE_SIZE) as usize;
+
+    // One entry past the page tells whether another page follows
+    let mut entries = transaction_keys_between(
+        from_ts.timestamp_nanos,
+        to_ts.timestamp_nanos,
+        cursor.as_ref(),
+        limit + 1,
+    );
+    let next_cursor = if entries.len() > limit {
+        entries.pop().map(TransactionCursor::from)
+    } else {
+        None
     };
 
-    // Pull the block from the ledger result or from the archive callback.
-    let ic_block_opt = if let Some(b) = resp.blocks.into_iter().next() {
-        Some(b)
-    } else {
-        // Find the archive range that covers our block and call its callback.
-        if let Some(range) = resp
-            .archived_blocks
+    let transactions = TRANSACTIONS.with(|transactions_ref| {
+        let transactions = transactions_ref.borrow();
+        entries
             .into_iter()
-            .find(|r| r.start <= block_index && (block_index - r.start) < r.length)
+            .filter_map(|(_, key)| transactions.get(&key))
+            .collect()
+    });
+
+    Ok(TransactionsPage {
+        transactions,
+        next_cursor,
+    })
+}
+
+// Upper bound on index entries one list_transactions_v2 call inspects, so that
+// a selective filter cannot run the query out of instructions
+const MAX_TRANSACTIONS_SCANNED: usize = 10_000;
+
+fn transaction_matches(
+    tx: &StoredTransactions,
+    filter: &TransactionFilter,
+    account: Option<&[u8]>,
+) -> bool {
+    if filter
+        .sweep_status
+        .as_ref()
+        .is_some_and(|status| *status != tx.sweep_status)
+    {
+        return false;
+    }
+    if let Some(account) = account {
+        let touches_account = tx
+            .operation
+            .as_ref()
+            .is_some_and(|operation| operation.accounts().contains(&account));
+        if !touches_account {
+            return false;
+        }
+    }
+    if filter.min_amount.is_some() || filter.max_amount.is_some() {
+        let Some(amount) = tx.operation.as_ref().map(Operation::amount) else {
+            return false;
+        };
+        if filter.min_amount.as_ref().is_some_and(|min| amount < min)
+            || filter.max_amount.as_ref().is_some_and(|max| amount > max)
         {
-            match ic_query_archived_blocks(&range.callback, args).await {
-                Ok(Ok(range)) => range.blocks.into_iter().next(),
-                Ok(Err(e)) => return Err(format!("archive get_blocks error: {:?}", e)),
-                Err((code, msg)) => {
-                    return Err(format!("archive call failed: {:?}: {}", code, msg))
-                }
-            }
-        } else {
-            None
+            return false;
         }
-    };
-
-    // No such block anywhere
-    let ic_block = match ic_block_opt {
-        Some(b) => b,
-        None => {
-            return Ok(format!(
-                "Block {} not found (ledger/archives).",
-                block_index
-            ))
+    }
+    true
+}
+
+// Filtered transactions in creation time order. A page can come back short
+// when the scan budget runs out; keep following next_cursor until it is null.
+#[query]
+fn list_transactions_v2(
+    filter: Option<TransactionFilter>,
+    cursor: Option<TransactionCursor>,
+    limit: Option<u64>,
+    order: Option<SortOrder>,
+) -> Result<TransactionsPage, Error> {
+    let filter = filter.unwrap_or_default();
+    let order = order.unwrap_or_default();
+    // An empty page would hand back its own cursor, so a limit of 0 reads as 1
+    let limit = limit.unwrap_or(100).clamp(1, MAX_TRANSACTIONS_PAGE_SIZE) as usize;
+
+    let from_nanos = filter.from_ts.as_ref().map_or(0, |ts| ts.timestamp_nanos);
+    let to_nanos = filter
+        .to_ts
+        .as_ref()
+        .map_or(u64::MAX, |ts| ts.timestamp_nanos);
+    if from_nanos > to_nanos {
+        let error_msg = format!(
+            "Invalid range: from_ts {} is after to_ts {}",
+            from_nanos, to_nanos
+        );
+        ic_cdk::println!("Error: {}", error_msg);
+        return Err(Error { message: error_msg });
+    }
+    let ledger_principal = filter.token_type.as_ref().map(get_token_ledger_canister_id);
+    let account = filter
+        .subaccount
+        .as_deref()
+        .map(parse_canister_account)
+        .transpose()?;
+    // Generated subaccounts have their own index, so only their transactions
+    // are scanned; other accounts are matched along the time index
+    let nonce = account.as_deref().and_then(account_id_nonce);
+
+    let lower = (from_nanos, (Principal::from_slice(&[]), 0));
+    let upper = (to_nanos, (Principal::from_slice(&[]), 0));
+    let cursor = cursor.as_ref().map(TransactionCursor::entry);
+    let bounds = match (order, cursor) {
+        (SortOrder::Ascending, Some(cursor)) => (Included(cursor.max(lower)), Excluded(upper)),
+        (SortOrder::Descending, Some(cursor)) if cursor < upper => {
+            (Included(lower), Included(cursor))
         }
+        _ => (Included(lower), Excluded(upper)),
     };
 
+    TX_TIME_INDEX.with(|time_index| {
+        SUBACCOUNT_TX_INDEX.with(|subaccount_index| {
+            TRANSACTIONS.with(|transactions_ref| {
+                let time_index = time_index.borrow();
+                let subaccount_index = subaccount_index.borrow();
+                let transactions = transactions_ref.borrow();
+
+                let entries: Box<dyn DoubleEndedIterator<Item = (u64, TransactionKey)>> =
+                    match nonce {
+                        Some(nonce) => {
+                            let (lower, upper) = bounds;
+                            let bounds = (lower.map(|e| (nonce, e)), upper.map(|e| (nonce, e)));
+                            Box::new(subaccount_index.range(bounds).map(|((_, entry), _)| entry))
+                        }
+                        None => Box::new(time_index.range(bounds).map(|(entry, _)| entry)),
+                    };
+                let entries: Box<dyn Iterator<Item = (u64, TransactionKey)>> = match order {
+                    SortOrder::Ascending => entries,
+                    SortOrder::Descending => Box::new(entries.rev()),
+                };
+
+                let mut page = Vec::new();
+                let mut next_cursor = None;
+                for (scanned, entry) in entries.enumerate() {
+                    if page.len() == limit || scanned == MAX_TRANSACTIONS_SCANNED {
+                        next_cursor = Some(TransactionCursor::from(entry));
+                        break;
+                    }
+                    let (_, key) = entry;
+                    if ledger_principal.is_some_and(|ledger| ledger != key.0) {
+                        continue;
+                    }
+                    if let Some(tx) = transactions.get(&key) {
+                        if transaction_matches(&tx, &filter, account.as_deref()) {
+                            page.push(tx);
+                        }
+                    }
+                }
+
+                Ok(TransactionsPage {
+                    transactions: page,
+                    next_cursor,
+                })
+            })
+        })
+    })
+}
+
+#[cfg(not(test))]
+fn icp_block_to_block(ic_block: ic_ledger_types::Block) -> Block {
+    use ic_ledger_types as iclt;
+
     fn ai_bytes(ai: iclt::AccountIdentifier) -> Vec<u8> {
         ai.as_ref().to_vec()
     }
-    fn e8s(t: iclt::Tokens) -> u64 {
-        t.e8s()
+    fn nat(t: iclt::Tokens) -> candid::Nat {
+        candid::Nat::from(t.e8s())
     }
 
-    let local_operation = match ic_block.transaction.operation.clone() {
+    let local_operation = match ic_block.transaction.operation {
         Some(iclt::Operation::Transfer {
             from,
             to,
@@ -1629,8 +3632,8 @@ async fn process_archived_block(block_index: u64) -> Result<String, String> {
         }) => Some(Operation::Transfer(Transfer {
             from: ai_bytes(from),
             to: ai_bytes(to),
-            amount: E8s { e8s: e8s(amount) },
-            fee: E8s { e8s: e8s(fee) },
+            amount: nat(amount),
+            fee: nat(fee),
             spender: None,
         })),
         Some(iclt::Operation::TransferFrom {
@@ -1642,17 +3645,17 @@ async fn process_archived_block(block_index: u64) -> Result<String, String> {
         }) => Some(Operation::Transfer(Transfer {
             from: ai_bytes(from),
             to: ai_bytes(to),
-            amount: E8s { e8s: e8s(amount) },
-            fee: E8s { e8s: e8s(fee) },
+            amount: nat(amount),
+            fee: nat(fee),
             spender: Some(ai_bytes(spender)),
         })),
         Some(iclt::Operation::Mint { to, amount }) => Some(Operation::Mint(Mint {
             to: ai_bytes(to),
-            amount: E8s { e8s: e8s(amount) },
+            amount: nat(amount),
         })),
         Some(iclt::Operation::Burn { from, amount }) => Some(Operation::Burn(Burn {
             from: ai_bytes(from),
-            amount: E8s { e8s: e8s(amount) },
+            amount: nat(amount),
             spender: None,
         })),
         Some(iclt::Operation::Approve {
@@ -1661,9 +3664,8 @@ async fn process_archived_block(block_index: u64) -> Result<String, String> {
             Some(Operation::Approve(Approve {
                 from: ai_bytes(from),
                 spender: ai_bytes(spender),
-                fee: E8s { e8s: e8s(fee) },
-                allowance: E8s { e8s: 0 }, // Default to 0 for unknown allowance
-                allowance_e8s: 0,          // Default to 0 for unknown allowance
+                fee: nat(fee),
+                allowance: candid::Nat::from(0u64), // Default to 0 for unknown allowance
                 expected_allowance: None,
                 expires_at: None,
             }))
@@ -1671,92 +3673,352 @@ async fn process_archived_block(block_index: u64) -> Result<String, String> {
         None => None,
     };
 
-    let local_block = Block {
+    Block {
         transaction: Transaction {
-            memo: ic_block.transaction.memo.0, // same u64 you already use
-            icrc1_memo: None,
+            memo: ic_block.transaction.memo.0,
+            icrc1_memo: ic_block.transaction.icrc1_memo.map(|m| m.into_vec()),
             operation: local_operation,
             created_at_time: Timestamp {
                 timestamp_nanos: ic_block.transaction.created_at_time.timestamp_nanos,
             },
+            from_account: None,
+            to_account: None,
+            spender_account: None,
         },
         timestamp: Timestamp {
             timestamp_nanos: ic_block.timestamp.timestamp_nanos,
         },
         parent_hash: ic_block.parent_hash.map(|h| h.to_vec()),
+        tx_hash: None,
+        block_hash: None,
+    }
+}
+
+// Fetches a single block from the ledger, following the archive range if needed
+async fn fetch_single_block(
+    ledger_principal: Principal,
+    block_index: u64,
+) -> Result<Option<Block>, String> {
+    let req = QueryBlocksRequest {
+        start: block_index,
+        length: 1,
     };
 
-    let mut processed = false;
-    let mut tx_hashes: Vec<String> = vec![];
-
-    if let Some(op) = local_block.transaction.operation.as_ref() {
-        let subaccount_exist = match op {
-            Operation::Approve(d) => includes_hash(&d.from) || includes_hash(&d.spender),
-            Operation::Burn(d) => {
-                includes_hash(&d.from)
-                    || d.spender
-                        .as_ref()
-                        .map(|s| includes_hash(s))
-                        .unwrap_or(false)
-            }
-            Operation::Mint(d) => includes_hash(&d.to),
-            Operation::Transfer(d) => {
-                includes_hash(&d.to)
-                    || d.spender
-                        .as_ref()
-                        .map(|s| includes_hash(s))
-                        .unwrap_or(false)
-            }
+    let (response,) = InterCanisterCallManager::query_blocks(ledger_principal, req.clone())
+        .await
+        .map_err(|(code, msg)| format!("query_blocks failed: {:?}: {}", code, msg))?;
+
+    if block_index >= response.first_block_index {
+        let offset = (block_index - response.first_block_index) as usize;
+        if let Some(block) = response.blocks.into_iter().nth(offset) {
+            return Ok(Some(block));
+        }
+    }
+
+    // Find the archive range that covers our block and call its callback.
+    match response
+        .archived_blocks
+        .into_iter()
+        .find(|r| r.start <= block_index && (block_index - r.start) < r.length)
+    {
+        Some(archived) => {
+            let blocks =
+                InterCanisterCallManager::query_archived_blocks(ledger_principal, archived, req)
+                    .await?;
+            Ok(blocks.into_iter().next())
+        }
+        None => Ok(None),
+    }
+}
+
+async fn process_archived_block(
+    token_type: TokenType,
+    ledger_principal: Principal,
+    block_index: u64,
+) -> Result<String, String> {
+    // No such block anywhere
+    let block = match fetch_single_block(ledger_principal, block_index).await? {
+        Some(b) => b,
+        None => {
+            return Ok(format!(
+                "Block {} not found (ledger/archives).",
+                block_index
+            ))
+        }
+    };
+
+    match index_block(&token_type, ledger_principal, block_index, &block) {
+        Some(hash) => Ok(format!(
+            "Processed {:?} block {} from archive path. Stored 1 tx(s). Hashes: {:?}",
+            token_type,
+            block_index,
+            vec![hash]
+        )),
+        None => Ok(format!(
+            "Processed {:?} block {} but found no new transactions for canister subaccounts",
+            token_type, block_index
+        )),
+    }
+}
+
+const BACKFILL_INTERVAL_SECONDS: u64 = 1;
+
+// Contiguous blocks from `start` on, read from the archives and the ledger.
+// Fewer than `length` blocks come back when an archive returns a short range.
+async fn fetch_block_range(
+    ledger_principal: Principal,
+    start: u64,
+    length: u64,
+) -> Result<Vec<Block>, String> {
+    let req = QueryBlocksRequest { start, length };
+    let (response,) = InterCanisterCallManager::query_blocks(ledger_principal, req.clone())
+        .await
+        .map_err(|(code, msg)| format!("query_blocks failed: {:?}: {}", code, msg))?;
+    record_ledger_page(ledger_principal, &req, &response);
+
+    let mut blocks = vec![];
+    let mut next = start;
+    for archived in response.archived_blocks.iter() {
+        let archived_end = archived.start + archived.length;
+        if archived_end <= next {
+            continue;
+        }
+        if archived.start > next {
+            return Ok(blocks);
+        }
+
+        let archived_req = QueryBlocksRequest {
+            start: next,
+            length: archived_end - next,
         };
+        let archived_blocks = InterCanisterCallManager::query_archived_blocks(
+            ledger_principal,
+            archived.clone(),
+            archived_req,
+        )
+        .await?;
+        next += archived_blocks.len() as u64;
+        blocks.extend(archived_blocks);
+        if next < archived_end {
+            return Ok(blocks);
+        }
+    }
 
-        if subaccount_exist {
-            TRANSACTIONS.with(|transactions_ref| {
-                let mut transactions = transactions_ref.borrow_mut();
+    if next >= response.first_block_index {
+        let offset = (next - response.first_block_index) as usize;
+        blocks.extend(response.blocks.into_iter().skip(offset));
+    }
+    blocks.truncate(length as usize);
+    Ok(blocks)
+}
+
+fn running_backfill_ids() -> Vec<u64> {
+    BACKFILL_JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .filter(|(_, job)| job.status == BackfillStatus::Running)
+            .map(|(id, _)| id)
+            .collect()
+    })
+}
 
-                let hash = match hash_transaction(&local_block.transaction) {
-                    Ok(h) => h,
-                    Err(err) => {
-                        ic_cdk::println!("ERROR hashing archived transaction: {}", err);
-                        "HASH-IS-NOT-AVAILABLE".to_string()
+// Keeps the backfill timer running exactly while a job is running
+fn schedule_backfills() {
+    let running = !running_backfill_ids().is_empty();
+    BACKFILL_TIMER.with(|timer_ref| {
+        let mut timer = timer_ref.borrow_mut();
+        if running && timer.is_none() {
+            let interval = std::time::Duration::from_secs(BACKFILL_INTERVAL_SECONDS);
+            *timer = Some(TimerManager::set_backfill_timer(interval));
+        } else if !running {
+            if let Some(timer_id) = timer.take() {
+                TimerManager::clear_timer(timer_id);
+            }
+        }
+    });
+}
+
+fn running_backfill(job_id: u64) -> Option<BackfillJob> {
+    BACKFILL_JOBS
+        .with(|jobs| jobs.borrow().get(&job_id))
+        .filter(|job| job.status == BackfillStatus::Running)
+}
+
+// Indexes the next chunk of a backfill job. Transactions already stored are
+// counted and left untouched, and no webhooks are sent for the range. Parent
+// hashes are checked within the chunk rather than against the ledger's
+// recorded tip, which the backfilled range sits behind, so each chunk
+// re-anchors on its first block.
+async fn run_backfill_chunk(job_id: u64) {
+    let _guard = match BackfillGuard::new(job_id) {
+        Some(guard) => guard,
+        None => {
+            ic_cdk::println!("Backfill {} still indexing its previous chunk", job_id);
+            return;
+        }
+    };
+    let job = match running_backfill(job_id) {
+        Some(job) => job,
+        None => return,
+    };
+    if chain_alert_raised(&job.token_type, job.ledger_principal) {
+        return;
+    }
+
+    let length = (job.to_block - job.next_block)
+        .saturating_add(1)
+        .min(catch_up_batch_size(job.ledger_principal));
+    let result = fetch_block_range(job.ledger_principal, job.next_block, length).await;
+
+    // Re-read the job, it may have been cancelled while the chunk was fetched
+    let mut job = match running_backfill(job_id) {
+        Some(job) => job,
+        None => return,
+    };
+    job.updated_at = CanisterApiManager::time();
+    match result {
+        // Left running, the chunk is retried on the next tick
+        Err(err) => {
+            ic_cdk::println!("ERROR in backfill {}: {}", job_id, err);
+            job.last_error = Some(err);
+        }
+        Ok(blocks) if blocks.is_empty() => {
+            let error_msg = format!("No block {} on ledger or archives", job.next_block);
+            ic_cdk::println!("Backfill {} failed: {}", job_id, error_msg);
+            job.status = BackfillStatus::Failed;
+            job.last_error = Some(error_msg);
+        }
+        Ok(blocks) => {
+            let mut last_hash = None;
+            job.last_error = None;
+            for block in blocks.iter() {
+                let block_index = job.next_block;
+                last_hash = match check_parent_hash(&job.token_type, last_hash, block_index, block)
+                {
+                    Ok(record) => record,
+                    Err(alert) => {
+                        job.last_error = Some(format!(
+                            "Parent hash mismatch at block {}, indexing halted",
+                            block_index
+                        ));
+                        raise_chain_alert(job.ledger_principal, alert);
+                        break;
                     }
                 };
 
-                let icp_principal = PRINCIPAL
-                    .with(|stored_ref| stored_ref.borrow().get().get_principal())
-                    .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
-
-                let tx = StoredTransactions::new(
-                    block_index,
-                    local_block.transaction.clone(),
-                    hash.clone(),
-                    TokenType::ICP,
-                    icp_principal,
-                );
+                let key = (job.ledger_principal, block_index);
+                if TRANSACTIONS.with(|transactions| transactions.borrow().contains_key(&key)) {
+                    job.already_indexed += 1;
+                } else if index_block(&job.token_type, job.ledger_principal, block_index, block)
+                    .is_some()
+                {
+                    job.indexed += 1;
+                }
 
-                if !transactions.contains_key(&block_index) {
-                    let _ = transactions.insert(block_index, tx);
-                    tx_hashes.push(hash);
-                    processed = true;
+                if block_index == job.to_block {
+                    job.status = BackfillStatus::Completed;
+                    break;
                 }
-            });
+                job.next_block = block_index + 1;
+            }
+            ic_cdk::println!(
+                "Backfill {} of {:?} at block {}, {} stored, {} already stored",
+                job_id,
+                job.token_type,
+                job.next_block,
+                job.indexed,
+                job.already_indexed
+            );
         }
     }
+    BACKFILL_JOBS.with(|jobs| jobs.borrow_mut().insert(job_id, job));
+}
 
-    if processed {
-        Ok(format!(
-            "Processed ICP block {} from archive path. Stored {} tx(s). Hashes: {:?}",
-            block_index,
-            tx_hashes.len(),
-            tx_hashes
-        ))
-    } else {
-        Ok(format!(
-            "Processed ICP block {} but found no transactions for canister subaccounts",
-            block_index
-        ))
+async fn run_backfill_jobs() {
+    for job_id in running_backfill_ids() {
+        run_backfill_chunk(job_id).await;
     }
+    schedule_backfills();
+}
+
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+struct Icrc1SupportedStandard {
+    name: String,
+    url: String,
+}
+
+// Index canister responses, only the transaction ids are decoded
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+struct IcpIndexGetAccountTransactionsArgs {
+    account_identifier: String,
+    start: Option<u64>,
+    max_results: u64,
+}
+
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+struct IcpIndexTransactionId {
+    id: u64,
+}
+
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+struct IcpIndexTransactions {
+    transactions: Vec<IcpIndexTransactionId>,
+}
+
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+enum IcpIndexGetTransactionsResult {
+    Ok(IcpIndexTransactions),
+    Err(IndexError),
+}
+
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+struct IcrcIndexGetAccountTransactionsArgs {
+    account: icrc_ledger_types::icrc1::account::Account,
+    start: Option<candid::Nat>,
+    max_results: candid::Nat,
+}
+
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+struct IcrcIndexTransactionId {
+    id: candid::Nat,
+}
+
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+struct IcrcIndexTransactions {
+    transactions: Vec<IcrcIndexTransactionId>,
 }
 
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+enum IcrcIndexGetTransactionsResult {
+    Ok(IcrcIndexTransactions),
+    Err(IndexError),
+}
+
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+struct IndexError {
+    message: String,
+}
+
+// ICP query_encoded_blocks response, the certificate and archived ranges are
+// already taken from query_blocks
+#[cfg(not(test))]
+#[derive(CandidType, Deserialize)]
+struct IcpQueryEncodedBlocksResponse {
+    first_block_index: u64,
+    blocks: Vec<serde_bytes::ByteBuf>,
+}
+
+#[cfg(not(test))]
 #[derive(CandidType, Deserialize, Clone)]
 struct Icrc3GetBlocksRequest {
     start: candid::Nat,
@@ -1769,6 +4031,7 @@ struct Icrc3BlockWithId {
     block: Icrc3Value,
 }
 
+#[cfg(not(test))]
 #[derive(CandidType, Deserialize)]
 struct Icrc3GetBlocksResult {
     log_length: candid::Nat,
@@ -1778,6 +4041,7 @@ struct Icrc3GetBlocksResult {
 }
 
 // Minimal stub just to deserialize; we won't call the callback from here.
+#[cfg(not(test))]
 #[derive(CandidType, Deserialize)]
 struct Icrc3ArchivedBlocksStub {
     // different ledgers may expose single or vec args; keep flexible
@@ -1795,11 +4059,13 @@ enum Icrc3Value {
     Array(Vec<Icrc3Value>),
 }
 
+#[cfg(not(test))]
 #[derive(CandidType, Deserialize)]
 struct Icrc3GetArchivesArgs {
     from: Option<Principal>,
 }
 
+#[cfg(not(test))]
 #[derive(CandidType, Deserialize)]
 struct Icrc3ArchiveInfo {
     canister_id: Principal,
@@ -1808,12 +4074,107 @@ struct Icrc3ArchiveInfo {
 }
 
 // ICRC-3 get_archives returns the vector directly, not wrapped in a record
+#[cfg(not(test))]
 type Icrc3GetArchivesResult = Vec<Icrc3ArchiveInfo>;
 
-fn icrc3_block_to_block(icrc3: &Icrc3BlockWithId) -> Option<Block> {
+#[cfg(not(test))]
+fn is_icrc_ledger(ledger_principal: Principal) -> bool {
+    get_token_config_by_ledger(ledger_principal)
+        .map(|config| config.standard == TokenStandard::ICRC1)
+        .unwrap_or(false)
+}
+
+// Maps the archived ranges of an icrc3_get_blocks response onto the archive
+// canisters listed by icrc3_get_archives
+#[cfg(not(test))]
+async fn resolve_icrc3_archives(
+    ledger_principal: Principal,
+    archived: &[Icrc3ArchivedBlocksStub],
+) -> CallResult<Vec<ArchivedBlock>> {
+    let (archives,): (Icrc3GetArchivesResult,) = ic_cdk::call(
+        ledger_principal,
+        "icrc3_get_archives",
+        (Icrc3GetArchivesArgs { from: None },),
+    )
+    .await?;
+
+    let mut result = Vec::new();
+    for args in archived.iter().flat_map(|a| a.args.iter()) {
+        let start = args.start.0.to_u64().unwrap_or(u64::MAX);
+        let end = start.saturating_add(args.length.0.to_u64().unwrap_or(0));
+
+        for archive in archives.iter() {
+            let archive_start = archive.start.0.to_u64().unwrap_or(u64::MAX);
+            // ICRC-3 archive ranges are inclusive of `end`
+            let archive_end = archive.end.0.to_u64().unwrap_or(0).saturating_add(1);
+
+            let range_start = start.max(archive_start);
+            let range_end = end.min(archive_end);
+            if range_start < range_end {
+                result.push(ArchivedBlock {
+                    callback: ArchiveCallback {
+                        canister_id: archive.canister_id,
+                        method: "icrc3_get_blocks".to_string(),
+                    },
+                    start: range_start,
+                    length: range_end - range_start,
+                });
+            }
+        }
+    }
+
+    result.sort_by_key(|a| a.start);
+    Ok(result)
+}
+
+// ICRC account value [owner, subaccount?]
+fn icrc3_account(value: &Icrc3Value) -> Option<IcrcAccount> {
+    let fields = match value {
+        Icrc3Value::Array(fields) => fields,
+        _ => return None,
+    };
+    let owner = match fields.first() {
+        Some(Icrc3Value::Blob(owner)) => Principal::from_slice(owner),
+        _ => return None,
+    };
+    let subaccount = match fields.get(1) {
+        Some(Icrc3Value::Blob(sa)) if sa.len() == 32 => {
+            let mut a = [0u8; 32];
+            a.copy_from_slice(sa);
+            Some(a)
+        }
+        _ => None,
+    };
+    Some(IcrcAccount::new(owner, subaccount))
+}
+
+// ICP AccountIdentifier bytes of an ICRC account, which is what the subaccount
+// lookup and the stored operations use
+fn icrc_account_bytes(account: &IcrcAccount) -> Vec<u8> {
+    let subaccount = account
+        .subaccount
+        .map(Subaccount)
+        .unwrap_or(DEFAULT_SUBACCOUNT);
+    AccountIdentifier::new(&account.owner, &subaccount)
+        .as_ref()
+        .to_vec()
+}
+
+fn icrc3_nat(value: &Icrc3Value) -> Option<candid::Nat> {
+    match value {
+        Icrc3Value::Nat(n) => Some(n.clone()),
+        _ => None,
+    }
+}
+
+// Unsupported operations are kept as blocks without an operation so that
+// block numbering stays aligned with the ledger
+fn icrc3_block_to_block(icrc3: &Icrc3BlockWithId) -> Block {
     let mut tx_map: Option<Icrc3Value> = None;
     let mut ts_nanos: u64 = 0;
     let mut phash: Option<Vec<u8>> = None;
+    let mut btype = String::new();
+    let mut block_fee: Option<candid::Nat> = None;
 
     if let Icrc3Value::Map(fields) = &icrc3.block {
         for (k, v) in fields {
@@ -1829,21 +4190,37 @@ fn icrc3_block_to_block(icrc3: &Icrc3BlockWithId) -> Option<Block> {
                         phash = Some(b.clone())
                     }
                 }
+                "btype" => {
+                    if let Icrc3Value::Text(s) = v {
+                        btype = s.clone()
+                    }
+                }
+                // Fee charged by the ledger when the caller did not set one
+                "fee" => block_fee = icrc3_nat(v),
                 _ => {}
             }
         }
     }
 
-    let Icrc3Value::Map(tx_fields) = tx_map? else {
-        return None;
+    // ICRC-3 identifies a transaction by the representation-independent hash of tx
+    let tx_hash = tx_map.as_ref().map(|tx| icrc3::hash_value(tx).to_hex());
+
+    let tx_fields = match tx_map {
+        Some(Icrc3Value::Map(tx_fields)) => tx_fields,
+        _ => vec![],
     };
 
     let mut op_txt = String::new();
-    let mut from_bytes: Vec<u8> = vec![];
-    let mut to_bytes: Vec<u8> = vec![];
-    let mut amount = 0u64;
-    let mut fee = 0u64;
+    let mut from_account: Option<IcrcAccount> = None;
+    let mut to_account: Option<IcrcAccount> = None;
+    let mut spender_account: Option<IcrcAccount> = None;
+    let mut amount = candid::Nat::from(0u64);
+    let mut fee: Option<candid::Nat> = None;
+    let mut expected_allowance: Option<candid::Nat> = None;
+    let mut expires_at: Option<Timestamp> = None;
     let mut memo = 0u64;
+    let mut icrc1_memo: Option<Vec<u8>> = None;
+    let mut created_at_time: Option<u64> = None;
 
     for (k, v) in tx_fields {
         match k.as_str() {
@@ -1852,163 +4229,94 @@ fn icrc3_block_to_block(icrc3: &Icrc3BlockWithId) -> Option<Block> {
                     op_txt = s
                 }
             }
-            "from" => {
-                // ICRC account: [owner_principal, opt subaccount] -> synthesize ICP AccountIdentifier bytes (32)
-                if let Icrc3Value::Array(arr) = v {
-                    if let Some(Icrc3Value::Blob(owner)) = arr.first() {
-                        let p = Principal::from_slice(owner);
-                        let sub = DEFAULT_SUBACCOUNT; // 'from' rarely includes subaccount in transfers
-                        from_bytes = AccountIdentifier::new(&p, &sub).as_ref().to_vec();
-                    }
-                }
-            }
-            "to" => {
-                if let Icrc3Value::Array(arr) = v {
-                    // expect [owner, subaccount?]
-                    let owner = arr.first().and_then(|v| {
-                        if let Icrc3Value::Blob(b) = v {
-                            Some(b)
-                        } else {
-                            None
-                        }
-                    });
-                    let subacc = arr.get(1).and_then(|v| {
-                        if let Icrc3Value::Blob(b) = v {
-                            Some(b)
-                        } else {
-                            None
-                        }
-                    });
-                    if let Some(owner_bytes) = owner {
-                        let p = Principal::from_slice(owner_bytes);
-                        let sub = if let Some(sa) = subacc {
-                            let mut a = [0u8; 32];
-                            if sa.len() == 32 {
-                                a.copy_from_slice(sa);
-                                Subaccount(a)
-                            } else {
-                                DEFAULT_SUBACCOUNT
-                            }
-                        } else {
-                            DEFAULT_SUBACCOUNT
-                        };
-                        to_bytes = AccountIdentifier::new(&p, &sub).as_ref().to_vec();
-                    }
-                }
-            }
+            "from" => from_account = icrc3_account(&v),
+            "to" => to_account = icrc3_account(&v),
+            "spender" => spender_account = icrc3_account(&v),
             "amt" => {
-                if let Icrc3Value::Nat(n) = v {
-                    amount = n.0.to_u64().unwrap_or(0)
-                }
-            }
-            "fee" => {
-                if let Icrc3Value::Nat(n) = v {
-                    fee = n.0.to_u64().unwrap_or(0)
+                if let Some(n) = icrc3_nat(&v) {
+                    amount = n
                 }
             }
-            "memo" => {
-                if let Icrc3Value::Nat(n) = v {
-                    memo = n.0.to_u64().unwrap_or(0)
-                }
+            "fee" => fee = icrc3_nat(&v),
+            "expected_allowance" => expected_allowance = icrc3_nat(&v),
+            "expires_at" => {
+                expires_at = icrc3_nat(&v)
+                    .and_then(|n| n.0.to_u64())
+                    .map(Timestamp::from_nanos)
             }
-            _ => {}
-        }
-    }
-
-    // Only map transfers for now.
-    if op_txt != "xfer" {
-        return None;
-    }
-
-    Some(Block {
-        transaction: Transaction {
-            memo,
-            icrc1_memo: None,
-            operation: Some(Operation::Transfer(Transfer {
-                from: from_bytes,
-                to: to_bytes,
-                amount: E8s { e8s: amount },
-                fee: E8s { e8s: fee },
-                spender: None,
-            })),
-            created_at_time: Timestamp {
-                timestamp_nanos: ts_nanos,
+            // ICRC-1 memos are blobs, some older ledgers encode a numeric memo
+            "memo" => match v {
+                Icrc3Value::Blob(b) => icrc1_memo = Some(b),
+                Icrc3Value::Nat(n) => memo = n.0.to_u64().unwrap_or(0),
+                _ => {}
             },
-        },
-        timestamp: Timestamp {
-            timestamp_nanos: ts_nanos,
-        },
-        parent_hash: phash,
-    })
-}
-
-async fn query_single_icrc3_block_from_archives(
-    ledger_principal: Principal,
-    block_index: u64,
-) -> Result<Vec<Block>, String> {
-    // 1) Try the ledger directly
-    let req = vec![Icrc3GetBlocksRequest {
-        start: candid::Nat::from(block_index),
-        length: candid::Nat::from(1u32),
-    }];
-    if let Ok((res,)) = ic_cdk::call::<_, (Icrc3GetBlocksResult,)>(
-        ledger_principal,
-        "icrc3_get_blocks",
-        (req.clone(),),
-    )
-    .await
-    {
-        if !res.blocks.is_empty() {
-            let mut out = Vec::new();
-            for b in res.blocks {
-                if let Some(block) = icrc3_block_to_block(&b) {
-                    out.push(block);
-                }
-            }
-            return Ok(out);
-        }
-    }
-
-    // 2) Locate the archive that owns this block
-    let (archives_res,): (Icrc3GetArchivesResult,) = ic_cdk::call(
-        ledger_principal,
-        "icrc3_get_archives",
-        (Icrc3GetArchivesArgs { from: None },),
-    )
-    .await
-    .map_err(|(_, m)| format!("icrc3_get_archives failed: {}", m))?;
-
-    let mut target_archive: Option<Principal> = None;
-    for a in archives_res {
-        let start = a.start.0.to_u64().unwrap_or(u64::MAX);
-        let end = a.end.0.to_u64().unwrap_or(0);
-        // Most ledgers treat end as inclusive; handle both safely.
-        if block_index >= start && block_index <= end {
-            target_archive = Some(a.canister_id);
-            break;
-        }
-        // If the ledger uses [start, end) semantics, also catch end-exclusive:
-        if block_index >= start && block_index < end {
-            target_archive = Some(a.canister_id);
-            break;
+            // Set by the caller, unlike the block ts which is set by the ledger
+            "created_at_time" => created_at_time = icrc3_nat(&v).and_then(|n| n.0.to_u64()),
+            _ => {}
         }
     }
 
-    let archive = target_archive
-        .ok_or_else(|| "No archive canister covers the requested block".to_string())?;
-
-    // 3) Fetch the single block from the archive
-    let (res2,): (Icrc3GetBlocksResult,) = ic_cdk::call(archive, "icrc3_get_blocks", (req,))
-        .await
-        .map_err(|(_, m)| format!("archive icrc3_get_blocks failed: {}", m))?;
+    let fee = fee.or(block_fee).unwrap_or_else(|| candid::Nat::from(0u64));
+    let from_bytes = from_account
+        .as_ref()
+        .map(icrc_account_bytes)
+        .unwrap_or_default();
+    let to_bytes = to_account
+        .as_ref()
+        .map(icrc_account_bytes)
+        .unwrap_or_default();
+    let spender = spender_account.as_ref().map(icrc_account_bytes);
+
+    // Older blocks only carry tx.op, ICRC-3 blocks may only carry btype
+    let kind = if op_txt.is_empty() { btype } else { op_txt };
+    let operation = match kind.as_str() {
+        // 2xfer is an ICRC-2 transfer_from, recorded with its spender
+        "xfer" | "1xfer" | "2xfer" => Some(Operation::Transfer(Transfer {
+            from: from_bytes,
+            to: to_bytes,
+            amount,
+            fee,
+            spender,
+        })),
+        "mint" | "1mint" => Some(Operation::Mint(Mint {
+            to: to_bytes,
+            amount,
+        })),
+        "burn" | "1burn" => Some(Operation::Burn(Burn {
+            from: from_bytes,
+            amount,
+            spender,
+        })),
+        "approve" | "2approve" => Some(Operation::Approve(Approve {
+            fee,
+            from: from_bytes,
+            allowance: amount,
+            expected_allowance,
+            expires_at,
+            spender: spender.unwrap_or_default(),
+        })),
+        _ => None,
+    };
 
-    let mut out = Vec::new();
-    for b in res2.blocks {
-        if let Some(block) = icrc3_block_to_block(&b) {
-            out.push(block);
-        }
+    Block {
+        transaction: Transaction {
+            memo,
+            icrc1_memo,
+            operation,
+            created_at_time: Timestamp {
+                timestamp_nanos: created_at_time.unwrap_or(ts_nanos),
+            },
+            from_account,
+            to_account,
+            spender_account,
+        },
+        timestamp: Timestamp {
+            timestamp_nanos: ts_nanos,
+        },
+        parent_hash: phash,
+        tx_hash,
+        block_hash: Some(icrc3::hash_value(&icrc3.block).as_slice().to_vec()),
     }
-    Ok(out)
 }
 
 #[update]
@@ -2018,118 +4326,117 @@ async fn process_token_archived_block(
 ) -> Result<String, String> {
     authenticate()?;
 
-    match token_type {
-        TokenType::ICP => process_archived_block(block_index).await,
-        TokenType::CKUSDC | TokenType::CKUSDT | TokenType::CKBTC => {
-            let ledger_principal = get_token_ledger_canister_id(&token_type);
-            let blocks =
-                query_single_icrc3_block_from_archives(ledger_principal, block_index).await?;
-
-            // Since we're processing a single archived block, get the first (and likely only) block
-            let block = blocks
-                .first()
-                .ok_or_else(|| "No block returned from archives".to_string())?;
-
-            let mut processed_count = 0usize;
-            let mut found_hashes: Vec<String> = vec![];
-
-            if let Some(operation) = block.transaction.operation.as_ref() {
-                let subaccount_exist = match operation {
-                    Operation::Approve(data) => {
-                        let from = data.from.clone();
-                        if includes_hash(&from) {
-                            true
-                        } else {
-                            let spender = data.spender.clone();
-                            includes_hash(&spender)
-                        }
-                    }
-                    Operation::Burn(data) => {
-                        let from = data.from.clone();
-                        if includes_hash(&from) {
-                            true
-                        } else {
-                            match &data.spender {
-                                Some(spender) => includes_hash(spender),
-                                None => false,
-                            }
-                        }
-                    }
-                    Operation::Mint(data) => {
-                        let to = data.to.clone();
-                        includes_hash(&to)
-                    }
-                    Operation::Transfer(data) => {
-                        let to = data.to.clone();
-                        if includes_hash(&to) {
-                            true
-                        } else {
-                            match &data.spender {
-                                Some(spender) => includes_hash(spender),
-                                None => false,
-                            }
-                        }
-                    }
-                };
+    let ledger_principal = get_token_ledger_canister_id(&token_type);
+    process_archived_block(token_type, ledger_principal, block_index).await
+}
 
-                if subaccount_exist {
-                    let already_exists = TRANSACTIONS.with(|transactions_ref| {
-                        let mut transactions = transactions_ref.borrow_mut();
+// Re-indexes blocks from_block..=to_block of a token in timer driven chunks
+#[update]
+fn start_backfill(token_type: TokenType, from_block: u64, to_block: u64) -> Result<u64, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
 
-                        if transactions.contains_key(&block_index) {
-                            return true;
-                        }
+    if from_block > to_block {
+        let error_msg = format!(
+            "Backfill range start {} is after its end {}",
+            from_block, to_block
+        );
+        ic_cdk::println!("Error: {}", error_msg);
+        return Err(Error { message: error_msg });
+    }
 
-                        let hash = match hash_transaction(&block.transaction) {
-                            Ok(h) => h,
-                            Err(err) => {
-                                ic_cdk::println!("ERROR hashing ICRC-3 archived tx: {}", err);
-                                "HASH-IS-NOT-AVAILABLE".to_string()
-                            }
-                        };
-
-                        let tx = StoredTransactions::new(
-                            block_index,               // use the requested index
-                            block.transaction.clone(), // your Transaction type
-                            hash.clone(),
-                            token_type.clone(),
-                            ledger_principal,
-                        );
+    let ledger_principal = match get_token_config(&token_type) {
+        Some(config) => config.ledger_canister_id,
+        None => {
+            let error_msg = format!("Token {:?} is not registered", token_type);
+            ic_cdk::println!("Error: {}", error_msg);
+            return Err(Error { message: error_msg });
+        }
+    };
 
-                        let _ = transactions.insert(block_index, tx);
-                        found_hashes.push(hash);
-                        processed_count += 1;
-                        false
-                    });
+    let now = CanisterApiManager::time();
+    let job_id = BACKFILL_JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        let job_id = jobs.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        jobs.insert(
+            job_id,
+            BackfillJob {
+                id: job_id,
+                token_type: token_type.clone(),
+                ledger_principal,
+                from_block,
+                to_block,
+                next_block: from_block,
+                indexed: 0,
+                already_indexed: 0,
+                status: BackfillStatus::Running,
+                last_error: None,
+                started_at: now,
+                updated_at: now,
+            },
+        );
+        job_id
+    });
+    ic_cdk::println!(
+        "Started backfill {} of {:?} blocks {}..={}",
+        job_id,
+        token_type,
+        from_block,
+        to_block
+    );
+
+    schedule_backfills();
+    Ok(job_id)
+}
 
-                    if already_exists {
-                        return Ok(format!(
-                            "Transaction already exists at index {}",
-                            block_index
-                        ));
-                    }
-                }
-            }
+// A single job, or all jobs when no id is given
+#[query]
+fn get_backfill_status(job_id: Option<u64>) -> Result<Vec<BackfillJob>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
 
-            if processed_count > 0 {
-                Ok(format!(
-                    "Processed {} archived block(s) for {:?} at index {}. Found tx hashes: {:?}",
-                    processed_count, token_type, block_index, found_hashes
-                ))
-            } else {
-                Ok(format!(
-                    "Processed archived block {} for {:?} but found no transactions for canister subaccounts",
-                    block_index, token_type
-                ))
-            }
+    BACKFILL_JOBS.with(|jobs| {
+        let jobs = jobs.borrow();
+        match job_id {
+            Some(job_id) => jobs.get(&job_id).map(|job| vec![job]).ok_or_else(|| {
+                let error_msg = format!("Backfill {} not found", job_id);
+                ic_cdk::println!("Error: {}", error_msg);
+                Error { message: error_msg }
+            }),
+            None => Ok(jobs.iter().map(|(_, job)| job).collect()),
         }
-    }
+    })
+}
+
+#[update]
+fn cancel_backfill(job_id: u64) -> Result<BackfillJob, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let mut job = running_backfill(job_id).ok_or_else(|| {
+        let error_msg = format!("Backfill {} is not running", job_id);
+        ic_cdk::println!("Error: {}", error_msg);
+        Error { message: error_msg }
+    })?;
+    job.status = BackfillStatus::Cancelled;
+    job.updated_at = CanisterApiManager::time();
+    BACKFILL_JOBS.with(|jobs| jobs.borrow_mut().insert(job_id, job.clone()));
+
+    schedule_backfills();
+    Ok(job)
 }
 
 #[update]
 fn clear_transactions(
     up_to_index: Option<u64>,
     up_to_timestamp: Option<Timestamp>,
+    token_type: Option<TokenType>,
 ) -> Result<Vec<StoredTransactions>, Error> {
     authenticate().map_err(|e| {
         ic_cdk::println!("Authentication error: {}", e);
@@ -2142,30 +4449,49 @@ fn clear_transactions(
         Some(timestamp) => timestamp,
         None => Timestamp::from_nanos(0),
     };
+    // If token_type is set then only transactions of that token are considered
+    let ledger_principal = token_type.as_ref().map(get_token_ledger_canister_id);
+
+    let mut keys_to_remove: BTreeSet<TransactionKey> = BTreeSet::new();
+
+    // If up_to_index is set then remove transactions with a index less than up_to_index
+    if up_to_index != 0 {
+        TRANSACTIONS.with(|transactions_ref| {
+            keys_to_remove.extend(
+                transactions_ref
+                    .borrow()
+                    .iter()
+                    .filter(|(_key, value)| value.index <= up_to_index)
+                    .map(|(k, _)| k),
+            )
+        });
+    }
 
-    TRANSACTIONS.with(|transactions_ref| {
-        // Collect keys that are less than the cutoff
-        let mut transactions_borrow = transactions_ref.borrow_mut();
-        let keys_to_remove: Vec<u64> = transactions_borrow
-            .iter()
-            .filter(|transaction| {
-                // If up_to_index is set then remove transactions with a index less than up_to_index
-                // If up_to_timestamp is set then remove transactions with a timestamp less than up_to_timestamp
-                (up_to_index != 0 && transaction.1.index <= up_to_index)
-                    || (up_to_timestamp.timestamp_nanos != 0
-                        && transaction.1.created_at_time.timestamp_nanos
-                            <= up_to_timestamp.timestamp_nanos)
-            })
-            .map(|(k, _)| k)
-            .collect();
+    // If up_to_timestamp is set then remove transactions with a timestamp less than up_to_timestamp,
+    // the time index only visits the transactions below the cutoff
+    if up_to_timestamp.timestamp_nanos != 0 {
+        let to_nanos = up_to_timestamp.timestamp_nanos.saturating_add(1);
+        keys_to_remove.extend(
+            transaction_keys_between(0, to_nanos, None, usize::MAX)
+                .into_iter()
+                .map(|(_, key)| key),
+        );
+    }
 
-        // Remove elements with those keys
-        for key in keys_to_remove {
-            transactions_borrow.remove(&key);
+    keys_to_remove
+        .retain(|(ledger, _index)| ledger_principal.is_none() || ledger_principal == Some(*ledger));
+
+    // Remove elements with those keys
+    for key in keys_to_remove {
+        let removed = remove_transaction(&key);
+        if removed.as_ref().and_then(subaccount_deposit).is_some() && balance_tracks_deposit(&key) {
+            PRUNED_DEPOSITS.with(|pruned| pruned.borrow_mut().insert(key, ()));
         }
+    }
 
+    TRANSACTIONS.with(|transactions_ref| {
         let mut result = Vec::new();
-        transactions_borrow.iter().for_each(|(_key, value)| {
+        transactions_ref.borrow().iter().for_each(|(_key, value)| {
             result.push(value.clone());
         });
         Ok(result)
@@ -2173,36 +4499,133 @@ fn clear_transactions(
 }
 
 #[update]
-async fn refund(transaction_index: u64) -> Result<String, Error> {
+async fn refund(transaction_index: u64, token_type: Option<TokenType>) -> Result<String, Error> {
     authenticate().map_err(|e| {
         ic_cdk::println!("Authentication error: {}", e);
         Error { message: e }
     })?;
 
-    let transaction_opt = TRANSACTIONS
-        .with(|transactions_ref| transactions_ref.borrow().get(&transaction_index).clone());
+    let token_type = token_type.unwrap_or(TokenType::ICP);
+    let key = (get_token_ledger_canister_id(&token_type), transaction_index);
+
+    let transaction_opt =
+        TRANSACTIONS.with(|transactions_ref| transactions_ref.borrow().get(&key).clone());
 
     let transaction = match transaction_opt {
         Some(value) => value,
         None => {
-            let error_msg = format!("Transaction index {} is not found", transaction_index);
+            let error_msg = format!(
+                "Transaction index {} is not found for {:?}",
+                transaction_index, token_type
+            );
             ic_cdk::println!("Error: {}", error_msg);
             return Err(Error { message: error_msg });
         }
     };
 
-    // construct transfer args
-    let (transfer_args, token_ledger_canister_id) = to_refund_args(&transaction)?;
+    if !is_icrc_token(&token_type) {
+        // construct transfer args
+        let (transfer_args, token_ledger_canister_id) = to_refund_args(&transaction)?;
 
-    InterCanisterCallManager::transfer(transfer_args, token_ledger_canister_id)
+        transfer_from_subaccount(
+            transfer_args,
+            token_ledger_canister_id,
+            &token_type,
+            Some(key),
+        )
+        .await
+        .map_err(|e| Error { message: e })?;
+    } else {
+        let (transfer_arg, token_ledger_canister_id) = to_icrc1_refund_args(&transaction)?;
+
+        icrc1_transfer_from_subaccount(
+            transfer_arg,
+            token_ledger_canister_id,
+            &token_type,
+            Some(key),
+        )
         .await
         .map_err(|e| Error { message: e })?;
+    }
 
     update_status(&transaction, SweepStatus::Swept)?;
 
     Ok("Refund & tx update is successful".to_string())
 }
 
+// Sweeps a stored deposit to the custodian and records the outcome in its
+// sweep status. Transactions that are not deposits into a generated
+// subaccount (approvals, burns, spender-only blocks) are reported and left
+// untouched, so they cannot hold up a sweep.
+async fn sweep_transaction(key: TransactionKey, tx_data: &StoredTransactions) -> String {
+    if subaccount_deposit(tx_data).is_none() {
+        return format!(
+            "tx: {}, sweep: not a deposit into a canister subaccount",
+            tx_data.index
+        );
+    }
+
+    let transfer_result = if !is_icrc_token(&tx_data.token_type) {
+        match to_sweep_args(tx_data) {
+            Ok((transfer_args, token_ledger_canister_id)) => {
+                ic_cdk::println!(
+                    "ICP transfer_args: {:?}, token_type: {:?}",
+                    transfer_args,
+                    tx_data.token_type
+                );
+                transfer_from_subaccount(
+                    transfer_args,
+                    token_ledger_canister_id,
+                    &tx_data.token_type,
+                    Some(key),
+                )
+                .await
+                .map(|idx| idx.to_string())
+            }
+            Err(e) => Err(e.message),
+        }
+    } else {
+        match to_icrc1_sweep_args(tx_data) {
+            Ok((icrc1_args, token_ledger_canister_id)) => {
+                ic_cdk::println!(
+                    "ICRC-1 transfer_args: {:?}, token_type: {:?}",
+                    icrc1_args,
+                    tx_data.token_type
+                );
+                icrc1_transfer_from_subaccount(
+                    icrc1_args,
+                    token_ledger_canister_id,
+                    &tx_data.token_type,
+                    Some(key),
+                )
+                .await
+                .map(|nat| nat.to_string())
+            }
+            Err(e) => Err(e.message),
+        }
+    };
+
+    match transfer_result {
+        Ok(block_idx) => match update_status(tx_data, SweepStatus::Swept) {
+            Ok(_) => format!(
+                "tx: {}, sweep: ok (block {}), status_update: ok",
+                tx_data.index, block_idx
+            ),
+            Err(err) => format!(
+                "tx: {}, sweep: ok (block {}), status_update: {}",
+                tx_data.index, block_idx, err.message
+            ),
+        },
+        Err(e) => match update_status(tx_data, SweepStatus::FailedToSweep) {
+            Ok(_) => format!("tx: {}, sweep: {}, status_update: ok", tx_data.index, e),
+            Err(err) => format!(
+                "tx: {}, sweep: {}, status_update: {}",
+                tx_data.index, e, err.message
+            ),
+        },
+    }
+}
+
 #[update]
 async fn sweep() -> Result<Vec<String>, Error> {
     authenticate().map_err(|e| {
@@ -2210,16 +4633,24 @@ async fn sweep() -> Result<Vec<String>, Error> {
         Error { message: e }
     })?;
 
+    // A missing custodian fails the whole call rather than every transaction
+    get_custodian_id().map_err(|e| {
+        ic_cdk::println!("Error getting custodian ID: {}", e);
+        Error { message: e }
+    })?;
+
     // get relevant txs
     let txs = TRANSACTIONS.with(|transactions_ref| {
         let transactions_borrow = transactions_ref.borrow();
 
         ic_cdk::println!("transactions_len: {}", transactions_borrow.len());
 
-        // Filter transactions where sweep_status == NotSwept
+        // Filter deposits where sweep_status == NotSwept
         let filtered_transactions: Vec<_> = transactions_borrow
             .iter()
-            .filter(|(_key, value)| value.sweep_status == SweepStatus::NotSwept)
+            .filter(|(_key, value)| {
+                value.sweep_status == SweepStatus::NotSwept && subaccount_deposit(value).is_some()
+            })
             .collect();
 
         // If filtered_transactions.len() is less than up_to_count, return all transactions
@@ -2244,68 +4675,8 @@ async fn sweep() -> Result<Vec<String>, Error> {
     let mut results = Vec::<String>::new();
 
     // Process each transaction
-    for tx in txs.iter() {
-        let tx_data = tx.1.clone();
-
-        let transfer_result = match tx_data.token_type {
-            TokenType::ICP => {
-                let (transfer_args, token_ledger_canister_id) = to_sweep_args(&tx_data)?;
-                ic_cdk::println!(
-                    "ICP transfer_args: {:?}, token_type: {:?}",
-                    transfer_args,
-                    tx_data.token_type
-                );
-                InterCanisterCallManager::transfer(transfer_args, token_ledger_canister_id)
-                    .await
-                    .map(|idx| idx.to_string())
-            }
-            TokenType::CKUSDC | TokenType::CKUSDT | TokenType::CKBTC => {
-                let (icrc1_args, token_ledger_canister_id) = to_icrc1_sweep_args(&tx_data)?;
-                ic_cdk::println!(
-                    "ICRC-1 transfer_args: {:?}, token_type: {:?}",
-                    icrc1_args,
-                    tx_data.token_type
-                );
-                InterCanisterCallManager::icrc1_transfer(icrc1_args, token_ledger_canister_id)
-                    .await
-                    .map(|nat| nat.to_string())
-            }
-        };
-
-        match transfer_result {
-            Ok(block_idx) => {
-                let status_update = update_status(&tx_data, SweepStatus::Swept);
-                if status_update.is_ok() {
-                    results.push(format!(
-                        "tx: {}, sweep: ok (block {}), status_update: ok",
-                        tx_data.index, block_idx
-                    ));
-                } else {
-                    results.push(format!(
-                        "tx: {}, sweep: ok (block {}), status_update: {}",
-                        tx_data.index,
-                        block_idx,
-                        status_update.unwrap_err().message
-                    ));
-                }
-            }
-            Err(e) => {
-                let status_update = update_status(&tx_data, SweepStatus::FailedToSweep);
-                if status_update.is_ok() {
-                    results.push(format!(
-                        "tx: {}, sweep: {}, status_update: ok",
-                        tx_data.index, e
-                    ));
-                } else {
-                    results.push(format!(
-                        "tx: {}, sweep: {}, status_update: {}",
-                        tx_data.index,
-                        e,
-                        status_update.unwrap_err().message
-                    ));
-                }
-            }
-        }
+    for (key, tx_data) in txs.iter() {
+        results.push(sweep_transaction(*key, tx_data).await);
     }
 
     Ok(results)
@@ -2318,84 +4689,20 @@ async fn single_sweep(tx_hash_arg: String) -> Result<Vec<String>, Error> {
         Error { message: e }
     })?;
 
-    // get relevant txs
-    let txs = TRANSACTIONS.with(|transactions_ref| {
-        let transactions_borrow = transactions_ref.borrow();
-
-        // Filter transactions where tx_hash == tx_hash_arg
-        let filtered_transactions: Vec<_> = transactions_borrow
-            .iter()
-            .filter(|(_key, value)| value.tx_hash == tx_hash_arg)
-            .collect();
+    // A missing custodian fails the whole call rather than every transaction
+    get_custodian_id().map_err(|e| {
+        ic_cdk::println!("Error getting custodian ID: {}", e);
+        Error { message: e }
+    })?;
 
-        filtered_transactions
-    });
+    // get relevant txs
+    let txs = transactions_by_hash(&tx_hash_arg);
 
     let mut results = Vec::<String>::new();
 
     // Process each transaction
-    for tx in txs.iter() {
-        let tx_data = tx.1.clone();
-
-        let transfer_result = match tx_data.token_type {
-            TokenType::ICP => {
-                let (transfer_args, token_ledger_canister_id) = to_sweep_args(&tx_data)?;
-                ic_cdk::println!(
-                    "ICP transfer_args: {:?}, token_type: {:?}",
-                    transfer_args,
-                    tx_data.token_type
-                );
-                InterCanisterCallManager::transfer(transfer_args, token_ledger_canister_id)
-                    .await
-                    .map(|idx| idx.to_string())
-            }
-            TokenType::CKUSDC | TokenType::CKUSDT | TokenType::CKBTC => {
-                let (icrc1_args, token_ledger_canister_id) = to_icrc1_sweep_args(&tx_data)?;
-                ic_cdk::println!(
-                    "ICRC-1 transfer_args: {:?}, token_type: {:?}",
-                    icrc1_args,
-                    tx_data.token_type
-                );
-                InterCanisterCallManager::icrc1_transfer(icrc1_args, token_ledger_canister_id)
-                    .await
-                    .map(|nat| nat.to_string())
-            }
-        };
-
-        match transfer_result {
-            Ok(block_idx) => {
-                let status_update = update_status(&tx_data, SweepStatus::Swept);
-                if status_update.is_ok() {
-                    results.push(format!(
-                        "tx: {}, sweep: ok (block {}), status_update: ok",
-                        tx_data.index, block_idx
-                    ));
-                } else {
-                    results.push(format!(
-                        "tx: {}, sweep: ok (block {}), status_update: {}",
-                        tx_data.index,
-                        block_idx,
-                        status_update.unwrap_err().message
-                    ));
-                }
-            }
-            Err(e) => {
-                let status_update = update_status(&tx_data, SweepStatus::FailedToSweep);
-                if status_update.is_ok() {
-                    results.push(format!(
-                        "tx: {}, sweep: {}, status_update: ok",
-                        tx_data.index, e
-                    ));
-                } else {
-                    results.push(format!(
-                        "tx: {}, sweep: {}, status_update: {}",
-                        tx_data.index,
-                        e,
-                        status_update.unwrap_err().message
-                    ));
-                }
-            }
-        }
+    for (key, tx_data) in txs.iter() {
+        results.push(sweep_transaction(*key, tx_data).await);
     }
 
     Ok(results)
@@ -2404,7 +4711,7 @@ async fn single_sweep(tx_hash_arg: String) -> Result<Vec<String>, Error> {
 #[update]
 async fn sweep_subaccount(
     subaccountid_hex: String,
-    amount: f64,
+    amount: candid::Nat,
     token_type: Option<TokenType>,
 ) -> Result<u64, Error> {
     authenticate().map_err(|e| {
@@ -2417,16 +4724,10 @@ async fn sweep_subaccount(
         Error { message: e }
     })?;
 
-    let matching_subaccount = LIST_OF_SUBACCOUNTS.with(|subaccounts| {
-        subaccounts
-            .borrow()
-            .iter()
-            .find(|(_, subaccount)| {
-                let subaccountid = to_subaccount_id(**subaccount);
-                subaccountid.to_hex() == subaccountid_hex
-            })
-            .map(|(_, &subaccount)| subaccount)
-    });
+    let matching_subaccount = from_hex(&subaccountid_hex)
+        .ok()
+        .and_then(|account_id| account_id_nonce(&account_id))
+        .map(to_subaccount);
 
     let subaccount = matching_subaccount.ok_or_else(|| {
         ic_cdk::println!("Error: Subaccount with ID {} not found", subaccountid_hex);
@@ -2435,101 +4736,82 @@ async fn sweep_subaccount(
         }
     })?;
 
-    // Convert amount to e8s, handling potential precision issues
-    let amount_e8s = (amount * 100_000_000.0).round() as u64;
-
-    // Check for potential overflow or underflow
-    if amount_e8s == u64::MAX || amount < 0.0 {
-        ic_cdk::println!(
-            "Error: Invalid amount: {} (e8s: {}) - overflow or negative value",
-            amount,
-            amount_e8s
-        );
-        return Err(Error {
-            message: "Invalid amount: overflow or negative value".to_string(),
-        });
-    }
-
     // Default to ICP if no token type is specified
     let token_type = token_type.unwrap_or(TokenType::ICP);
 
     // Get the ledger canister ID for the token type
-    let token_ledger_canister_id = match token_type {
-        TokenType::ICP => MAINNET_LEDGER_CANISTER_ID,
-        TokenType::CKUSDC => CKUSDC_LEDGER_CANISTER_ID,
-        TokenType::CKUSDT => CKUSDT_LEDGER_CANISTER_ID,
-        TokenType::CKBTC => CKBTC_LEDGER_CANISTER_ID,
-    };
+    let token_ledger_canister_id = get_token_ledger_canister_id(&token_type);
 
-    match token_type {
-        TokenType::ICP => {
-            let transfer_args = TransferArgs {
-                memo: Memo(0),
-                amount: Tokens::from_e8s(amount_e8s),
-                fee: Tokens::from_e8s(10_000),
-                from_subaccount: Some(subaccount),
-                to: custodian_id,
-                created_at_time: None,
-            };
+    if !is_icrc_token(&token_type) {
+        // The amount is in e8s, which the ICP ledger takes as a u64
+        let amount_e8s = amount.0.to_u64().ok_or_else(|| {
+            let error_msg = format!("Invalid amount: {} does not fit an ICP transfer", amount);
+            ic_cdk::println!("Error: {}", error_msg);
+            Error { message: error_msg }
+        })?;
 
-            InterCanisterCallManager::transfer(transfer_args, token_ledger_canister_id)
-                .await
-                .map_err(|e| Error { message: e })
-        }
-        TokenType::CKUSDC | TokenType::CKUSDT | TokenType::CKBTC => {
-            let custodian_principal_opt =
-                CUSTODIAN_PRINCIPAL.with(|stored_ref| stored_ref.borrow().get().clone());
-            let custodian_principal =
-                custodian_principal_opt
-                    .get_principal()
-                    .ok_or_else(|| Error {
-                        message: "Failed to get custodian principal".to_string(),
-                    })?;
+        let transfer_args = TransferArgs {
+            memo: Memo(0),
+            amount: Tokens::from_e8s(amount_e8s),
+            fee: Tokens::from_e8s(get_token_fee(&token_type)),
+            from_subaccount: Some(subaccount),
+            to: custodian_id,
+            created_at_time: None,
+        };
 
-            // Different tokens have different fees
-            let fee = match token_type {
-                TokenType::CKBTC => 10u64, // 10 satoshis for ckBTC
-                _ => 10_000u64,            // 10,000 units for ckUSDC/ckUSDT
-            };
+        transfer_from_subaccount(transfer_args, token_ledger_canister_id, &token_type, None)
+            .await
+            .map_err(|e| Error { message: e })
+    } else {
+        let custodian_principal_opt =
+            CUSTODIAN_PRINCIPAL.with(|stored_ref| stored_ref.borrow().get().clone());
+        let custodian_principal = custodian_principal_opt
+            .get_principal()
+            .ok_or_else(|| Error {
+                message: "Failed to get custodian principal".to_string(),
+            })?;
 
-            let icrc1_args = Icrc1TransferArg {
-                to: icrc_ledger_types::icrc1::account::Account {
-                    owner: custodian_principal,
-                    subaccount: None,
-                },
-                fee: Some(candid::Nat::from(fee)),
-                memo: None,
-                from_subaccount: Some(subaccount.0),
-                created_at_time: None,
-                amount: candid::Nat::from(amount_e8s),
-            };
+        // Different tokens have different fees
+        let fee = get_token_fee(&token_type);
+
+        let icrc1_args = Icrc1TransferArg {
+            to: icrc_ledger_types::icrc1::account::Account {
+                owner: custodian_principal,
+                subaccount: None,
+            },
+            fee: Some(candid::Nat::from(fee)),
+            memo: None,
+            from_subaccount: Some(subaccount.0),
+            created_at_time: None,
+            amount,
+        };
 
-            InterCanisterCallManager::icrc1_transfer(icrc1_args, token_ledger_canister_id)
+        let block_index =
+            icrc1_transfer_from_subaccount(icrc1_args, token_ledger_canister_id, &token_type, None)
                 .await
-                .map(|nat| nat.0.to_u64().unwrap_or(0))
-                .map_err(|e| Error { message: e })
-        }
+                .map_err(|e| Error { message: e })?;
+
+        // The transfer has gone through at this point, so report its index
+        // rather than a made-up one when it is too large for the u64 result
+        block_index.0.to_u64().ok_or_else(|| {
+            let error_msg = format!(
+                "Transfer succeeded at block {}, which does not fit in u64",
+                block_index
+            );
+            ic_cdk::println!("Error: {}", error_msg);
+            Error { message: error_msg }
+        })
     }
 }
 
-#[update]
-async fn set_sweep_failed(tx_hash_arg: String) -> Result<Vec<String>, Error> {
-    authenticate().map_err(|e| {
-        ic_cdk::println!("Authentication error: {}", e);
-        Error { message: e }
-    })?;
-
-    let txs = TRANSACTIONS.with(|transactions_ref| {
-        let transactions_borrow = transactions_ref.borrow();
-
-        // Filter transactions where tx_hash == tx_hash_arg
-        let filtered_transactions: Vec<_> = transactions_borrow
-            .iter()
-            .filter(|(_key, value)| value.tx_hash == tx_hash_arg)
-            .collect();
+#[update]
+async fn set_sweep_failed(tx_hash_arg: String) -> Result<Vec<String>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
 
-        filtered_transactions
-    });
+    let txs = transactions_by_hash(&tx_hash_arg);
 
     let mut results = Vec::<String>::new();
 
@@ -2569,51 +4851,56 @@ fn canister_status() -> Result<String, String> {
 
 #[query]
 fn get_transaction_token_type(tx_hash: String) -> Result<TokenType, String> {
-    TRANSACTIONS.with(|transactions_ref| {
-        let transactions_borrow = transactions_ref.borrow();
-        for (_, tx) in transactions_borrow.iter() {
-            if tx.tx_hash == tx_hash {
-                return Ok(tx.token_type.clone());
-            }
-        }
-        Err("Transaction not found".to_string())
-    })
+    transactions_by_hash(&tx_hash)
+        .into_iter()
+        .next()
+        .map(|(_, tx)| tx.token_type)
+        .ok_or_else(|| "Transaction not found".to_string())
+}
+
+#[query]
+fn get_transaction_by_hash(tx_hash: String) -> Result<Vec<StoredTransactions>, Error> {
+    let txs: Vec<StoredTransactions> = transactions_by_hash(&tx_hash)
+        .into_iter()
+        .map(|(_, tx)| tx)
+        .collect();
+    if txs.is_empty() {
+        let error_msg = format!("Transaction {} not found", tx_hash);
+        ic_cdk::println!("Error: {}", error_msg);
+        return Err(Error { message: error_msg });
+    }
+    Ok(txs)
 }
 
 #[query]
 fn get_registered_tokens() -> Result<Vec<(TokenType, String)>, String> {
-    TOKEN_LEDGER_PRINCIPALS.with(|tl| {
-        let tl_borrow = tl.borrow();
+    TOKEN_REGISTRY.with(|registry| {
+        let registry_borrow = registry.borrow();
         let mut result = Vec::new();
-        for (_, (token_type, principal)) in tl_borrow.iter() {
-            result.push((token_type.clone(), principal.to_string()));
+        for (principal, config) in registry_borrow.iter() {
+            result.push((config.token_type.clone(), principal.to_string()));
         }
         Ok(result)
     })
 }
 
-fn get_token_ledger_canister_id(token_type: &TokenType) -> Principal {
-    // First check in registered tokens
-    let registered_id = TOKEN_LEDGER_PRINCIPALS.with(|tl| {
-        let tl_borrow = tl.borrow();
-        for (_, (registered_type, principal)) in tl_borrow.iter() {
-            if registered_type == *token_type {
-                return Some(principal);
-            }
-        }
-        None
-    });
-
-    if let Some(id) = registered_id {
-        return id;
-    }
+#[query]
+fn get_token_registry() -> Result<Vec<TokenConfig>, String> {
+    Ok(
+        TOKEN_REGISTRY
+            .with(|registry| registry.borrow().iter().map(|(_, config)| config).collect()),
+    )
+}
 
-    // Fallback to hardcoded constants
-    match token_type {
-        TokenType::ICP => MAINNET_LEDGER_CANISTER_ID,
-        TokenType::CKUSDC => CKUSDC_LEDGER_CANISTER_ID,
-        TokenType::CKUSDT => CKUSDT_LEDGER_CANISTER_ID,
-        TokenType::CKBTC => CKBTC_LEDGER_CANISTER_ID,
+fn get_token_ledger_canister_id(token_type: &TokenType) -> Principal {
+    // Registered tokens first, then the built-in presets
+    match get_token_config(token_type) {
+        Some(config) => config.ledger_canister_id,
+        // Unregistered custom tokens are identified by their ledger
+        None => match token_type {
+            TokenType::Custom(ledger_principal) => *ledger_principal,
+            _ => MAINNET_LEDGER_CANISTER_ID,
+        },
     }
 }
 
@@ -2621,6 +4908,7 @@ fn get_token_ledger_canister_id(token_type: &TokenType) -> Principal {
 async fn register_token(
     token_type: TokenType,
     token_ledger_principal: String,
+    metadata: Option<TokenMetadata>,
 ) -> Result<(), Error> {
     authenticate().map_err(|e| {
         ic_cdk::println!("Authentication error: {}", e);
@@ -2633,22 +4921,344 @@ async fn register_token(
         Error { message: error_msg }
     })?;
 
-    // Store the token type and its ledger canister ID
-    let token_id = match token_type {
-        TokenType::ICP => 1,
-        TokenType::CKUSDC => 2,
-        TokenType::CKUSDT => 3,
-        TokenType::CKBTC => 4,
+    if let TokenType::Custom(ledger_principal) = &token_type {
+        if *ledger_principal != principal {
+            let error_msg = format!(
+                "Custom token ledger {} does not match principal {}",
+                ledger_principal, principal
+            );
+            ic_cdk::println!("Error: {}", error_msg);
+            return Err(Error { message: error_msg });
+        }
+    }
+
+    let config = match metadata {
+        Some(metadata) => TokenConfig {
+            token_type: token_type.clone(),
+            ledger_canister_id: principal,
+            symbol: metadata.symbol,
+            decimals: metadata.decimals,
+            fee: metadata.fee,
+            standard: metadata.standard,
+            fee_updated_at: None,
+        },
+        None => match InterCanisterCallManager::query_token_metadata(principal).await {
+            Ok(metadata) => TokenConfig {
+                token_type: token_type.clone(),
+                ledger_canister_id: principal,
+                symbol: metadata.symbol,
+                decimals: metadata.decimals,
+                fee: metadata.fee,
+                // The ICP ledger also serves ICRC-1, but is indexed through query_blocks
+                standard: match token_type {
+                    TokenType::ICP => TokenStandard::ICP,
+                    _ => metadata.standard,
+                },
+                fee_updated_at: Some(CanisterApiManager::time()),
+            },
+            Err(e) => {
+                ic_cdk::println!("Failed to fetch metadata from ledger {}: {}", principal, e);
+                match preset_token_config(&token_type) {
+                    Some(preset) => TokenConfig {
+                        ledger_canister_id: principal,
+                        ..preset
+                    },
+                    None => {
+                        let error_msg = format!(
+                            "Could not fetch metadata for {:?} from ledger {}: {}",
+                            token_type, principal, e
+                        );
+                        ic_cdk::println!("Error: {}", error_msg);
+                        return Err(Error { message: error_msg });
+                    }
+                }
+            }
+        },
     };
 
-    TOKEN_LEDGER_PRINCIPALS.with(|tl| {
-        let mut tl_mut = tl.borrow_mut();
-        tl_mut.insert(token_id, (token_type, principal));
+    let previous_ledger = polled_ledger(&token_type).filter(|ledger| *ledger != principal);
+
+    // Store the token config under its ledger canister ID
+    TOKEN_REGISTRY.with(|registry| {
+        let mut registry_mut = registry.borrow_mut();
+
+        // A token type is indexed from a single ledger, drop any previous registration
+        let previous: Vec<Principal> = registry_mut
+            .iter()
+            .filter(|(ledger, config)| config.token_type == token_type && *ledger != principal)
+            .map(|(ledger, _)| ledger)
+            .collect();
+        for ledger in previous {
+            registry_mut.remove(&ledger);
+        }
+
+        registry_mut.insert(principal, config);
     });
 
+    // A token on its own interval keeps it, polling the new ledger
+    if let Some(previous_ledger) = previous_ledger {
+        let seconds =
+            TOKEN_INTERVALS.with(|intervals| intervals.borrow_mut().remove(&previous_ledger));
+        if let Some(seconds) = seconds {
+            TOKEN_INTERVALS.with(|intervals| intervals.borrow_mut().insert(principal, seconds));
+            start_token_timer(&token_type, principal, seconds);
+        }
+    }
+
     Ok(())
 }
 
+#[query]
+fn get_chain_alerts() -> Result<Vec<ChainAlert>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    Ok(TOKEN_CHAIN_ALERTS.with(|alerts| alerts.borrow().iter().map(|(_, alert)| alert).collect()))
+}
+
+#[query]
+fn get_token_lag() -> Result<Vec<TokenLag>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let chain_lengths: Vec<(Principal, u64)> =
+        TOKEN_CHAIN_LENGTHS.with(|lengths| lengths.borrow().iter().collect());
+
+    Ok(chain_lengths
+        .into_iter()
+        .filter_map(|(ledger_principal, chain_length)| {
+            let token_type = TOKEN_REGISTRY
+                .with(|registry| registry.borrow().get(&ledger_principal))
+                .map(|config| config.token_type)
+                .or_else(|| {
+                    (polled_ledger(&TokenType::ICP) == Some(ledger_principal))
+                        .then_some(TokenType::ICP)
+                })?;
+            let next_block = get_token_next_block(&token_type);
+            Some(TokenLag {
+                lag: chain_length.saturating_sub(next_block),
+                catching_up: is_catching_up(ledger_principal, next_block),
+                ledger_reset: TOKEN_LEDGER_RESETS
+                    .with(|resets| resets.borrow().contains_key(&ledger_principal)),
+                token_type,
+                next_block,
+                chain_length,
+            })
+        })
+        .collect())
+}
+
+#[query]
+fn get_ledger_resets() -> Result<Vec<LedgerReset>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    Ok(TOKEN_LEDGER_RESETS.with(|resets| resets.borrow().iter().map(|(_, reset)| reset).collect()))
+}
+
+// Running balances of a subaccount, one per token it received
+#[query]
+fn get_subaccount_balances(nonce: u32) -> Result<Vec<SubaccountBalance>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    Ok(SUBACCOUNT_BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .range((nonce, Principal::from_slice(&[]))..)
+            .take_while(|((entry_nonce, _), _)| *entry_nonce == nonce)
+            .map(|(_, balance)| balance)
+            .collect()
+    }))
+}
+
+// Compares every running balance, or those of one token, with the balance the
+// ledger reports. Mismatches are kept until a later run finds them settled.
+#[update]
+async fn reconcile_balances(token_type: Option<TokenType>) -> Result<ReconciliationReport, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let _guard = ReconciliationGuard::new().ok_or_else(|| {
+        let error_msg = "Reconciliation is already running".to_string();
+        ic_cdk::println!("Error: {}", error_msg);
+        Error { message: error_msg }
+    })?;
+
+    let ledger_principal = token_type.as_ref().map(get_token_ledger_canister_id);
+    let balances: Vec<SubaccountBalance> = SUBACCOUNT_BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .iter()
+            .map(|(_, balance)| balance)
+            .filter(|balance| {
+                ledger_principal.is_none_or(|ledger| ledger == balance.ledger_canister_id)
+            })
+            .collect()
+    });
+
+    let mut report = ReconciliationReport {
+        checked: 0,
+        matched: 0,
+        discrepancies: Vec::new(),
+        errors: Vec::new(),
+        finished_at: 0,
+    };
+    for balance in balances {
+        let key = (balance.nonce, balance.ledger_canister_id);
+        let ledger_balance = match InterCanisterCallManager::query_balance(
+            balance.ledger_canister_id,
+            to_subaccount(balance.nonce),
+        )
+        .await
+        {
+            Ok(ledger_balance) => ledger_balance,
+            Err(e) => {
+                ic_cdk::println!(
+                    "Error reading balance of nonce {} on {:?}: {}",
+                    balance.nonce,
+                    balance.token_type,
+                    e
+                );
+                report.errors.push(format!(
+                    "nonce {} {:?}: {}",
+                    balance.nonce, balance.token_type, e
+                ));
+                continue;
+            }
+        };
+        report.checked += 1;
+
+        // Deposits indexed while the ledger was being read are not a mismatch
+        let indexed_balance = SUBACCOUNT_BALANCES
+            .with(|balances| balances.borrow().get(&key))
+            .map_or(balance.balance, |current| current.balance);
+        if indexed_balance == ledger_balance {
+            report.matched += 1;
+            BALANCE_DISCREPANCIES.with(|discrepancies| discrepancies.borrow_mut().remove(&key));
+            continue;
+        }
+
+        let discrepancy = BalanceDiscrepancy {
+            nonce: balance.nonce,
+            token_type: balance.token_type,
+            ledger_canister_id: balance.ledger_canister_id,
+            indexed_balance,
+            ledger_balance,
+            detected_at: CanisterApiManager::time(),
+        };
+        ic_cdk::println!("Balance discrepancy: {:?}", discrepancy);
+        BALANCE_DISCREPANCIES
+            .with(|discrepancies| discrepancies.borrow_mut().insert(key, discrepancy.clone()));
+        report.discrepancies.push(discrepancy);
+    }
+
+    report.finished_at = CanisterApiManager::time();
+    Ok(report)
+}
+
+#[query]
+fn get_balance_discrepancies(
+    token_type: Option<TokenType>,
+) -> Result<Vec<BalanceDiscrepancy>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let ledger_principal = token_type.as_ref().map(get_token_ledger_canister_id);
+    Ok(BALANCE_DISCREPANCIES.with(|discrepancies| {
+        discrepancies
+            .borrow()
+            .iter()
+            .map(|(_, discrepancy)| discrepancy)
+            .filter(|discrepancy| {
+                ledger_principal.is_none_or(|ledger| ledger == discrepancy.ledger_canister_id)
+            })
+            .collect()
+    }))
+}
+
+#[query]
+fn get_skipped_ticks() -> Result<Vec<SkippedTicks>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    Ok(TOKEN_SKIPPED_TICKS
+        .with(|skipped| skipped.borrow().iter().map(|(_, ticks)| ticks).collect()))
+}
+
+#[query]
+fn get_tip_anchors() -> Result<Vec<TipAnchor>, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    Ok(TOKEN_TIP_ANCHORS
+        .with(|anchors| anchors.borrow().iter().map(|(_, anchor)| anchor).collect()))
+}
+
+// Resumes indexing of a halted token. The recorded chain is dropped so the
+// next block re-anchors it, e.g. after intentionally replacing a local ledger.
+#[update]
+fn clear_chain_alert(token_type: TokenType) -> Result<String, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let ledger_principal = get_token_ledger_canister_id(&token_type);
+    let removed = TOKEN_CHAIN_ALERTS.with(|alerts| alerts.borrow_mut().remove(&ledger_principal));
+    if removed.is_none() {
+        let error_msg = format!("No chain alert raised for {:?}", token_type);
+        ic_cdk::println!("Error: {}", error_msg);
+        return Err(Error { message: error_msg });
+    }
+    TOKEN_LAST_BLOCK_HASH.with(|hashes| hashes.borrow_mut().remove(&ledger_principal));
+
+    Ok(format!("Chain alert cleared for {:?}", token_type))
+}
+
+#[update]
+async fn update_token_fee(token_type: TokenType) -> Result<u64, Error> {
+    authenticate().map_err(|e| {
+        ic_cdk::println!("Authentication error: {}", e);
+        Error { message: e }
+    })?;
+
+    let ledger_principal = match get_token_config(&token_type) {
+        Some(config)
+            if TOKEN_REGISTRY.with(|r| r.borrow().contains_key(&config.ledger_canister_id)) =>
+        {
+            config.ledger_canister_id
+        }
+        _ => {
+            let error_msg = format!("Token {:?} is not registered", token_type);
+            ic_cdk::println!("Error: {}", error_msg);
+            return Err(Error { message: error_msg });
+        }
+    };
+
+    refresh_token_fee(ledger_principal).await.map_err(|e| {
+        let error_msg = format!("Failed to refresh fee for {:?}: {}", token_type, e);
+        ic_cdk::println!("Error: {}", error_msg);
+        Error { message: error_msg }
+    })
+}
+
 #[update]
 async fn sweep_by_token_type(token_type: TokenType) -> Result<Vec<String>, Error> {
     authenticate().map_err(|e| {
@@ -2656,17 +5266,25 @@ async fn sweep_by_token_type(token_type: TokenType) -> Result<Vec<String>, Error
         Error { message: e }
     })?;
 
+    // A missing custodian fails the whole call rather than every transaction
+    get_custodian_id().map_err(|e| {
+        ic_cdk::println!("Error getting custodian ID: {}", e);
+        Error { message: e }
+    })?;
+
     // get relevant txs
     let txs = TRANSACTIONS.with(|transactions_ref| {
         let transactions_borrow = transactions_ref.borrow();
 
         ic_cdk::println!("transactions_len: {}", transactions_borrow.len());
 
-        // Filter transactions where sweep_status == NotSwept and token_type matches
+        // Filter deposits where sweep_status == NotSwept and token_type matches
         let filtered_transactions: Vec<_> = transactions_borrow
             .iter()
             .filter(|(_key, value)| {
-                value.sweep_status == SweepStatus::NotSwept && value.token_type == token_type
+                value.sweep_status == SweepStatus::NotSwept
+                    && value.token_type == token_type
+                    && subaccount_deposit(value).is_some()
             })
             .collect();
 
@@ -2692,68 +5310,8 @@ async fn sweep_by_token_type(token_type: TokenType) -> Result<Vec<String>, Error
     let mut results = Vec::<String>::new();
 
     // Process each transaction
-    for tx in txs.iter() {
-        let tx_data = tx.1.clone();
-
-        let transfer_result = match tx_data.token_type {
-            TokenType::ICP => {
-                let (transfer_args, token_ledger_canister_id) = to_sweep_args(&tx_data)?;
-                ic_cdk::println!(
-                    "ICP transfer_args: {:?}, token_type: {:?}",
-                    transfer_args,
-                    tx_data.token_type
-                );
-                InterCanisterCallManager::transfer(transfer_args, token_ledger_canister_id)
-                    .await
-                    .map(|idx| idx.to_string())
-            }
-            TokenType::CKUSDC | TokenType::CKUSDT | TokenType::CKBTC => {
-                let (icrc1_args, token_ledger_canister_id) = to_icrc1_sweep_args(&tx_data)?;
-                ic_cdk::println!(
-                    "ICRC-1 transfer_args: {:?}, token_type: {:?}",
-                    icrc1_args,
-                    tx_data.token_type
-                );
-                InterCanisterCallManager::icrc1_transfer(icrc1_args, token_ledger_canister_id)
-                    .await
-                    .map(|nat| nat.to_string())
-            }
-        };
-
-        match transfer_result {
-            Ok(block_idx) => {
-                let status_update = update_status(&tx_data, SweepStatus::Swept);
-                if status_update.is_ok() {
-                    results.push(format!(
-                        "tx: {}, sweep: ok (block {}), status_update: ok",
-                        tx_data.index, block_idx
-                    ));
-                } else {
-                    results.push(format!(
-                        "tx: {}, sweep: ok (block {}), status_update: {}",
-                        tx_data.index,
-                        block_idx,
-                        status_update.unwrap_err().message
-                    ));
-                }
-            }
-            Err(e) => {
-                let status_update = update_status(&tx_data, SweepStatus::FailedToSweep);
-                if status_update.is_ok() {
-                    results.push(format!(
-                        "tx: {}, sweep: {}, status_update: ok",
-                        tx_data.index, e
-                    ));
-                } else {
-                    results.push(format!(
-                        "tx: {}, sweep: {}, status_update: {}",
-                        tx_data.index,
-                        e,
-                        status_update.unwrap_err().message
-                    ));
-                }
-            }
-        }
+    for (key, tx_data) in txs.iter() {
+        results.push(sweep_transaction(*key, tx_data).await);
     }
 
     Ok(results)
@@ -2780,23 +5338,108 @@ fn convert_to_icrc_account(account_hex: String) -> Result<String, Error> {
         });
     }
 
-    // Try to find the nonce that generated this account
-    let canister_id = CanisterApiManager::id();
-    let nonce_count = nonce();
+    let nonce = account_id_nonce(&account_bytes).ok_or_else(|| Error {
+        message: "Account not found in generated subaccounts".to_string(),
+    })?;
+    let icrc_account = IcrcAccount::from_principal_and_index(CanisterApiManager::id(), nonce);
+    Ok(icrc_account.to_text())
+}
+
+fn account_id_nonce(account_id: &[u8]) -> Option<u32> {
+    let account_id = <[u8; 32]>::try_from(account_id).ok()?;
+    SUBACCOUNT_NONCES.with(|nonces| nonces.borrow().get(&account_id))
+}
+
+// Transactions touching a generated subaccount, newest first. Pass the
+// returned next_cursor back to continue where the page stopped.
+#[query]
+fn get_subaccount_transactions(
+    nonce_param: u32,
+    token_type: Option<TokenType>,
+    cursor: Option<TransactionCursor>,
+    limit: Option<u64>,
+) -> Result<TransactionsPage, Error> {
+    let current_nonce = nonce();
+    if nonce_param >= current_nonce {
+        let error_msg = format!("Index out of bounds: {} >= {}", nonce_param, current_nonce);
+        ic_cdk::println!("Error: {}", error_msg);
+        return Err(Error { message: error_msg });
+    }
+    let limit = limit.unwrap_or(100).clamp(1, MAX_TRANSACTIONS_PAGE_SIZE) as usize;
+    let ledger_principal = token_type.as_ref().map(get_token_ledger_canister_id);
+
+    let lower = Included((nonce_param, (0, (Principal::from_slice(&[]), 0))));
+    let upper = match (cursor, nonce_param.checked_add(1)) {
+        (Some(cursor), _) => Included((nonce_param, cursor.entry())),
+        (None, Some(next_nonce)) => Excluded((next_nonce, (0, (Principal::from_slice(&[]), 0)))),
+        (None, None) => Unbounded,
+    };
+
+    SUBACCOUNT_TX_INDEX.with(|index| {
+        TRANSACTIONS.with(|transactions_ref| {
+            let index = index.borrow();
+            let transactions = transactions_ref.borrow();
+
+            let mut page = Vec::new();
+            let mut next_cursor = None;
+            let entries = index
+                .range((lower, upper))
+                .rev()
+                .map(|((_, entry), _)| entry);
+            for (scanned, entry) in entries.enumerate() {
+                if page.len() == limit || scanned == MAX_TRANSACTIONS_SCANNED {
+                    next_cursor = Some(TransactionCursor::from(entry));
+                    break;
+                }
+                let (_, key) = entry;
+                if ledger_principal.is_some_and(|ledger| ledger != key.0) {
+                    continue;
+                }
+                if let Some(tx) = transactions.get(&key) {
+                    page.push(tx);
+                }
+            }
 
-    for i in 0..nonce_count {
-        let test_subaccount = to_subaccount(i);
-        let test_account_id = to_subaccount_id(test_subaccount);
+            Ok(TransactionsPage {
+                transactions: page,
+                next_cursor,
+            })
+        })
+    })
+}
 
-        if test_account_id.to_hex() == account_hex {
-            // Found the matching nonce
-            let icrc_account = IcrcAccount::from_principal_and_index(canister_id, i);
-            return Ok(icrc_account.to_text());
+// Account identifier bytes of an account given as hex or as ICRC-1 text owned
+// by this canister
+fn parse_canister_account(account: &str) -> Result<Vec<u8>, Error> {
+    match hex::decode(account) {
+        Ok(bytes) if bytes.len() == 32 => Ok(bytes),
+        _ => {
+            let icrc_account = IcrcAccount::from_text(account).map_err(|e| {
+                let error_msg = format!("Invalid account identifier or ICRC-1 account: {}", e);
+                ic_cdk::println!("Error: {}", error_msg);
+                Error { message: error_msg }
+            })?;
+            if icrc_account.owner != CanisterApiManager::id() {
+                let error_msg = format!("Account {} is not owned by this canister", account);
+                ic_cdk::println!("Error: {}", error_msg);
+                return Err(Error { message: error_msg });
+            }
+            Ok(icrc_account_bytes(&icrc_account))
         }
     }
+}
 
-    Err(Error {
-        message: "Account not found in generated subaccounts".to_string(),
+// Nonce of a subaccount given as account identifier hex or as ICRC-1 text.
+// ICRC accounts are looked up by their account identifier, which is what the
+// subaccount index is keyed by.
+#[query]
+fn get_nonce_for_account(account: String) -> Result<u32, Error> {
+    let account_id = parse_canister_account(&account)?;
+
+    account_id_nonce(&account_id).ok_or_else(|| {
+        let error_msg = format!("Account {} not found in generated subaccounts", account);
+        ic_cdk::println!("Error: {}", error_msg);
+        Error { message: error_msg }
     })
 }
 
@@ -2821,17 +5464,25 @@ async fn set_token_next_block_update(token_type: TokenType, block: u64) -> Resul
         Error { message: e }
     })?;
     set_token_next_block(&token_type, block);
+
+    // Setting the next block acknowledges a ledger reset, tokens discovered
+    // through an index canister also rediscover transactions from that block
+    if let Some(ledger_principal) = polled_ledger(&token_type) {
+        TOKEN_LEDGER_RESETS.with(|resets| resets.borrow_mut().remove(&ledger_principal));
+        drop_index_cursors(ledger_principal, block);
+    }
     Ok(block)
 }
 
 #[query]
 fn get_all_token_blocks() -> Result<Vec<(TokenType, u64)>, String> {
-    let result = vec![
-        (TokenType::ICP, get_token_next_block(&TokenType::ICP)),
-        (TokenType::CKUSDC, get_token_next_block(&TokenType::CKUSDC)),
-        (TokenType::CKUSDT, get_token_next_block(&TokenType::CKUSDT)),
-        (TokenType::CKBTC, get_token_next_block(&TokenType::CKBTC)),
-    ];
+    let result = known_token_types()
+        .into_iter()
+        .map(|token_type| {
+            let next_block = get_token_next_block(&token_type);
+            (token_type, next_block)
+        })
+        .collect();
 
     Ok(result)
 }
@@ -2843,10 +5494,16 @@ async fn reset_token_blocks() -> Result<String, Error> {
         Error { message: e }
     })?;
 
-    set_token_next_block(&TokenType::ICP, 1);
-    set_token_next_block(&TokenType::CKUSDC, 1);
-    set_token_next_block(&TokenType::CKUSDT, 1);
-    set_token_next_block(&TokenType::CKBTC, 1);
+    for token_type in known_token_types() {
+        set_token_next_block(&token_type, 1);
+    }
+    TOKEN_LEDGER_RESETS.with(|resets| {
+        let mut resets = resets.borrow_mut();
+        let ledgers: Vec<Principal> = resets.iter().map(|(ledger, _)| ledger).collect();
+        for ledger_principal in ledgers {
+            resets.remove(&ledger_principal);
+        }
+    });
 
     Ok("All token blocks reset to 1".to_string())
 }
@@ -1,20 +1,46 @@
+use candid::Principal;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
 use ic_stable_structures::DefaultMemoryImpl;
 use ic_stable_structures::{StableBTreeMap, StableCell};
 use std::cell::RefCell;
 
-use crate::types::{Memory, Network, StoredPrincipal, StoredTransactions, TokenType};
+use crate::types::{
+    BackfillJob, BalanceDiscrepancy, BlockHashRecord, ChainAlert, LedgerReset, Memory,
+    MigrationProgress, Network, SkippedTicks, StoredPrincipal, StoredTransactions,
+    SubaccountBalance, SubaccountTransactionKey, TipAnchor, TokenConfig, TokenType, TransactionKey,
+};
 
 const PRINCIPAL_MEMORY: MemoryId = MemoryId::new(0);
 const LAST_SUBACCOUNT_NONCE_MEMORY: MemoryId = MemoryId::new(1);
 const NEXT_BLOCK_MEMORY: MemoryId = MemoryId::new(2);
 const INTERVAL_IN_SECONDS_MEMORY: MemoryId = MemoryId::new(3);
-const TRANSACTIONS_MEMORY: MemoryId = MemoryId::new(4);
+const LEGACY_TRANSACTIONS_MEMORY: MemoryId = MemoryId::new(4);
 const CUSTODIAN_PRINCIPAL_MEMORY: MemoryId = MemoryId::new(5);
 const NETWORK_MEMORY: MemoryId = MemoryId::new(6);
 const WEBHOOK_URL_MEMORY: MemoryId = MemoryId::new(7);
 const TOKEN_LEDGER_MEMORY: MemoryId = MemoryId::new(8);
 const TOKEN_NEXT_BLOCKS_MEMORY: MemoryId = MemoryId::new(9);
+const TRANSACTIONS_MEMORY: MemoryId = MemoryId::new(10);
+const TOKEN_REGISTRY_MEMORY: MemoryId = MemoryId::new(11);
+const TOKEN_BLOCKS_MEMORY: MemoryId = MemoryId::new(12);
+const TOKEN_LAST_BLOCK_HASH_MEMORY: MemoryId = MemoryId::new(13);
+const TOKEN_CHAIN_ALERTS_MEMORY: MemoryId = MemoryId::new(14);
+const TOKEN_TIP_ANCHORS_MEMORY: MemoryId = MemoryId::new(15);
+const TOKEN_SKIPPED_TICKS_MEMORY: MemoryId = MemoryId::new(16);
+const TOKEN_INTERVALS_MEMORY: MemoryId = MemoryId::new(17);
+const TOKEN_CHAIN_LENGTHS_MEMORY: MemoryId = MemoryId::new(18);
+const TOKEN_INDEX_CANISTERS_MEMORY: MemoryId = MemoryId::new(19);
+const SUBACCOUNT_INDEX_CURSORS_MEMORY: MemoryId = MemoryId::new(20);
+const BACKFILL_JOBS_MEMORY: MemoryId = MemoryId::new(21);
+const TOKEN_LEDGER_RESETS_MEMORY: MemoryId = MemoryId::new(22);
+const SUBACCOUNT_NONCES_MEMORY: MemoryId = MemoryId::new(23);
+const TX_HASH_INDEX_MEMORY: MemoryId = MemoryId::new(24);
+const TX_TIME_INDEX_MEMORY: MemoryId = MemoryId::new(25);
+const SUBACCOUNT_TX_INDEX_MEMORY: MemoryId = MemoryId::new(26);
+const SUBACCOUNT_BALANCES_MEMORY: MemoryId = MemoryId::new(27);
+const BALANCE_DISCREPANCIES_MEMORY: MemoryId = MemoryId::new(28);
+const MIGRATION_PROGRESS_MEMORY: MemoryId = MemoryId::new(29);
+const PRUNED_DEPOSITS_MEMORY: MemoryId = MemoryId::new(30);
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
@@ -45,9 +71,10 @@ thread_local! {
             5 // Default is 5 seconds
         ).expect("Initializing INTERVAL_IN_SECONDS StableCell failed")
     );
-    pub static TRANSACTIONS: RefCell<StableBTreeMap<u64, StoredTransactions, Memory>> = RefCell::new(
+    // Keyed by block index only; kept so post_upgrade can move entries to TRANSACTIONS
+    pub static LEGACY_TRANSACTIONS: RefCell<StableBTreeMap<u64, StoredTransactions, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(TRANSACTIONS_MEMORY))
+            MEMORY_MANAGER.with(|m| m.borrow().get(LEGACY_TRANSACTIONS_MEMORY))
         )
     );
     pub static CUSTODIAN_PRINCIPAL: RefCell<StableCell<StoredPrincipal, Memory>> = RefCell::new(
@@ -68,14 +95,133 @@ thread_local! {
             String::default()
         ).expect("Initializing WEBHOOK_URL StableCell failed")
     );
-    pub static TOKEN_LEDGER_PRINCIPALS: RefCell<StableBTreeMap<u64, (TokenType, candid::Principal), Memory>> = RefCell::new(
+    // Token id -> ledger pairs from before the registry; migrated into TOKEN_REGISTRY on upgrade
+    pub static LEGACY_TOKEN_LEDGER_PRINCIPALS: RefCell<StableBTreeMap<u64, (TokenType, Principal), Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_LEDGER_MEMORY))
         )
     );
-    pub static TOKEN_NEXT_BLOCKS: RefCell<StableBTreeMap<u8, u64, Memory>> = RefCell::new(
+    // Next block keyed by token id; migrated into TOKEN_NEXT_BLOCKS on upgrade
+    pub static LEGACY_TOKEN_NEXT_BLOCKS: RefCell<StableBTreeMap<u8, u64, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_NEXT_BLOCKS_MEMORY))
         )
     );
+    pub static TRANSACTIONS: RefCell<StableBTreeMap<TransactionKey, StoredTransactions, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRANSACTIONS_MEMORY))
+        )
+    );
+    pub static TOKEN_REGISTRY: RefCell<StableBTreeMap<Principal, TokenConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_REGISTRY_MEMORY))
+        )
+    );
+    pub static TOKEN_NEXT_BLOCKS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_BLOCKS_MEMORY))
+        )
+    );
+    pub static TOKEN_LAST_BLOCK_HASH: RefCell<StableBTreeMap<Principal, BlockHashRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_LAST_BLOCK_HASH_MEMORY))
+        )
+    );
+    pub static TOKEN_CHAIN_ALERTS: RefCell<StableBTreeMap<Principal, ChainAlert, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_CHAIN_ALERTS_MEMORY))
+        )
+    );
+    pub static TOKEN_TIP_ANCHORS: RefCell<StableBTreeMap<Principal, TipAnchor, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_TIP_ANCHORS_MEMORY))
+        )
+    );
+    pub static TOKEN_SKIPPED_TICKS: RefCell<StableBTreeMap<Principal, SkippedTicks, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_SKIPPED_TICKS_MEMORY))
+        )
+    );
+    // Polling interval in seconds of tokens that do not follow INTERVAL_IN_SECONDS
+    pub static TOKEN_INTERVALS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_INTERVALS_MEMORY))
+        )
+    );
+    // Chain length each ledger reported on its last query
+    pub static TOKEN_CHAIN_LENGTHS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_CHAIN_LENGTHS_MEMORY))
+        )
+    );
+    // Index canister of tokens discovered through an index instead of ledger scans
+    pub static TOKEN_INDEX_CANISTERS: RefCell<StableBTreeMap<Principal, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_INDEX_CANISTERS_MEMORY))
+        )
+    );
+    // Highest transaction id indexed per (ledger, subaccount nonce)
+    pub static SUBACCOUNT_INDEX_CURSORS: RefCell<StableBTreeMap<(Principal, u32), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SUBACCOUNT_INDEX_CURSORS_MEMORY))
+        )
+    );
+    pub static BACKFILL_JOBS: RefCell<StableBTreeMap<u64, BackfillJob, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BACKFILL_JOBS_MEMORY))
+        )
+    );
+    pub static TOKEN_LEDGER_RESETS: RefCell<StableBTreeMap<Principal, LedgerReset, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_LEDGER_RESETS_MEMORY))
+        )
+    );
+    // Account identifier of each subaccount -> its nonce
+    pub static SUBACCOUNT_NONCES: RefCell<StableBTreeMap<[u8; 32], u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SUBACCOUNT_NONCES_MEMORY))
+        )
+    );
+    // (SHA-256 of tx hash, transaction key) of every stored transaction
+    pub static TX_HASH_INDEX: RefCell<StableBTreeMap<([u8; 32], TransactionKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TX_HASH_INDEX_MEMORY))
+        )
+    );
+    // (created_at_time nanos, transaction key) of every stored transaction
+    pub static TX_TIME_INDEX: RefCell<StableBTreeMap<(u64, TransactionKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TX_TIME_INDEX_MEMORY))
+        )
+    );
+    // History entry of every stored transaction per generated subaccount it touches
+    pub static SUBACCOUNT_TX_INDEX: RefCell<StableBTreeMap<SubaccountTransactionKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SUBACCOUNT_TX_INDEX_MEMORY))
+        )
+    );
+    // (subaccount nonce, token ledger) -> running balance
+    pub static SUBACCOUNT_BALANCES: RefCell<StableBTreeMap<(u32, Principal), SubaccountBalance, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SUBACCOUNT_BALANCES_MEMORY))
+        )
+    );
+    // (subaccount nonce, token ledger) -> mismatch found by the last reconciliation
+    pub static BALANCE_DISCREPANCIES: RefCell<StableBTreeMap<(u32, Principal), BalanceDiscrepancy, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BALANCE_DISCREPANCIES_MEMORY))
+        )
+    );
+    // Migration id -> progress of rewriting a stable structure's values
+    pub static MIGRATION_PROGRESS: RefCell<StableBTreeMap<u32, MigrationProgress, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MIGRATION_PROGRESS_MEMORY))
+        )
+    );
+    // Deposits removed by clear_transactions whose credit stays in the running balances
+    pub static PRUNED_DEPOSITS: RefCell<StableBTreeMap<TransactionKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PRUNED_DEPOSITS_MEMORY))
+        )
+    );
 }
@@ -2,6 +2,7 @@
 mod tests {
     use crate::types::*;
     use crate::*;
+    use ic_stable_structures::Storable;
     use icrc_ledger_types::icrc1::transfer::TransferArg;
     use once_cell::sync::Lazy;
     use std::future::Future;
@@ -11,6 +12,22 @@ mod tests {
             TimerId::default()
         }
 
+        fn set_token_timer(
+            _interval: std::time::Duration,
+            _token_type: TokenType,
+            _ledger_principal: Principal,
+        ) -> TimerId {
+            TimerId::default()
+        }
+
+        fn set_backfill_timer(_interval: std::time::Duration) -> TimerId {
+            TimerId::default()
+        }
+
+        fn set_migration_timer(_interval: std::time::Duration) -> TimerId {
+            TimerId::default()
+        }
+
         fn clear_timer(_timer_id: TimerId) {}
     }
 
@@ -18,29 +35,104 @@ mod tests {
         std::sync::Mutex::new(Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap())
     });
 
+    thread_local! {
+        static MOCK_TIME: RefCell<u64> = const { RefCell::new(1_700_000_000_000_000_000) };
+        static MOCK_INSTRUCTIONS: RefCell<u64> = const { RefCell::new(0) };
+    }
+
     impl CanisterApiManagerTrait for CanisterApiManager {
         fn id() -> Principal {
             *STATIC_PRINCIPAL.lock().unwrap()
         }
+
+        fn time() -> u64 {
+            MOCK_TIME.with(|time| *time.borrow())
+        }
+
+        fn instruction_counter() -> u64 {
+            MOCK_INSTRUCTIONS.with(|instructions| *instructions.borrow())
+        }
+    }
+
+    // Mock ledger chain for the happy path; blocks below MOCK_LEDGER_FIRST_INDEX
+    // are only served through the archive callback
+    #[cfg(feature = "happy_path")]
+    thread_local! {
+        static MOCK_LEDGER_BLOCKS: RefCell<Vec<Block>> = const { RefCell::new(vec![]) };
+        static MOCK_LEDGER_FIRST_INDEX: RefCell<u64> = const { RefCell::new(0) };
+        // Most blocks the mock ledger returns per call, unlimited when unset
+        static MOCK_LEDGER_PAGE_SIZE: RefCell<Option<u64>> = const { RefCell::new(None) };
+        // Transaction ids the mock index canister knows per subaccount
+        static MOCK_INDEX_TRANSACTIONS: RefCell<HashMap<[u8; 32], Vec<u64>>> = RefCell::default();
+        static MOCK_INDEX_CALLS: RefCell<u64> = const { RefCell::new(0) };
+        // Fee the mock ICRC-1 ledger requires, any fee is accepted when unset
+        static MOCK_ICRC1_FEE: RefCell<Option<u64>> = const { RefCell::new(None) };
+        static MOCK_ICRC1_TRANSFERS: RefCell<Vec<TransferArg>> = const { RefCell::new(vec![]) };
+        // Block index the mock ICRC-1 ledger returns for transfers, 1 when unset
+        static MOCK_ICRC1_BLOCK_INDEX: RefCell<Option<candid::Nat>> = const { RefCell::new(None) };
+        // Balances the mock ledger reports per subaccount, zero when unset
+        static MOCK_LEDGER_BALANCES: RefCell<HashMap<[u8; 32], u64>> = RefCell::default();
     }
 
     // Happy path implementation - returns success
     #[cfg(feature = "happy_path")]
     impl InterCanisterCallManagerTrait for InterCanisterCallManager {
         async fn query_blocks(
-            _ledger_principal: Principal,
-            _req: QueryBlocksRequest,
+            ledger_principal: Principal,
+            req: QueryBlocksRequest,
         ) -> CallResult<(QueryBlocksResponse,)> {
+            let chain = MOCK_LEDGER_BLOCKS.with(|b| b.borrow().clone());
+            let chain_length = chain.len() as u64;
+            let end = (req.start + req.length).min(chain_length);
+            let first_index = MOCK_LEDGER_FIRST_INDEX.with(|f| *f.borrow()).min(end);
+            let ledger_start = req.start.max(first_index);
+
+            let mut blocks = if ledger_start < end {
+                chain[ledger_start as usize..end as usize].to_vec()
+            } else {
+                vec![]
+            };
+            if let Some(page_size) = MOCK_LEDGER_PAGE_SIZE.with(|p| *p.borrow()) {
+                blocks.truncate(page_size as usize);
+            }
+            let archived_blocks = if req.start < first_index {
+                vec![ArchivedBlock {
+                    callback: ArchiveCallback {
+                        canister_id: ledger_principal,
+                        method: "get_blocks".to_string(),
+                    },
+                    start: req.start,
+                    length: first_index - req.start,
+                }]
+            } else {
+                vec![]
+            };
+
             let response = QueryBlocksResponse {
-                certificate: None,       // Assuming no certificate for this example
-                blocks: vec![],          // Assuming no blocks for this example
-                chain_length: 0,         // Example value
-                first_block_index: 0,    // Example value
-                archived_blocks: vec![], // Assuming no archived blocks for this example
+                certificate: None,
+                hash_tree: None,
+                blocks,
+                chain_length,
+                first_block_index: ledger_start,
+                archived_blocks,
             };
             Ok((response,))
         }
 
+        async fn query_archived_blocks(
+            _ledger_principal: Principal,
+            _archived: ArchivedBlock,
+            req: QueryBlocksRequest,
+        ) -> Result<Vec<Block>, String> {
+            let chain = MOCK_LEDGER_BLOCKS.with(|b| b.borrow().clone());
+            let first_index = MOCK_LEDGER_FIRST_INDEX.with(|f| *f.borrow());
+            let end = (req.start + req.length).min(first_index);
+            if req.start >= end {
+                return Ok(vec![]);
+            }
+            Ok(chain[req.start as usize..end as usize].to_vec())
+        }
+
         async fn transfer(
             _args: TransferArgs,
             _token_ledger_canister_id: Principal,
@@ -49,10 +141,65 @@ mod tests {
         }
 
         async fn icrc1_transfer(
-            _args: TransferArg,
+            args: TransferArg,
             _token_ledger_canister_id: Principal,
+        ) -> Result<Result<candid::Nat, Icrc1TransferError>, String> {
+            MOCK_ICRC1_TRANSFERS.with(|t| t.borrow_mut().push(args.clone()));
+            if let Some(expected_fee) = MOCK_ICRC1_FEE.with(|f| *f.borrow()) {
+                let expected_fee = candid::Nat::from(expected_fee);
+                if args.fee.as_ref() != Some(&expected_fee) {
+                    return Ok(Err(Icrc1TransferError::BadFee { expected_fee }));
+                }
+            }
+            let block_index = MOCK_ICRC1_BLOCK_INDEX.with(|b| b.borrow().clone());
+            Ok(Ok(block_index.unwrap_or_else(|| candid::Nat::from(1u64))))
+        }
+
+        async fn query_token_metadata(
+            _ledger_principal: Principal,
+        ) -> Result<TokenMetadata, String> {
+            Ok(TokenMetadata {
+                symbol: "MOCK".to_string(),
+                decimals: 8,
+                fee: 20_000,
+                standard: TokenStandard::ICRC1,
+            })
+        }
+
+        async fn query_token_fee(_ledger_principal: Principal) -> Result<u64, String> {
+            Ok(20_000)
+        }
+
+        async fn query_balance(
+            _ledger_principal: Principal,
+            subaccount: Subaccount,
         ) -> Result<candid::Nat, String> {
-            Ok(candid::Nat::from(1u64))
+            let balance = MOCK_LEDGER_BALANCES
+                .with(|balances| balances.borrow().get(&subaccount.0).copied().unwrap_or(0));
+            Ok(candid::Nat::from(balance))
+        }
+
+        async fn query_account_transaction_ids(
+            _index_principal: Principal,
+            _ledger_principal: Principal,
+            subaccount: Subaccount,
+            start: Option<u64>,
+            max_results: u64,
+        ) -> Result<Vec<u64>, String> {
+            MOCK_INDEX_CALLS.with(|calls| *calls.borrow_mut() += 1);
+            let mut ids = MOCK_INDEX_TRANSACTIONS.with(|index| {
+                index
+                    .borrow()
+                    .get(&subaccount.0)
+                    .cloned()
+                    .unwrap_or_default()
+            });
+            ids.sort_unstable_by(|a, b| b.cmp(a));
+            Ok(ids
+                .into_iter()
+                .filter(|id| start.is_none_or(|start| *id <= start))
+                .take(max_results as usize)
+                .collect())
         }
     }
 
@@ -64,7 +211,8 @@ mod tests {
             _req: QueryBlocksRequest,
         ) -> CallResult<(QueryBlocksResponse,)> {
             let response = QueryBlocksResponse {
-                certificate: None,       // Assuming no certificate for this example
+                certificate: None, // Assuming no certificate for this example
+                hash_tree: None,
                 blocks: vec![],          // Assuming no blocks for this example
                 chain_length: 0,         // Example value
                 first_block_index: 0,    // Example value
@@ -73,6 +221,14 @@ mod tests {
             Ok((response,))
         }
 
+        async fn query_archived_blocks(
+            _ledger_principal: Principal,
+            _archived: ArchivedBlock,
+            _req: QueryBlocksRequest,
+        ) -> Result<Vec<Block>, String> {
+            Err("archive call failed".to_string())
+        }
+
         async fn transfer(
             _args: TransferArgs,
             _token_ledger_canister_id: Principal,
@@ -83,9 +239,36 @@ mod tests {
         async fn icrc1_transfer(
             _args: TransferArg,
             _token_ledger_canister_id: Principal,
-        ) -> Result<candid::Nat, String> {
+        ) -> Result<Result<candid::Nat, Icrc1TransferError>, String> {
             Err("transfer failed".to_string())
         }
+
+        async fn query_token_metadata(
+            _ledger_principal: Principal,
+        ) -> Result<TokenMetadata, String> {
+            Err("icrc1_metadata call failed".to_string())
+        }
+
+        async fn query_token_fee(_ledger_principal: Principal) -> Result<u64, String> {
+            Err("icrc1_fee call failed".to_string())
+        }
+
+        async fn query_balance(
+            _ledger_principal: Principal,
+            _subaccount: Subaccount,
+        ) -> Result<candid::Nat, String> {
+            Err("icrc1_balance_of call failed".to_string())
+        }
+
+        async fn query_account_transaction_ids(
+            _index_principal: Principal,
+            _ledger_principal: Principal,
+            _subaccount: Subaccount,
+            _start: Option<u64>,
+            _max_results: u64,
+        ) -> Result<Vec<u64>, String> {
+            Err("get_account_transactions call failed".to_string())
+        }
     }
 
     // Default test implementation when no features are enabled
@@ -96,7 +279,8 @@ mod tests {
             _req: QueryBlocksRequest,
         ) -> CallResult<(QueryBlocksResponse,)> {
             let response = QueryBlocksResponse {
-                certificate: None,       // Assuming no certificate for this example
+                certificate: None, // Assuming no certificate for this example
+                hash_tree: None,
                 blocks: vec![],          // Assuming no blocks for this example
                 chain_length: 0,         // Example value
                 first_block_index: 0,    // Example value
@@ -105,6 +289,14 @@ mod tests {
             Ok((response,))
         }
 
+        async fn query_archived_blocks(
+            _ledger_principal: Principal,
+            _archived: ArchivedBlock,
+            _req: QueryBlocksRequest,
+        ) -> Result<Vec<Block>, String> {
+            Ok(vec![])
+        }
+
         async fn transfer(
             _args: TransferArgs,
             _token_ledger_canister_id: Principal,
@@ -115,8 +307,40 @@ mod tests {
         async fn icrc1_transfer(
             _args: TransferArg,
             _token_ledger_canister_id: Principal,
+        ) -> Result<Result<candid::Nat, Icrc1TransferError>, String> {
+            Ok(Ok(candid::Nat::from(1u64)))
+        }
+
+        async fn query_token_metadata(
+            _ledger_principal: Principal,
+        ) -> Result<TokenMetadata, String> {
+            Ok(TokenMetadata {
+                symbol: "MOCK".to_string(),
+                decimals: 8,
+                fee: 10_000,
+                standard: TokenStandard::ICRC1,
+            })
+        }
+
+        async fn query_token_fee(_ledger_principal: Principal) -> Result<u64, String> {
+            Ok(10_000)
+        }
+
+        async fn query_balance(
+            _ledger_principal: Principal,
+            _subaccount: Subaccount,
         ) -> Result<candid::Nat, String> {
-            Ok(candid::Nat::from(1u64))
+            Ok(candid::Nat::from(0u64))
+        }
+
+        async fn query_account_transaction_ids(
+            _index_principal: Principal,
+            _ledger_principal: Principal,
+            _subaccount: Subaccount,
+            _start: Option<u64>,
+            _max_results: u64,
+        ) -> Result<Vec<u64>, String> {
+            Ok(vec![])
         }
     }
 
@@ -134,27 +358,9 @@ mod tests {
             let _ = principal_ref.borrow_mut().set(stored_principal);
         });
 
-        let spender_subaccount = nonce_to_subaccount(0);
-        let spender_subaccountid: AccountIdentifier = to_subaccount_id(spender_subaccount);
-
-        let to_subaccount = nonce_to_subaccount(1);
-        let to_subaccountid: AccountIdentifier = to_subaccount_id(to_subaccount);
-
-        let from_subaccount = nonce_to_subaccount(2);
-        let from_subaccountid: AccountIdentifier = to_subaccount_id(from_subaccount);
-
-        LIST_OF_SUBACCOUNTS.with(|subaccounts| {
-            let mut subaccounts_mut = subaccounts.borrow_mut();
-
-            let account_id_hash = spender_subaccountid.to_u64_hash();
-            subaccounts_mut.insert(account_id_hash, spender_subaccount);
-
-            let account_id_hash = to_subaccountid.to_u64_hash();
-            subaccounts_mut.insert(account_id_hash, to_subaccount);
-
-            let account_id_hash = from_subaccountid.to_u64_hash();
-            subaccounts_mut.insert(account_id_hash, from_subaccount);
-        });
+        let spender_subaccountid = index_subaccount(0);
+        let to_subaccountid = index_subaccount(1);
+        let from_subaccountid = index_subaccount(2);
 
         (spender_subaccountid, to_subaccountid, from_subaccountid)
     }
@@ -164,39 +370,34 @@ mod tests {
         let (spender_subaccountid, to_subaccountid, from_subaccountid) = setup_principals();
 
         let timestamp_nanos = timestamp_nanos.unwrap_or(1000);
-        TRANSACTIONS.with(|transactions_ref| {
-            let mut transactions_borrow = transactions_ref.borrow_mut();
-            for i in 1..=count {
-                let transaction = Transaction {
-                    memo: i,
-                    icrc1_memo: None,
-                    operation: Some(Operation::Transfer(Transfer {
-                        to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
-                        fee: E8s { e8s: 100 },
-                        from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
-                        amount: E8s { e8s: 10000 },
-                        spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
-                    })),
-                    created_at_time: Timestamp { timestamp_nanos },
-                };
+        let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+        for i in 1..=count {
+            let transaction = Transaction {
+                memo: i,
+                icrc1_memo: None,
+                operation: Some(Operation::Transfer(Transfer {
+                    to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
+                    fee: candid::Nat::from(100u64),
+                    from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
+                    amount: candid::Nat::from(10000u64),
+                    spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
+                })),
+                created_at_time: Timestamp { timestamp_nanos },
+                from_account: None,
+                to_account: None,
+                spender_account: None,
+            };
 
-                let hash = match hash_transaction(&transaction) {
-                    Ok(content) => content,
-                    Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
-                };
+            let hash = match hash_transaction(&transaction) {
+                Ok(content) => content,
+                Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
+            };
 
-                transactions_borrow.insert(
-                    i,
-                    StoredTransactions::new(
-                        i,
-                        transaction,
-                        hash,
-                        TokenType::ICP,
-                        *STATIC_PRINCIPAL.lock().unwrap(),
-                    ),
-                );
-            }
-        });
+            insert_transaction(
+                (ledger_principal, i),
+                StoredTransactions::new(i, transaction, hash, TokenType::ICP, ledger_principal),
+            );
+        }
 
         NEXT_BLOCK.with(|next_block_ref| {
             let _ = next_block_ref.borrow_mut().set(count);
@@ -225,36 +426,30 @@ mod tests {
         let (spender_subaccountid, to_subaccountid, from_subaccountid) = setup_principals();
 
         // Setup transactions
-        TRANSACTIONS.with(|t| {
-            let mut transactions = t.borrow_mut();
-
-            let transaction = Transaction {
-                memo: 123,
-                icrc1_memo: None,
-                operation: Some(Operation::Transfer(Transfer {
-                    to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
-                    fee: E8s { e8s: 100 },
-                    from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
-                    amount: E8s { e8s: 10000 },
-                    spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
-                })),
-                created_at_time: Timestamp { timestamp_nanos: 0 },
-            };
-            let hash = match hash_transaction(&transaction) {
-                Ok(content) => content,
-                Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
-            };
-            transactions.insert(
-                1,
-                StoredTransactions::new(
-                    1,
-                    transaction,
-                    hash,
-                    TokenType::ICP,
-                    *STATIC_PRINCIPAL.lock().unwrap(),
-                ),
-            );
-        });
+        let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+        let transaction = Transaction {
+            memo: 123,
+            icrc1_memo: None,
+            operation: Some(Operation::Transfer(Transfer {
+                to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
+                fee: candid::Nat::from(100u64),
+                from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
+                amount: candid::Nat::from(10000u64),
+                spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
+            })),
+            created_at_time: Timestamp { timestamp_nanos: 0 },
+            from_account: None,
+            to_account: None,
+            spender_account: None,
+        };
+        let hash = match hash_transaction(&transaction) {
+            Ok(content) => content,
+            Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
+        };
+        insert_transaction(
+            (ledger_principal, 1),
+            StoredTransactions::new(1, transaction, hash, TokenType::ICP, ledger_principal),
+        );
     }
 
     fn refund_teardown() {
@@ -270,92 +465,98 @@ mod tests {
         // Populate TRANSACTIONS with a mixture of swept and not swept transactions
         TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
 
-        TRANSACTIONS.with(|t| {
-            let mut transactions = t.borrow_mut();
-
-            let transaction = Transaction {
-                memo: 100,
-                icrc1_memo: None,
-                operation: Some(Operation::Transfer(Transfer {
-                    to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
-                    fee: E8s { e8s: 100 },
-                    from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
-                    amount: E8s { e8s: 10000 },
-                    spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
-                })),
-                created_at_time: Timestamp { timestamp_nanos: 0 },
-            };
-            let first_hash = match hash_transaction(&transaction) {
-                Ok(content) => content,
-                Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
-            };
-            transactions.insert(
+        let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+        let transaction = Transaction {
+            memo: 100,
+            icrc1_memo: None,
+            operation: Some(Operation::Transfer(Transfer {
+                to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
+                fee: candid::Nat::from(100u64),
+                from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
+                amount: candid::Nat::from(10000u64),
+                spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
+            })),
+            created_at_time: Timestamp { timestamp_nanos: 0 },
+            from_account: None,
+            to_account: None,
+            spender_account: None,
+        };
+        let first_hash = match hash_transaction(&transaction) {
+            Ok(content) => content,
+            Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
+        };
+        insert_transaction(
+            (ledger_principal, 1),
+            StoredTransactions::new(
                 1,
-                StoredTransactions::new(
-                    1,
-                    transaction,
-                    first_hash.clone(),
-                    TokenType::ICP,
-                    *STATIC_PRINCIPAL.lock().unwrap(),
-                ),
-            );
-
-            let transaction = Transaction {
-                memo: 101,
-                icrc1_memo: None,
-                operation: Some(Operation::Transfer(Transfer {
-                    to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
-                    fee: E8s { e8s: 100 },
-                    from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
-                    amount: E8s { e8s: 10000 },
-                    spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
-                })),
-                created_at_time: Timestamp { timestamp_nanos: 0 },
-            };
-            let second_hash = match hash_transaction(&transaction) {
-                Ok(content) => content,
-                Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
-            };
-            transactions.insert(
+                transaction,
+                first_hash.clone(),
+                TokenType::ICP,
+                ledger_principal,
+            ),
+        );
+
+        let transaction = Transaction {
+            memo: 101,
+            icrc1_memo: None,
+            operation: Some(Operation::Transfer(Transfer {
+                to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
+                fee: candid::Nat::from(100u64),
+                from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
+                amount: candid::Nat::from(10000u64),
+                spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
+            })),
+            created_at_time: Timestamp { timestamp_nanos: 0 },
+            from_account: None,
+            to_account: None,
+            spender_account: None,
+        };
+        let second_hash = match hash_transaction(&transaction) {
+            Ok(content) => content,
+            Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
+        };
+        insert_transaction(
+            (ledger_principal, 2),
+            StoredTransactions::new(
                 2,
-                StoredTransactions::new(
-                    2,
-                    transaction,
-                    second_hash.clone(),
-                    TokenType::ICP,
-                    *STATIC_PRINCIPAL.lock().unwrap(),
-                ),
-            );
-
-            let transaction = Transaction {
-                memo: 102,
-                icrc1_memo: None,
-                operation: Some(Operation::Transfer(Transfer {
-                    to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
-                    fee: E8s { e8s: 100 },
-                    from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
-                    amount: E8s { e8s: 10000 },
-                    spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
-                })),
-                created_at_time: Timestamp { timestamp_nanos: 0 },
-            };
-            let third_hash = match hash_transaction(&transaction) {
-                Ok(content) => content,
-                Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
-            };
-            transactions.insert(
+                transaction,
+                second_hash.clone(),
+                TokenType::ICP,
+                ledger_principal,
+            ),
+        );
+
+        let transaction = Transaction {
+            memo: 102,
+            icrc1_memo: None,
+            operation: Some(Operation::Transfer(Transfer {
+                to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
+                fee: candid::Nat::from(100u64),
+                from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
+                amount: candid::Nat::from(10000u64),
+                spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
+            })),
+            created_at_time: Timestamp { timestamp_nanos: 0 },
+            from_account: None,
+            to_account: None,
+            spender_account: None,
+        };
+        let third_hash = match hash_transaction(&transaction) {
+            Ok(content) => content,
+            Err(_) => "HASH-IS-NOT-AVAILABLE".to_string(),
+        };
+        insert_transaction(
+            (ledger_principal, 3),
+            StoredTransactions::new(
                 3,
-                StoredTransactions::new(
-                    3,
-                    transaction,
-                    third_hash.clone(),
-                    TokenType::ICP,
-                    *STATIC_PRINCIPAL.lock().unwrap(),
-                ),
-            );
+                transaction,
+                third_hash.clone(),
+                TokenType::ICP,
+                ledger_principal,
+            ),
+        );
 
-            vec![first_hash, second_hash, third_hash]
-        })
+        vec![first_hash, second_hash, third_hash]
     }
 
     fn teardown_sweep_environment() {
@@ -462,13 +663,7 @@ mod tests {
             });
 
             // Create a subaccount for this nonce
-            let subaccount = to_subaccount(nonce);
-            let subaccountid = to_subaccount_id(subaccount);
-            let account_id_hash = subaccountid.to_u64_hash();
-
-            LIST_OF_SUBACCOUNTS.with(|list_ref| {
-                list_ref.borrow_mut().insert(account_id_hash, subaccount);
-            });
+            index_subaccount(nonce);
 
             // Call the function we're testing
             let result = get_icrc_account(nonce);
@@ -649,13 +844,7 @@ mod tests {
             });
 
             // Create and register the subaccount
-            let subaccount = to_subaccount(nonce);
-            let subaccountid = to_subaccount_id(subaccount);
-            let account_id_hash = subaccountid.to_u64_hash();
-
-            LIST_OF_SUBACCOUNTS.with(|list_ref| {
-                list_ref.borrow_mut().insert(account_id_hash, subaccount);
-            });
+            let subaccountid = index_subaccount(nonce);
 
             // Now call the function we're testing with CKUSDC token type
             let result = get_subaccountid(nonce, Some(TokenType::CKUSDC));
@@ -725,6 +914,25 @@ mod tests {
             );
         }
 
+        #[test]
+        fn test_get_nonce_for_account_resolves_hex_and_icrc_text() {
+            let subaccountid = index_subaccount(7);
+            let canister_id = CanisterApiManager::id();
+            let icrc_account = IcrcAccount::from_principal_and_index(canister_id, 7);
+
+            assert_eq!(get_nonce_for_account(subaccountid.to_hex()).unwrap(), 7);
+            assert_eq!(get_nonce_for_account(icrc_account.to_text()).unwrap(), 7);
+
+            // Accounts of other owners and unknown nonces are not found
+            let foreign = IcrcAccount::from_principal_and_index(Principal::anonymous(), 7);
+            assert!(get_nonce_for_account(foreign.to_text()).is_err());
+            let unknown = IcrcAccount::from_principal_and_index(canister_id, 8);
+            assert!(get_nonce_for_account(unknown.to_text()).is_err());
+            assert!(get_nonce_for_account("not an account".to_string()).is_err());
+
+            SUBACCOUNT_NONCES.with(|s| s.borrow_mut().clear_new());
+        }
+
         #[test]
         fn test_convert_to_icrc_account_success() {
             // Save original principal to restore later
@@ -734,13 +942,7 @@ mod tests {
             let nonce = the_nonce();
 
             // Setup the subaccount
-            let subaccount = to_subaccount(nonce);
-            let subaccountid = to_subaccount_id(subaccount);
-            let account_id_hash = subaccountid.to_u64_hash();
-
-            LIST_OF_SUBACCOUNTS.with(|list_ref| {
-                list_ref.borrow_mut().insert(account_id_hash, subaccount);
-            });
+            let subaccountid = index_subaccount(nonce);
 
             LAST_SUBACCOUNT_NONCE.with(|nonce_ref| {
                 let _ = nonce_ref.borrow_mut().set(nonce + 1);
@@ -853,9 +1055,9 @@ mod tests {
             let icrc1_memo = Some(vec![1, 2, 3, 4]);
             let operation = Some(Operation::Transfer(Transfer {
                 to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
-                fee: E8s { e8s: 100 },
+                fee: candid::Nat::from(100u64),
                 from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
-                amount: E8s { e8s: 10000 },
+                amount: candid::Nat::from(10000u64),
                 spender: Some(hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap()),
             }));
             let created_at_time = SystemTime::now()
@@ -870,6 +1072,9 @@ mod tests {
                 created_at_time: Timestamp {
                     timestamp_nanos: created_at_time,
                 },
+                from_account: None,
+                to_account: None,
+                spender_account: None,
             };
 
             let hash = match hash_transaction(&transaction) {
@@ -940,7 +1145,7 @@ mod tests {
             let specific_timestamp = Timestamp::from_nanos(nanos);
             populate_transactions(100, None);
 
-            let cleared = clear_transactions(None, Some(specific_timestamp)).unwrap();
+            let cleared = clear_transactions(None, Some(specific_timestamp), None).unwrap();
             assert_eq!(cleared.len(), 0);
         }
 
@@ -951,7 +1156,7 @@ mod tests {
             let specific_timestamp = Timestamp::from_nanos(nanos);
             populate_transactions(100, Some(nanos));
 
-            let cleared = clear_transactions(None, Some(specific_timestamp)).unwrap();
+            let cleared = clear_transactions(None, Some(specific_timestamp), None).unwrap();
             assert_eq!(cleared.len(), 0);
         }
 
@@ -959,7 +1164,7 @@ mod tests {
         fn clear_transactions_with_none_parameters() {
             populate_transactions(100, None);
 
-            let cleared = clear_transactions(None, None).unwrap();
+            let cleared = clear_transactions(None, None, None).unwrap();
             assert_eq!(cleared.len(), 100); // Assuming no transactions are removed
         }
 
@@ -969,7 +1174,7 @@ mod tests {
             populate_transactions(100, None);
 
             // Clear transactions up to a specific index, excluding transactions with a higher index
-            let cleared = clear_transactions(Some(50), None).unwrap();
+            let cleared = clear_transactions(Some(50), None, None).unwrap();
             assert_eq!(
                 cleared.len(),
                 50,
@@ -982,7 +1187,8 @@ mod tests {
             populate_transactions(100, Some(50000)); // Populate 100 transactions, all with the same timestamp for simplicity
 
             // Clear transactions with a count less than 80 and a timestamp less than 60000 nanoseconds
-            let cleared = clear_transactions(Some(80), Some(Timestamp::from_nanos(60000))).unwrap();
+            let cleared =
+                clear_transactions(Some(80), Some(Timestamp::from_nanos(60000)), None).unwrap();
             // This assumes that the criteria are combined with an OR logic, not AND
             assert_eq!(
                 cleared.len(),
@@ -996,7 +1202,8 @@ mod tests {
             populate_transactions(100, Some(100000)); // Populate transactions with a specific timestamp
 
             // Clear transactions with a timestamp exactly equal to one of the transactions' timestamps
-            let cleared = clear_transactions(None, Some(Timestamp::from_nanos(100000))).unwrap();
+            let cleared =
+                clear_transactions(None, Some(Timestamp::from_nanos(100000)), None).unwrap();
             // Depending on implementation, this may remove all transactions if they're considered "up to and including" the given timestamp
             assert!(
                 cleared.is_empty(),
@@ -1016,7 +1223,7 @@ mod tests {
                 "Expected to list only the last 100 transactions from a large dataset"
             );
 
-            let cleared = clear_transactions(Some(large_number / 2), None).unwrap();
+            let cleared = clear_transactions(Some(large_number / 2), None, None).unwrap();
             // Expecting half of the transactions to be cleared
             assert_eq!(
                 cleared.len(),
@@ -1030,7 +1237,7 @@ mod tests {
             refund_setup();
 
             // Your refund test logic for a valid transaction
-            let result = refund(1);
+            let result = refund(1, None);
             assert!(
                 result.await.is_ok(),
                 "Refund should succeed for a valid transaction"
@@ -1059,35 +1266,251 @@ mod tests {
         }
 
         #[tokio::test]
-        async fn test_single_sweep_success() {
-            let hashes = setup_sweep_environment();
+        async fn test_sweep_skips_transactions_that_are_not_deposits() {
+            setup_sweep_environment();
+            let (spender_subaccountid, to_subaccountid, from_subaccountid) = setup_principals();
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let stored = |index: u64, operation: Operation| {
+                let transaction = Transaction {
+                    memo: index,
+                    icrc1_memo: None,
+                    operation: Some(operation),
+                    created_at_time: Timestamp { timestamp_nanos: 0 },
+                    from_account: None,
+                    to_account: None,
+                    spender_account: None,
+                };
+                let hash = hash_transaction(&transaction).unwrap();
+                let tx = StoredTransactions::new(
+                    index,
+                    transaction,
+                    hash,
+                    TokenType::ICP,
+                    ledger_principal,
+                );
+                insert_transaction((ledger_principal, index), tx.clone());
+                tx
+            };
 
-            let result = single_sweep(hashes[0].to_string()).await.unwrap();
+            // An approval naming a generated subaccount as spender is not a deposit
+            let approve = stored(
+                3,
+                Operation::Approve(Approve {
+                    fee: candid::Nat::from(100u64),
+                    from: hex_str_to_vec(&from_subaccountid.to_hex()).unwrap(),
+                    allowance: candid::Nat::from(50_000u64),
+                    expected_allowance: None,
+                    expires_at: None,
+                    spender: hex_str_to_vec(&spender_subaccountid.to_hex()).unwrap(),
+                }),
+            );
+            // Minted ICP deposits are swept like transfers
+            stored(
+                4,
+                Operation::Mint(Mint {
+                    to: hex_str_to_vec(&to_subaccountid.to_hex()).unwrap(),
+                    amount: candid::Nat::from(20_000u64),
+                }),
+            );
 
-            assert_eq!(result.len(), 1);
-            for res in result {
-                assert!(res.contains("sweep: ok"));
-            }
+            let results = sweep().await.unwrap();
+            assert_eq!(results.len(), 3);
+            assert!(results.iter().all(|result| result.contains("sweep: ok")));
+            TRANSACTIONS.with(|t| {
+                for (key, tx) in t.borrow().iter() {
+                    let expected = if key.1 == 3 {
+                        SweepStatus::NotSwept
+                    } else {
+                        SweepStatus::Swept
+                    };
+                    assert_eq!(tx.sweep_status, expected, "tx {}", key.1);
+                }
+            });
+
+            // Sweeping the approval by hash reports it instead of failing the call
+            let results = single_sweep(approve.tx_hash.clone()).await.unwrap();
+            assert_eq!(
+                results,
+                vec!["tx: 3, sweep: not a deposit into a canister subaccount".to_string()]
+            );
+            assert!(sweep_by_token_type(TokenType::ICP)
+                .await
+                .unwrap()
+                .is_empty());
 
             teardown_sweep_environment();
         }
 
         #[tokio::test]
-        async fn test_set_and_get_webhook_url_valid() {
-            let valid_url = "https://example.com/webhook".to_string();
-            let result = set_webhook_url(valid_url.clone()).await;
-
-            assert!(result.is_ok(), "Setting a valid webhook URL should succeed");
+        async fn test_running_balances_follow_sweeps_and_reconcile() {
+            SUBACCOUNT_BALANCES.with(|b| b.borrow_mut().clear_new());
+            BALANCE_DISCREPANCIES.with(|d| d.borrow_mut().clear_new());
+            let hashes = setup_sweep_environment();
+            // The fixture stores the deposits directly, as before balances existed
+            seed_subaccount_balances(None, MIGRATION_BATCH_SIZE);
+
+            // Three deposits of 10000 to nonce 1, rewriting them credits nothing
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let balance_of = || get_subaccount_balances(1).unwrap()[0].balance.clone();
+            assert_eq!(balance_of(), candid::Nat::from(30_000u64));
+            let first = TRANSACTIONS.with(|t| t.borrow().get(&(ledger_principal, 1)).unwrap());
+            update_status(&first, SweepStatus::FailedToSweep).unwrap();
+            assert_eq!(balance_of(), candid::Nat::from(30_000u64));
+
+            // A sweep moves the deposit less fee and pays the fee
+            let result = single_sweep(hashes[0].clone()).await.unwrap();
+            assert!(result[0].contains("sweep: ok"));
+            assert_eq!(balance_of(), candid::Nat::from(20_000u64));
+
+            // A partial manual sweep of 5000 e8s
+            let fee = get_token_fee(&TokenType::ICP);
+            let subaccount_hex = index_subaccount(1).to_hex();
+            sweep_subaccount(subaccount_hex, candid::Nat::from(5_000u64), None)
+                .await
+                .unwrap();
+            let expected = 15_000 - fee;
+            assert_eq!(balance_of(), candid::Nat::from(expected));
+            let balance = &get_subaccount_balances(1).unwrap()[0];
+            assert_eq!(balance.credited, candid::Nat::from(30_000u64));
+            assert_eq!(balance.debited, candid::Nat::from(15_000 + fee));
+
+            let subaccount = to_subaccount(1).0;
+            MOCK_LEDGER_BALANCES.with(|b| b.borrow_mut().insert(subaccount, expected + 7));
+            let report = reconcile_balances(None).await.unwrap();
+            assert_eq!((report.checked, report.matched), (1, 0));
+            assert_eq!(report.discrepancies.len(), 1);
+            let discrepancies = get_balance_discrepancies(Some(TokenType::ICP)).unwrap();
+            assert_eq!(discrepancies.len(), 1);
+            assert_eq!(discrepancies[0].nonce, 1);
             assert_eq!(
-                result.unwrap(),
-                valid_url,
-                "The function should return the set URL"
+                discrepancies[0].indexed_balance,
+                candid::Nat::from(expected)
+            );
+            assert_eq!(
+                discrepancies[0].ledger_balance,
+                candid::Nat::from(expected + 7)
             );
 
-            // Verify that the URL was actually set
-            let result = get_webhook_url();
+            // Once the ledger agrees the discrepancy is cleared
+            MOCK_LEDGER_BALANCES.with(|b| b.borrow_mut().insert(subaccount, expected));
+            let report = reconcile_balances(Some(TokenType::ICP)).await.unwrap();
+            assert_eq!((report.checked, report.matched), (1, 1));
+            assert!(get_balance_discrepancies(None).unwrap().is_empty());
+
+            // Sweeping more than the balance counted debits only what it credited
+            let subaccount_hex = index_subaccount(1).to_hex();
+            sweep_subaccount(subaccount_hex, candid::Nat::from(30_000u64), None)
+                .await
+                .unwrap();
+            let balance = &get_subaccount_balances(1).unwrap()[0];
+            assert_eq!(balance.balance, candid::Nat::from(0u64));
+            assert_eq!(balance.debited, balance.credited);
 
-            assert!(result.is_ok(), "Getting the webhook URL should succeed");
+            teardown_sweep_environment();
+        }
+
+        #[test]
+        fn test_upgrade_seeds_balances_of_swept_legacy_deposits() {
+            let (_, to_subaccountid, from_subaccountid) = setup_principals();
+            let deposit = |amount: u64| Transaction {
+                memo: 0,
+                icrc1_memo: None,
+                operation: Some(Operation::Transfer(Transfer {
+                    to: to_subaccountid.as_ref().to_vec(),
+                    fee: candid::Nat::from(100u64),
+                    from: from_subaccountid.as_ref().to_vec(),
+                    amount: candid::Nat::from(amount),
+                    spender: None,
+                })),
+                created_at_time: Timestamp::from_nanos(1000),
+                from_account: None,
+                to_account: None,
+                spender_account: None,
+            };
+
+            // A baseline store: one deposit already swept, one still held
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            LEGACY_TRANSACTIONS.with(|t| {
+                let mut transactions = t.borrow_mut();
+                for (index, amount, status) in [
+                    (1, 10_000, SweepStatus::Swept),
+                    (2, 20_000, SweepStatus::NotSwept),
+                ] {
+                    let mut stored = StoredTransactions::new(
+                        index,
+                        deposit(amount),
+                        format!("hash-{}", index),
+                        TokenType::ICP,
+                        ledger_principal,
+                    );
+                    stored.token_ledger_canister_id = None;
+                    stored.sweep_status = status;
+                    transactions.insert(index, stored);
+                }
+            });
+
+            register_migrations(false);
+            while next_migration().is_some() {
+                run_migrations();
+            }
+
+            assert_eq!(LEGACY_TRANSACTIONS.with(|t| t.borrow().len()), 0);
+            let balance = get_subaccount_balances(1).unwrap()[0].clone();
+            // The swept deposit left the subaccount, it is neither credited nor debited
+            assert_eq!(balance.balance, candid::Nat::from(20_000u64));
+            assert_eq!(balance.credited, candid::Nat::from(20_000u64));
+            assert_eq!(balance.debited, candid::Nat::from(0u64));
+
+            // Re-indexing a pruned deposit, as a backfill does, credits nothing
+            clear_transactions(Some(2), None, None).unwrap();
+            let block = Block {
+                transaction: deposit(20_000),
+                timestamp: Timestamp::from_nanos(1000),
+                parent_hash: None,
+                tx_hash: None,
+                block_hash: None,
+            };
+            assert!(index_block(&TokenType::ICP, ledger_principal, 2, &block).is_some());
+            let balance = get_subaccount_balances(1).unwrap()[0].clone();
+            assert_eq!(balance.balance, candid::Nat::from(20_000u64));
+            assert!(PRUNED_DEPOSITS.with(|p| p.borrow().get(&(ledger_principal, 2)).is_none()));
+
+            // A deposit never seen before is credited
+            assert!(index_block(&TokenType::ICP, ledger_principal, 3, &block).is_some());
+            let balance = get_subaccount_balances(1).unwrap()[0].clone();
+            assert_eq!(balance.balance, candid::Nat::from(40_000u64));
+        }
+
+        #[tokio::test]
+        async fn test_single_sweep_success() {
+            let hashes = setup_sweep_environment();
+
+            let result = single_sweep(hashes[0].to_string()).await.unwrap();
+
+            assert_eq!(result.len(), 1);
+            for res in result {
+                assert!(res.contains("sweep: ok"));
+            }
+
+            teardown_sweep_environment();
+        }
+
+        #[tokio::test]
+        async fn test_set_and_get_webhook_url_valid() {
+            let valid_url = "https://example.com/webhook".to_string();
+            let result = set_webhook_url(valid_url.clone()).await;
+
+            assert!(result.is_ok(), "Setting a valid webhook URL should succeed");
+            assert_eq!(
+                result.unwrap(),
+                valid_url,
+                "The function should return the set URL"
+            );
+
+            // Verify that the URL was actually set
+            let result = get_webhook_url();
+
+            assert!(result.is_ok(), "Getting the webhook URL should succeed");
             assert_eq!(
                 result.unwrap(),
                 valid_url,
@@ -1096,200 +1519,2194 @@ mod tests {
         }
 
         #[tokio::test]
-        async fn test_sweep_subaccount_decimal_amount() {
-            // Setup
+        async fn test_sweep_subaccount_base_unit_amount() {
+            // Setup
+            let (_, to_subaccountid, _) = setup_principals();
+            let subaccountid_hex = to_subaccountid.to_hex();
+            let amount = candid::Nat::from(125_000_000u64); // 1.25 ICP
+
+            // Execute
+            let result = sweep_subaccount(subaccountid_hex, amount, Some(TokenType::ICP)).await;
+
+            // Assert
+            assert!(
+                result.is_ok(),
+                "Sweeping subaccount with a base unit amount should succeed"
+            );
+            assert_eq!(result.unwrap(), 1, "BlockIndex should be 1");
+        }
+
+        // New tests for multi-token support
+
+        #[test]
+        fn test_get_registered_tokens() {
+            // Initially should be empty since no tokens are registered by default in tests
+            let result = get_registered_tokens();
+            assert!(result.is_ok(), "Getting registered tokens should succeed");
+
+            let tokens = result.unwrap();
+            // In test environment, tokens are not automatically registered
+            assert_eq!(tokens.len(), 0, "Should have 0 token types initially");
+        }
+
+        #[tokio::test]
+        async fn test_register_custom_token() {
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+            let ledger = Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap();
+            let custom = TokenType::Custom(ledger);
+
+            // Without explicit metadata the ledger is queried
+            let result = register_token(custom.clone(), ledger.to_text(), None).await;
+            assert!(result.is_ok(), "Custom token metadata should be fetched");
+            assert_eq!(get_token_config(&custom).unwrap().symbol, "MOCK");
+
+            let metadata = TokenMetadata {
+                symbol: "ckETH".to_string(),
+                decimals: 18,
+                fee: 2_000_000_000_000,
+                standard: TokenStandard::ICRC1,
+            };
+            let other = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+            let result = register_token(custom.clone(), other.to_text(), Some(metadata.clone()));
+            assert!(
+                result.await.is_err(),
+                "Custom token ledger must match the registered principal"
+            );
+
+            let result = register_token(custom.clone(), ledger.to_text(), Some(metadata)).await;
+            assert!(result.is_ok(), "Registering a custom token should succeed");
+
+            let registry = get_token_registry().unwrap();
+            assert_eq!(registry.len(), 1);
+            assert_eq!(registry[0].symbol, "ckETH");
+            assert_eq!(registry[0].decimals, 18);
+            assert_eq!(get_token_ledger_canister_id(&custom), ledger);
+            assert_eq!(get_token_fee(&custom), 2_000_000_000_000);
+            assert!(is_icrc_token(&custom));
+
+            // Custom tokens are tracked next to the built-in presets
+            let blocks = get_all_token_blocks().unwrap();
+            assert_eq!(blocks.len(), 5);
+            assert!(blocks.iter().any(|(token_type, _)| *token_type == custom));
+
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_register_builtin_token_fetches_ledger_metadata() {
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+            let local_ledger = Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap();
+            let other_ledger = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+
+            let result = register_token(TokenType::CKUSDC, local_ledger.to_text(), None).await;
+            assert!(result.is_ok(), "Registering ckUSDC should succeed");
+
+            // Metadata and fee come from the ledger rather than the preset
+            let config = get_token_config(&TokenType::CKUSDC).unwrap();
+            assert_eq!(config.ledger_canister_id, local_ledger);
+            assert_eq!(config.symbol, "MOCK");
+            assert_eq!(config.decimals, 8);
+            assert_eq!(config.fee, 20_000);
+            assert_eq!(config.fee_updated_at, Some(CanisterApiManager::time()));
+
+            assert_eq!(set_token_interval(TokenType::CKUSDC, Some(30)).unwrap(), 30);
+
+            // Registering the same token on another ledger replaces the old entry
+            let result = register_token(TokenType::CKUSDC, other_ledger.to_text(), None).await;
+            assert!(result.is_ok());
+            let registry = get_token_registry().unwrap();
+            assert_eq!(registry.len(), 1);
+            assert_eq!(registry[0].ledger_canister_id, other_ledger);
+
+            // and its own interval moves to the new ledger
+            assert_eq!(
+                TOKEN_INTERVALS.with(|i| i.borrow().get(&local_ledger)),
+                None
+            );
+            assert_eq!(
+                TOKEN_INTERVALS.with(|i| i.borrow().get(&other_ledger)),
+                Some(30)
+            );
+            assert!(get_token_intervals()
+                .unwrap()
+                .contains(&(TokenType::CKUSDC, 30)));
+            assert!(TOKEN_LEDGER_TIMERS.with(|t| t.borrow().contains_key(&TokenType::CKUSDC)));
+
+            let _ = set_token_interval(TokenType::CKUSDC, None);
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_refresh_stale_token_fees() {
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+            let now = CanisterApiManager::time();
+            let fresh_ledger = Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap();
+            let stale_ledger = Principal::from_text("xevnm-gaaaa-aaaar-qafnq-cai").unwrap();
+            TOKEN_REGISTRY.with(|registry| {
+                let mut registry = registry.borrow_mut();
+                registry.insert(
+                    fresh_ledger,
+                    TokenConfig {
+                        ledger_canister_id: fresh_ledger,
+                        fee_updated_at: Some(now - 60 * 1_000_000_000),
+                        ..preset_token_config(&TokenType::CKBTC).unwrap()
+                    },
+                );
+                registry.insert(
+                    stale_ledger,
+                    TokenConfig {
+                        ledger_canister_id: stale_ledger,
+                        fee_updated_at: Some(now - FEE_REFRESH_INTERVAL_NANOS),
+                        ..preset_token_config(&TokenType::CKUSDC).unwrap()
+                    },
+                );
+            });
+
+            refresh_stale_token_fees().await;
+
+            // Only the stale entry is re-read from the ledger
+            assert_eq!(get_token_fee(&TokenType::CKBTC), 10);
+            assert_eq!(get_token_fee(&TokenType::CKUSDC), 20_000);
+            let config = get_token_config(&TokenType::CKUSDC).unwrap();
+            assert_eq!(config.fee_updated_at, Some(now));
+
+            // The endpoint refreshes a single registered token on demand
+            let result = update_token_fee(TokenType::CKBTC).await;
+            assert_eq!(result.unwrap(), 20_000);
+            let result = update_token_fee(TokenType::CKUSDT).await;
+            assert!(
+                result.is_err(),
+                "Unregistered tokens have no ledger fee to refresh"
+            );
+
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_bad_fee_is_stored_and_the_transfer_retried() {
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+            let ledger = Principal::from_text("xevnm-gaaaa-aaaar-qafnq-cai").unwrap();
+            let config = TokenConfig {
+                ledger_canister_id: ledger,
+                fee_updated_at: None,
+                ..preset_token_config(&TokenType::CKUSDC).unwrap()
+            };
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().insert(ledger, config));
+            MOCK_ICRC1_FEE.with(|f| *f.borrow_mut() = Some(20_000));
+            MOCK_ICRC1_TRANSFERS.with(|t| t.borrow_mut().clear());
+            let transfer = |amount: u64| Icrc1TransferArg {
+                to: icrc_ledger_types::icrc1::account::Account {
+                    owner: ledger,
+                    subaccount: None,
+                },
+                fee: Some(candid::Nat::from(10_000u64)),
+                memo: None,
+                from_subaccount: None,
+                created_at_time: None,
+                amount: candid::Nat::from(amount),
+            };
+
+            // Transfers of a chosen amount keep it and pay the new fee on top
+            let result =
+                icrc1_transfer_from_subaccount(transfer(50_000), ledger, &TokenType::CKUSDC, None)
+                    .await;
+            assert!(result.is_ok());
+            let config = get_token_config(&TokenType::CKUSDC).unwrap();
+            assert_eq!(config.fee, 20_000);
+            assert_eq!(config.fee_updated_at, Some(CanisterApiManager::time()));
+
+            // Sweeps and refunds take the new fee out of the deposit
+            let deposit = Some((ledger, 42));
+            let result = icrc1_transfer_from_subaccount(
+                transfer(90_000),
+                ledger,
+                &TokenType::CKUSDC,
+                deposit,
+            )
+            .await;
+            assert!(result.is_ok());
+            let result = icrc1_transfer_from_subaccount(
+                transfer(5_000),
+                ledger,
+                &TokenType::CKUSDC,
+                deposit,
+            )
+            .await;
+            assert!(result.is_err(), "The deposit does not cover the new fee");
+
+            let sent: Vec<(u64, u64)> = MOCK_ICRC1_TRANSFERS.with(|t| {
+                t.borrow()
+                    .iter()
+                    .map(|args| {
+                        (
+                            args.amount.0.to_u64().unwrap(),
+                            args.fee.as_ref().unwrap().0.to_u64().unwrap(),
+                        )
+                    })
+                    .collect()
+            });
+            assert_eq!(
+                sent,
+                vec![
+                    (50_000, 10_000),
+                    (50_000, 20_000),
+                    (90_000, 10_000),
+                    (80_000, 20_000),
+                    (5_000, 10_000),
+                ]
+            );
+
+            MOCK_ICRC1_FEE.with(|f| *f.borrow_mut() = None);
+            MOCK_ICRC1_TRANSFERS.with(|t| t.borrow_mut().clear());
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+        }
+
+        #[test]
+        fn test_migrate_token_registry() {
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+            TOKEN_NEXT_BLOCKS.with(|b| b.borrow_mut().clear_new());
+
+            let ckbtc_ledger = Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap();
+            LEGACY_TOKEN_LEDGER_PRINCIPALS.with(|tl| {
+                tl.borrow_mut().insert(4, (TokenType::CKBTC, ckbtc_ledger));
+            });
+            LEGACY_TOKEN_NEXT_BLOCKS.with(|blocks| {
+                let mut blocks = blocks.borrow_mut();
+                blocks.insert(1, 42);
+                blocks.insert(4, 7);
+            });
+
+            migrate_token_registry();
+
+            assert!(LEGACY_TOKEN_LEDGER_PRINCIPALS.with(|tl| tl.borrow().is_empty()));
+            assert!(LEGACY_TOKEN_NEXT_BLOCKS.with(|b| b.borrow().is_empty()));
+
+            let config = TOKEN_REGISTRY
+                .with(|r| r.borrow().get(&ckbtc_ledger))
+                .expect("ckBTC should be migrated to the registry");
+            assert_eq!(config.token_type, TokenType::CKBTC);
+            assert_eq!(config.fee, 10);
+            assert_eq!(get_token_next_block(&TokenType::ICP), 42);
+            assert_eq!(get_token_next_block(&TokenType::CKBTC), 7);
+
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+            TOKEN_NEXT_BLOCKS.with(|b| b.borrow_mut().clear_new());
+        }
+
+        #[test]
+        fn test_get_transaction_by_hash_follows_updates_and_clears() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            TX_HASH_INDEX.with(|i| i.borrow_mut().clear_new());
+            populate_transactions(3, None);
+
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let second = TRANSACTIONS.with(|t| t.borrow().get(&(ledger_principal, 2)).unwrap());
+
+            let found = get_transaction_by_hash(second.tx_hash.clone()).unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].index, 2);
+
+            // Status updates rewrite the record under the same hash
+            update_status(&second, SweepStatus::Swept).unwrap();
+            let found = get_transaction_by_hash(second.tx_hash.clone()).unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].sweep_status, SweepStatus::Swept);
+
+            // Transactions sharing the placeholder hash are all returned
+            let mut placeholder = second.clone();
+            placeholder.tx_hash = "HASH-IS-NOT-AVAILABLE".to_string();
+            for index in [10, 11] {
+                placeholder.index = index;
+                insert_transaction((ledger_principal, index), placeholder.clone());
+            }
+            let placeholders = get_transaction_by_hash("HASH-IS-NOT-AVAILABLE".to_string());
+            assert_eq!(placeholders.unwrap().len(), 2);
+
+            clear_transactions(Some(3), None, None).unwrap();
+            assert!(get_transaction_by_hash(second.tx_hash.clone()).is_err());
+            let placeholders = get_transaction_by_hash("HASH-IS-NOT-AVAILABLE".to_string());
+            assert_eq!(placeholders.unwrap().len(), 2);
+            TX_HASH_INDEX.with(|i| assert_eq!(i.borrow().len(), 2));
+        }
+
+        #[test]
+        fn test_list_transactions_between_pages_by_creation_time() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            TX_TIME_INDEX.with(|i| i.borrow_mut().clear_new());
+            populate_transactions(6, None);
+
+            // Block n was created at n * 100 nanos
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            for index in 1..=6 {
+                let mut tx =
+                    TRANSACTIONS.with(|t| t.borrow().get(&(ledger_principal, index)).unwrap());
+                tx.created_at_time = Timestamp::from_nanos(index * 100);
+                insert_transaction((ledger_principal, index), tx);
+            }
+            TX_TIME_INDEX.with(|i| assert_eq!(i.borrow().len(), 6));
+
+            let from_ts = Timestamp::from_nanos(200);
+            let to_ts = Timestamp::from_nanos(500);
+            let page =
+                list_transactions_between(from_ts.clone(), to_ts.clone(), None, Some(2)).unwrap();
+            let indexes: Vec<u64> = page.transactions.iter().map(|tx| tx.index).collect();
+            assert_eq!(indexes, vec![2, 3]);
+            assert_eq!(
+                page.next_cursor,
+                Some(TransactionCursor {
+                    timestamp_nanos: 400,
+                    token_ledger_canister_id: ledger_principal,
+                    index: 4,
+                })
+            );
+
+            let page =
+                list_transactions_between(from_ts, to_ts, page.next_cursor, Some(2)).unwrap();
+            let indexes: Vec<u64> = page.transactions.iter().map(|tx| tx.index).collect();
+            assert_eq!(indexes, vec![4]);
+            assert_eq!(page.next_cursor, None);
+
+            // A zero limit still moves the cursor forward
+            let page = list_transactions_between(
+                Timestamp::from_nanos(200),
+                Timestamp::from_nanos(500),
+                None,
+                Some(0),
+            )
+            .unwrap();
+            assert_eq!(page.transactions.len(), 1);
+            assert_eq!(page.next_cursor.map(|cursor| cursor.index), Some(3));
+
+            assert!(list_transactions_between(
+                Timestamp::from_nanos(500),
+                Timestamp::from_nanos(200),
+                None,
+                None
+            )
+            .is_err());
+
+            // Timestamp pruning goes through the same index
+            let remaining =
+                clear_transactions(None, Some(Timestamp::from_nanos(300)), None).unwrap();
+            assert_eq!(remaining.len(), 3);
+            TX_TIME_INDEX.with(|i| assert_eq!(i.borrow().len(), 3));
+        }
+
+        #[test]
+        fn test_list_transactions_v2_filters_and_pages_both_ways() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            TX_TIME_INDEX.with(|i| i.borrow_mut().clear_new());
+            populate_transactions(6, None);
+
+            // Block n was created at n * 100 nanos, 2 and 4 are swept, 5 is
+            // larger and 6 is a deposit to another subaccount
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let other_subaccount = index_subaccount(5);
+            for index in 1..=6 {
+                let mut tx =
+                    TRANSACTIONS.with(|t| t.borrow().get(&(ledger_principal, index)).unwrap());
+                tx.created_at_time = Timestamp::from_nanos(index * 100);
+                if index % 2 == 0 && index < 6 {
+                    tx.sweep_status = SweepStatus::Swept;
+                }
+                if let Some(Operation::Transfer(transfer)) = tx.operation.as_mut() {
+                    if index == 5 {
+                        transfer.amount = candid::Nat::from(50_000u64);
+                    }
+                    if index == 6 {
+                        transfer.to = other_subaccount.as_ref().to_vec();
+                    }
+                }
+                insert_transaction((ledger_principal, index), tx);
+            }
+
+            let indexes = |page: &TransactionsPage| -> Vec<u64> {
+                page.transactions.iter().map(|tx| tx.index).collect()
+            };
+
+            let not_swept = TransactionFilter {
+                sweep_status: Some(SweepStatus::NotSwept),
+                ..Default::default()
+            };
+            let page = list_transactions_v2(Some(not_swept.clone()), None, Some(2), None).unwrap();
+            assert_eq!(indexes(&page), vec![1, 3]);
+            assert_eq!(page.next_cursor.as_ref().map(|c| c.index), Some(4));
+            let page =
+                list_transactions_v2(Some(not_swept), page.next_cursor, Some(2), None).unwrap();
+            assert_eq!(indexes(&page), vec![5, 6]);
+            assert_eq!(page.next_cursor, None);
+
+            let descending = Some(SortOrder::Descending);
+            let page = list_transactions_v2(None, None, Some(3), descending).unwrap();
+            assert_eq!(indexes(&page), vec![6, 5, 4]);
+            let page = list_transactions_v2(None, page.next_cursor, Some(3), descending).unwrap();
+            assert_eq!(indexes(&page), vec![3, 2, 1]);
+            assert_eq!(page.next_cursor, None);
+
+            let page = list_transactions_v2(None, None, Some(0), descending).unwrap();
+            assert_eq!(indexes(&page), vec![6]);
+            assert_eq!(page.next_cursor.as_ref().map(|c| c.index), Some(5));
+
+            let large = TransactionFilter {
+                min_amount: Some(candid::Nat::from(20_000u64)),
+                ..Default::default()
+            };
+            let page = list_transactions_v2(Some(large), None, None, None).unwrap();
+            assert_eq!(indexes(&page), vec![5]);
+
+            let by_subaccount = TransactionFilter {
+                subaccount: Some(other_subaccount.to_hex()),
+                ..Default::default()
+            };
+            let page = list_transactions_v2(Some(by_subaccount), None, None, None).unwrap();
+            assert_eq!(indexes(&page), vec![6]);
+
+            // Subaccount filters page through the subaccount's own index
+            let deposit_subaccount = TransactionFilter {
+                subaccount: Some(index_subaccount(1).to_hex()),
+                from_ts: Some(Timestamp::from_nanos(200)),
+                ..Default::default()
+            };
+            let page =
+                list_transactions_v2(Some(deposit_subaccount.clone()), None, Some(2), descending)
+                    .unwrap();
+            assert_eq!(indexes(&page), vec![5, 4]);
+            let page =
+                list_transactions_v2(Some(deposit_subaccount), page.next_cursor, None, descending)
+                    .unwrap();
+            assert_eq!(indexes(&page), vec![3, 2]);
+            assert_eq!(page.next_cursor, None);
+
+            let window = TransactionFilter {
+                token_type: Some(TokenType::ICP),
+                from_ts: Some(Timestamp::from_nanos(200)),
+                to_ts: Some(Timestamp::from_nanos(400)),
+                ..Default::default()
+            };
+            let page = list_transactions_v2(Some(window), None, None, descending).unwrap();
+            assert_eq!(indexes(&page), vec![3, 2]);
+
+            let other_token = TransactionFilter {
+                token_type: Some(TokenType::Custom(Principal::management_canister())),
+                ..Default::default()
+            };
+            let page = list_transactions_v2(Some(other_token), None, None, None).unwrap();
+            assert!(page.transactions.is_empty());
+        }
+
+        #[test]
+        fn test_get_subaccount_transactions_pages_newest_first() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            SUBACCOUNT_TX_INDEX.with(|i| i.borrow_mut().clear_new());
+            LAST_SUBACCOUNT_NONCE.with(|n| {
+                let _ = n.borrow_mut().set(6);
+            });
+            populate_transactions(5, None);
+
+            // Block n was created at n * 100 nanos, block 5 went to nonce 5
+            // instead of nonce 1
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            for index in 1..=5 {
+                let mut tx =
+                    TRANSACTIONS.with(|t| t.borrow().get(&(ledger_principal, index)).unwrap());
+                tx.created_at_time = Timestamp::from_nanos(index * 100);
+                if let Some(Operation::Transfer(transfer)) = tx.operation.as_mut() {
+                    if index == 5 {
+                        transfer.to = index_subaccount(5).as_ref().to_vec();
+                    }
+                }
+                insert_transaction((ledger_principal, index), tx);
+            }
+
+            let indexes = |page: &TransactionsPage| -> Vec<u64> {
+                page.transactions.iter().map(|tx| tx.index).collect()
+            };
+
+            let page = get_subaccount_transactions(1, None, None, Some(2)).unwrap();
+            assert_eq!(indexes(&page), vec![4, 3]);
+            assert_eq!(page.next_cursor.as_ref().map(|c| c.index), Some(2));
+            let page = get_subaccount_transactions(1, None, page.next_cursor, Some(2)).unwrap();
+            assert_eq!(indexes(&page), vec![2, 1]);
+            assert_eq!(page.next_cursor, None);
+            let page = get_subaccount_transactions(1, None, None, Some(0)).unwrap();
+            assert_eq!(indexes(&page), vec![4]);
+            assert_eq!(page.next_cursor.as_ref().map(|c| c.index), Some(3));
+
+            let page = get_subaccount_transactions(5, None, None, None).unwrap();
+            assert_eq!(indexes(&page), vec![5]);
+            let page = get_subaccount_transactions(3, None, None, None).unwrap();
+            assert!(page.transactions.is_empty());
+
+            let other_token = Some(TokenType::Custom(Principal::management_canister()));
+            let page = get_subaccount_transactions(1, other_token, None, None).unwrap();
+            assert!(page.transactions.is_empty());
+            assert!(get_subaccount_transactions(6, None, None, None).is_err());
+
+            // Pruned transactions leave the history of every subaccount they touched
+            clear_transactions(Some(2), None, None).unwrap();
+            let page = get_subaccount_transactions(1, None, None, None).unwrap();
+            assert_eq!(indexes(&page), vec![4, 3]);
+            SUBACCOUNT_TX_INDEX.with(|i| assert_eq!(i.borrow().len(), 9));
+        }
+
+        #[test]
+        fn test_get_transaction_token_type() {
+            // Setup a transaction with specific token type
+            populate_transactions(1, None);
+
+            // Get the transaction hash from populated transactions
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let tx_hash = TRANSACTIONS.with(|t| {
+                let transactions = t.borrow();
+                transactions
+                    .get(&(ledger_principal, 1))
+                    .map(|tx| tx.tx_hash.clone())
+                    .unwrap_or_else(|| "HASH-IS-NOT-AVAILABLE".to_string())
+            });
+
+            // Get the transaction token type
+            let result = get_transaction_token_type(tx_hash);
+            assert!(
+                result.is_ok(),
+                "Getting transaction token type should succeed"
+            );
+            assert_eq!(
+                result.unwrap(),
+                TokenType::ICP,
+                "Should return ICP token type"
+            );
+        }
+
+        #[test]
+        fn test_get_transaction_token_type_not_found() {
+            let result = get_transaction_token_type("non-existent-hash".to_string());
+            assert!(result.is_err(), "Should fail for non-existent transaction");
+            assert_eq!(
+                result.unwrap_err(),
+                "Transaction not found",
+                "Should return correct error message"
+            );
+        }
+
+        #[test]
+        fn test_get_token_next_block_query() {
+            // This function returns the next block for a specific token
+            let result = get_token_next_block_query(TokenType::CKUSDC);
+            assert!(result.is_ok(), "Getting token next block should succeed");
+            // Should return 1 by default
+            assert_eq!(result.unwrap(), 1, "Should return default block 1");
+        }
+
+        #[test]
+        fn test_get_all_token_blocks() {
+            let result = get_all_token_blocks();
+            assert!(result.is_ok(), "Getting all token blocks should succeed");
+
+            let blocks = result.unwrap();
+            // In production, this returns all three token types with default block 1
+            // The actual behavior depends on the implementation
+            assert!(blocks.len() <= 4, "Should have at most 4 token blocks");
+            // All blocks should default to 1
+            for (_, block) in blocks {
+                assert_eq!(block, 1, "All blocks should default to 1");
+            }
+        }
+
+        #[test]
+        fn test_get_network() {
+            // The network is set during initialization, let's test it
+            let result = get_network();
+            assert!(result.is_ok(), "Getting network should succeed");
+            // Network could be either Local or Mainnet depending on initialization
+        }
+
+        #[test]
+        fn test_get_next_block() {
+            // Test getting the next block
+            let result = get_next_block();
+            assert!(result.is_ok(), "Getting next block should succeed");
+            // Should have some default value
+            assert!(result.unwrap() >= 0, "Should return a valid block number");
+        }
+
+        #[test]
+        fn test_get_canister_principal() {
+            let result = get_canister_principal();
+            assert!(result.is_ok(), "Getting canister principal should succeed");
+            // Should return the principal as a string
+            let principal_str = result.unwrap();
+            assert!(
+                !principal_str.is_empty(),
+                "Principal string should not be empty"
+            );
+            // Verify it's a valid principal format
+            assert_eq!(principal_str, STATIC_PRINCIPAL.lock().unwrap().to_text());
+        }
+
+        #[test]
+        fn test_get_transactions_count() {
+            // Clear transactions first
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+
+            // Initially should be 0
+            let result = get_transactions_count();
+            assert!(result.is_ok(), "Getting transactions count should succeed");
+            assert_eq!(result.unwrap(), 0, "Should have 0 transactions initially");
+
+            // Add some transactions
+            populate_transactions(5, None);
+
+            let result = get_transactions_count();
+            assert!(result.is_ok(), "Getting transactions count should succeed");
+            assert_eq!(result.unwrap(), 5, "Should have 5 transactions");
+        }
+
+        #[test]
+        fn test_get_oldest_block() {
+            // Clear transactions first
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+
+            // Without transactions
+            let result = get_oldest_block(None);
+            assert!(result.is_ok(), "Getting oldest block should succeed");
+            assert_eq!(
+                result.unwrap(),
+                None,
+                "Should return None when no transactions"
+            );
+
+            // Add transactions starting from block 10
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            TRANSACTIONS.with(|t| {
+                let mut transactions = t.borrow_mut();
+                for i in 10..15 {
+                    let transaction = Transaction {
+                        memo: i,
+                        icrc1_memo: None,
+                        operation: None,
+                        created_at_time: Timestamp {
+                            timestamp_nanos: 1000,
+                        },
+                        from_account: None,
+                        to_account: None,
+                        spender_account: None,
+                    };
+                    let hash = format!("hash-{}", i);
+                    transactions.insert(
+                        (ledger_principal, i),
+                        StoredTransactions::new(
+                            i,
+                            transaction,
+                            hash,
+                            TokenType::ICP,
+                            ledger_principal,
+                        ),
+                    );
+                }
+            });
+
+            let result = get_oldest_block(None);
+            assert!(result.is_ok(), "Getting oldest block should succeed");
+            assert_eq!(
+                result.unwrap(),
+                Some(10),
+                "Should return Some(10) as the oldest block number"
+            );
+        }
+
+        #[test]
+        fn test_same_block_index_on_different_ledgers() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+
+            let icp_ledger = *STATIC_PRINCIPAL.lock().unwrap();
+            TRANSACTIONS.with(|t| {
+                let mut transactions = t.borrow_mut();
+                for (token_type, ledger, index) in [
+                    (TokenType::ICP, icp_ledger, 7),
+                    (TokenType::CKUSDC, CKUSDC_LEDGER_CANISTER_ID, 7),
+                    (TokenType::CKUSDC, CKUSDC_LEDGER_CANISTER_ID, 3),
+                ] {
+                    let transaction = Transaction {
+                        memo: index,
+                        icrc1_memo: None,
+                        operation: None,
+                        created_at_time: Timestamp {
+                            timestamp_nanos: 1000,
+                        },
+                        from_account: None,
+                        to_account: None,
+                        spender_account: None,
+                    };
+                    transactions.insert(
+                        (ledger, index),
+                        StoredTransactions::new(
+                            index,
+                            transaction,
+                            format!("hash-{:?}-{}", token_type, index),
+                            token_type,
+                            ledger,
+                        ),
+                    );
+                }
+            });
+
+            assert_eq!(get_transactions_count().unwrap(), 3);
+            assert_eq!(get_oldest_block(None).unwrap(), Some(7));
+            assert_eq!(get_oldest_block(Some(TokenType::CKUSDC)).unwrap(), Some(3));
+            assert_eq!(get_oldest_block(Some(TokenType::CKBTC)).unwrap(), None);
+
+            // Clearing by index for one token leaves the other ledger untouched
+            let remaining = clear_transactions(Some(10), None, Some(TokenType::CKUSDC)).unwrap();
+            assert_eq!(remaining.len(), 1);
+            assert_eq!(remaining[0].token_type, TokenType::ICP);
+            assert_eq!(remaining[0].index, 7);
+
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[test]
+        fn test_migrate_transaction_keys() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            LEGACY_TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+
+            LEGACY_TRANSACTIONS.with(|t| {
+                let mut transactions = t.borrow_mut();
+                for (index, token_type) in [(1, TokenType::ICP), (2, TokenType::CKBTC)] {
+                    let transaction = Transaction {
+                        memo: index,
+                        icrc1_memo: None,
+                        operation: None,
+                        created_at_time: Timestamp {
+                            timestamp_nanos: 1000,
+                        },
+                        from_account: None,
+                        to_account: None,
+                        spender_account: None,
+                    };
+                    let mut stored = StoredTransactions::new(
+                        index,
+                        transaction,
+                        format!("hash-{}", index),
+                        token_type,
+                        Principal::anonymous(),
+                    );
+                    // Entries written before per-token tracking carry no ledger id
+                    stored.token_ledger_canister_id = None;
+                    transactions.insert(index, stored);
+                }
+            });
+
+            migrate_transaction_keys(None, MIGRATION_BATCH_SIZE);
+
+            assert_eq!(LEGACY_TRANSACTIONS.with(|t| t.borrow().len()), 0);
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            TRANSACTIONS.with(|t| {
+                let transactions = t.borrow();
+                assert_eq!(transactions.len(), 2);
+                let icp_tx = transactions
+                    .get(&(ledger_principal, 1))
+                    .expect("ICP transaction should be migrated");
+                assert_eq!(icp_tx.token_ledger_canister_id, Some(ledger_principal));
+                assert!(transactions.get(&(CKBTC_LEDGER_CANISTER_ID, 2)).is_some());
+            });
+
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[test]
+        fn test_decode_transactions_with_u64_amounts() {
+            let v2 = StoredTransactionsV2 {
+                index: 7,
+                memo: 0,
+                icrc1_memo: None,
+                operation: Some(OperationV1::Transfer(TransferV1 {
+                    to: vec![1; 32],
+                    fee: E8s { e8s: 10_000 },
+                    from: vec![2; 32],
+                    amount: E8s { e8s: 5_000_000 },
+                    spender: None,
+                })),
+                created_at_time: Timestamp::from_nanos(1000),
+                sweep_status: SweepStatus::NotSwept,
+                tx_hash: "hash-7".to_string(),
+                token_type: TokenType::ICP,
+                token_ledger_canister_id: None,
+            };
+
+            let decoded =
+                StoredTransactions::from_bytes(Cow::Owned(candid::encode_one(&v2).unwrap()));
+            match &decoded.operation {
+                Some(Operation::Transfer(transfer)) => {
+                    assert_eq!(transfer.amount, candid::Nat::from(5_000_000u64));
+                    assert_eq!(transfer.fee, candid::Nat::from(10_000u64));
+                }
+                other => panic!("Expected a transfer, got {:?}", other),
+            }
+            assert_eq!(decoded.tx_hash, "hash-7");
+
+            // 18-decimal amounts above u64::MAX survive a round trip
+            let eth = candid::Nat::from(5_000_000_000_000_000_000_000u128);
+            let mut stored = decoded;
+            stored.operation = Some(Operation::Mint(Mint {
+                to: vec![1; 32],
+                amount: eth.clone(),
+            }));
+            let decoded = StoredTransactions::from_bytes(stored.to_bytes());
+            assert_eq!(
+                decoded.operation,
+                Some(Operation::Mint(Mint {
+                    to: vec![1; 32],
+                    amount: eth,
+                }))
+            );
+        }
+
+        #[test]
+        fn test_versioned_envelope_reads_legacy_values() {
+            let config = preset_token_config(&TokenType::CKUSDC).unwrap();
+
+            let bytes = config.to_bytes();
+            let (version, payload) = split_envelope(&bytes);
+            assert_eq!(version, TokenConfig::VERSION);
+            assert_eq!(payload, candid::encode_one(&config).unwrap().as_slice());
+            assert_eq!(TokenConfig::from_bytes(bytes), config);
+
+            // Values written before the envelope are plain candid
+            let legacy = candid::encode_one(&config).unwrap();
+            assert_eq!(split_envelope(&legacy).0, LEGACY_SCHEMA_VERSION);
+            assert_eq!(TokenConfig::from_bytes(Cow::Owned(legacy)), config);
+
+            // A value written by a newer schema is not silently misread
+            let mut newer = config.to_bytes().into_owned();
+            newer[3] = TokenConfig::VERSION + 1;
+            let result = std::panic::catch_unwind(|| TokenConfig::from_bytes(Cow::Owned(newer)));
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn test_tip_anchors_written_with_certificates_still_decode() {
+            #[derive(CandidType)]
+            struct TipAnchorV1 {
+                token_type: TokenType,
+                start_block: u64,
+                next_block: u64,
+                last_block_hash: Option<String>,
+                certificate: Option<Vec<u8>>,
+                hash_tree: Option<Vec<u8>>,
+                certified_block_index: Option<u64>,
+                certified_block_hash: Option<String>,
+                verified: bool,
+                recorded_at: u64,
+            }
+            let v1 = TipAnchorV1 {
+                token_type: TokenType::CKUSDC,
+                start_block: 10,
+                next_block: 20,
+                last_block_hash: Some("ab".repeat(32)),
+                certificate: Some(vec![1; 64]),
+                hash_tree: Some(vec![2; 64]),
+                certified_block_index: Some(19),
+                certified_block_hash: Some("ab".repeat(32)),
+                verified: false,
+                recorded_at: 7,
+            };
+
+            let mut bytes = b"ISV".to_vec();
+            bytes.push(1);
+            bytes.extend(candid::encode_one(&v1).unwrap());
+            let anchor = TipAnchor::from_bytes(Cow::Owned(bytes));
+            assert_eq!(
+                anchor,
+                TipAnchor {
+                    token_type: TokenType::CKUSDC,
+                    start_block: 10,
+                    next_block: 20,
+                    last_block_hash: Some("ab".repeat(32)),
+                    recorded_at: 7,
+                }
+            );
+            assert_eq!(split_envelope(&anchor.to_bytes()).0, 2);
+        }
+
+        #[test]
+        fn test_migrations_rewrite_values_in_resumable_batches() {
+            populate_transactions(MIGRATION_BATCH_SIZE + 10, None);
+
+            register_migrations(false);
+            schedule_migrations();
+            let transactions = MIGRATION_PROGRESS.with(|p| p.borrow().get(&1)).unwrap();
+            assert_eq!(transactions.status, MigrationStatus::Pending);
+            assert_eq!(transactions.total, MIGRATION_BATCH_SIZE + 10);
+            assert_eq!(transactions.version, StoredTransactions::VERSION);
+            assert!(MIGRATION_TIMER.with(|timer| timer.borrow().is_some()));
+
+            // Migrations listed before the rewrite find nothing to move
+            let transactions_status = || {
+                MIGRATION_PROGRESS
+                    .with(|p| p.borrow().get(&1))
+                    .unwrap()
+                    .status
+            };
+            while transactions_status() == MigrationStatus::Pending {
+                run_migrations();
+            }
+            let transactions = MIGRATION_PROGRESS.with(|p| p.borrow().get(&1)).unwrap();
+            assert_eq!(transactions.status, MigrationStatus::Running);
+            assert_eq!(transactions.migrated, MIGRATION_BATCH_SIZE);
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            assert_eq!(
+                transactions.cursor,
+                Some(
+                    (ledger_principal, MIGRATION_BATCH_SIZE)
+                        .to_bytes()
+                        .into_owned()
+                )
+            );
+
+            // Timers are lost on upgrade; the progress is not
+            MIGRATION_TIMER.with(|timer| timer.borrow_mut().take());
+            register_migrations(false);
+            schedule_migrations();
+            assert!(MIGRATION_TIMER.with(|timer| timer.borrow().is_some()));
+
+            run_migrations();
+            let transactions = MIGRATION_PROGRESS.with(|p| p.borrow().get(&1)).unwrap();
+            assert_eq!(transactions.status, MigrationStatus::Completed);
+            assert_eq!(transactions.migrated, MIGRATION_BATCH_SIZE + 10);
+
+            let mut ticks = 0;
+            while MIGRATION_TIMER.with(|timer| timer.borrow().is_some()) {
+                run_migrations();
+                ticks += 1;
+                // The index build and balance seeding walk the transactions in
+                // two batches as well
+                assert!(ticks <= MIGRATIONS.len() + 2);
+            }
+            let status = get_migration_status().unwrap();
+            assert_eq!(status.len(), MIGRATIONS.len());
+            assert!(status
+                .iter()
+                .all(|progress| progress.status == MigrationStatus::Completed));
+            let tx = TRANSACTIONS.with(|t| t.borrow().get(&(ledger_principal, 1)).unwrap());
+            assert_eq!(tx.index, 1);
+
+            // Registering at a new schema version starts the migration over
+            MIGRATION_PROGRESS.with(|p| {
+                let mut transactions = p.borrow().get(&1).unwrap();
+                transactions.version = LEGACY_SCHEMA_VERSION;
+                p.borrow_mut().insert(1, transactions);
+            });
+            register_migrations(false);
+            let transactions = MIGRATION_PROGRESS.with(|p| p.borrow().get(&1)).unwrap();
+            assert_eq!(transactions.status, MigrationStatus::Pending);
+            assert_eq!(transactions.migrated, 0);
+            assert_eq!(transactions.cursor, None);
+        }
+
+        #[test]
+        fn test_transaction_indexes_built_in_batches_after_upgrade() {
+            populate_transactions(MIGRATION_BATCH_SIZE + 5, None);
+            // Transactions stored before the secondary indexes existed
+            TX_HASH_INDEX.with(|i| i.borrow_mut().clear_new());
+            TX_TIME_INDEX.with(|i| i.borrow_mut().clear_new());
+            SUBACCOUNT_TX_INDEX.with(|i| i.borrow_mut().clear_new());
+
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let last = TRANSACTIONS.with(|t| {
+                t.borrow()
+                    .get(&(ledger_principal, MIGRATION_BATCH_SIZE + 5))
+                    .unwrap()
+            });
+            assert!(get_transaction_by_hash(last.tx_hash.clone()).is_err());
+
+            register_migrations(false);
+            let indexes_done = || {
+                MIGRATION_PROGRESS
+                    .with(|p| p.borrow().get(&13))
+                    .unwrap()
+                    .status
+                    == MigrationStatus::Completed
+            };
+            while !indexes_done() {
+                run_migrations();
+            }
+
+            let progress = MIGRATION_PROGRESS.with(|p| p.borrow().get(&13)).unwrap();
+            assert_eq!(progress.migrated, MIGRATION_BATCH_SIZE + 5);
+            assert_eq!(get_transaction_by_hash(last.tx_hash).unwrap().len(), 1);
+            assert_eq!(
+                TX_TIME_INDEX.with(|i| i.borrow().len()),
+                MIGRATION_BATCH_SIZE + 5
+            );
+            assert!(SUBACCOUNT_TX_INDEX.with(|i| !i.borrow().is_empty()));
+        }
+
+        fn icrc3_account(subaccount: Subaccount) -> Icrc3Value {
+            let owner = *STATIC_PRINCIPAL.lock().unwrap();
+            Icrc3Value::Array(vec![
+                Icrc3Value::Blob(owner.as_slice().to_vec()),
+                Icrc3Value::Blob(subaccount.0.to_vec()),
+            ])
+        }
+
+        fn icrc3_block(
+            id: u64,
+            fields: Vec<(&str, Icrc3Value)>,
+            tx: Vec<(&str, Icrc3Value)>,
+        ) -> Icrc3BlockWithId {
+            let tx = tx.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+            let mut block: Vec<(String, Icrc3Value)> = fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect();
+            block.push((
+                "ts".to_string(),
+                Icrc3Value::Nat(candid::Nat::from(1_000u64)),
+            ));
+            block.push(("tx".to_string(), Icrc3Value::Map(tx)));
+            Icrc3BlockWithId {
+                id: candid::Nat::from(id),
+                block: Icrc3Value::Map(block),
+            }
+        }
+
+        #[test]
+        fn test_icrc3_decodes_mint_burn_approve_and_transfer_from() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (spender_subaccountid, to_subaccountid, _) = setup_principals();
+            let eth = candid::Nat::from(3_000_000_000_000_000_000u128);
+
+            // ICRC-3 mint blocks carry the type in btype and have no tx.op
+            let mint = icrc3_block_to_block(&icrc3_block(
+                1,
+                vec![("btype", Icrc3Value::Text("1mint".to_string()))],
+                vec![
+                    ("to", icrc3_account(nonce_to_subaccount(1))),
+                    ("amt", Icrc3Value::Nat(eth.clone())),
+                ],
+            ));
+            assert_eq!(
+                mint.transaction.operation,
+                Some(Operation::Mint(Mint {
+                    to: to_subaccountid.as_ref().to_vec(),
+                    amount: eth.clone(),
+                }))
+            );
+            assert!(index_block(&TokenType::CKBTC, CKBTC_LEDGER_CANISTER_ID, 1, &mint).is_some());
+
+            // transfer_from keeps its spender and falls back to the block-level fee
+            let transfer_from = icrc3_block_to_block(&icrc3_block(
+                2,
+                vec![
+                    ("btype", Icrc3Value::Text("2xfer".to_string())),
+                    ("fee", Icrc3Value::Nat(candid::Nat::from(10u64))),
+                ],
+                vec![
+                    ("from", icrc3_account(Subaccount([9; 32]))),
+                    ("to", icrc3_account(Subaccount([8; 32]))),
+                    ("spender", icrc3_account(nonce_to_subaccount(0))),
+                    ("amt", Icrc3Value::Nat(candid::Nat::from(500u64))),
+                ],
+            ));
+            match &transfer_from.transaction.operation {
+                Some(Operation::Transfer(transfer)) => {
+                    assert_eq!(
+                        transfer.spender,
+                        Some(spender_subaccountid.as_ref().to_vec())
+                    );
+                    assert_eq!(transfer.fee, candid::Nat::from(10u64));
+                }
+                other => panic!("Expected a transfer, got {:?}", other),
+            }
+            assert!(index_block(
+                &TokenType::CKBTC,
+                CKBTC_LEDGER_CANISTER_ID,
+                2,
+                &transfer_from
+            )
+            .is_some());
+
+            let burn = icrc3_block_to_block(&icrc3_block(
+                3,
+                vec![],
+                vec![
+                    ("op", Icrc3Value::Text("burn".to_string())),
+                    ("from", icrc3_account(Subaccount([9; 32]))),
+                    ("amt", Icrc3Value::Nat(candid::Nat::from(700u64))),
+                ],
+            ));
+            assert!(matches!(
+                burn.transaction.operation,
+                Some(Operation::Burn(ref b)) if b.amount == 700u64
+            ));
+
+            let approve = icrc3_block_to_block(&icrc3_block(
+                4,
+                vec![],
+                vec![
+                    ("op", Icrc3Value::Text("approve".to_string())),
+                    ("from", icrc3_account(Subaccount([9; 32]))),
+                    ("spender", icrc3_account(nonce_to_subaccount(0))),
+                    ("amt", Icrc3Value::Nat(candid::Nat::from(900u64))),
+                    ("fee", Icrc3Value::Nat(candid::Nat::from(10u64))),
+                    ("expires_at", Icrc3Value::Nat(candid::Nat::from(5_000u64))),
+                ],
+            ));
+            match &approve.transaction.operation {
+                Some(Operation::Approve(data)) => {
+                    assert_eq!(data.allowance, candid::Nat::from(900u64));
+                    assert_eq!(data.spender, spender_subaccountid.as_ref().to_vec());
+                    assert_eq!(data.expires_at, Some(Timestamp::from_nanos(5_000)));
+                }
+                other => panic!("Expected an approve, got {:?}", other),
+            }
+            assert!(
+                index_block(&TokenType::CKBTC, CKBTC_LEDGER_CANISTER_ID, 4, &approve).is_some()
+            );
+
+            // The mint deposit can be swept like a transfer
+            let stored = TRANSACTIONS
+                .with(|t| t.borrow().get(&(CKBTC_LEDGER_CANISTER_ID, 1)))
+                .unwrap();
+            let (args, ledger) = to_icrc1_sweep_args(&stored).unwrap();
+            assert_eq!(ledger, CKBTC_LEDGER_CANISTER_ID);
+            assert_eq!(args.amount, eth - candid::Nat::from(10u64));
+
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[test]
+        fn test_icrc3_preserves_sender_subaccount_and_memo() {
+            let memo_blob = b"invoice-42".to_vec();
+            let block = icrc3_block_to_block(&icrc3_block(
+                5,
+                vec![],
+                vec![
+                    ("op", Icrc3Value::Text("xfer".to_string())),
+                    ("from", icrc3_account(Subaccount([9; 32]))),
+                    ("to", icrc3_account(nonce_to_subaccount(1))),
+                    ("amt", Icrc3Value::Nat(candid::Nat::from(500u64))),
+                    ("memo", Icrc3Value::Blob(memo_blob.clone())),
+                    (
+                        "created_at_time",
+                        Icrc3Value::Nat(candid::Nat::from(900u64)),
+                    ),
+                ],
+            ));
+
+            let sender = to_subaccount_id(Subaccount([9; 32]));
+            match &block.transaction.operation {
+                Some(Operation::Transfer(transfer)) => {
+                    assert_eq!(transfer.from, sender.as_ref().to_vec());
+                }
+                other => panic!("Expected a transfer, got {:?}", other),
+            }
+            assert_eq!(block.transaction.icrc1_memo, Some(memo_blob.clone()));
+            // created_at_time comes from the transaction, ts stays on the block
+            assert_eq!(block.transaction.created_at_time.timestamp_nanos, 900);
+            assert_eq!(block.timestamp.timestamp_nanos, 1_000);
+
+            let stored = StoredTransactions::new(
+                5,
+                block.transaction.clone(),
+                "hash".to_string(),
+                TokenType::CKBTC,
+                CKBTC_LEDGER_CANISTER_ID,
+            );
+            assert_eq!(stored.icrc1_memo, Some(memo_blob));
+            assert_eq!(stored.created_at_time.timestamp_nanos, 900);
+
+            // The memo blob is part of the transaction hash
+            let mut without_memo = block.transaction.clone();
+            without_memo.icrc1_memo = None;
+            assert_ne!(
+                hash_transaction(&block.transaction).unwrap(),
+                hash_transaction(&without_memo).unwrap()
+            );
+        }
+
+        #[test]
+        fn test_icrc3_hash_value_matches_standard_examples() {
+            let hash = |value: Icrc3Value| icrc3::hash_value(&value).to_hex();
+
+            // Examples from the ICRC-3 standard
+            assert_eq!(
+                hash(Icrc3Value::Nat(candid::Nat::from(42u64))),
+                "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"
+            );
+            assert_eq!(
+                hash(Icrc3Value::Int(candid::Int::from(-42))),
+                "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc"
+            );
+            assert_eq!(
+                hash(Icrc3Value::Text("Hello, World!".to_string())),
+                "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+            );
+            assert_eq!(
+                hash(Icrc3Value::Blob(vec![1, 2, 3, 4])),
+                "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a"
+            );
+            assert_eq!(
+                hash(Icrc3Value::Array(vec![
+                    Icrc3Value::Nat(candid::Nat::from(3u64)),
+                    Icrc3Value::Text("foo".to_string()),
+                    Icrc3Value::Blob(vec![5, 6]),
+                ])),
+                "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6"
+            );
+
+            let from = vec![
+                0x00, 0xab, 0xcd, 0xef, 0x00, 0x12, 0x34, 0x00, 0x56, 0x78, 0x9a, 0x00, 0xbc, 0xde,
+                0xf0, 0x00, 0x01, 0x23, 0x45, 0x67, 0x89, 0x00, 0xab, 0xcd, 0xef, 0x01,
+            ];
+            let mut to = from.clone();
+            to[2] = 0x0d;
+            let map = |entries: Vec<(&str, Icrc3Value)>| {
+                Icrc3Value::Map(
+                    entries
+                        .into_iter()
+                        .map(|(k, v)| (k.to_string(), v))
+                        .collect(),
+                )
+            };
+            let entries = vec![
+                ("from", Icrc3Value::Blob(from)),
+                ("to", Icrc3Value::Blob(to)),
+                ("amount", Icrc3Value::Nat(candid::Nat::from(42u64))),
+                (
+                    "created_at",
+                    Icrc3Value::Nat(candid::Nat::from(1699218263u64)),
+                ),
+                ("memo", Icrc3Value::Nat(candid::Nat::from(0u64))),
+            ];
+            let expected = "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75";
+            assert_eq!(hash(map(entries.clone())), expected);
+
+            // Map hashing does not depend on the order of the entries
+            let mut reversed = entries;
+            reversed.reverse();
+            assert_eq!(hash(map(reversed)), expected);
+        }
+
+        #[test]
+        fn test_icrc_transactions_are_stored_under_icrc3_hash() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            setup_principals();
+            let tx = vec![
+                ("op", Icrc3Value::Text("xfer".to_string())),
+                ("from", icrc3_account(Subaccount([9; 32]))),
+                ("to", icrc3_account(nonce_to_subaccount(1))),
+                ("amt", Icrc3Value::Nat(candid::Nat::from(500u64))),
+            ];
+            let expected = icrc3::hash_value(&Icrc3Value::Map(
+                tx.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+            ))
+            .to_hex();
+
+            let icrc3 = icrc3_block(7, vec![], tx);
+            let block = icrc3_block_to_block(&icrc3);
+            assert_eq!(block.tx_hash, Some(expected.clone()));
+            // The whole block is hashed for the next block's phash
+            assert_eq!(
+                block.block_hash,
+                Some(icrc3::hash_value(&icrc3.block).as_slice().to_vec())
+            );
+
+            let hash = index_block(&TokenType::CKUSDC, CKUSDC_LEDGER_CANISTER_ID, 7, &block);
+            assert_eq!(hash, Some(expected.clone()));
+            let stored = TRANSACTIONS
+                .with(|t| t.borrow().get(&(CKUSDC_LEDGER_CANISTER_ID, 7)))
+                .unwrap();
+            assert_eq!(stored.tx_hash, expected);
+
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_icrc_accounts_are_stored_and_used_for_refunds() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+            let sender = Principal::from_text("xevnm-gaaaa-aaaar-qafnq-cai").unwrap();
+            let block = icrc3_block_to_block(&icrc3_block(
+                6,
+                vec![],
+                vec![
+                    ("op", Icrc3Value::Text("xfer".to_string())),
+                    (
+                        "from",
+                        Icrc3Value::Array(vec![
+                            Icrc3Value::Blob(sender.as_slice().to_vec()),
+                            Icrc3Value::Blob(vec![4; 32]),
+                        ]),
+                    ),
+                    ("to", icrc3_account(nonce_to_subaccount(1))),
+                    ("amt", Icrc3Value::Nat(candid::Nat::from(500u64))),
+                ],
+            ));
+            assert!(index_block(&TokenType::CKBTC, CKBTC_LEDGER_CANISTER_ID, 6, &block).is_some());
+
+            let stored = TRANSACTIONS
+                .with(|t| t.borrow().get(&(CKBTC_LEDGER_CANISTER_ID, 6)))
+                .unwrap();
+            assert_eq!(
+                stored.from_account,
+                Some(IcrcAccount::new(sender, Some([4; 32])))
+            );
+            let canister_id = *STATIC_PRINCIPAL.lock().unwrap();
+            assert_eq!(
+                stored.to_account,
+                Some(IcrcAccount::new(
+                    canister_id,
+                    Some(nonce_to_subaccount(1).0)
+                ))
+            );
+            assert_eq!(stored.spender_account, None);
+            // The AccountIdentifier form is still kept on the operation
+            match &stored.operation {
+                Some(Operation::Transfer(transfer)) => {
+                    assert_eq!(transfer.to, to_subaccountid.as_ref().to_vec())
+                }
+                other => panic!("Expected a transfer, got {:?}", other),
+            }
+
+            // Refunds go back to the sender's ICRC account
+            let (args, _) = to_icrc1_refund_args(&stored).unwrap();
+            assert_eq!(args.to.owner, sender);
+            assert_eq!(args.to.subaccount, Some([4; 32]));
+            assert_eq!(args.from_subaccount, Some(nonce_to_subaccount(1).0));
+            assert_eq!(args.amount, 490u64);
+
+            let result = refund(6, Some(TokenType::CKBTC)).await;
+            assert!(result.is_ok(), "ICRC refund should succeed: {:?}", result);
+
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        fn mock_transfer_block(index: u64, to: &AccountIdentifier) -> Block {
+            let from = to_subaccount_id(Subaccount([9; 32]));
+            Block {
+                transaction: Transaction {
+                    memo: index,
+                    icrc1_memo: None,
+                    operation: Some(Operation::Transfer(Transfer {
+                        to: to.as_ref().to_vec(),
+                        fee: candid::Nat::from(10_000u64),
+                        from: from.as_ref().to_vec(),
+                        amount: candid::Nat::from(1_000_000u64),
+                        spender: None,
+                    })),
+                    created_at_time: Timestamp {
+                        timestamp_nanos: index,
+                    },
+                    from_account: None,
+                    to_account: None,
+                    spender_account: None,
+                },
+                timestamp: Timestamp {
+                    timestamp_nanos: index,
+                },
+                parent_hash: None,
+                tx_hash: None,
+                block_hash: None,
+            }
+        }
+
+        #[tokio::test]
+        async fn test_query_token_ledger_fetches_archived_blocks() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+            let outsider = to_subaccount_id(Subaccount([7; 32]));
+
+            // Deposits at block 1 (archived) and block 4 (still on the ledger)
+            let chain: Vec<Block> = (0..6)
+                .map(|i| {
+                    if i == 1 || i == 4 {
+                        mock_transfer_block(i, &to_subaccountid)
+                    } else {
+                        mock_transfer_block(i, &outsider)
+                    }
+                })
+                .collect();
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+            MOCK_LEDGER_FIRST_INDEX.with(|f| *f.borrow_mut() = 3);
+
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let next_block = query_token_ledger(TokenType::ICP, ledger_principal, 0).await;
+            assert_eq!(
+                next_block, 6,
+                "Should advance past archived and ledger blocks"
+            );
+
+            TRANSACTIONS.with(|t| {
+                let transactions = t.borrow();
+                assert_eq!(transactions.len(), 2);
+                for index in [1, 4] {
+                    let tx = transactions
+                        .get(&(ledger_principal, index))
+                        .expect("Deposit should be stored under its ledger block index");
+                    assert_eq!(tx.index, index);
+                    assert_eq!(tx.memo, index);
+                }
+            });
+
+            // A lagging start inside the ledger range is numbered from first_block_index
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let next_block = query_token_ledger(TokenType::ICP, ledger_principal, 4).await;
+            assert_eq!(next_block, 6);
+            assert!(TRANSACTIONS.with(|t| t.borrow().contains_key(&(ledger_principal, 4))));
+
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            MOCK_LEDGER_FIRST_INDEX.with(|f| *f.borrow_mut() = 0);
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_parent_hash_mismatch_halts_indexing() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+
+            // Block i hashes to [i; 32] and points at [i - 1; 32], except block 3
+            let chain: Vec<Block> = (0..5u8)
+                .map(|i| {
+                    let mut block = mock_transfer_block(i as u64, &to_subaccountid);
+                    block.block_hash = Some(vec![i; 32]);
+                    block.parent_hash = match i {
+                        0 => None,
+                        3 => Some(vec![42; 32]),
+                        _ => Some(vec![i - 1; 32]),
+                    };
+                    block
+                })
+                .collect();
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let next_block = query_token_ledger(TokenType::ICP, ledger_principal, 0).await;
+            assert_eq!(
+                next_block, 3,
+                "Should stop before the block that breaks the chain"
+            );
+            assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 3);
+
+            let alerts = get_chain_alerts().unwrap();
+            assert_eq!(alerts.len(), 1);
+            assert_eq!(alerts[0].token_type, TokenType::ICP);
+            assert_eq!(alerts[0].block_index, 3);
+            assert_eq!(alerts[0].expected_parent_hash, hex::encode([2u8; 32]));
+            assert_eq!(alerts[0].found_parent_hash, Some(hex::encode([42u8; 32])));
+
+            // Halted tokens are not queried until the alert is cleared
+            let next_block = query_token_ledger(TokenType::ICP, ledger_principal, 3).await;
+            assert_eq!(next_block, 3);
+
+            assert!(clear_chain_alert(TokenType::ICP).is_ok());
+            assert!(clear_chain_alert(TokenType::ICP).is_err());
+            assert!(get_chain_alerts().unwrap().is_empty());
+
+            // Clearing re-anchors the chain on the next block
+            let next_block = query_token_ledger(TokenType::ICP, ledger_principal, 3).await;
+            assert_eq!(next_block, 5);
+            let last = TOKEN_LAST_BLOCK_HASH.with(|h| h.borrow().get(&ledger_principal));
+            assert_eq!(
+                last,
+                Some(BlockHashRecord {
+                    block_index: 4,
+                    hash: vec![4; 32]
+                })
+            );
+
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TOKEN_LAST_BLOCK_HASH.with(|h| h.borrow_mut().remove(&ledger_principal));
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_icp_chain_alert_from_encoded_hashes_halts_every_path() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let encoded: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 8]).collect();
+            let encoded_hash = |i: usize| Sha256::digest(&encoded[i]).to_vec();
+
+            let mut chain: Vec<Block> = (0..3)
+                .map(|i| mock_transfer_block(i, &to_subaccountid))
+                .collect();
+            attach_encoded_block_hashes(&mut chain, 1, 0, &encoded);
+            assert!(
+                chain.iter().all(|block| block.block_hash.is_none()),
+                "Encodings of another range are not used"
+            );
+            attach_encoded_block_hashes(&mut chain, 0, 0, &encoded);
+            assert_eq!(chain[2].block_hash, Some(encoded_hash(2)));
+
+            // Block 2 points at block 0 instead of block 1
+            chain[1].parent_hash = Some(encoded_hash(0));
+            chain[2].parent_hash = Some(encoded_hash(0));
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+
+            let next_block = query_token_ledger(TokenType::ICP, ledger_principal, 0).await;
+            assert_eq!(next_block, 2);
+            let alerts = get_chain_alerts().unwrap();
+            assert_eq!(alerts.len(), 1);
+            assert_eq!(alerts[0].expected_parent_hash, hex::encode(encoded_hash(1)));
+
+            // Backfills and index canister discovery are halted as well
+            let job_id = start_backfill(TokenType::ICP, 0, 2).unwrap();
+            run_backfill_jobs().await;
+            let job = get_backfill_status(Some(job_id)).unwrap().remove(0);
+            assert_eq!(job.status, BackfillStatus::Running);
+            assert_eq!(job.next_block, 0);
+            assert!(cancel_backfill(job_id).is_ok());
+
+            let index_principal = Principal::from_text("qhbym-qaaaa-aaaaa-aaafq-cai").unwrap();
+            MOCK_INDEX_CALLS.with(|calls| *calls.borrow_mut() = 0);
+            index_token_via_index(&TokenType::ICP, ledger_principal, index_principal).await;
+            assert_eq!(MOCK_INDEX_CALLS.with(|calls| *calls.borrow()), 0);
+
+            assert!(clear_chain_alert(TokenType::ICP).is_ok());
+            BACKFILL_JOBS.with(|jobs| jobs.borrow_mut().remove(&job_id));
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TOKEN_LAST_BLOCK_HASH.with(|h| h.borrow_mut().remove(&ledger_principal));
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_catch_up_batches_are_capped_at_the_ledger_page() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let outsider = to_subaccount_id(Subaccount([7; 32]));
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let chain: Vec<Block> = (0..250)
+                .map(|i| mock_transfer_block(i, &outsider))
+                .collect();
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+            MOCK_LEDGER_PAGE_SIZE.with(|p| *p.borrow_mut() = Some(60));
+            TOKEN_CHAIN_LENGTHS.with(|l| l.borrow_mut().insert(ledger_principal, 250));
+            assert_eq!(catch_up_batch_size(ledger_principal), CATCH_UP_BATCH_SIZE);
+
+            let next_block = query_token_ledger(TokenType::ICP, ledger_principal, 0).await;
+            assert_eq!(next_block, 60);
+            assert_eq!(catch_up_batch_size(ledger_principal), 60);
+
+            // A short batch at the chain tip does not shrink the page
+            MOCK_LEDGER_PAGE_SIZE.with(|p| *p.borrow_mut() = None);
+            let next_block = query_token_ledger(TokenType::ICP, ledger_principal, 200).await;
+            assert_eq!(next_block, 250);
+            assert_eq!(catch_up_batch_size(ledger_principal), 60);
+
+            LEDGER_PAGE_SIZES.with(|s| s.borrow_mut().remove(&ledger_principal));
+            TOKEN_CHAIN_LENGTHS.with(|l| l.borrow_mut().remove(&ledger_principal));
+            TOKEN_LAST_BLOCK_HASH.with(|h| h.borrow_mut().remove(&ledger_principal));
+            TOKEN_TIP_ANCHORS.with(|a| a.borrow_mut().remove(&ledger_principal));
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_index_token_skips_tick_while_in_flight() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+            let chain: Vec<Block> = (0..3)
+                .map(|i| mock_transfer_block(i, &to_subaccountid))
+                .collect();
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+            set_token_next_block(&TokenType::ICP, 0);
+
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let now = CanisterApiManager::time();
+            let guard = TokenGuard::new(ledger_principal, now - 500).unwrap();
+
+            index_token(TokenType::ICP, ledger_principal).await;
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(get_token_next_block(&TokenType::ICP), 0);
+            assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 0);
+
+            let skipped = get_skipped_ticks().unwrap();
+            assert_eq!(skipped.len(), 1);
+            assert_eq!(skipped[0].token_type, TokenType::ICP);
+            assert_eq!(skipped[0].count, 2);
+            assert_eq!(skipped[0].last_skipped_at, now);
+            assert_eq!(skipped[0].last_in_flight_nanos, 500);
+
+            // Once the pass in flight finishes the next tick indexes again
+            drop(guard);
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(get_token_next_block(&TokenType::ICP), 3);
+            assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 3);
+            assert!(
+                TokenGuard::new(ledger_principal, now).is_ok(),
+                "Guard should be released after the pass"
+            );
+
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TOKEN_SKIPPED_TICKS.with(|s| s.borrow_mut().remove(&ledger_principal));
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_index_token_catches_up_within_budget() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            setup_principals();
+            let outsider = to_subaccount_id(Subaccount([7; 32]));
+            let chain: Vec<Block> = (0..350)
+                .map(|i| mock_transfer_block(i, &outsider))
+                .collect();
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+
+            // The first batch reveals the lag, the pass then pages to the tip
+            set_token_next_block(&TokenType::ICP, 0);
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(get_token_next_block(&TokenType::ICP), 350);
+            let lag = get_token_lag().unwrap();
+            assert_eq!(lag.len(), 1);
+            assert_eq!(lag[0].token_type, TokenType::ICP);
+            assert_eq!(lag[0].chain_length, 350);
+            assert_eq!(lag[0].lag, 0);
+            assert!(!lag[0].catching_up);
+
+            // Once the instruction budget is spent the rest waits for the next tick
+            TOKEN_CHAIN_LENGTHS.with(|l| l.borrow_mut().remove(&ledger_principal));
+            set_token_next_block(&TokenType::ICP, 0);
+            MOCK_INSTRUCTIONS.with(|i| *i.borrow_mut() = CATCH_UP_INSTRUCTION_BUDGET);
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(
+                get_token_next_block(&TokenType::ICP),
+                STEADY_STATE_BATCH_SIZE
+            );
+            let lag = get_token_lag().unwrap();
+            assert_eq!(lag[0].lag, 350 - STEADY_STATE_BATCH_SIZE);
+            assert!(lag[0].catching_up);
+
+            // A lagging token asks for full pages
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(get_token_next_block(&TokenType::ICP), 350);
+
+            MOCK_INSTRUCTIONS.with(|i| *i.borrow_mut() = 0);
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TOKEN_CHAIN_LENGTHS.with(|l| l.borrow_mut().remove(&ledger_principal));
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_ledger_reset_is_flagged_and_rewound_on_local() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
             let (_, to_subaccountid, _) = setup_principals();
-            let subaccountid_hex = to_subaccountid.to_hex();
-            let amount = 1.25; // 1.25 ICP
-
-            // Execute
-            let result = sweep_subaccount(subaccountid_hex, amount, Some(TokenType::ICP)).await;
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let chain = |length: u64| -> Vec<Block> {
+                (0..length)
+                    .map(|i| mock_transfer_block(i, &to_subaccountid))
+                    .collect()
+            };
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain(5));
+            set_token_next_block(&TokenType::ICP, 0);
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(get_token_next_block(&TokenType::ICP), 5);
+
+            // Outside local networks the token is only flagged
+            NETWORK.with(|n| *n.borrow_mut() = Network::Mainnet);
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain(2));
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(get_token_next_block(&TokenType::ICP), 5);
+            assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 5);
+            // Endpoints authenticate the caller outside local networks
+            NETWORK.with(|n| *n.borrow_mut() = Network::Local);
+            let resets = get_ledger_resets().unwrap();
+            assert_eq!(resets.len(), 1);
+            assert_eq!(resets[0].token_type, TokenType::ICP);
+            assert_eq!(resets[0].next_block, 5);
+            assert_eq!(resets[0].chain_length, 2);
+            assert!(!resets[0].rewound);
+            let lag = get_token_lag().unwrap();
+            assert!(lag[0].ledger_reset);
+            assert_eq!(lag[0].lag, 0);
+
+            // Setting the next block acknowledges the reset
+            assert!(set_token_next_block_update(TokenType::ICP, 5).await.is_ok());
+            assert!(get_ledger_resets().unwrap().is_empty());
+
+            // Local ledgers are rewound and re-indexed from the first block
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(get_token_next_block(&TokenType::ICP), 0);
+            assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 0);
+            let resets = get_ledger_resets().unwrap();
+            assert_eq!(resets.len(), 1);
+            assert!(resets[0].rewound);
+
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(get_token_next_block(&TokenType::ICP), 2);
+            assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 2);
+
+            assert!(reset_token_blocks().await.is_ok());
+            assert!(get_ledger_resets().unwrap().is_empty());
+
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TOKEN_CHAIN_LENGTHS.with(|l| l.borrow_mut().remove(&ledger_principal));
+            TOKEN_TIP_ANCHORS.with(|a| a.borrow_mut().remove(&ledger_principal));
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
 
-            // Assert
+        #[tokio::test]
+        async fn test_backfill_job_indexes_range_through_archives() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+            let outsider = to_subaccount_id(Subaccount([7; 32]));
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+
+            // Deposits at blocks 2, 5 and 8; blocks below 4 are archived
+            let chain: Vec<Block> = (0..10)
+                .map(|i| {
+                    if [2, 5, 8].contains(&i) {
+                        mock_transfer_block(i, &to_subaccountid)
+                    } else {
+                        mock_transfer_block(i, &outsider)
+                    }
+                })
+                .collect();
+            index_block(&TokenType::ICP, ledger_principal, 2, &chain[2]);
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+            MOCK_LEDGER_FIRST_INDEX.with(|f| *f.borrow_mut() = 4);
+
+            assert!(start_backfill(TokenType::ICP, 6, 5).is_err());
+            let job_id = start_backfill(TokenType::ICP, 1, 6).unwrap();
+            assert!(BACKFILL_TIMER.with(|timer| timer.borrow().is_some()));
+
+            run_backfill_jobs().await;
+            let job = get_backfill_status(Some(job_id)).unwrap().remove(0);
+            assert_eq!(job.status, BackfillStatus::Completed);
+            assert_eq!(job.next_block, 6);
+            assert_eq!(job.indexed, 1, "Only block 5 is new");
+            assert_eq!(job.already_indexed, 1, "Block 2 was already stored");
+            assert_eq!(job.last_error, None);
+            TRANSACTIONS.with(|t| {
+                let transactions = t.borrow();
+                assert_eq!(transactions.len(), 2);
+                assert!(transactions.contains_key(&(ledger_principal, 5)));
+                assert!(!transactions.contains_key(&(ledger_principal, 8)));
+            });
             assert!(
-                result.is_ok(),
-                "Sweeping subaccount with decimal amount should succeed"
+                BACKFILL_TIMER.with(|timer| timer.borrow().is_none()),
+                "The timer stops once no job is running"
             );
-            assert_eq!(result.unwrap(), 1, "BlockIndex should be 1");
+
+            // Cancelled jobs are not advanced
+            let cancelled_id = start_backfill(TokenType::ICP, 7, 9).unwrap();
+            let cancelled = cancel_backfill(cancelled_id).unwrap();
+            assert_eq!(cancelled.status, BackfillStatus::Cancelled);
+            assert!(cancel_backfill(cancelled_id).is_err());
+            run_backfill_jobs().await;
+            assert!(!TRANSACTIONS.with(|t| t.borrow().contains_key(&(ledger_principal, 8))));
+
+            // Ranges past the ledger tip fail instead of waiting forever
+            let failed_id = start_backfill(TokenType::ICP, 12, 20).unwrap();
+            run_backfill_jobs().await;
+            let failed = get_backfill_status(Some(failed_id)).unwrap().remove(0);
+            assert_eq!(failed.status, BackfillStatus::Failed);
+            assert!(failed.last_error.is_some());
+
+            assert_eq!(get_backfill_status(None).unwrap().len(), 3);
+            assert!(get_backfill_status(Some(42)).is_err());
+
+            BACKFILL_JOBS.with(|jobs| {
+                for id in [job_id, cancelled_id, failed_id] {
+                    jobs.borrow_mut().remove(&id);
+                }
+            });
+            MOCK_LEDGER_FIRST_INDEX.with(|f| *f.borrow_mut() = 0);
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
         }
 
-        // New tests for multi-token support
+        #[tokio::test]
+        async fn test_backfill_checks_parent_hashes_of_registered_tokens() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+
+            let unregistered = Principal::from_text("qhbym-qaaaa-aaaaa-aaafq-cai").unwrap();
+            assert!(start_backfill(TokenType::Custom(unregistered), 0, 5).is_err());
+
+            // Block i hashes to [i; 32] and points at [i - 1; 32], except block 3
+            let chain: Vec<Block> = (0..6u8)
+                .map(|i| {
+                    let mut block = mock_transfer_block(i as u64, &to_subaccountid);
+                    block.block_hash = Some(vec![i; 32]);
+                    block.parent_hash = match i {
+                        0 => None,
+                        3 => Some(vec![42; 32]),
+                        _ => Some(vec![i - 1; 32]),
+                    };
+                    block
+                })
+                .collect();
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+
+            let job_id = start_backfill(TokenType::ICP, 0, 5).unwrap();
+            run_backfill_jobs().await;
+            let job = get_backfill_status(Some(job_id)).unwrap().remove(0);
+            assert_eq!(job.status, BackfillStatus::Running);
+            assert_eq!(
+                job.next_block, 3,
+                "Stops before the block that breaks the chain"
+            );
+            assert_eq!(job.indexed, 3);
+            assert!(job.last_error.is_some());
+            assert_eq!(get_chain_alerts().unwrap()[0].block_index, 3);
+            assert!(
+                TOKEN_LAST_BLOCK_HASH
+                    .with(|h| h.borrow().get(&ledger_principal))
+                    .is_none(),
+                "The ledger tip anchor is left alone"
+            );
 
-        #[test]
-        fn test_get_registered_tokens() {
-            // Initially should be empty since no tokens are registered by default in tests
-            let result = get_registered_tokens();
-            assert!(result.is_ok(), "Getting registered tokens should succeed");
+            // Once cleared the job re-anchors on the block it stopped at
+            assert!(clear_chain_alert(TokenType::ICP).is_ok());
+            run_backfill_jobs().await;
+            let job = get_backfill_status(Some(job_id)).unwrap().remove(0);
+            assert_eq!(job.status, BackfillStatus::Completed);
+            assert_eq!(job.indexed, 6);
+            assert_eq!(job.last_error, None);
 
-            let tokens = result.unwrap();
-            // In test environment, tokens are not automatically registered
-            assert_eq!(tokens.len(), 0, "Should have 0 token types initially");
+            BACKFILL_JOBS.with(|jobs| jobs.borrow_mut().remove(&job_id));
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
         }
 
-        #[test]
-        fn test_get_transaction_token_type() {
-            // Setup a transaction with specific token type
-            populate_transactions(1, None);
-
-            // Get the transaction hash from populated transactions
-            let tx_hash = TRANSACTIONS.with(|t| {
-                let transactions = t.borrow();
-                transactions
-                    .get(&1)
-                    .map(|tx| tx.tx_hash.clone())
-                    .unwrap_or_else(|| "HASH-IS-NOT-AVAILABLE".to_string())
+        #[tokio::test]
+        async fn test_index_canister_discovery_keeps_subaccount_cursors() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+            let outsider = to_subaccount_id(Subaccount([7; 32]));
+            let previous_nonce = nonce();
+            LAST_SUBACCOUNT_NONCE
+                .with(|n| n.borrow_mut().set(3))
+                .unwrap();
+
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let index_principal = Principal::from_text("qhbym-qaaaa-aaaaa-aaafq-cai").unwrap();
+            let deposits = [3, 7, 9];
+            let chain: Vec<Block> = (0..10)
+                .map(|i| {
+                    if deposits.contains(&i) {
+                        mock_transfer_block(i, &to_subaccountid)
+                    } else {
+                        mock_transfer_block(i, &outsider)
+                    }
+                })
+                .collect();
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+            MOCK_INDEX_TRANSACTIONS.with(|index| {
+                index
+                    .borrow_mut()
+                    .insert(nonce_to_subaccount(1).0, vec![3, 7])
             });
 
-            // Get the transaction token type
-            let result = get_transaction_token_type(tx_hash);
             assert!(
-                result.is_ok(),
-                "Getting transaction token type should succeed"
+                set_token_index_canister(TokenType::ICP, Some("not a principal".to_string()))
+                    .is_err()
+            );
+            assert!(
+                set_token_index_canister(TokenType::ICP, Some(index_principal.to_text())).is_ok()
             );
             assert_eq!(
-                result.unwrap(),
-                TokenType::ICP,
-                "Should return ICP token type"
+                get_token_index_canisters().unwrap(),
+                vec![(TokenType::ICP, index_principal)]
             );
-        }
 
-        #[test]
-        fn test_get_transaction_token_type_not_found() {
-            let result = get_transaction_token_type("non-existent-hash".to_string());
-            assert!(result.is_err(), "Should fail for non-existent transaction");
+            set_token_next_block(&TokenType::ICP, 0);
+            index_token(TokenType::ICP, ledger_principal).await;
+            TRANSACTIONS.with(|t| {
+                let transactions = t.borrow();
+                assert_eq!(transactions.len(), 2);
+                assert!(transactions.contains_key(&(ledger_principal, 3)));
+                assert!(transactions.contains_key(&(ledger_principal, 7)));
+            });
             assert_eq!(
-                result.unwrap_err(),
-                "Transaction not found",
-                "Should return correct error message"
+                get_token_next_block(&TokenType::ICP),
+                0,
+                "The ledger is not scanned"
+            );
+            assert_eq!(
+                SUBACCOUNT_INDEX_CURSORS.with(|c| c.borrow().get(&(ledger_principal, 1))),
+                Some(7)
             );
-        }
 
-        #[test]
-        fn test_get_token_next_block_query() {
-            // This function returns the next block for a specific token
-            let result = get_token_next_block_query(TokenType::CKUSDC);
-            assert!(result.is_ok(), "Getting token next block should succeed");
-            // Should return 1 by default
-            assert_eq!(result.unwrap(), 1, "Should return default block 1");
-        }
+            // Only transactions above the cursor are fetched on the next pass
+            MOCK_INDEX_TRANSACTIONS.with(|index| {
+                index
+                    .borrow_mut()
+                    .insert(nonce_to_subaccount(1).0, deposits.to_vec())
+            });
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            index_token(TokenType::ICP, ledger_principal).await;
+            TRANSACTIONS.with(|t| {
+                let transactions = t.borrow();
+                assert_eq!(transactions.len(), 1);
+                assert!(transactions.contains_key(&(ledger_principal, 9)));
+            });
 
-        #[test]
-        fn test_get_all_token_blocks() {
-            let result = get_all_token_blocks();
-            assert!(result.is_ok(), "Getting all token blocks should succeed");
+            assert!(set_token_index_canister(TokenType::ICP, None).is_ok());
+            assert!(get_token_index_canisters().unwrap().is_empty());
 
-            let blocks = result.unwrap();
-            // In production, this returns all three token types with default block 1
-            // The actual behavior depends on the implementation
-            assert!(blocks.len() <= 4, "Should have at most 4 token blocks");
-            // All blocks should default to 1
-            for (_, block) in blocks {
-                assert_eq!(block, 1, "All blocks should default to 1");
-            }
+            LAST_SUBACCOUNT_NONCE
+                .with(|n| n.borrow_mut().set(previous_nonce))
+                .unwrap();
+            MOCK_INDEX_TRANSACTIONS.with(|index| index.borrow_mut().clear());
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            SUBACCOUNT_INDEX_CURSORS.with(|c| c.borrow_mut().remove(&(ledger_principal, 1)));
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
         }
 
-        #[test]
-        fn test_get_network() {
-            // The network is set during initialization, let's test it
-            let result = get_network();
-            assert!(result.is_ok(), "Getting network should succeed");
-            // Network could be either Local or Mainnet depending on initialization
+        #[tokio::test]
+        async fn test_index_canister_discovery_detects_ledger_reset() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+            let previous_nonce = nonce();
+            LAST_SUBACCOUNT_NONCE
+                .with(|n| n.borrow_mut().set(3))
+                .unwrap();
+
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let index_principal = Principal::from_text("qhbym-qaaaa-aaaaa-aaafq-cai").unwrap();
+            let subaccount = nonce_to_subaccount(1).0;
+            let cursor =
+                || SUBACCOUNT_INDEX_CURSORS.with(|c| c.borrow().get(&(ledger_principal, 1)));
+            let chain = |length: u64| -> Vec<Block> {
+                (0..length)
+                    .map(|i| mock_transfer_block(i, &to_subaccountid))
+                    .collect()
+            };
+            assert!(
+                set_token_index_canister(TokenType::ICP, Some(index_principal.to_text())).is_ok()
+            );
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain(10));
+            MOCK_INDEX_TRANSACTIONS.with(|index| index.borrow_mut().insert(subaccount, vec![3, 7]));
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert_eq!(cursor(), Some(7));
+
+            // Outside local networks the token is only flagged
+            NETWORK.with(|n| *n.borrow_mut() = Network::Mainnet);
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain(2));
+            MOCK_INDEX_TRANSACTIONS.with(|index| index.borrow_mut().insert(subaccount, vec![1]));
+            index_token(TokenType::ICP, ledger_principal).await;
+            NETWORK.with(|n| *n.borrow_mut() = Network::Local);
+            assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 2);
+            let resets = get_ledger_resets().unwrap();
+            assert_eq!(resets.len(), 1);
+            assert_eq!(resets[0].next_block, 8);
+            assert_eq!(resets[0].chain_length, 2);
+            assert!(!resets[0].rewound);
+
+            // Setting the next block acknowledges the reset and drops the cursors past it
+            assert!(set_token_next_block_update(TokenType::ICP, 0).await.is_ok());
+            assert!(get_ledger_resets().unwrap().is_empty());
+            assert_eq!(cursor(), None);
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert!(TRANSACTIONS.with(|t| t.borrow().contains_key(&(ledger_principal, 1))));
+            assert_eq!(cursor(), Some(1));
+
+            // Local ledgers are rewound, dropping the transactions and cursors of the old chain
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain(1));
+            index_token(TokenType::ICP, ledger_principal).await;
+            assert!(get_ledger_resets().unwrap()[0].rewound);
+            assert_eq!(TRANSACTIONS.with(|t| t.borrow().len()), 0);
+            assert_eq!(cursor(), None);
+
+            assert!(set_token_index_canister(TokenType::ICP, None).is_ok());
+            LAST_SUBACCOUNT_NONCE
+                .with(|n| n.borrow_mut().set(previous_nonce))
+                .unwrap();
+            TOKEN_LEDGER_RESETS.with(|r| r.borrow_mut().remove(&ledger_principal));
+            TOKEN_CHAIN_LENGTHS.with(|l| l.borrow_mut().remove(&ledger_principal));
+            MOCK_INDEX_TRANSACTIONS.with(|index| index.borrow_mut().clear());
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
         }
 
-        #[test]
-        fn test_get_next_block() {
-            // Test getting the next block
-            let result = get_next_block();
-            assert!(result.is_ok(), "Getting next block should succeed");
-            // Should have some default value
-            assert!(result.unwrap() >= 0, "Should return a valid block number");
+        #[tokio::test]
+        async fn test_new_transaction_ids_pages_down_to_cursor() {
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let subaccount = nonce_to_subaccount(5);
+            MOCK_INDEX_TRANSACTIONS
+                .with(|index| index.borrow_mut().insert(subaccount.0, (0..250).collect()));
+            MOCK_INDEX_CALLS.with(|calls| *calls.borrow_mut() = 0);
+
+            let ids = new_transaction_ids(ledger_principal, ledger_principal, subaccount, None)
+                .await
+                .unwrap();
+            assert_eq!(ids, (0..250).collect::<Vec<u64>>());
+            assert_eq!(MOCK_INDEX_CALLS.with(|calls| *calls.borrow()), 3);
+
+            // A cursor stops paging at the last indexed transaction
+            MOCK_INDEX_CALLS.with(|calls| *calls.borrow_mut() = 0);
+            let ids =
+                new_transaction_ids(ledger_principal, ledger_principal, subaccount, Some(239))
+                    .await
+                    .unwrap();
+            assert_eq!(ids, (240..250).collect::<Vec<u64>>());
+            assert_eq!(MOCK_INDEX_CALLS.with(|calls| *calls.borrow()), 1);
+
+            MOCK_INDEX_TRANSACTIONS.with(|index| index.borrow_mut().clear());
         }
 
-        #[test]
-        fn test_get_canister_principal() {
-            let result = get_canister_principal();
-            assert!(result.is_ok(), "Getting canister principal should succeed");
-            // Should return the principal as a string
-            let principal_str = result.unwrap();
-            assert!(
-                !principal_str.is_empty(),
-                "Principal string should not be empty"
+        #[tokio::test]
+        async fn test_index_canister_discovery_caps_subaccounts_per_tick() {
+            setup_principals();
+            let previous_nonce = nonce();
+            let subaccount_count = INDEX_SUBACCOUNTS_PER_TICK + 20;
+            LAST_SUBACCOUNT_NONCE
+                .with(|n| n.borrow_mut().set(subaccount_count))
+                .unwrap();
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+            let index_principal = Principal::from_text("qhbym-qaaaa-aaaaa-aaafq-cai").unwrap();
+            let resume_nonce =
+                || INDEX_RESUME_NONCES.with(|r| r.borrow().get(&ledger_principal).copied());
+
+            // Subaccounts without transactions take one index call each
+            MOCK_INDEX_CALLS.with(|calls| *calls.borrow_mut() = 0);
+            index_token_via_index(&TokenType::ICP, ledger_principal, index_principal).await;
+            assert_eq!(
+                MOCK_INDEX_CALLS.with(|calls| *calls.borrow()),
+                INDEX_SUBACCOUNTS_PER_TICK as u64
             );
-            // Verify it's a valid principal format
-            assert_eq!(principal_str, STATIC_PRINCIPAL.lock().unwrap().to_text());
+            assert_eq!(resume_nonce(), Some(INDEX_SUBACCOUNTS_PER_TICK));
+
+            // The next tick picks up the remaining subaccounts
+            MOCK_INDEX_CALLS.with(|calls| *calls.borrow_mut() = 0);
+            index_token_via_index(&TokenType::ICP, ledger_principal, index_principal).await;
+            assert_eq!(MOCK_INDEX_CALLS.with(|calls| *calls.borrow()), 20);
+            assert_eq!(resume_nonce(), None);
+
+            LAST_SUBACCOUNT_NONCE
+                .with(|n| n.borrow_mut().set(previous_nonce))
+                .unwrap();
+            TOKEN_CHAIN_LENGTHS.with(|l| l.borrow_mut().remove(&ledger_principal));
         }
 
         #[test]
-        fn test_get_transactions_count() {
-            // Clear transactions first
-            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        fn test_per_token_intervals_are_persisted_and_restored() {
+            setup_principals();
+            let icp_ledger = *STATIC_PRINCIPAL.lock().unwrap();
+            let ckbtc = preset_token_config(&TokenType::CKBTC).unwrap();
+            TOKEN_REGISTRY.with(|r| {
+                r.borrow_mut()
+                    .insert(ckbtc.ledger_canister_id, ckbtc.clone())
+            });
+            let _ = set_interval(5);
 
-            // Initially should be 0
-            let result = get_transactions_count();
-            assert!(result.is_ok(), "Getting transactions count should succeed");
-            assert_eq!(result.unwrap(), 0, "Should have 0 transactions initially");
+            assert_eq!(set_token_interval(TokenType::ICP, Some(2)).unwrap(), 2);
+            assert_eq!(set_token_interval(TokenType::CKBTC, Some(60)).unwrap(), 60);
+            assert!(set_token_interval(TokenType::CKBTC, Some(0)).is_err());
+            assert!(
+                set_token_interval(TokenType::CKUSDT, Some(10)).is_err(),
+                "Unregistered tokens are not polled"
+            );
 
-            // Add some transactions
-            populate_transactions(5, None);
+            let intervals = get_token_intervals().unwrap();
+            assert!(intervals.contains(&(TokenType::ICP, 2)));
+            assert!(intervals.contains(&(TokenType::CKBTC, 60)));
+            assert_eq!(
+                TOKEN_INTERVALS.with(|i| i.borrow().get(&icp_ledger)),
+                Some(2)
+            );
+            assert!(has_own_interval(icp_ledger));
+
+            // Timers are lost on upgrade and rebuilt from stable memory
+            TOKEN_LEDGER_TIMERS.with(|t| t.borrow_mut().clear());
+            restore_timers();
+            TOKEN_LEDGER_TIMERS.with(|t| {
+                let timers = t.borrow();
+                assert_eq!(timers.len(), 2);
+                assert!(timers.contains_key(&TokenType::ICP));
+                assert!(timers.contains_key(&TokenType::CKBTC));
+            });
 
-            let result = get_transactions_count();
-            assert!(result.is_ok(), "Getting transactions count should succeed");
-            assert_eq!(result.unwrap(), 5, "Should have 5 transactions");
+            // Clearing an interval puts the token back on the shared timer
+            assert_eq!(set_token_interval(TokenType::CKBTC, None).unwrap(), 5);
+            assert!(!TOKEN_LEDGER_TIMERS.with(|t| t.borrow().contains_key(&TokenType::CKBTC)));
+            assert!(get_token_intervals()
+                .unwrap()
+                .contains(&(TokenType::CKBTC, 5)));
+
+            let _ = set_token_interval(TokenType::ICP, None);
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().remove(&ckbtc.ledger_canister_id));
         }
 
-        #[test]
-        fn test_get_oldest_block() {
-            // Clear transactions first
+        #[tokio::test]
+        async fn test_batches_are_anchored_to_indexed_tip() {
             TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
+
+            let chain: Vec<Block> = (0..5u8)
+                .map(|i| {
+                    let mut block = mock_transfer_block(i as u64, &to_subaccountid);
+                    block.block_hash = Some(vec![i; 32]);
+                    block.parent_hash = i.checked_sub(1).map(|p| vec![p; 32]);
+                    block
+                })
+                .collect();
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+            MOCK_LEDGER_PAGE_SIZE.with(|p| *p.borrow_mut() = Some(3));
 
-            // Without transactions
-            let result = get_oldest_block();
-            assert!(result.is_ok(), "Getting oldest block should succeed");
             assert_eq!(
-                result.unwrap(),
-                None,
-                "Should return None when no transactions"
+                query_token_ledger(TokenType::ICP, ledger_principal, 0).await,
+                3
             );
+            let anchors = get_tip_anchors().unwrap();
+            assert_eq!(anchors.len(), 1);
+            assert_eq!(anchors[0].start_block, 0);
+            assert_eq!(anchors[0].next_block, 3);
+            assert_eq!(anchors[0].last_block_hash, Some(hex::encode([2u8; 32])));
 
-            // Add transactions starting from block 10
+            // The next batch replaces the anchor
+            assert_eq!(
+                query_token_ledger(TokenType::ICP, ledger_principal, 3).await,
+                5
+            );
+            let anchor = TOKEN_TIP_ANCHORS
+                .with(|a| a.borrow().get(&ledger_principal))
+                .unwrap();
+            assert_eq!((anchor.start_block, anchor.next_block), (3, 5));
+            assert_eq!(anchor.last_block_hash, Some(hex::encode([4u8; 32])));
+
+            MOCK_LEDGER_PAGE_SIZE.with(|p| *p.borrow_mut() = None);
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            TOKEN_LAST_BLOCK_HASH.with(|h| h.borrow_mut().remove(&ledger_principal));
+            TOKEN_TIP_ANCHORS.with(|a| a.borrow_mut().remove(&ledger_principal));
+            LEDGER_PAGE_SIZES.with(|p| p.borrow_mut().clear());
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+        }
+
+        #[tokio::test]
+        async fn test_process_token_archived_block_uses_archive() {
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
+            let (_, to_subaccountid, _) = setup_principals();
+
+            let chain: Vec<Block> = (0..4)
+                .map(|i| mock_transfer_block(i, &to_subaccountid))
+                .collect();
+            MOCK_LEDGER_BLOCKS.with(|b| *b.borrow_mut() = chain);
+            MOCK_LEDGER_FIRST_INDEX.with(|f| *f.borrow_mut() = 2);
+
+            let result = process_token_archived_block(TokenType::ICP, 1).await;
+            assert!(result.is_ok(), "Processing archived block should succeed");
+
+            let ledger_principal = *STATIC_PRINCIPAL.lock().unwrap();
             TRANSACTIONS.with(|t| {
-                let mut transactions = t.borrow_mut();
-                for i in 10..15 {
-                    let transaction = Transaction {
-                        memo: i,
-                        icrc1_memo: None,
-                        operation: None,
-                        created_at_time: Timestamp {
-                            timestamp_nanos: 1000,
-                        },
-                    };
-                    let hash = format!("hash-{}", i);
-                    transactions.insert(
-                        i,
-                        StoredTransactions::new(
-                            i,
-                            transaction,
-                            hash,
-                            TokenType::ICP,
-                            *STATIC_PRINCIPAL.lock().unwrap(),
-                        ),
-                    );
-                }
+                let transactions = t.borrow();
+                assert_eq!(transactions.len(), 1);
+                assert!(transactions.contains_key(&(ledger_principal, 1)));
             });
 
-            let result = get_oldest_block();
-            assert!(result.is_ok(), "Getting oldest block should succeed");
-            assert_eq!(
-                result.unwrap(),
-                Some(10),
-                "Should return Some(10) as the oldest block number"
-            );
+            MOCK_LEDGER_BLOCKS.with(|b| b.borrow_mut().clear());
+            MOCK_LEDGER_FIRST_INDEX.with(|f| *f.borrow_mut() = 0);
+            TRANSACTIONS.with(|t| t.borrow_mut().clear_new());
         }
 
         #[test]
         fn test_get_subaccount_count() {
             // Clear subaccounts first
-            LIST_OF_SUBACCOUNTS.with(|s| s.borrow_mut().clear());
+            SUBACCOUNT_NONCES.with(|s| s.borrow_mut().clear_new());
 
             // Initially should be 0
             let result = get_subaccount_count();
@@ -1304,6 +3721,37 @@ mod tests {
             assert_eq!(result.unwrap(), 3, "Should have 3 subaccounts");
         }
 
+        #[test]
+        fn test_subaccount_index_matches_exact_account_ids() {
+            SUBACCOUNT_NONCES.with(|s| s.borrow_mut().clear_new());
+            LAST_SUBACCOUNT_NONCE
+                .with(|n| n.borrow_mut().set(3))
+                .unwrap();
+
+            // Nonces handed out before the index existed are indexed once
+            index_missing_subaccounts();
+            assert_eq!(get_subaccount_count().unwrap(), 3);
+            index_missing_subaccounts();
+            assert_eq!(get_subaccount_count().unwrap(), 3);
+
+            let subaccountid = to_subaccount_id(to_subaccount(2));
+            assert!(is_canister_account(subaccountid.as_ref()));
+            assert_eq!(get_subaccount(&subaccountid).unwrap(), to_subaccount(2));
+
+            // Only the full 32 bytes match
+            let mut foreign = subaccountid.as_ref().to_vec();
+            foreign[31] ^= 1;
+            assert!(!is_canister_account(&foreign));
+            assert!(!is_canister_account(&subaccountid.as_ref()[..28]));
+            let not_indexed = to_subaccount_id(to_subaccount(3));
+            assert!(get_subaccount(&not_indexed).is_err());
+
+            SUBACCOUNT_NONCES.with(|s| s.borrow_mut().clear_new());
+            LAST_SUBACCOUNT_NONCE
+                .with(|n| n.borrow_mut().set(0))
+                .unwrap();
+        }
+
         #[test]
         fn test_get_nonce() {
             // Set a specific nonce
@@ -1357,7 +3805,7 @@ mod tests {
                         // ICP addresses should be hex format (64 chars)
                         assert_eq!(address.len(), 64, "ICP address should be 64 hex chars");
                     }
-                    TokenType::CKUSDC | TokenType::CKUSDT | TokenType::CKBTC => {
+                    _ => {
                         // ICRC-1 addresses should contain the canister principal
                         assert!(
                             address.contains(&STATIC_PRINCIPAL.lock().unwrap().to_text()),
@@ -1375,12 +3823,13 @@ mod tests {
 
             // Test sweeping with different token types
             let token_amounts = vec![
-                (TokenType::ICP, 1.5),
-                (TokenType::CKUSDC, 100.25),
-                (TokenType::CKUSDT, 50.75),
+                (TokenType::ICP, 150_000_000u64),
+                (TokenType::CKUSDC, 100_250_000u64),
+                (TokenType::CKUSDT, 50_750_000u64),
             ];
 
             for (token_type, amount) in token_amounts {
+                let amount = candid::Nat::from(amount);
                 let result =
                     sweep_subaccount(subaccountid_hex.clone(), amount, Some(token_type.clone()))
                         .await;
@@ -1388,6 +3837,24 @@ mod tests {
                 assert_eq!(result.unwrap(), 1, "BlockIndex should be 1");
             }
         }
+
+        #[tokio::test]
+        async fn test_sweep_subaccount_rejects_block_index_beyond_u64() {
+            let (_, to_subaccountid, _) = setup_principals();
+            let block_index = candid::Nat::from(u64::MAX) + candid::Nat::from(1u64);
+            MOCK_ICRC1_BLOCK_INDEX.with(|b| *b.borrow_mut() = Some(block_index.clone()));
+
+            let result = sweep_subaccount(
+                to_subaccountid.to_hex(),
+                candid::Nat::from(100_000u64),
+                Some(TokenType::CKUSDC),
+            )
+            .await;
+            MOCK_ICRC1_BLOCK_INDEX.with(|b| *b.borrow_mut() = None);
+
+            let err = result.expect_err("an index beyond u64 must not be reported as 0");
+            assert!(err.message.contains(&block_index.to_string()));
+        }
     }
 
     #[cfg(feature = "sad_path")]
@@ -1544,13 +4011,13 @@ mod tests {
             populate_transactions(10, None);
 
             // Edge case 1: up_to_index is larger than the total transactions
-            let cleared = clear_transactions(Some(50), None).unwrap();
+            let cleared = clear_transactions(Some(50), None, None).unwrap();
             assert_eq!(cleared.len(), 0); // Assuming all transactions are cleared
 
             // Edge case 2: up_to_timestamp is before any stored transaction
             let early_timestamp = Timestamp::from_nanos(1); // Example early timestamp
             populate_transactions(10, None); // Repopulate transactions after they were all cleared
-            let cleared = clear_transactions(None, Some(early_timestamp)).unwrap();
+            let cleared = clear_transactions(None, Some(early_timestamp), None).unwrap();
             assert_eq!(cleared.len(), 10); // Assuming no transactions are removed because all are after the timestamp
         }
 
@@ -1559,7 +4026,7 @@ mod tests {
             refund_setup();
 
             // Attempt to refund a transaction that doesn't exist
-            let result = refund(999); // Assuming transaction with index 999 does not exist
+            let result = refund(999, None); // Assuming transaction with index 999 does not exist
             assert!(
                 result.await.is_err(),
                 "Refund should fail for a non-existent transaction"
@@ -1625,6 +4092,22 @@ mod tests {
             teardown_sweep_environment();
         }
 
+        #[tokio::test]
+        async fn test_reconcile_balances_reports_unreadable_ledger() {
+            SUBACCOUNT_BALANCES.with(|b| b.borrow_mut().clear_new());
+            BALANCE_DISCREPANCIES.with(|d| d.borrow_mut().clear_new());
+            setup_sweep_environment();
+            seed_subaccount_balances(None, MIGRATION_BATCH_SIZE);
+
+            let report = reconcile_balances(None).await.unwrap();
+            assert_eq!(report.checked, 0);
+            assert_eq!(report.errors.len(), 1);
+            assert!(report.errors[0].contains("icrc1_balance_of call failed"));
+            assert!(get_balance_discrepancies(None).unwrap().is_empty());
+
+            teardown_sweep_environment();
+        }
+
         #[tokio::test]
         async fn test_set_sweep_failed() {
             let hashes = setup_sweep_environment();
@@ -1676,7 +4159,7 @@ mod tests {
             // Setup
             let nonexistent_subaccountid =
                 "1234567890abcdef1234567890abcdef1234567890abcdef1234567890abcdef";
-            let amount = 1.25;
+            let amount = candid::Nat::from(125_000_000u64);
 
             // Execute
             let result = sweep_subaccount(
@@ -1704,7 +4187,7 @@ mod tests {
             // Setup
             let (_, to_subaccountid, _) = setup_principals();
             let subaccountid_hex = to_subaccountid.to_hex();
-            let amount = 1.25;
+            let amount = candid::Nat::from(125_000_000u64);
 
             // Execute
             let result = sweep_subaccount(subaccountid_hex, amount, Some(TokenType::ICP)).await;
@@ -1721,31 +4204,12 @@ mod tests {
             );
         }
 
-        #[tokio::test]
-        async fn test_sweep_subaccount_negative_amount() {
-            // Setup
-            let (_, to_subaccountid, _) = setup_principals();
-            let subaccountid_hex = to_subaccountid.to_hex();
-            let amount = -1.0;
-
-            // Execute
-            let result = sweep_subaccount(subaccountid_hex, amount, Some(TokenType::ICP)).await;
-
-            // Assert
-            assert!(result.is_err(), "Sweeping with negative amount should fail");
-            assert_eq!(
-                result.unwrap_err().message,
-                "Invalid amount: overflow or negative value",
-                "Error message should indicate invalid amount"
-            );
-        }
-
         #[tokio::test]
         async fn test_sweep_subaccount_overflow_amount() {
             // Setup
             let (_, to_subaccountid, _) = setup_principals();
             let subaccountid_hex = to_subaccountid.to_hex();
-            let amount = f64::MAX;
+            let amount = candid::Nat::from(u64::MAX) + 1u64;
 
             // Execute
             let result = sweep_subaccount(subaccountid_hex, amount, Some(TokenType::ICP)).await;
@@ -1754,7 +4218,7 @@ mod tests {
             assert!(result.is_err(), "Sweeping with overflow amount should fail");
             assert_eq!(
                 result.unwrap_err().message,
-                "Invalid amount: overflow or negative value",
+                "Invalid amount: 18_446_744_073_709_551_616 does not fit an ICP transfer",
                 "Error message should indicate invalid amount"
             );
         }
@@ -1821,7 +4285,12 @@ mod tests {
             let (_, to_subaccountid, _) = setup_principals();
             let subaccountid_hex = to_subaccountid.to_hex();
 
-            let result = sweep_subaccount(subaccountid_hex, 0.0, Some(TokenType::ICP)).await;
+            let result = sweep_subaccount(
+                subaccountid_hex,
+                candid::Nat::from(0u64),
+                Some(TokenType::ICP),
+            )
+            .await;
             // In sad path tests, transfer always fails with "transfer failed"
             assert!(result.is_err(), "Sweeping should fail in sad path tests");
             let error_msg = result.unwrap_err().message;
@@ -1854,7 +4323,7 @@ mod tests {
             populate_transactions(10, None);
 
             // Test with index that would clear all transactions
-            let result = clear_transactions(Some(u64::MAX), None);
+            let result = clear_transactions(Some(u64::MAX), None, None);
             assert!(result.is_ok(), "Should succeed but clear all transactions");
             let remaining = result.unwrap();
             assert_eq!(remaining.len(), 0, "Should have cleared all transactions");
@@ -2051,14 +4520,7 @@ mod tests {
 
             // Create a subaccount first
             let nonce = get_nonce().unwrap();
-            let subaccount = to_subaccount(nonce);
-            let subaccountid = to_subaccount_id(subaccount);
-            let account_id_hash = subaccountid.to_u64_hash();
-
-            // Store it
-            LIST_OF_SUBACCOUNTS.with(|list_ref| {
-                list_ref.borrow_mut().insert(account_id_hash, subaccount);
-            });
+            let subaccountid = index_subaccount(nonce);
 
             // Update nonce
             LAST_SUBACCOUNT_NONCE.with(|nonce_ref| {
@@ -2116,16 +4578,14 @@ mod tests {
             let principal = Principal::from_text(ckbtc_ledger).unwrap();
 
             // Register CKBTC directly in storage
-            let token_id = match TokenType::CKBTC {
-                TokenType::ICP => 1,
-                TokenType::CKUSDC => 2,
-                TokenType::CKUSDT => 3,
-                TokenType::CKBTC => 4,
+            let config = TokenConfig {
+                ledger_canister_id: principal,
+                ..preset_token_config(&TokenType::CKBTC).unwrap()
             };
 
-            TOKEN_LEDGER_PRINCIPALS.with(|tl| {
-                let mut tl_mut = tl.borrow_mut();
-                tl_mut.insert(token_id, (TokenType::CKBTC, principal));
+            TOKEN_REGISTRY.with(|registry| {
+                let mut registry_mut = registry.borrow_mut();
+                registry_mut.insert(principal, config);
             });
 
             // Verify it's registered
@@ -2139,5 +4599,35 @@ mod tests {
 
             assert!(ckbtc_registered, "CKBTC should be in registered tokens");
         }
+
+        #[tokio::test]
+        async fn test_register_token_falls_back_to_preset() {
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+            let ledger = Principal::from_text("xevnm-gaaaa-aaaar-qafnq-cai").unwrap();
+
+            // The ledger is unreachable, so the built-in preset is used
+            let result = register_token(TokenType::CKUSDC, ledger.to_text(), None).await;
+            assert!(result.is_ok());
+            let config = get_token_config(&TokenType::CKUSDC).unwrap();
+            assert_eq!(config.symbol, "ckUSDC");
+            assert_eq!(config.fee, 10_000);
+            assert_eq!(config.fee_updated_at, None);
+
+            // Custom tokens have no preset to fall back to
+            let custom = TokenType::Custom(ledger);
+            let result = register_token(custom, ledger.to_text(), None).await;
+            assert!(result.is_err());
+
+            // A failed refresh keeps the cached fee and is retried after a while
+            let now = CanisterApiManager::time();
+            refresh_stale_token_fees().await;
+            assert_eq!(get_token_fee(&TokenType::CKUSDC), 10_000);
+            let config = get_token_config(&TokenType::CKUSDC).unwrap();
+            assert!(!is_fee_stale(&config, now));
+            assert!(is_fee_stale(&config, now + FEE_RETRY_INTERVAL_NANOS));
+            assert!(update_token_fee(TokenType::CKUSDC).await.is_err());
+
+            TOKEN_REGISTRY.with(|r| r.borrow_mut().clear_new());
+        }
     }
 }
@@ -1,25 +1,38 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
 use core::future::Future;
 use ic_cdk::api::call::CallResult;
 use ic_cdk_timers::TimerId;
-use ic_ledger_types::{BlockIndex, TransferArgs};
+use ic_ledger_types::{BlockIndex, Subaccount, TransferArgs};
 use ic_stable_structures::{
     memory_manager::VirtualMemory,
     storable::{Bound, Storable},
     DefaultMemoryImpl,
 };
 use icrc_ledger_types::icrc1::transfer::TransferArg;
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::BTreeSet;
-use std::{borrow::Cow, collections::HashMap};
+use std::collections::{BTreeMap, BTreeSet};
 
 pub struct State {
     pending_requests: BTreeSet<Principal>,
+    // Ledgers being indexed, with the time the pass started
+    tokens_in_flight: BTreeMap<Principal, u64>,
+    // Backfill jobs with a chunk being indexed
+    backfills_in_flight: BTreeSet<u64>,
+    // Whether balances are being reconciled against the ledgers
+    reconciliation_in_flight: bool,
 }
 
 thread_local! {
-    pub static STATE: RefCell<State> = const { RefCell::new(State{pending_requests: BTreeSet::new()}) };
+    pub static STATE: RefCell<State> = const {
+        RefCell::new(State {
+            pending_requests: BTreeSet::new(),
+            tokens_in_flight: BTreeMap::new(),
+            backfills_in_flight: BTreeSet::new(),
+            reconciliation_in_flight: false,
+        })
+    };
 }
 
 // CallerGuard section was inspired by or directly uses work done by AlphaCQ
@@ -62,6 +75,81 @@ impl Drop for CallerGuard {
     }
 }
 
+// Held while a token's ledger is indexed so overlapping timer ticks skip it
+pub struct TokenGuard {
+    ledger_principal: Principal,
+}
+
+impl TokenGuard {
+    // Fails with the start time of the pass in flight if the ledger is busy
+    pub fn new(ledger_principal: Principal, now: u64) -> Result<Self, u64> {
+        STATE.with(|state| {
+            let tokens_in_flight = &mut state.borrow_mut().tokens_in_flight;
+            if let Some(started_at) = tokens_in_flight.get(&ledger_principal) {
+                return Err(*started_at);
+            }
+            tokens_in_flight.insert(ledger_principal, now);
+            Ok(Self { ledger_principal })
+        })
+    }
+}
+
+impl Drop for TokenGuard {
+    fn drop(&mut self) {
+        STATE.with(|state| {
+            state
+                .borrow_mut()
+                .tokens_in_flight
+                .remove(&self.ledger_principal);
+        })
+    }
+}
+
+// Held while a chunk of a backfill job is indexed so overlapping ticks skip it
+pub struct BackfillGuard {
+    job_id: u64,
+}
+
+impl BackfillGuard {
+    pub fn new(job_id: u64) -> Option<Self> {
+        STATE.with(|state| {
+            state
+                .borrow_mut()
+                .backfills_in_flight
+                .insert(job_id)
+                .then_some(Self { job_id })
+        })
+    }
+}
+
+impl Drop for BackfillGuard {
+    fn drop(&mut self) {
+        STATE.with(|state| {
+            state.borrow_mut().backfills_in_flight.remove(&self.job_id);
+        })
+    }
+}
+
+// Held while balances are reconciled so that runs do not overlap
+pub struct ReconciliationGuard;
+
+impl ReconciliationGuard {
+    pub fn new() -> Option<Self> {
+        STATE.with(|state| {
+            let in_flight = &mut state.borrow_mut().reconciliation_in_flight;
+            (!std::mem::replace(in_flight, true)).then_some(Self)
+        })
+    }
+}
+
+impl Drop for ReconciliationGuard {
+    fn drop(&mut self) {
+        STATE.with(|state| {
+            state.borrow_mut().reconciliation_in_flight = false;
+        })
+    }
+}
+
 #[derive(CandidType, Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
 pub enum Network {
     Mainnet,
@@ -70,33 +158,18 @@ pub enum Network {
 
 impl Storable for Network {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        match candid::encode_one(self) {
-            Ok(bytes) => Cow::Owned(bytes),
-            Err(e) => {
-                let error_msg = format!("CRITICAL ERROR encoding Network: {:?}", e);
-                ic_cdk::println!("{}", error_msg);
-                // Still panic but with more detailed error info
-                panic!("Failed to encode Network: {:?}", e);
-            }
-        }
+        encode_versioned(self)
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        match candid::decode_one(bytes.as_ref()) {
-            Ok(decoded) => decoded,
-            Err(e) => {
-                let error_msg = format!("CRITICAL ERROR decoding Network: {:?}", e);
-                ic_cdk::println!("{}", error_msg);
-                // Still panic but with more debug info
-                panic!("Failed to decode Network: {:?}", e);
-            }
-        }
+        decode_versioned(bytes.as_ref())
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for Network {
+    const VERSION: u8 = 1;
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -178,6 +251,8 @@ pub struct InsufficientFundsRecord {
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct QueryBlocksResponse {
     pub certificate: Option<Vec<u8>>,
+    // Hash tree certified by the certificate (ICRC-3 tip certificates)
+    pub hash_tree: Option<Vec<u8>>,
     pub blocks: Vec<Block>,
     pub chain_length: u64,
     pub first_block_index: u64,
@@ -189,6 +264,13 @@ pub struct Block {
     pub transaction: Transaction,
     pub timestamp: Timestamp,
     pub parent_hash: Option<Vec<u8>>,
+    // Set when the ledger format defines the transaction hash (ICRC-3),
+    // otherwise it is derived from the transaction as on the ICP ledger
+    pub tx_hash: Option<String>,
+    // Hash the next block carries as its parent hash. ICRC-3 blocks are hashed
+    // from the decoded block, ICP blocks from query_encoded_blocks; archived
+    // ICP blocks have none
+    pub block_hash: Option<Vec<u8>>,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -197,6 +279,10 @@ pub struct Transaction {
     pub icrc1_memo: Option<Vec<u8>>,
     pub operation: Option<Operation>,
     pub created_at_time: Timestamp,
+    // Native accounts of ICRC ledgers, the operation keeps AccountIdentifier bytes
+    pub from_account: Option<IcrcAccount>,
+    pub to_account: Option<IcrcAccount>,
+    pub spender_account: Option<IcrcAccount>,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -217,8 +303,79 @@ pub enum Operation {
     Transfer(Transfer),
 }
 
+// Amounts are arbitrary-precision so that 18-decimal tokens fit
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Approve {
+    pub fee: Nat,
+    pub from: Vec<u8>,
+    pub allowance: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<Timestamp>,
+    pub spender: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Burn {
+    pub from: Vec<u8>,
+    pub amount: Nat,
+    pub spender: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Mint {
+    pub to: Vec<u8>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub to: Vec<u8>,
+    pub fee: Nat,
+    pub from: Vec<u8>,
+    pub amount: Nat,
+    pub spender: Option<Vec<u8>>,
+}
+
+impl Operation {
+    // Amount moved by the operation, the allowance for approvals
+    pub fn amount(&self) -> &Nat {
+        match self {
+            Operation::Approve(data) => &data.allowance,
+            Operation::Burn(data) => &data.amount,
+            Operation::Mint(data) => &data.amount,
+            Operation::Transfer(data) => &data.amount,
+        }
+    }
+
+    // Account identifiers the operation touches
+    pub fn accounts(&self) -> Vec<&[u8]> {
+        match self {
+            Operation::Approve(data) => vec![&data.from, &data.spender],
+            Operation::Burn(data) => std::iter::once(&data.from)
+                .chain(data.spender.as_ref())
+                .map(Vec::as_slice)
+                .collect(),
+            Operation::Mint(data) => vec![&data.to],
+            Operation::Transfer(data) => [&data.from, &data.to]
+                .into_iter()
+                .chain(data.spender.as_ref())
+                .map(Vec::as_slice)
+                .collect(),
+        }
+    }
+}
+
+// Operations as stored before amounts became Nat, only used to decode old records
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum OperationV1 {
+    Approve(ApproveV1),
+    Burn(BurnV1),
+    Mint(MintV1),
+    Transfer(TransferV1),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ApproveV1 {
     pub fee: E8s,
     pub from: Vec<u8>,
     pub allowance_e8s: i64,
@@ -229,20 +386,20 @@ pub struct Approve {
 }
 
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
-pub struct Burn {
+pub struct BurnV1 {
     pub from: Vec<u8>,
     pub amount: E8s,
     pub spender: Option<Vec<u8>>,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
-pub struct Mint {
+pub struct MintV1 {
     pub to: Vec<u8>,
     pub amount: E8s,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
-pub struct Transfer {
+pub struct TransferV1 {
     pub to: Vec<u8>,
     pub fee: E8s,
     pub from: Vec<u8>,
@@ -255,29 +412,56 @@ pub struct E8s {
     pub e8s: u64,
 }
 
+impl From<E8s> for Nat {
+    fn from(amount: E8s) -> Self {
+        Nat::from(amount.e8s)
+    }
+}
+
+impl From<OperationV1> for Operation {
+    fn from(v1: OperationV1) -> Self {
+        match v1 {
+            OperationV1::Approve(approve) => Operation::Approve(Approve {
+                fee: approve.fee.into(),
+                from: approve.from,
+                allowance: approve.allowance.into(),
+                expected_allowance: approve.expected_allowance.map(Nat::from),
+                expires_at: approve.expires_at,
+                spender: approve.spender,
+            }),
+            OperationV1::Burn(burn) => Operation::Burn(Burn {
+                from: burn.from,
+                amount: burn.amount.into(),
+                spender: burn.spender,
+            }),
+            OperationV1::Mint(mint) => Operation::Mint(Mint {
+                to: mint.to,
+                amount: mint.amount.into(),
+            }),
+            OperationV1::Transfer(transfer) => Operation::Transfer(Transfer {
+                to: transfer.to,
+                fee: transfer.fee.into(),
+                from: transfer.from,
+                amount: transfer.amount.into(),
+                spender: transfer.spender,
+            }),
+        }
+    }
+}
+
+// A range of blocks that the ledger has moved to an archive canister
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct ArchivedBlock {
-    pub callback: HashMap<String, Callback>,
+    pub callback: ArchiveCallback,
     pub start: u64,
     pub length: u64,
 }
 
+// Canister and method to call to fetch an archived range
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
-pub enum Callback {
-    Ok { blocks: Vec<Block> },
-    Err(CallbackError),
-}
-
-#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
-pub enum CallbackError {
-    BadFirstBlockIndex {
-        requested_index: u64,
-        first_valid_index: u64,
-    },
-    Other {
-        error_message: String,
-        error_code: u64,
-    },
+pub struct ArchiveCallback {
+    pub canister_id: Principal,
+    pub method: String,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -294,6 +478,8 @@ pub enum TokenType {
     CKUSDC,
     CKUSDT,
     CKBTC,
+    // Any other ledger registered at runtime, identified by its ledger canister id
+    Custom(Principal),
 }
 
 impl Storable for TokenType {
@@ -327,12 +513,359 @@ impl Storable for TokenType {
     };
 }
 
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum TokenStandard {
+    // ICP ledger: query_blocks and account identifier transfers
+    ICP,
+    // ICRC-1 ledger: icrc3_get_blocks and icrc1_transfer
+    ICRC1,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TokenConfig {
+    pub token_type: TokenType,
+    pub ledger_canister_id: Principal,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: u64,
+    pub standard: TokenStandard,
+    // Time in nanoseconds the fee was last read from the ledger
+    pub fee_updated_at: Option<u64>,
+}
+
+impl Storable for TokenConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for TokenConfig {
+    const VERSION: u8 = 1;
+}
+
+// Metadata supplied when registering a token; built-in tokens fall back to their preset
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TokenMetadata {
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: u64,
+    pub standard: TokenStandard,
+}
+
+// Hash of the last block whose parent hash was verified, per token ledger
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct BlockHashRecord {
+    pub block_index: u64,
+    pub hash: Vec<u8>,
+}
+
+impl Storable for BlockHashRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for BlockHashRecord {
+    const VERSION: u8 = 1;
+}
+
+// Raised when a block does not chain onto the last verified block; indexing of
+// the token stays halted until the alert is cleared
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ChainAlert {
+    pub token_type: TokenType,
+    pub block_index: u64,
+    pub expected_parent_hash: String,
+    pub found_parent_hash: Option<String>,
+    pub detected_at: u64,
+}
+
+impl Storable for ChainAlert {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for ChainAlert {
+    const VERSION: u8 = 1;
+}
+
+// Ledger state the last indexed batch of a token was anchored to. Only the
+// latest anchor per ledger is kept. It is unverified: its last block hash is
+// checked off-chain against the ledger's certified tip.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TipAnchor {
+    pub token_type: TokenType,
+    pub start_block: u64,
+    pub next_block: u64,
+    pub last_block_hash: Option<String>,
+    pub recorded_at: u64,
+}
+
+impl Storable for TipAnchor {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    // Version 1 anchors carried ledger certificates of no fixed size
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Version 2 dropped the certificate fields, older anchors decode without them
+impl Versioned for TipAnchor {
+    const VERSION: u8 = 2;
+}
+
+// How far a token's indexing is behind the ledger
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TokenLag {
+    pub token_type: TokenType,
+    pub next_block: u64,
+    pub chain_length: u64,
+    pub lag: u64,
+    pub catching_up: bool,
+    // The ledger reported a chain shorter than the next block to index
+    pub ledger_reset: bool,
+}
+
+// Raised when a ledger's chain is shorter than the token's next block, as
+// after a ledger reinstall
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LedgerReset {
+    pub token_type: TokenType,
+    pub next_block: u64,
+    pub chain_length: u64,
+    pub detected_at: u64,
+    // Set when the token was rewound to the ledger's first block
+    pub rewound: bool,
+}
+
+impl Storable for LedgerReset {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for LedgerReset {
+    const VERSION: u8 = 1;
+}
+
+// Timer ticks that found a token still being indexed by an earlier tick
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SkippedTicks {
+    pub token_type: TokenType,
+    pub count: u64,
+    pub last_skipped_at: u64,
+    // How long the pass in flight had been running when the tick was skipped
+    pub last_in_flight_nanos: u64,
+}
+
+impl Storable for SkippedTicks {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for SkippedTicks {
+    const VERSION: u8 = 1;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum BackfillStatus {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+// Re-indexes a range of a token's blocks in timer driven chunks
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct BackfillJob {
+    pub id: u64,
+    pub token_type: TokenType,
+    pub ledger_principal: Principal,
+    pub from_block: u64,
+    // Last block of the range, inclusive
+    pub to_block: u64,
+    pub next_block: u64,
+    // Transactions stored by the job
+    pub indexed: u64,
+    // Blocks of canister subaccounts that were already stored
+    pub already_indexed: u64,
+    pub status: BackfillStatus,
+    pub last_error: Option<String>,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for BackfillJob {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    // Ledger and archive errors are stored as received
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for BackfillJob {
+    const VERSION: u8 = 1;
+}
+
+// What the indexer believes a subaccount holds of a token: indexed deposits
+// credit it, transfers out of the subaccount debit amount and fee
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SubaccountBalance {
+    pub nonce: u32,
+    pub token_type: TokenType,
+    pub ledger_canister_id: Principal,
+    pub balance: Nat,
+    pub credited: Nat,
+    pub debited: Nat,
+    pub updated_at: u64,
+}
+
+impl Storable for SubaccountBalance {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    // Amounts are arbitrary-precision
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for SubaccountBalance {
+    const VERSION: u8 = 1;
+}
+
+// A subaccount whose balance on the ledger differs from the running balance
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct BalanceDiscrepancy {
+    pub nonce: u32,
+    pub token_type: TokenType,
+    pub ledger_canister_id: Principal,
+    pub indexed_balance: Nat,
+    pub ledger_balance: Nat,
+    pub detected_at: u64,
+}
+
+impl Storable for BalanceDiscrepancy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    // Amounts are arbitrary-precision
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for BalanceDiscrepancy {
+    const VERSION: u8 = 1;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ReconciliationReport {
+    pub checked: u64,
+    pub matched: u64,
+    pub discrepancies: Vec<BalanceDiscrepancy>,
+    // Balances that could not be read from the ledger
+    pub errors: Vec<String>,
+    pub finished_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum MigrationStatus {
+    Pending,
+    Running,
+    Completed,
+}
+
+// Rewrites the values of a stable structure at the current schema version in
+// timer driven batches
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MigrationProgress {
+    pub id: u32,
+    pub name: String,
+    // Schema version the values are rewritten to, or the revision of a
+    // migration that builds data from them
+    pub version: u8,
+    pub status: MigrationStatus,
+    // Key of the last value rewritten, the next batch resumes after it
+    pub cursor: Option<Vec<u8>>,
+    pub migrated: u64,
+    // Values stored when the migration was registered
+    pub total: u64,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for MigrationProgress {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_versioned(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for MigrationProgress {
+    const VERSION: u8 = 1;
+}
+
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
 pub struct StoredTransactionsV1 {
     pub index: u64,
     pub memo: u64,
     pub icrc1_memo: Option<Vec<u8>>,
-    pub operation: Option<Operation>,
+    pub operation: Option<OperationV1>,
     pub created_at_time: Timestamp,
     pub sweep_status: SweepStatus,
     pub tx_hash: String,
@@ -340,6 +873,19 @@ pub struct StoredTransactionsV1 {
 
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
 pub struct StoredTransactionsV2 {
+    pub index: u64,
+    pub memo: u64,
+    pub icrc1_memo: Option<Vec<u8>>,
+    pub operation: Option<OperationV1>,
+    pub created_at_time: Timestamp,
+    pub sweep_status: SweepStatus,
+    pub tx_hash: String,
+    pub token_type: TokenType,
+    pub token_ledger_canister_id: Option<Principal>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct StoredTransactionsV3 {
     pub index: u64,
     pub memo: u64,
     pub icrc1_memo: Option<Vec<u8>>,
@@ -349,6 +895,9 @@ pub struct StoredTransactionsV2 {
     pub tx_hash: String,
     pub token_type: TokenType,
     pub token_ledger_canister_id: Option<Principal>,
+    pub from_account: Option<IcrcAccount>,
+    pub to_account: Option<IcrcAccount>,
+    pub spender_account: Option<IcrcAccount>,
 }
 
 impl From<StoredTransactionsV1> for StoredTransactionsV2 {
@@ -367,10 +916,37 @@ impl From<StoredTransactionsV1> for StoredTransactionsV2 {
     }
 }
 
+impl From<StoredTransactionsV2> for StoredTransactionsV3 {
+    fn from(v2: StoredTransactionsV2) -> Self {
+        Self {
+            index: v2.index,
+            memo: v2.memo,
+            icrc1_memo: v2.icrc1_memo,
+            operation: v2.operation.map(Operation::from),
+            created_at_time: v2.created_at_time,
+            sweep_status: v2.sweep_status,
+            tx_hash: v2.tx_hash,
+            token_type: v2.token_type,
+            token_ledger_canister_id: v2.token_ledger_canister_id,
+            from_account: None,
+            to_account: None,
+            spender_account: None,
+        }
+    }
+}
+
 // Type alias for backward compatibility
-pub type StoredTransactions = StoredTransactionsV2;
+pub type StoredTransactions = StoredTransactionsV3;
 
-impl StoredTransactionsV2 {
+// Transactions are keyed by (token ledger canister id, block index) so that
+// identical block indexes on different ledgers do not collide
+pub type TransactionKey = (Principal, u64);
+
+// Subaccount history entries are keyed by (nonce, (created_at_time nanos, key))
+// so that each subaccount's transactions sit together in creation time order
+pub type SubaccountTransactionKey = (u32, (u64, TransactionKey));
+
+impl StoredTransactionsV3 {
     pub fn new(
         index: u64,
         transaction: Transaction,
@@ -388,10 +964,69 @@ impl StoredTransactionsV2 {
             tx_hash: hash,
             token_type,
             token_ledger_canister_id: Some(token_ledger_canister_id),
+            from_account: transaction.from_account,
+            to_account: transaction.to_account,
+            spender_account: transaction.spender_account,
+        }
+    }
+}
+
+// Position of a transaction in creation time order. Pages hand out the cursor
+// of the first transaction they did not include.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TransactionCursor {
+    pub timestamp_nanos: u64,
+    pub token_ledger_canister_id: Principal,
+    pub index: u64,
+}
+
+impl TransactionCursor {
+    // Entry of the transaction in the creation time index
+    pub fn entry(&self) -> (u64, TransactionKey) {
+        (
+            self.timestamp_nanos,
+            (self.token_ledger_canister_id, self.index),
+        )
+    }
+}
+
+impl From<(u64, TransactionKey)> for TransactionCursor {
+    fn from((timestamp_nanos, (token_ledger_canister_id, index)): (u64, TransactionKey)) -> Self {
+        Self {
+            timestamp_nanos,
+            token_ledger_canister_id,
+            index,
         }
     }
 }
 
+// Criteria a transaction must all meet to be listed. The subaccount is given as
+// account identifier hex or as ICRC-1 text, amounts are bounds inclusive and
+// the time range is [from_ts, to_ts).
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TransactionFilter {
+    pub token_type: Option<TokenType>,
+    pub sweep_status: Option<SweepStatus>,
+    pub subaccount: Option<String>,
+    pub min_amount: Option<Nat>,
+    pub max_amount: Option<Nat>,
+    pub from_ts: Option<Timestamp>,
+    pub to_ts: Option<Timestamp>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum SortOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct TransactionsPage {
+    pub transactions: Vec<StoredTransactions>,
+    pub next_cursor: Option<TransactionCursor>,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Default)]
 pub struct StoredPrincipal {
     principal: Option<Principal>,
@@ -409,127 +1044,167 @@ impl StoredPrincipal {
     }
 }
 
+// TokenType is a member of tuple values, whose layout depends on its bound, so
+// it keeps a bounded encoding without an envelope
 const MAX_VALUE_SIZE: u32 = 500;
-impl Storable for StoredTransactionsV1 {
-    fn to_bytes(&self) -> Cow<'_, [u8]> {
-        match candid::encode_one(self) {
-            Ok(bytes) => Cow::Owned(bytes),
-            Err(e) => {
-                let error_msg = format!(
-                    "CRITICAL ERROR encoding StoredTransactionsV1 with index {}: {:?}",
-                    self.index, e
-                );
-                ic_cdk::println!("{}", error_msg);
-                panic!("Failed to encode StoredTransactionsV1: {:?}", e);
-            }
-        }
+
+// Stored values are ENVELOPE_MAGIC, a schema version byte and the candid
+// payload. Candid payloads start with "DIDL", so values written before the
+// envelope existed are read back as LEGACY_SCHEMA_VERSION.
+const ENVELOPE_MAGIC: &[u8; 3] = b"ISV";
+pub const LEGACY_SCHEMA_VERSION: u8 = 0;
+
+pub trait Versioned: CandidType + DeserializeOwned {
+    // Version written by to_bytes. Bump it when the schema changes and decode the
+    // older payloads in decode_version.
+    const VERSION: u8;
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, String> {
+        let _ = version;
+        candid::decode_one(payload).map_err(|e| e.to_string())
     }
+}
 
-    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        match candid::decode_one(bytes.as_ref()) {
-            Ok(decoded) => decoded,
-            Err(e) => {
-                let error_msg = format!("CRITICAL ERROR decoding StoredTransactionsV1: {:?}", e);
-                ic_cdk::println!("{}", error_msg);
-                panic!("Failed to decode StoredTransactionsV1: {:?}", e);
-            }
+// Splits stored bytes into their schema version and payload
+pub fn split_envelope(bytes: &[u8]) -> (u8, &[u8]) {
+    match bytes.strip_prefix(ENVELOPE_MAGIC.as_slice()) {
+        Some([version, payload @ ..]) => (*version, payload),
+        _ => (LEGACY_SCHEMA_VERSION, bytes),
+    }
+}
+
+pub fn encode_versioned<T: Versioned>(value: &T) -> Cow<'static, [u8]> {
+    match candid::encode_one(value) {
+        Ok(payload) => {
+            let mut bytes = Vec::with_capacity(ENVELOPE_MAGIC.len() + 1 + payload.len());
+            bytes.extend_from_slice(ENVELOPE_MAGIC);
+            bytes.push(T::VERSION);
+            bytes.extend_from_slice(&payload);
+            Cow::Owned(bytes)
+        }
+        Err(e) => {
+            let error_msg = format!(
+                "CRITICAL ERROR encoding {}: {:?}",
+                std::any::type_name::<T>(),
+                e
+            );
+            ic_cdk::println!("{}", error_msg);
+            panic!("Failed to encode {}: {:?}", std::any::type_name::<T>(), e);
         }
     }
+}
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+pub fn decode_versioned<T: Versioned>(bytes: &[u8]) -> T {
+    let (version, payload) = split_envelope(bytes);
+    if version > T::VERSION {
+        let error_msg = format!(
+            "CRITICAL ERROR decoding {}: schema version {} is newer than {}",
+            std::any::type_name::<T>(),
+            version,
+            T::VERSION
+        );
+        ic_cdk::println!("{}", error_msg);
+        panic!("{}", error_msg);
+    }
+    match T::decode_version(version, payload) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            let error_msg = format!(
+                "CRITICAL ERROR decoding {} at schema version {}: {}",
+                std::any::type_name::<T>(),
+                version,
+                e
+            );
+            ic_cdk::println!("{}", error_msg);
+            panic!("Failed to decode {}: {}", std::any::type_name::<T>(), e);
+        }
+    }
 }
 
-impl Storable for StoredTransactionsV2 {
-    fn to_bytes(&self) -> Cow<'_, [u8]> {
-        match candid::encode_one(self) {
-            Ok(bytes) => Cow::Owned(bytes),
+impl StoredTransactionsV2 {
+    // Decodes a record written without an envelope, by the V2 or the V1 schema
+    fn decode_legacy(bytes: &[u8]) -> Result<Self, String> {
+        match candid::decode_one::<StoredTransactionsV2>(bytes) {
+            Ok(decoded) => Ok(decoded),
             Err(e) => {
-                let error_msg = format!("CRITICAL ERROR encoding StoredTransactionsV2 with index {} and token type {:?}: {:?}", 
-                    self.index, self.token_type, e);
-                ic_cdk::println!("{}", error_msg);
-                panic!("Failed to encode StoredTransactionsV2: {:?}", e);
+                ic_cdk::println!("Failed to decode as StoredTransactionsV2: {:?}", e);
+                ic_cdk::println!("Attempting to decode as StoredTransactionsV1...");
+                let v1 = candid::decode_one::<StoredTransactionsV1>(bytes).map_err(|e2| {
+                    format!(
+                        "Failed to decode as StoredTransactionsV1: {:?}. Original V2 error: {:?}",
+                        e2, e
+                    )
+                })?;
+                ic_cdk::println!(
+                    "Successfully decoded as StoredTransactionsV1 with index {}, upgrading to V2",
+                    v1.index
+                );
+                Ok(StoredTransactionsV2::from(v1))
             }
         }
     }
+}
+
+impl Storable for StoredTransactionsV3 {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        encode_versioned(self)
+    }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        // First try to decode as V2 (current version)
-        match candid::decode_one(bytes.as_ref()) {
-            Ok(decoded) => decoded,
-            Err(e) => {
-                // If that fails, try to decode as V1 and convert
-                let error_msg = format!("Failed to decode as StoredTransactionsV2: {:?}", e);
-                ic_cdk::println!("{}", error_msg);
-                ic_cdk::println!("Attempting to decode as StoredTransactionsV1...");
+        decode_versioned(bytes.as_ref())
+    }
+
+    // Transactions carry up to three accounts, memos and hashes
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for StoredTransactionsV3 {
+    const VERSION: u8 = 1;
 
-                match candid::decode_one::<StoredTransactionsV1>(bytes.as_ref()) {
-                    Ok(v1) => {
-                        ic_cdk::println!(
-                            "Successfully decoded as StoredTransactionsV1 with index {}, upgrading to V2",
-                            v1.index
-                        );
-                        StoredTransactionsV2::from(v1)
-                    }
-                    Err(e2) => {
-                        let critical_error = format!(
-                            "CRITICAL ERROR: Failed to decode as StoredTransactionsV1: {:?}. Original V2 error: {:?}",
-                            e2, e
-                        );
-                        ic_cdk::println!("{}", critical_error);
-                        panic!("Failed to decode StoredTransactionsV2 as either V2 or V1 format");
-                    }
-                }
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, String> {
+        if version > LEGACY_SCHEMA_VERSION {
+            return candid::decode_one(payload).map_err(|e| e.to_string());
+        }
+        // Records written before the Nat migration hold u64 amounts. Candid decodes
+        // a mismatched opt field as null, so a V2 record can come back from the V3
+        // decoder with its operation dropped rather than as an error.
+        match candid::decode_one::<StoredTransactionsV3>(payload) {
+            Ok(decoded) if decoded.operation.is_some() => Ok(decoded),
+            _ => {
+                ic_cdk::println!("Attempting to decode as StoredTransactionsV2...");
+                StoredTransactionsV2::decode_legacy(payload).map(StoredTransactionsV3::from)
             }
         }
     }
-
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
 }
 
 impl Storable for StoredPrincipal {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        match candid::encode_one(self) {
-            Ok(bytes) => Cow::Owned(bytes),
-            Err(e) => {
-                let error_msg = format!(
-                    "CRITICAL ERROR encoding StoredPrincipal {:?}: {:?}",
-                    self.principal, e
-                );
-                ic_cdk::println!("{}", error_msg);
-                // Still panic but with more debug info
-                panic!("Failed to encode StoredPrincipal: {:?}", e);
-            }
-        }
+        encode_versioned(self)
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        match candid::decode_one(bytes.as_ref()) {
-            Ok(decoded) => decoded,
-            Err(e) => {
-                let error_msg = format!("CRITICAL ERROR decoding StoredPrincipal: {:?}", e);
-                ic_cdk::println!("{}", error_msg);
-                // Still panic but with more debug info
-                panic!("Failed to decode StoredPrincipal: {:?}", e);
-            }
-        }
+        decode_versioned(bytes.as_ref())
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for StoredPrincipal {
+    const VERSION: u8 = 1;
 }
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 pub trait TimerManagerTrait {
     fn set_timer(interval: std::time::Duration) -> TimerId;
+    fn set_token_timer(
+        interval: std::time::Duration,
+        token_type: TokenType,
+        ledger_principal: Principal,
+    ) -> TimerId;
+    fn set_backfill_timer(interval: std::time::Duration) -> TimerId;
+    fn set_migration_timer(interval: std::time::Duration) -> TimerId;
     fn clear_timer(timer_id: TimerId);
 }
 
@@ -537,25 +1212,81 @@ pub struct TimerManager;
 
 pub trait CanisterApiManagerTrait {
     fn id() -> Principal;
+    fn time() -> u64;
+    // Instructions used by the current call context, across awaits
+    fn instruction_counter() -> u64;
 }
 
 pub struct CanisterApiManager;
 
+// Rejection returned by an ICRC-1 ledger for a transfer
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum Icrc1TransferError {
+    BadFee {
+        expected_fee: candid::Nat,
+    },
+    BadBurn {
+        min_burn_amount: candid::Nat,
+    },
+    InsufficientFunds {
+        balance: candid::Nat,
+    },
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    Duplicate {
+        duplicate_of: candid::Nat,
+    },
+    TemporarilyUnavailable,
+    GenericError {
+        error_code: candid::Nat,
+        message: String,
+    },
+}
+
 pub trait InterCanisterCallManagerTrait {
     async fn query_blocks(
         ledger_principal: Principal,
         req: QueryBlocksRequest,
     ) -> CallResult<(QueryBlocksResponse,)>;
 
+    async fn query_archived_blocks(
+        ledger_principal: Principal,
+        archived: ArchivedBlock,
+        req: QueryBlocksRequest,
+    ) -> Result<Vec<Block>, String>;
+
     async fn transfer(
         args: TransferArgs,
         token_ledger_canister_id: Principal,
     ) -> Result<BlockIndex, String>;
 
+    async fn query_token_metadata(ledger_principal: Principal) -> Result<TokenMetadata, String>;
+
+    async fn query_token_fee(ledger_principal: Principal) -> Result<u64, String>;
+
+    // Balance of one of the canister's subaccounts as the ledger reports it
+    async fn query_balance(
+        ledger_principal: Principal,
+        subaccount: Subaccount,
+    ) -> Result<candid::Nat, String>;
+
+    // Err when the call fails, Ok(Err) when the ledger rejects the transfer
     async fn icrc1_transfer(
         args: TransferArg,
         token_ledger_canister_id: Principal,
-    ) -> Result<candid::Nat, String>;
+    ) -> Result<Result<candid::Nat, Icrc1TransferError>, String>;
+
+    // Ids of the transactions of one of the canister's subaccounts known to an
+    // index canister, newest first, from `start` (inclusive) when given
+    async fn query_account_transaction_ids(
+        index_principal: Principal,
+        ledger_principal: Principal,
+        subaccount: Subaccount,
+        start: Option<u64>,
+        max_results: u64,
+    ) -> Result<Vec<u64>, String>;
 }
 
 pub struct InterCanisterCallManager;
@@ -575,7 +1306,6 @@ pub struct IcrcAccount {
 
 impl IcrcAccount {
     /// Create a new account with the given principal and optional subaccount
-    #[allow(dead_code)]
     pub fn new(owner: Principal, subaccount: Option<[u8; 32]>) -> Self {
         Self { owner, subaccount }
     }
@@ -721,9 +1451,16 @@ impl IcrcAccount {
         // The subaccount is the part after the dot
         let subaccount_hex = parts[1];
 
+        // Leading zeros are dropped from the text form, pad back to whole bytes
+        let padded_hex = if subaccount_hex.len() % 2 == 1 {
+            format!("0{}", subaccount_hex)
+        } else {
+            subaccount_hex.to_string()
+        };
+
         // Decode the subaccount hex
         let mut subaccount = [0; 32];
-        let decoded = hex::decode(subaccount_hex).map_err(|e| {
+        let decoded = hex::decode(padded_hex).map_err(|e| {
             let error_msg = format!(
                 "Invalid subaccount hex encoding '{}': {}",
                 subaccount_hex, e